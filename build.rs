@@ -1,11 +1,43 @@
 extern crate rustc_version;
 
+use std::env;
+
 fn main() {
     check_rustc_version();
+    emit_lua_version();
 }
 
 fn check_rustc_version() {
+    println!("cargo:rustc-check-cfg=cfg(rust_nightly)");
     if let rustc_version::Channel::Nightly = rustc_version::version_meta().unwrap().channel {
         println!("cargo:rustc-cfg=rust_nightly");
     }
 }
+
+/// Emits the `LUA_VERSION` cfgs for the Lua version found by lua-sys,
+/// with values from major.0 to major.minor.
+fn emit_lua_version() {
+    println!("cargo:rustc-check-cfg=cfg(LUA_VERSION, values(\"5.0\", \"5.1\", \"5.2\", \"5.3\"))");
+
+    let version = env::var("DEP_LUA_VERSION").expect("lua-sys did not provide the Lua version");
+    let mut split = version.split('.');
+    let major: u32 = split
+        .next()
+        .and_then(|v| v.parse().ok())
+        .expect("invalid Lua major");
+    let minor: u32 = split
+        .next()
+        .and_then(|v| v.parse().ok())
+        .expect("invalid Lua minor");
+    // lua-sys can bind older versions, this crate uses functions added in Lua 5.3
+    if (major, minor) < (5, 3) {
+        panic!(
+            "pollua requires Lua 5.3, but lua-sys was built against Lua {}.{}",
+            major, minor
+        );
+    }
+
+    for m in 0..=minor {
+        println!("cargo:rustc-cfg=LUA_VERSION=\"{}.{}\"", major, m);
+    }
+}
@@ -8,7 +8,7 @@ use std::io::{self, BufRead, Write};
 fn main() {
     Thread::spawn(move |thread| {
         // load the standard libraries
-        unsafe { sys::luaL_openlibs(thread.as_raw().as_ptr()) };
+        thread.open_libs().unwrap();
 
         let stdin = io::stdin();
         let mut stdout = io::stdout();
@@ -7,11 +7,20 @@ pub fn configure(mut config: LuaConfig) {
 
     let bitsint = (0 as libc::c_int).count_zeros();
 
-    let bits32 = config.emit_if_defined("LUA_32BITS");
-    let c89_numbers = config.emit_if_defined("LUA_C89_NUMBERS");
-    config.emit_if_defined("LUA_USE_C89");
-    config.emit_if_defined("LUA_NOCVTN2S");
-    config.emit_if_defined("LUA_NOCVTS2N");
+    let bits32 = config.emit_if_enabled("LUA_32BITS", cfg!(feature = "lua-32bits"));
+    let c89_numbers = config.emit_if_enabled("LUA_C89_NUMBERS", cfg!(feature = "lua-c89-numbers"));
+    config.emit_if_enabled("LUA_USE_C89", cfg!(feature = "lua-use-c89"));
+    config.emit_if_enabled("LUA_NOCVTN2S", cfg!(feature = "lua-nocvtn2s"));
+    config.emit_if_enabled("LUA_NOCVTS2N", cfg!(feature = "lua-nocvts2n"));
+    #[cfg(feature = "system-lua")]
+    {
+        if bits32 || c89_numbers {
+            println!(
+                "cargo:warning=the Lua number types must match the configuration \
+                 of the system Lua library"
+            );
+        }
+    }
 
     let mut int_type;
     let mut float_type;
@@ -31,6 +40,24 @@ pub fn configure(mut config: LuaConfig) {
         float_type = Some("LUA_FLOAT_DOUBLE".to_string());
     }
 
+    // luaconf.h sets the number types itself when LUA_32BITS or LUA_C89_NUMBERS is defined
+    let derived = (int_type.clone(), float_type.clone());
+
+    match (cfg!(feature = "lua-int32"), cfg!(feature = "lua-int64")) {
+        (true, true) => panic!("features lua-int32 and lua-int64 cannot be enabled together"),
+        (true, false) if bitsint >= 32 => int_type = Some("LUA_INT_INT".to_string()),
+        (true, false) => int_type = Some("LUA_INT_LONG".to_string()),
+        (false, true) => int_type = Some("LUA_INT_LONGLONG".to_string()),
+        (false, false) => (),
+    }
+
+    match (cfg!(feature = "lua-float32"), cfg!(feature = "lua-float64")) {
+        (true, true) => panic!("features lua-float32 and lua-float64 cannot be enabled together"),
+        (true, false) => float_type = Some("LUA_FLOAT_FLOAT".to_string()),
+        (false, true) => float_type = Some("LUA_FLOAT_DOUBLE".to_string()),
+        (false, false) => (),
+    }
+
     if let Some(v) = config.env("LUA_INT_TYPE") {
         if v != "LUA_INT_INT" && v != "LUA_INT_LONG" && v != "LUA_INT_LONGLONG" {
             panic!(
@@ -51,13 +78,45 @@ pub fn configure(mut config: LuaConfig) {
         float_type = Some(v);
     }
 
+    if (bits32 || c89_numbers) && (int_type.clone(), float_type.clone()) != derived {
+        panic!(
+            "the number types set by LUA_32BITS or LUA_C89_NUMBERS cannot be changed \
+             with LUA_INT_TYPE, LUA_FLOAT_TYPE or the lua-int*/lua-float* features"
+        );
+    }
+
     config.emit("LUA_INT_TYPE", int_type.as_ref().map(|x| &**x));
     config.emit("LUA_FLOAT_TYPE", float_type.as_ref().map(|x| &**x));
 
     emit_lua_version(&mut config);
 }
 
-const EMBEDDED_VERSION: (u32, u32, u32) = (5, 3, 5);
+/// Returns the version of the embedded Lua sources, read from `embedded/lua.h`.
+pub fn embedded_version() -> (u32, u32, u32) {
+    use std::fs;
+
+    println!("cargo:rerun-if-changed=embedded/lua.h");
+    let header = fs::read_to_string("embedded/lua.h").expect("Could not read embedded/lua.h");
+    let define = |name: &str| -> u32 {
+        header
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next(), words.next()) {
+                    (Some("#define"), Some(n), Some(v)) if n == name => Some(v),
+                    _ => None,
+                }
+            })
+            .next()
+            .and_then(|v| v.trim_matches('"').parse().ok())
+            .unwrap_or_else(|| panic!("missing {} in embedded/lua.h", name))
+    };
+    (
+        define("LUA_VERSION_MAJOR"),
+        define("LUA_VERSION_MINOR"),
+        define("LUA_VERSION_RELEASE"),
+    )
+}
 
 fn emit_lua_version(config: &mut LuaConfig) {
     use std::fs::File;
@@ -67,40 +126,44 @@ fn emit_lua_version(config: &mut LuaConfig) {
 
     let path = Path::new(&env::var("OUT_DIR").unwrap()).join("lua_version.rs");
     let version_str = config.env("LUA_VERSION").or_else(|| config.version.clone());
+    let embedded = embedded_version();
 
     let (major, minor, patch) = match version_str {
         Some(v) => {
             let version = parse_lua_version(&v);
-            #[cfg(feature = "system-lua")]
+            #[cfg(not(feature = "system-lua"))]
             {
                 println!(
                     "cargo:warning=Tried to override LUA_VERSION of embedded Lua from {}.{}.{} to {}.{}.{}",
-                    EMBEDDED_VERSION.0,
-                    EMBEDDED_VERSION.1,
-                    EMBEDDED_VERSION.2,
+                    embedded.0,
+                    embedded.1,
+                    embedded.2,
                     version.0,
                     version.1,
                     version.2,
                 );
-                EMBEDDED_VERSION
+                embedded
             }
-            #[cfg(not(feature = "system-lua"))]
+            #[cfg(feature = "system-lua")]
             {
                 println!("Lua version: {}.{}.{}", version.0, version.1, version.2);
                 version
             }
         }
-        None => EMBEDDED_VERSION,
+        None => embedded,
     };
 
     if major != 5 {
         panic!("LUA_VERSION major must be equal to 5");
     }
 
+    println!("cargo:rustc-check-cfg=cfg(LUA_VERSION, values(\"5.0\", \"5.1\", \"5.2\", \"5.3\"))");
     // emits LUA_VERSION with values from major.0 to major.minor
     for m in 0..=minor {
         println!("cargo:rustc-cfg=LUA_VERSION=\"{}.{}\"", major, m);
     }
+    // exposes the version to dependents as DEP_LUA_VERSION
+    println!("cargo:version={}.{}", major, minor);
 
     let mut out =
         BufWriter::new(File::create(&path).expect(&format!("Could not create {}", path.display())));
@@ -143,14 +206,13 @@ fn parse_lua_version(version: &str) -> (u32, u32, u32) {
             .expect("missing minor in LUA_VERSION")
             .parse()
             .expect("invalid minor in LUA_VERSION"),
+        // pkg-config versions may omit the patch
         split
             .next()
-            .expect("missing patch in LUA_VERSION")
-            .parse()
-            .expect("invalid patch in LUA_VERSION"),
+            .map_or(0, |p| p.parse().expect("invalid patch in LUA_VERSION")),
     );
     if split.next().is_some() {
-        panic!("LUA_VERSION must be of format major.minor.patch");
+        panic!("LUA_VERSION must be of format major.minor[.patch]");
     }
     release
 }
@@ -204,11 +266,12 @@ impl LuaConfig<'_> {
     /// Gets the key and the prefix of an envionment variable.
     #[inline]
     pub fn env(&self, key: &str) -> Option<String> {
-        match &self.prefix {
-            Some(p) => env::var(format!("{}{}", p, key)),
-            None => env::var(key),
-        }
-        .ok()
+        let key = match &self.prefix {
+            Some(p) => format!("{}{}", p, key),
+            None => key.to_owned(),
+        };
+        println!("cargo:rerun-if-env-changed={}", key);
+        env::var(key).ok()
     }
 
     /// Emits a key-value pair as rust config and as a C define.
@@ -232,11 +295,9 @@ impl LuaConfig<'_> {
     }
 
     /// Emits the key as a rust config key and C define
-    /// if it is present in the environement.
-    /// Does not emit the irinal value of the env variable.
-    #[inline]
-    pub fn emit_if_defined(&mut self, key: &str) -> bool {
-        if self.env(key).is_some() {
+    /// if the matching cargo feature is `enabled` or if it is present in the environement.
+    pub fn emit_if_enabled(&mut self, key: &str, enabled: bool) -> bool {
+        if enabled || self.env(key).is_some() {
             self.emit(key, None);
             true
         } else {
@@ -102,7 +102,8 @@ fn use_embedded_lua() {
         ]
     );
 
-    cc_config.compile("liblua5.3.a");
+    let (major, minor, _) = luaconf::embedded_version();
+    cc_config.compile(&format!("liblua{}.{}.a", major, minor));
 }
 
 #[cfg(feature = "system-lua")]
@@ -127,24 +128,135 @@ fn find_vcpkg() -> bool {
     false
 }
 
+/// Lua version to link against, selected by the `LUA_SYS_VERSION` environment variable
+/// or by one of the `lua51`, `lua52` and `lua53` features.
+#[cfg(feature = "system-lua")]
+#[derive(Clone, Copy, PartialEq)]
+enum LuaSysVersion {
+    Lua51,
+    Lua52,
+    Lua53,
+    LuaJit,
+}
+
+#[cfg(feature = "system-lua")]
+impl LuaSysVersion {
+    /// Returns the selected version, or `None` to accept any supported version.
+    fn selected() -> Option<LuaSysVersion> {
+        println!("cargo:rerun-if-env-changed=LUA_SYS_VERSION");
+        let version = std::env::var("LUA_SYS_VERSION").unwrap_or_default();
+        if !version.trim().is_empty() {
+            return Some(match version.trim() {
+                "5.1" | "51" => LuaSysVersion::Lua51,
+                "5.2" | "52" => LuaSysVersion::Lua52,
+                "5.3" | "53" => LuaSysVersion::Lua53,
+                "luajit" | "LuaJIT" => LuaSysVersion::LuaJit,
+                v => panic!(
+                    "invalid LUA_SYS_VERSION {:?}, expected 5.1, 5.2, 5.3 or luajit",
+                    v
+                ),
+            });
+        }
+        if cfg!(feature = "lua53") {
+            Some(LuaSysVersion::Lua53)
+        } else if cfg!(feature = "lua52") {
+            Some(LuaSysVersion::Lua52)
+        } else if cfg!(feature = "lua51") {
+            Some(LuaSysVersion::Lua51)
+        } else {
+            None
+        }
+    }
+
+    /// The Lua language version, LuaJIT implements the Lua 5.1 API.
+    fn lua_version(self) -> &'static str {
+        match self {
+            LuaSysVersion::Lua51 | LuaSysVersion::LuaJit => "5.1",
+            LuaSysVersion::Lua52 => "5.2",
+            LuaSysVersion::Lua53 => "5.3",
+        }
+    }
+
+    /// pkg-config package names, in probing order.
+    fn candidates(self) -> &'static [&'static str] {
+        match self {
+            LuaSysVersion::Lua51 => &["lua5.1", "lua-5.1", "lua51", "lua"],
+            LuaSysVersion::Lua52 => &["lua5.2", "lua-5.2", "lua52", "lua"],
+            LuaSysVersion::Lua53 => &["lua5.3", "lua-5.3", "lua53", "lua"],
+            LuaSysVersion::LuaJit => &["luajit"],
+        }
+    }
+}
+
 /// Attempts to find the Lua package using pkg-config.
 ///
 /// panics if the package was not found.
 #[cfg(feature = "system-lua")]
 fn find_pkg_config(config: &mut LuaConfig) {
-    let candidates = ["lua5.3", "lua5.2", "lua5.1", "lua"];
+    let selected = LuaSysVersion::selected();
+    let candidates: Vec<(&str, LuaSysVersion)> = match selected {
+        Some(v) => v.candidates().iter().map(|c| (*c, v)).collect(),
+        None => [
+            LuaSysVersion::Lua53,
+            LuaSysVersion::Lua52,
+            LuaSysVersion::Lua51,
+            LuaSysVersion::LuaJit,
+        ]
+        .iter()
+        .flat_map(|v| v.candidates().iter().map(move |c| (*c, *v)))
+        .filter(|(c, v)| *c != "lua" || *v == LuaSysVersion::Lua53)
+        .collect(),
+    };
 
-    match candidates.iter().try_fold(None, |_, candidate| {
-        match pkg_config::Config::new().probe(candidate) {
-            Ok(lib) => Err(lib),
-            Err(e) => Ok(Some(e)),
-        }
-    }) {
-        Ok(err) => panic!("pkg-config did not find the lua package: {}", err.unwrap()),
-        Err(lib) => {
-            if !lib.version.is_empty() {
-                config.set_version(&lib.version);
+    let mut last_error = None;
+    for (candidate, version) in candidates {
+        // probe without emitting the link flags, the package may be of the wrong version
+        let lib = match pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe(candidate)
+        {
+            Ok(lib) => lib,
+            Err(e) => {
+                last_error = Some(e.to_string());
+                continue;
             }
+        };
+        let lua_version = if version == LuaSysVersion::LuaJit {
+            // the package version is the LuaJIT one
+            version.lua_version().to_owned()
+        } else if lib.version.is_empty() {
+            version.lua_version().to_owned()
+        } else {
+            lib.version.clone()
+        };
+        let matches = match selected {
+            Some(v) => {
+                v == LuaSysVersion::LuaJit
+                    || lua_version == v.lua_version()
+                    || lua_version.starts_with(&format!("{}.", v.lua_version()))
+            }
+            // the generic package is only accepted if it has a supported version
+            None => ["5.1", "5.2", "5.3"]
+                .iter()
+                .any(|v| lua_version == *v || lua_version.starts_with(&format!("{}.", v))),
+        };
+        if !matches {
+            last_error = Some(format!(
+                "package {} has version {}, expected {}",
+                candidate,
+                lua_version,
+                selected.map_or("5.1, 5.2 or 5.3", LuaSysVersion::lua_version)
+            ));
+            continue;
         }
+        pkg_config::Config::new()
+            .probe(candidate)
+            .expect("pkg-config failed to probe a package it has found");
+        config.set_version(&lua_version);
+        return;
     }
+    panic!(
+        "pkg-config did not find the lua package: {}",
+        last_error.unwrap_or_default()
+    );
 }
@@ -88,11 +88,11 @@ pub struct luaL_Reg {
 
 #[repr(C)]
 #[derive(Debug, Clone)]
-struct luaL_Stream {
+pub struct luaL_Stream {
     /// Stream (NULL for incompletely created streams)
-    f: *mut libc::FILE,
+    pub f: *mut libc::FILE,
     /// To close stream (NULL for closed streams)
-    closef: lua_CFunction,
+    pub closef: lua_CFunction,
 }
 
 // //////////////////////////////////////////// //
@@ -408,8 +408,8 @@ pub unsafe fn luaL_checklong(L: *mut lua_State, arg: libc::c_int) -> libc::c_lon
 }
 
 #[inline]
-pub unsafe fn luaL_checkstring(L: *mut lua_State, arg: libc::c_int) {
-    luaL_checklstring(L, arg, ptr::null_mut());
+pub unsafe fn luaL_checkstring(L: *mut lua_State, arg: libc::c_int) -> *const libc::c_char {
+    luaL_checklstring(L, arg, ptr::null_mut())
 }
 
 #[inline]
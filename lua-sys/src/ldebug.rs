@@ -4,23 +4,43 @@ use crate::*;
 // Lua types                                    //
 // //////////////////////////////////////////// //
 
-#[repr(C)]
-pub struct lua_Debug {
-    pub event: libc::c_int,
-    pub name: *const libc::c_char,
-    pub namewhat: *const libc::c_char,
-    pub what: *const libc::c_char,
-    pub source: *const libc::c_char,
-    pub currentline: libc::c_int,
-    pub linedefined: libc::c_int,
-    pub lastlinedefined: libc::c_int,
-    pub nups: libc::c_uchar,
-    pub nparams: libc::c_uchar,
-    pub isvararg: libc::c_char,
-    pub istailcall: libc::c_char,
-    pub short_src: [libc::c_char; LUA_IDSIZE],
-    /* private part */
-    _private: private::lua_Debug,
+cfg_if::cfg_if! {
+    if #[cfg(LUA_VERSION = "5.2")] {
+        #[repr(C)]
+        pub struct lua_Debug {
+            pub event: libc::c_int,
+            pub name: *const libc::c_char,
+            pub namewhat: *const libc::c_char,
+            pub what: *const libc::c_char,
+            pub source: *const libc::c_char,
+            pub currentline: libc::c_int,
+            pub linedefined: libc::c_int,
+            pub lastlinedefined: libc::c_int,
+            pub nups: libc::c_uchar,
+            pub nparams: libc::c_uchar,
+            pub isvararg: libc::c_char,
+            pub istailcall: libc::c_char,
+            pub short_src: [libc::c_char; LUA_IDSIZE],
+            /* private part */
+            _private: private::lua_Debug,
+        }
+    } else {
+        #[repr(C)]
+        pub struct lua_Debug {
+            pub event: libc::c_int,
+            pub name: *const libc::c_char,
+            pub namewhat: *const libc::c_char,
+            pub what: *const libc::c_char,
+            pub source: *const libc::c_char,
+            pub currentline: libc::c_int,
+            pub nups: libc::c_int,
+            pub linedefined: libc::c_int,
+            pub lastlinedefined: libc::c_int,
+            pub short_src: [libc::c_char; LUA_IDSIZE],
+            /* private part */
+            _private: private::lua_Debug,
+        }
+    }
 }
 
 pub type lua_Hook = Option<unsafe extern "C" fn(L: *mut lua_State, ar: *mut lua_Debug)>;
@@ -29,11 +49,18 @@ pub type lua_Hook = Option<unsafe extern "C" fn(L: *mut lua_State, ar: *mut lua_
 mod private {
     use super::*;
 
+    #[cfg(LUA_VERSION = "5.2")]
     #[repr(C)]
     pub struct lua_Debug {
         i_ci: *mut CallInfo,
     }
 
+    #[cfg(not(LUA_VERSION = "5.2"))]
+    #[repr(C)]
+    pub struct lua_Debug {
+        i_ci: libc::c_int,
+    }
+
     #[repr(C)]
     struct CallInfo {
         _private: [u8; 0],
@@ -75,8 +102,10 @@ extern "C" {
         funcindex: libc::c_int,
         n: libc::c_int,
     ) -> *const libc::c_char;
+    #[cfg(LUA_VERSION = "5.2")]
     pub fn lua_upvalueid(L: *mut lua_State, fidx: libc::c_int, n: libc::c_int)
         -> *mut libc::c_void;
+    #[cfg(LUA_VERSION = "5.2")]
     pub fn lua_upvaluejoin(
         L: *mut lua_State,
         fidx1: libc::c_int,
@@ -19,7 +19,7 @@ pub const LUA_UTF8LIBNAME: &str = "utf8";
 pub const LUA_BITLIBNAME: &str = "bit32";
 
 pub const LUA_MATHLIBNAME: &str = "math";
-pub const LUA_DBLIBNAME: &str = "db";
+pub const LUA_DBLIBNAME: &str = "debug";
 pub const LUA_LOADLIBNAME: &str = "package";
 
 // //////////////////////////////////////////// //
@@ -1,6 +1,9 @@
 extern crate lua_sys;
+mod common;
 
+use common::*;
 use lua_sys::*;
+use std::ffi::CStr;
 use std::mem::MaybeUninit;
 
 #[test]
@@ -29,3 +32,56 @@ fn test_buffers() {
         lua_close(state);
     }
 }
+
+#[test]
+fn test_references() {
+    run_thread(|l| unsafe {
+        lua_pushinteger(l, 42);
+        let r = luaL_ref(l, LUA_REGISTRYINDEX);
+        assert_ne!(r, LUA_NOREF);
+        assert_eq!(lua_gettop(l), 0);
+
+        assert_eq!(
+            lua_rawgeti(l, LUA_REGISTRYINDEX, r as lua_Integer),
+            LUA_TNUMBER
+        );
+        assert_eq!(lua_tointeger(l, -1), 42);
+        lua_pop(l, 1);
+
+        luaL_unref(l, LUA_REGISTRYINDEX, r);
+        lua_pushnil(l);
+        assert_eq!(luaL_ref(l, LUA_REGISTRYINDEX), LUA_REFNIL);
+    });
+}
+
+#[test]
+fn test_checkstring() {
+    unsafe extern "C" fn first_char(l: *mut lua_State) -> libc::c_int {
+        let s = luaL_checkstring(l, 1);
+        lua_pushinteger(l, *s as lua_Integer);
+        1
+    }
+
+    run_thread(|l| unsafe {
+        lua_pushcfunction(l, Some(first_char));
+        lua_pushliteral(l, cstr(b"A\0"));
+        assert_eq!(lua_pcall(l, 1, 1, 0), LUA_OK);
+        assert_eq!(lua_tointeger(l, -1), b'A' as lua_Integer);
+        lua_pop(l, 1);
+
+        lua_pushcfunction(l, Some(first_char));
+        lua_pushnil(l);
+        assert_eq!(lua_pcall(l, 1, 1, 0), LUA_ERRRUN);
+        lua_pop(l, 1);
+    });
+}
+
+#[test]
+fn test_traceback() {
+    run_thread(|l| unsafe {
+        luaL_traceback(l, l, cstr(b"message\0"), 0);
+        let trace = CStr::from_ptr(lua_tostring(l, -1)).to_string_lossy();
+        assert!(trace.starts_with("message\nstack traceback:"));
+        lua_pop(l, 1);
+    });
+}
@@ -86,3 +86,19 @@ fn test_call_cfunction() {
         lua_pop(l, 2);
     });
 }
+
+#[test]
+fn test_number_types() {
+    run_thread(|l| unsafe {
+        luaL_openlibs(l);
+        // the sizes of the Rust aliases must match the types Lua was compiled with
+        let chunk = cstr(b"return math.maxinteger, string.pack('n', 0):len()\0");
+        assert_eq!(luaL_dostring(l, chunk), 0);
+        assert_eq!(lua_tointeger(l, 1), lua_Integer::MAX);
+        assert_eq!(
+            lua_tointeger(l, 2) as usize,
+            std::mem::size_of::<lua_Number>()
+        );
+        lua_pop(l, 2);
+    });
+}
@@ -0,0 +1,67 @@
+extern crate lua_sys;
+mod common;
+
+use common::*;
+use lua_sys::*;
+use std::{cell::RefCell, ffi::CStr, mem};
+
+#[test]
+fn test_getinfo() {
+    unsafe extern "C" fn where_(l: *mut lua_State) -> libc::c_int {
+        let mut ar: lua_Debug = mem::zeroed();
+        // level 1 is the Lua function calling this one
+        assert_eq!(lua_getstack(l, 1, &mut ar), 1);
+        assert_ne!(lua_getinfo(l, cstr(b"Sl\0"), &mut ar), 0);
+        lua_pushinteger(l, ar.currentline as lua_Integer);
+        lua_pushstring(l, ar.short_src.as_ptr());
+        lua_pushstring(l, ar.what);
+        3
+    }
+
+    run_thread(|l| unsafe {
+        lua_pushcfunction(l, Some(where_));
+        lua_setglobal(l, cstr(b"where\0"));
+        let chunk = b"\n\nreturn where()\0";
+        assert_eq!(
+            luaL_loadbufferx(
+                l,
+                chunk.as_ptr() as *const _,
+                chunk.len() - 1,
+                cstr(b"=chunk\0"),
+                std::ptr::null()
+            ),
+            LUA_OK
+        );
+        assert_eq!(lua_pcall(l, 0, 3, 0), LUA_OK);
+        assert_eq!(lua_tointeger(l, 1), 3);
+        assert_eq!(CStr::from_ptr(lua_tostring(l, 2)).to_bytes(), b"chunk");
+        assert_eq!(CStr::from_ptr(lua_tostring(l, 3)).to_bytes(), b"main");
+        lua_pop(l, 3);
+    });
+}
+
+#[test]
+fn test_sethook() {
+    thread_local! {
+        static LINES: RefCell<Vec<libc::c_int>> = RefCell::new(Vec::new());
+    }
+
+    unsafe extern "C" fn hook(l: *mut lua_State, ar: *mut lua_Debug) {
+        assert_eq!((*ar).event, LUA_HOOKLINE);
+        LINES.with(|lines| lines.borrow_mut().push((*ar).currentline));
+        assert!(lua_gethook(l).is_some());
+    }
+
+    run_thread(|l| unsafe {
+        lua_sethook(l, Some(hook), LUA_MASKLINE, 0);
+        assert_eq!(lua_gethookmask(l), LUA_MASKLINE);
+        assert_eq!(lua_gethookcount(l), 0);
+        let chunk = b"local a = 1\nlocal b = 2\n\nlocal c = a + b\0";
+        assert_eq!(luaL_loadstring(l, chunk.as_ptr() as *const _), LUA_OK);
+        assert_eq!(lua_pcall(l, 0, 0, 0), LUA_OK);
+        LINES.with(|lines| assert_eq!(*lines.borrow(), [1, 2, 4]));
+
+        lua_sethook(l, None, 0, 0);
+        assert!(lua_gethook(l).is_none());
+    });
+}
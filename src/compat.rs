@@ -0,0 +1,108 @@
+//! Best-effort conversions for code migrating from or interoperating with `mlua`.
+//!
+//! [`CompatValue`] has the same variants as the plain data variants of `mlua::Value`, so
+//! values can be moved between the two bindings with a simple `match` on the `mlua` side,
+//! without any of them depending on the other.
+//! Values tied to a Lua state (tables, functions, userdata and threads) cannot be moved
+//! between states and have no compatible representation.
+
+use crate::{value::LuaValue, Integer, Number};
+use std::convert::TryFrom;
+
+/// A Lua value that does not belong to any Lua state, with the same variants as the
+/// plain data variants of `mlua::Value`.
+///
+/// # Examples
+/// ```
+/// use pollua::{compat::CompatValue, value::LuaValue};
+/// use std::convert::TryFrom;
+///
+/// let value = LuaValue::from(CompatValue::Integer(42));
+/// assert_eq!(CompatValue::try_from(value).unwrap(), CompatValue::Integer(42));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompatValue {
+    Nil,
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    /// A Lua string, which may not be valid UTF-8.
+    String(Vec<u8>),
+}
+
+impl From<CompatValue> for LuaValue {
+    fn from(value: CompatValue) -> LuaValue {
+        match value {
+            CompatValue::Nil => LuaValue::Nil,
+            CompatValue::Boolean(b) => LuaValue::Boolean(b),
+            CompatValue::Integer(n) => LuaValue::Integer(n),
+            CompatValue::Number(n) => LuaValue::Number(n),
+            CompatValue::String(s) => LuaValue::String(s),
+        }
+    }
+}
+
+impl TryFrom<LuaValue> for CompatValue {
+    /// The value itself, for values tied to a Lua state and light userdata.
+    type Error = LuaValue;
+
+    fn try_from(value: LuaValue) -> Result<CompatValue, LuaValue> {
+        match value {
+            LuaValue::Nil => Ok(CompatValue::Nil),
+            LuaValue::Boolean(b) => Ok(CompatValue::Boolean(b)),
+            LuaValue::Integer(n) => Ok(CompatValue::Integer(n)),
+            LuaValue::Number(n) => Ok(CompatValue::Number(n)),
+            LuaValue::String(s) => Ok(CompatValue::String(s)),
+            value => Err(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::LoadingMode,
+        value::{FromLua, ValueType},
+        Thread,
+    };
+
+    #[test]
+    fn test_compat_value() {
+        Thread::spawn(move |thread| {
+            let mut values = thread
+                .caller_load(
+                    "return nil, true, 3, 0.5, 'a\\0b', {}",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call())
+                .unwrap();
+            let mut converted = Vec::new();
+            for i in 0..5 {
+                converted
+                    .push(CompatValue::try_from(values.value::<LuaValue>(i).unwrap()).unwrap());
+            }
+            assert_eq!(
+                converted,
+                [
+                    CompatValue::Nil,
+                    CompatValue::Boolean(true),
+                    CompatValue::Integer(3),
+                    CompatValue::Number(0.5),
+                    CompatValue::String(b"a\0b".to_vec()),
+                ]
+            );
+            let table = values.value::<LuaValue>(5).unwrap();
+            let err = CompatValue::try_from(table).unwrap_err();
+            assert_eq!(err.value_type(), ValueType::Table);
+            drop(values);
+
+            thread
+                .push(LuaValue::from(CompatValue::String(b"text".to_vec())))
+                .unwrap();
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "text");
+        })
+        .unwrap()
+    }
+}
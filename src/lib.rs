@@ -1,12 +1,16 @@
 //! High level bindings to Lua 5.3
 
-#![cfg_attr(rust_nightly, feature(non_exhaustive))]
-
 extern crate libc;
 pub extern crate lua_sys as sys;
 
 use std::{error, fmt, ptr};
 
+/// Conversions for `mlua` users, requires the `compat-mlua` feature.
+#[cfg(feature = "compat-mlua")]
+pub mod compat;
+/// Serde support, requires the `serde` feature.
+#[cfg(feature = "serde")]
+pub mod serde;
 /// Lua thread API.
 pub mod thread;
 /// Useful functions.
@@ -16,6 +20,13 @@ pub mod value;
 
 pub use thread::Thread;
 
+/// The type of floating-point numbers in Lua (`lua_Number`).
+pub type Number = sys::lua_Number;
+
+/// The type of integers in Lua (`lua_Integer`).
+/// Its width depends on the configuration of the Lua core.
+pub type Integer = sys::lua_Integer;
+
 /// Returns the version number stored in the Lua core.
 ///
 /// # Examples
@@ -23,7 +34,7 @@ pub use thread::Thread;
 /// println!("Lua version number: {}", pollua::lua_version());
 /// ```
 #[inline]
-pub fn lua_version() -> sys::lua_Number {
+pub fn lua_version() -> Number {
     unsafe { *sys::lua_version(ptr::null_mut()) }
 }
 
@@ -32,6 +43,10 @@ pub fn lua_version() -> sys::lua_Number {
 pub struct Error {
     kind: ErrorKind,
     msg: Option<String>,
+    traceback: Option<String>,
+    source_name: Option<String>,
+    line: Option<u32>,
+    versions: Option<(Number, Number)>,
 }
 
 /// A list specifying categories of Lua errors.
@@ -39,23 +54,59 @@ pub struct Error {
 ///
 /// [`Error`]: struct.Error.html
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-#[cfg_attr(rust_nightly, non_exhaustive)]
+#[non_exhaustive]
 pub enum ErrorKind {
     Runtime,
     Syntax,
     OutOfMemory,
     MessageHandler,
     GarbageCollection,
+    /// An error while reading a chunk from a reader.
     Io,
-    #[doc(hidden)]
-    #[cfg(not(rust_nightly))]
-    _NonExhaustive,
+    /// The file of a chunk could not be opened or read (`LUA_ERRFILE`).
+    FileLoad,
+    Yield,
+    /// The call exceeded its instruction limit, see [`Caller::call_limited`].
+    ///
+    /// Lua has no status code for it: it is only set by the hooks of this crate, so it
+    /// tells an aborted call apart from an error raised by the script.
+    ///
+    /// [`Caller::call_limited`]: thread/struct.Caller.html#method.call_limited
+    Timeout,
+    /// The call was cancelled from outside of the script, see [`Caller::call_cancellable`].
+    ///
+    /// Like [`Timeout`], it is only set by the hooks of this crate.
+    ///
+    /// [`Caller::call_cancellable`]: thread/struct.Caller.html#method.call_cancellable
+    /// [`Timeout`]: #variant.Timeout
+    Interrupted,
+    /// The Lua core does not have the version this crate was built for,
+    /// see [`Error::versions`].
+    ///
+    /// [`Error::versions`]: struct.Error.html#method.versions
+    Version,
+    /// A status code unknown to this version of the crate, holding the code.
+    Unknown(libc::c_int),
 }
 
 impl Error {
     #[inline]
     fn new(kind: ErrorKind, msg: Option<String>) -> Error {
-        Error { kind, msg }
+        Error {
+            kind,
+            msg,
+            traceback: None,
+            source_name: None,
+            line: None,
+            versions: None,
+        }
+    }
+
+    /// Creates a runtime error with the given message,
+    /// for instance to raise from a Rust function or a hook.
+    #[inline]
+    pub fn runtime<S: Into<String>>(msg: S) -> Error {
+        Error::new(ErrorKind::Runtime, Some(msg.into()))
     }
 
     /// Returns the corresponding `ErrorKind` for this error.
@@ -64,36 +115,110 @@ impl Error {
         self.kind
     }
 
-    /// Returns the message associated with this error.
+    /// Returns the message associated with this error, without the traceback.
     #[inline]
     pub fn msg(&self) -> Option<&str> {
-        self.msg.as_ref().map(|m| &**m)
+        self.msg.as_deref()
+    }
+
+    /// Returns the Lua stack traceback of this error,
+    /// only present if the traceback message handler was enabled when the error was raised.
+    #[inline]
+    pub fn traceback(&self) -> Option<&str> {
+        self.traceback.as_deref()
+    }
+
+    /// Returns the name of the chunk that failed to load, as given when loading it:
+    /// the chunk name passed to [`Thread::caller_load`] or the path of a file.
+    /// It is `None` for errors that are not load errors, or if no chunk name was given.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let err = thread
+    ///         .caller_load("x =", Some("=config.lua"), LoadingMode::Text)
+    ///         .unwrap_err();
+    ///     assert_eq!(err.source_name(), Some("=config.lua"));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::caller_load`]: thread/struct.Thread.html#method.caller_load
+    #[inline]
+    pub fn source_name(&self) -> Option<&str> {
+        self.source_name.as_deref()
+    }
+
+    /// Returns the line of the chunk where loading failed, read from the
+    /// `chunkname:line: message` format of syntax errors.
+    /// It is `None` for errors that are not load errors or whose message has another format.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let err = thread
+    ///         .caller_load("x = 1\ny =", Some("=config.lua"), LoadingMode::Text)
+    ///         .unwrap_err();
+    ///     assert_eq!(err.line(), Some(2));
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    /// Returns the expected and actual version numbers of an [`ErrorKind::Version`] error,
+    /// as checked by [`Thread::verify_version`].
+    ///
+    /// [`ErrorKind::Version`]: enum.ErrorKind.html#variant.Version
+    /// [`Thread::verify_version`]: thread/struct.Thread.html#method.verify_version
+    #[inline]
+    pub fn versions(&self) -> Option<(Number, Number)> {
+        self.versions
     }
 }
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        #[allow(unreachable_patterns)]
-        match self.kind {
+impl ErrorKind {
+    /// Returns a short description of the error kind.
+    fn as_str(self) -> &'static str {
+        match self {
             ErrorKind::Runtime => "runtime error",
             ErrorKind::Syntax => "syntax error",
             ErrorKind::OutOfMemory => "out of memory",
             ErrorKind::MessageHandler => "error while running the message handler",
             ErrorKind::GarbageCollection => "error while running a __gc metamethod",
             ErrorKind::Io => "IO error",
-            _ => "unknown error",
+            ErrorKind::FileLoad => "cannot load file",
+            ErrorKind::Yield => "thread yielded",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Version => "version mismatch",
+            ErrorKind::Unknown(_) => "unknown error",
         }
     }
-    fn cause(&self) -> Option<&dyn error::Error> {
-        None
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
+impl error::Error for Error {}
+
 impl fmt::Display for Error {
+    /// Formats the error as `<kind description>: <message>`, e.g. `runtime error: oops`,
+    /// followed by the traceback on a new line if present.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(error::Error::description(self))?;
-        match &self.msg {
-            Some(msg) => write!(f, ": {}", msg),
+        f.write_str(self.kind.as_str())?;
+        if let Some(msg) = &self.msg {
+            write!(f, ": {}", msg)?;
+        }
+        match &self.traceback {
+            Some(traceback) => write!(f, "\n{}", traceback),
             None => Ok(()),
         }
     }
@@ -101,3 +226,31 @@ impl fmt::Display for Error {
 
 /// The Lua result type
 pub type LuaResult<T> = Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::new(ErrorKind::Runtime, Some(String::from("oops")));
+        assert_eq!(err.to_string(), "runtime error: oops");
+        assert_eq!(
+            Error::new(ErrorKind::Syntax, None).to_string(),
+            "syntax error"
+        );
+        assert_eq!(ErrorKind::OutOfMemory.to_string(), "out of memory");
+        assert_eq!(ErrorKind::FileLoad.to_string(), "cannot load file");
+        assert_eq!(ErrorKind::Unknown(42).to_string(), "unknown error");
+        assert_eq!(ErrorKind::Interrupted.to_string(), "interrupted");
+        assert_eq!(ErrorKind::Version.to_string(), "version mismatch");
+
+        fn boxed() -> Result<(), Box<dyn error::Error + Send + Sync>> {
+            Err(Error::new(ErrorKind::Io, Some(String::from("no file"))))?;
+            Ok(())
+        }
+        let err = boxed().unwrap_err();
+        assert_eq!(err.to_string(), "IO error: no file");
+        assert_eq!(err.downcast_ref::<Error>().unwrap().kind(), ErrorKind::Io);
+    }
+}
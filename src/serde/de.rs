@@ -0,0 +1,608 @@
+use crate::{thread::Thread, Error, ErrorKind, LuaResult};
+use ::serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Unexpected, Visitor};
+use std::{ffi::CStr, slice, str};
+
+/// Deserializes the value at the given stack `index` into a `T`, the value is not popped.
+///
+/// Nil is read as `None` or as a unit value. Tables whose keys are exactly the integers
+/// `1..=n` are read as sequences when the type does not require a map or a struct,
+/// other tables are read as maps. Enum variants are read from their name if they hold
+/// no data, and from a table with a single `name = data` field otherwise.
+///
+/// Returns an error of kind [`ErrorKind::Runtime`] if the value does not match `T`.
+///
+/// # Examples
+/// ```
+/// use pollua::{thread::LoadingMode, Thread};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct Config {
+///     name: String,
+///     ports: Vec<u16>,
+///     timeout: Option<f64>,
+/// }
+///
+/// Thread::spawn(move |thread| {
+///     thread
+///         .caller_load("config = { name = 'server', ports = { 80, 443 } }", None, LoadingMode::Text)
+///         .and_then(|c| c.call().map(|_| ()))
+///         .unwrap();
+///     unsafe { pollua::sys::lua_getglobal(thread.as_raw().as_ptr(), b"config\0".as_ptr() as *const _) };
+///     let config: Config = pollua::serde::from_lua(thread, -1).unwrap();
+///     assert_eq!(config.name, "server");
+///     assert_eq!(config.ports, [80, 443]);
+///     assert!(config.timeout.is_none());
+/// }).unwrap()
+/// ```
+///
+/// [`ErrorKind::Runtime`]: ../enum.ErrorKind.html#variant.Runtime
+pub fn from_lua<T: DeserializeOwned>(thread: &mut Thread, index: libc::c_int) -> LuaResult<T> {
+    let top = thread.stack_len();
+    let result = T::deserialize(Deserializer::new(thread, index));
+    unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+    result
+}
+
+/// A serde deserializer reading a Lua value on the stack.
+/// Created by [`from_lua`].
+///
+/// [`from_lua`]: fn.from_lua.html
+#[derive(Debug)]
+pub struct Deserializer<'a> {
+    thread: &'a mut Thread,
+    /// Absolute stack index of the value.
+    index: libc::c_int,
+}
+
+impl<'a> Deserializer<'a> {
+    /// Creates a deserializer reading the value at the given stack `index`.
+    ///
+    /// On error, values may be left on the stack above the value, [`from_lua`] removes them.
+    ///
+    /// [`from_lua`]: fn.from_lua.html
+    #[inline]
+    pub fn new(thread: &'a mut Thread, index: libc::c_int) -> Deserializer<'a> {
+        let index = thread.abs_index(index);
+        Deserializer { thread, index }
+    }
+
+    #[inline]
+    fn ptr(&mut self) -> *mut sys::lua_State {
+        self.thread.as_raw().as_ptr()
+    }
+
+    #[inline]
+    fn type_code(&mut self) -> libc::c_int {
+        unsafe { sys::lua_type(self.ptr(), self.index) }
+    }
+
+    /// Returns the bytes of the string value, which must be a string.
+    fn bytes(&mut self) -> &[u8] {
+        unsafe {
+            let mut len = 0;
+            let s = sys::lua_tolstring(self.ptr(), self.index, &mut len);
+            // the string is kept alive by the stack while the thread is borrowed
+            slice::from_raw_parts(s as *const u8, len)
+        }
+    }
+
+    /// Returns the length of the table value if its keys are exactly `1..=n`.
+    fn sequence_len(&mut self) -> LuaResult<Option<usize>> {
+        self.thread.ensure_stack(2)?;
+        let ptr = self.ptr();
+        let mut count = 0;
+        let mut max = 0;
+        unsafe {
+            sys::lua_pushnil(ptr);
+            while sys::lua_next(ptr, self.index) != 0 {
+                // pop the value, keep the key for the next step
+                sys::lua_pop(ptr, 1);
+                let mut isint = 0;
+                let key = sys::lua_tointegerx(ptr, -1, &mut isint);
+                if sys::lua_type(ptr, -1) != sys::LUA_TNUMBER || isint == 0 || key < 1 {
+                    sys::lua_pop(ptr, 1);
+                    return Ok(None);
+                }
+                count += 1;
+                max = max.max(key);
+            }
+        }
+        // the keys are distinct, so they are `1..=count` if none is greater than `count`
+        Ok(if max == count as sys::lua_Integer {
+            Some(count)
+        } else {
+            None
+        })
+    }
+
+    /// Returns the name of the type of the value.
+    fn type_name(&mut self) -> String {
+        let name = unsafe { CStr::from_ptr(sys::luaL_typename(self.ptr(), self.index)) };
+        name.to_string_lossy().into_owned()
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident),*) => {
+        $(
+            /// Numbers with an exact integer representation are read as integers.
+            fn $method<V: Visitor<'de>>(mut self, visitor: V) -> LuaResult<V::Value> {
+                let mut isint = 0;
+                let n = unsafe { sys::lua_tointegerx(self.ptr(), self.index, &mut isint) };
+                if isint != 0 && self.type_code() == sys::LUA_TNUMBER {
+                    visitor.visit_i64(n as i64)
+                } else {
+                    self.deserialize_any(visitor)
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> LuaResult<V::Value> {
+        let ptr = self.ptr();
+        match self.type_code() {
+            sys::LUA_TNONE | sys::LUA_TNIL => visitor.visit_unit(),
+            sys::LUA_TBOOLEAN => {
+                visitor.visit_bool(unsafe { sys::lua_toboolean(ptr, self.index) } != 0)
+            }
+            #[cfg(LUA_VERSION = "5.3")]
+            sys::LUA_TNUMBER if unsafe { sys::lua_isinteger(ptr, self.index) } != 0 => {
+                visitor.visit_i64(unsafe { sys::lua_tointeger(ptr, self.index) } as i64)
+            }
+            sys::LUA_TNUMBER => {
+                visitor.visit_f64(unsafe { sys::lua_tonumber(ptr, self.index) } as f64)
+            }
+            sys::LUA_TSTRING => match str::from_utf8(self.bytes()) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(self.bytes()),
+            },
+            sys::LUA_TTABLE => match self.sequence_len()? {
+                Some(len) => visitor.visit_seq(SeqAccess::new(self.thread, self.index, len)),
+                None => visitor.visit_map(MapAccess::new(self.thread, self.index)),
+            },
+            _ => {
+                let msg = format!("cannot deserialize a {} value", self.type_name());
+                Err(Error::new(ErrorKind::Runtime, Some(msg)))
+            }
+        }
+    }
+
+    deserialize_integer!(
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64
+    );
+
+    fn deserialize_option<V: Visitor<'de>>(mut self, visitor: V) -> LuaResult<V::Value> {
+        match self.type_code() {
+            sys::LUA_TNONE | sys::LUA_TNIL => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> LuaResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> LuaResult<V::Value> {
+        if self.type_code() == sys::LUA_TTABLE {
+            let len = unsafe { sys::lua_rawlen(self.ptr(), self.index) };
+            visitor.visit_seq(SeqAccess::new(self.thread, self.index, len))
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> LuaResult<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> LuaResult<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(mut self, visitor: V) -> LuaResult<V::Value> {
+        if self.type_code() == sys::LUA_TTABLE {
+            visitor.visit_map(MapAccess::new(self.thread, self.index))
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> LuaResult<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        mut self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> LuaResult<V::Value> {
+        match self.type_code() {
+            sys::LUA_TSTRING => {
+                let variant = String::from_utf8_lossy(self.bytes()).into_owned();
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            sys::LUA_TTABLE => {
+                self.thread.ensure_stack(2)?;
+                let ptr = self.ptr();
+                unsafe {
+                    sys::lua_pushnil(ptr);
+                    if sys::lua_next(ptr, self.index) == 0 {
+                        return Err(de::Error::invalid_value(
+                            Unexpected::Map,
+                            &"a table with a single field",
+                        ));
+                    }
+                    // the value must be the only one
+                    sys::lua_pushvalue(ptr, -2);
+                    if sys::lua_next(ptr, self.index) != 0 {
+                        return Err(de::Error::invalid_value(
+                            Unexpected::Map,
+                            &"a table with a single field",
+                        ));
+                    }
+                }
+                let top = self.thread.stack_len();
+                let result = visitor.visit_enum(EnumAccess {
+                    thread: self.thread,
+                    key: top - 1,
+                });
+                unsafe { sys::lua_settop(ptr, top - 2) };
+                result
+            }
+            _ => Err(de::Error::invalid_type(
+                Unexpected::Other(&self.type_name()),
+                &"a string or a table",
+            )),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> LuaResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string bytes byte_buf unit unit_struct identifier
+    }
+}
+
+/// Reads the elements `1..=len` of a table.
+struct SeqAccess<'a> {
+    thread: &'a mut Thread,
+    index: libc::c_int,
+    next: usize,
+    len: usize,
+}
+
+impl<'a> SeqAccess<'a> {
+    fn new(thread: &'a mut Thread, index: libc::c_int, len: usize) -> SeqAccess<'a> {
+        SeqAccess {
+            thread,
+            index,
+            next: 1,
+            len,
+        }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> LuaResult<Option<T::Value>> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+        self.thread.ensure_stack(1)?;
+        let ptr = self.thread.as_raw().as_ptr();
+        unsafe { sys::lua_rawgeti(ptr, self.index, self.next as sys::lua_Integer) };
+        self.next += 1;
+        let top = self.thread.stack_len();
+        let value = seed.deserialize(Deserializer {
+            thread: self.thread,
+            index: top,
+        })?;
+        unsafe { sys::lua_settop(ptr, top - 1) };
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len + 1 - self.next)
+    }
+}
+
+/// Reads the key-value pairs of a table with `lua_next`.
+/// The current key is kept on the stack between steps.
+struct MapAccess<'a> {
+    thread: &'a mut Thread,
+    index: libc::c_int,
+    /// Stack index of the current key, 0 before the first step.
+    key: libc::c_int,
+}
+
+impl<'a> MapAccess<'a> {
+    fn new(thread: &'a mut Thread, index: libc::c_int) -> MapAccess<'a> {
+        MapAccess {
+            thread,
+            index,
+            key: 0,
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> LuaResult<Option<K::Value>> {
+        self.thread.ensure_stack(3)?;
+        let ptr = self.thread.as_raw().as_ptr();
+        unsafe {
+            if self.key == 0 {
+                sys::lua_pushnil(ptr);
+                self.key = self.thread.stack_len();
+            }
+            // discard anything above the previous key
+            sys::lua_settop(ptr, self.key);
+            if sys::lua_next(ptr, self.index) == 0 {
+                return Ok(None);
+            }
+        }
+        // deserializing the key must not modify it, lua_next needs it as is
+        seed.deserialize(Deserializer {
+            thread: self.thread,
+            index: self.key,
+        })
+        .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> LuaResult<V::Value> {
+        let value = seed.deserialize(Deserializer {
+            thread: self.thread,
+            index: self.key + 1,
+        })?;
+        unsafe { sys::lua_settop(self.thread.as_raw().as_ptr(), self.key) };
+        Ok(value)
+    }
+}
+
+/// Reads an enum variant from a `{ name = data }` table,
+/// the key and the value are on top of the stack.
+struct EnumAccess<'a> {
+    thread: &'a mut Thread,
+    /// Stack index of the variant name.
+    key: libc::c_int,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> LuaResult<(V::Value, VariantAccess<'a>)> {
+        let variant = seed.deserialize(Deserializer {
+            thread: self.thread,
+            index: self.key,
+        })?;
+        Ok((
+            variant,
+            VariantAccess {
+                de: Deserializer {
+                    thread: self.thread,
+                    index: self.key + 1,
+                },
+            },
+        ))
+    }
+}
+
+/// Reads the data of an enum variant.
+struct VariantAccess<'a> {
+    de: Deserializer<'a>,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> LuaResult<()> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> LuaResult<T::Value> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> LuaResult<V::Value> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> LuaResult<V::Value> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        serde::to_lua,
+        thread::{LoadingMode, StdLib},
+    };
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        ports: Vec<u16>,
+        debug: bool,
+        timeout: Option<f64>,
+        modes: Vec<Mode>,
+        limits: BTreeMap<String, i64>,
+        point: (i32, i32),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Fast,
+        Custom { level: u8 },
+        Pair(u8, u8),
+        Named(String),
+    }
+
+    /// Evaluates `expr` and deserializes the result.
+    fn eval<T: DeserializeOwned>(thread: &mut Thread, expr: &str) -> LuaResult<T> {
+        thread
+            .caller_load(&format!("v = {}", expr), Some("=eval"), LoadingMode::Text)
+            .and_then(|c| c.call().map(|_| ()))
+            .unwrap();
+        let top = thread.stack_len();
+        unsafe { sys::lua_getglobal(thread.as_raw().as_ptr(), b"v\0".as_ptr() as *const _) };
+        let result = from_lua(thread, -1);
+        assert_eq!(thread.stack_len(), top + 1);
+        unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+        result
+    }
+
+    #[test]
+    fn test_from_lua() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let config: Config = eval(
+                thread,
+                "{ name = 'server', ports = { 80, 443 }, debug = true, \
+                   modes = { 'Fast', { Custom = { level = 3 } }, { Pair = { 1, 2 } }, \
+                             { Named = 'x' } }, \
+                   limits = { cpu = 10 }, point = { -1, 4 / 2 } }",
+            )
+            .unwrap();
+            assert_eq!(
+                config,
+                Config {
+                    name: String::from("server"),
+                    ports: vec![80, 443],
+                    debug: true,
+                    timeout: None,
+                    modes: vec![
+                        Mode::Fast,
+                        Mode::Custom { level: 3 },
+                        Mode::Pair(1, 2),
+                        Mode::Named(String::from("x")),
+                    ],
+                    limits: vec![(String::from("cpu"), 10)].into_iter().collect(),
+                    point: (-1, 2),
+                }
+            );
+
+            // round trip through the serializer
+            to_lua(thread, &config).unwrap();
+            let back: Config = from_lua(thread, -1).unwrap();
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+            assert_eq!(back, config);
+
+            assert_eq!(eval::<Option<i64>>(thread, "nil").unwrap(), None);
+            assert_eq!(
+                eval::<Vec<String>>(thread, "{}").unwrap(),
+                Vec::<String>::new()
+            );
+            assert_eq!(eval::<f64>(thread, "3").unwrap(), 3.0);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_lua_any() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(untagged)]
+        enum Any {
+            Int(i64),
+            Str(String),
+            List(Vec<Any>),
+            IntMap(BTreeMap<i64, Any>),
+            Map(BTreeMap<String, Any>),
+        }
+
+        Thread::spawn(move |thread| {
+            let value: Any = eval(thread, "{ 1, 'a', { x = 2 } }").unwrap();
+            let map = vec![(String::from("x"), Any::Int(2))].into_iter().collect();
+            assert_eq!(
+                value,
+                Any::List(vec![
+                    Any::Int(1),
+                    Any::Str(String::from("a")),
+                    Any::Map(map)
+                ])
+            );
+
+            // sparse arrays are maps
+            let value: Any = eval(thread, "{ [1] = 'a', [3] = 'b' }").unwrap();
+            let map = vec![
+                (1, Any::Str(String::from("a"))),
+                (3, Any::Str(String::from("b"))),
+            ];
+            assert_eq!(value, Any::IntMap(map.into_iter().collect()));
+            let value: Any = eval(thread, "{ [0] = 'a' }").unwrap();
+            assert!(matches!(value, Any::IntMap(_)));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_lua_errors() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let err = eval::<Config>(thread, "{ name = 'x' }").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("missing field `ports`"));
+
+            let err = eval::<Vec<u8>>(thread, "{ 1, 'two' }").unwrap_err();
+            assert_eq!(err.msg(), Some("invalid type: string \"two\", expected u8"));
+            let err = eval::<u8>(thread, "1.5").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("invalid type: floating point `1.5`, expected u8")
+            );
+            let err = eval::<Mode>(thread, "{ Fast = 1, Pair = 2 }").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("invalid value: map, expected a table with a single field")
+            );
+            let err = eval::<String>(thread, "print").unwrap_err();
+            assert_eq!(err.msg(), Some("cannot deserialize a function value"));
+        })
+        .unwrap()
+    }
+}
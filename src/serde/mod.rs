@@ -0,0 +1,24 @@
+//! Conversions between Rust values and Lua values using serde.
+
+mod de;
+mod ser;
+
+pub use self::{
+    de::{from_lua, Deserializer},
+    ser::{to_lua, Serializer},
+};
+
+use crate::{Error, ErrorKind};
+use std::fmt::Display;
+
+impl ::serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(ErrorKind::Runtime, Some(msg.to_string()))
+    }
+}
+
+impl ::serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::new(ErrorKind::Runtime, Some(msg.to_string()))
+    }
+}
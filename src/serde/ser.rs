@@ -0,0 +1,563 @@
+use crate::{thread::Thread, Error, ErrorKind, Integer, LuaResult, Number};
+use ::serde::ser::{self, Serialize};
+use std::convert::TryFrom;
+
+/// Serializes `value` and pushes the result onto the stack of `thread`.
+///
+/// Structs and maps become tables with their fields or keys, sequences and tuples
+/// become 1-based array tables, and `None` and unit values become nil.
+/// Enum variants are pushed as their name if they hold no data,
+/// and as a table with a single `name = data` field otherwise.
+///
+/// Nothing is pushed if an error occurs.
+///
+/// # Examples
+/// ```
+/// use pollua::{thread::LoadingMode, Thread};
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// struct Config {
+///     name: String,
+///     ports: Vec<u16>,
+/// }
+///
+/// Thread::spawn(move |thread| {
+///     let config = Config { name: "server".into(), ports: vec![80, 443] };
+///     pollua::serde::to_lua(thread, &config).unwrap();
+///     unsafe { pollua::sys::lua_setglobal(thread.as_raw().as_ptr(), b"config\0".as_ptr() as *const _) };
+///
+///     let port: i64 = thread
+///         .caller_load("return config.ports[2]", None, LoadingMode::Text)
+///         .and_then(|c| c.call_ret())
+///         .unwrap();
+///     assert_eq!(port, 443);
+/// }).unwrap()
+/// ```
+pub fn to_lua<T: Serialize + ?Sized>(thread: &mut Thread, value: &T) -> LuaResult<()> {
+    let top = thread.stack_len();
+    let result = value.serialize(Serializer { thread });
+    if result.is_err() {
+        unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+    }
+    result
+}
+
+/// A serde serializer pushing a single Lua value onto the stack.
+/// Created by [`to_lua`].
+///
+/// [`to_lua`]: fn.to_lua.html
+#[derive(Debug)]
+pub struct Serializer<'a> {
+    thread: &'a mut Thread,
+}
+
+impl<'a> Serializer<'a> {
+    /// Creates a serializer pushing onto the stack of `thread`.
+    ///
+    /// On error, the values pushed so far are left on the stack, [`to_lua`] removes them.
+    ///
+    /// [`to_lua`]: fn.to_lua.html
+    #[inline]
+    pub fn new(thread: &'a mut Thread) -> Serializer<'a> {
+        Serializer { thread }
+    }
+
+    #[inline]
+    fn ptr(&mut self) -> *mut sys::lua_State {
+        self.thread.as_raw().as_ptr()
+    }
+
+    /// Pushes a new table, with an outer `{ variant = table }` table for enum variants.
+    fn begin_table(
+        self,
+        variant: Option<&'static str>,
+        narr: usize,
+        nrec: usize,
+    ) -> LuaResult<SerializeTable<'a>> {
+        self.thread.ensure_stack(3)?;
+        let ptr = self.thread.as_raw().as_ptr();
+        unsafe {
+            if let Some(variant) = variant {
+                sys::lua_createtable(ptr, 0, 1);
+                push_str(ptr, variant);
+            }
+            sys::lua_createtable(ptr, clamp(narr), clamp(nrec));
+        }
+        Ok(SerializeTable {
+            thread: self.thread,
+            len: 0,
+            variant: variant.is_some(),
+        })
+    }
+}
+
+#[inline]
+fn clamp(n: usize) -> libc::c_int {
+    n.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+#[inline]
+unsafe fn push_str(ptr: *mut sys::lua_State, s: &str) {
+    sys::lua_pushlstring(ptr, s.as_ptr() as *const libc::c_char, s.len());
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(mut self, v: bool) -> LuaResult<()> {
+        self.thread.ensure_stack(1)?;
+        unsafe { sys::lua_pushboolean(self.ptr(), v as libc::c_int) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    /// Integers that do not fit in a Lua integer, with `lua-32bits`, are pushed as floats.
+    #[allow(clippy::unnecessary_fallible_conversions)] // lua_Integer is not always i64
+    fn serialize_i64(mut self, v: i64) -> LuaResult<()> {
+        match Integer::try_from(v) {
+            Ok(n) => {
+                self.thread.ensure_stack(1)?;
+                unsafe { sys::lua_pushinteger(self.ptr(), n) };
+                Ok(())
+            }
+            Err(_) => self.serialize_f64(v as f64),
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> LuaResult<()> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    /// Integers that do not fit in a Lua integer are pushed as floats.
+    fn serialize_u64(self, v: u64) -> LuaResult<()> {
+        if v <= i64::MAX as u64 {
+            self.serialize_i64(v as i64)
+        } else {
+            self.serialize_f64(v as f64)
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> LuaResult<()> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(mut self, v: f64) -> LuaResult<()> {
+        self.thread.ensure_stack(1)?;
+        unsafe { sys::lua_pushnumber(self.ptr(), v as Number) };
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> LuaResult<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> LuaResult<()> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(mut self, v: &[u8]) -> LuaResult<()> {
+        self.thread.ensure_stack(1)?;
+        unsafe { sys::lua_pushlstring(self.ptr(), v.as_ptr() as *const libc::c_char, v.len()) };
+        Ok(())
+    }
+
+    fn serialize_none(self) -> LuaResult<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> LuaResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(mut self) -> LuaResult<()> {
+        self.thread.ensure_stack(1)?;
+        unsafe { sys::lua_pushnil(self.ptr()) };
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> LuaResult<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> LuaResult<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> LuaResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> LuaResult<()> {
+        self.thread.ensure_stack(2)?;
+        let ptr = self.thread.as_raw().as_ptr();
+        unsafe {
+            sys::lua_createtable(ptr, 0, 1);
+            push_str(ptr, variant);
+        }
+        value.serialize(Serializer {
+            thread: self.thread,
+        })?;
+        unsafe { sys::lua_rawset(ptr, -3) };
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(None, len.unwrap_or(0), 0)
+    }
+
+    fn serialize_tuple(self, len: usize) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(None, len, 0)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(None, len, 0)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(Some(variant), len, 0)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(None, 0, len.unwrap_or(0))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(None, 0, len)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> LuaResult<SerializeTable<'a>> {
+        self.begin_table(Some(variant), 0, len)
+    }
+}
+
+/// Fills the table at the top of the stack, for compound values.
+#[derive(Debug)]
+pub struct SerializeTable<'a> {
+    thread: &'a mut Thread,
+    /// Length of the array part filled so far.
+    len: Integer,
+    /// Whether the table is wrapped in a `{ variant = table }` table.
+    variant: bool,
+}
+
+impl SerializeTable<'_> {
+    #[inline]
+    fn serializer(&mut self) -> Serializer<'_> {
+        Serializer {
+            thread: self.thread,
+        }
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        value.serialize(self.serializer())?;
+        self.len += 1;
+        unsafe { sys::lua_rawseti(self.thread.as_raw().as_ptr(), -2, self.len) };
+        Ok(())
+    }
+
+    fn push_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> LuaResult<()> {
+        self.thread.ensure_stack(1)?;
+        unsafe { push_str(self.thread.as_raw().as_ptr(), key) };
+        value.serialize(self.serializer())?;
+        unsafe { sys::lua_rawset(self.thread.as_raw().as_ptr(), -3) };
+        Ok(())
+    }
+
+    fn finish(self) -> LuaResult<()> {
+        if self.variant {
+            unsafe { sys::lua_rawset(self.thread.as_raw().as_ptr(), -3) };
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> LuaResult<()> {
+        key.serialize(self.serializer())?;
+        let ptr = self.thread.as_raw().as_ptr();
+        let msg = match unsafe { sys::lua_type(ptr, -1) } {
+            sys::LUA_TNIL => "table index is nil",
+            sys::LUA_TNUMBER if unsafe { sys::lua_tonumber(ptr, -1) }.is_nan() => {
+                "table index is NaN"
+            }
+            _ => return Ok(()),
+        };
+        Err(Error::new(ErrorKind::Runtime, Some(String::from(msg))))
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> LuaResult<()> {
+        value.serialize(self.serializer())?;
+        unsafe { sys::lua_rawset(self.thread.as_raw().as_ptr(), -3) };
+        Ok(())
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> LuaResult<()> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> LuaResult<()> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> LuaResult<()> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::{LoadingMode, StdLib};
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        ports: Vec<u16>,
+        debug: bool,
+        timeout: Option<f64>,
+        modes: Vec<Mode>,
+        limits: BTreeMap<&'static str, u64>,
+        point: (i32, i32),
+    }
+
+    #[derive(Serialize)]
+    enum Mode {
+        Fast,
+        Custom { level: u8 },
+        Pair(u8, u8),
+        Named(String),
+    }
+
+    /// Serializes `value` as the global `v` and evaluates `expr`.
+    fn check<T: Serialize>(thread: &mut Thread, value: &T, expr: &str) -> bool {
+        to_lua(thread, value).unwrap();
+        unsafe { sys::lua_setglobal(thread.as_raw().as_ptr(), b"v\0".as_ptr() as *const _) };
+        thread
+            .caller_load(
+                &format!("return {}", expr),
+                Some("=check"),
+                LoadingMode::Text,
+            )
+            .and_then(|c| c.call_ret())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_to_lua() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = thread.stack_len();
+            let config = Config {
+                name: "server",
+                ports: vec![80, 443],
+                debug: true,
+                timeout: None,
+                modes: vec![
+                    Mode::Fast,
+                    Mode::Custom { level: 3 },
+                    Mode::Pair(1, 2),
+                    Mode::Named(String::from("x")),
+                ],
+                limits: vec![("memory", u64::MAX), ("cpu", 10)]
+                    .into_iter()
+                    .collect(),
+                point: (-1, 1),
+            };
+            assert!(check(
+                thread,
+                &config,
+                "v.name == 'server' and v.debug == true"
+            ));
+            assert!(check(
+                thread,
+                &config,
+                "#v.ports == 2 and v.ports[2] == 443"
+            ));
+            assert!(check(
+                thread,
+                &config,
+                "v.timeout == nil and v.point[1] == -1"
+            ));
+            assert!(check(thread, &config, "v.modes[1] == 'Fast'"));
+            assert!(check(thread, &config, "v.modes[2].Custom.level == 3"));
+            assert!(check(thread, &config, "v.modes[3].Pair[2] == 2"));
+            assert!(check(thread, &config, "v.modes[4].Named == 'x'"));
+            assert!(check(
+                thread,
+                &config,
+                "v.limits.cpu == 10 and v.limits.memory > 1e19"
+            ));
+            assert!(check(thread, &Some('é'), "v == 'é'"));
+            assert!(check(thread, &(), "v == nil"));
+            assert_eq!(thread.stack_len(), top);
+
+            let mut map = BTreeMap::new();
+            map.insert(None::<i32>, 1);
+            let err = to_lua(thread, &map).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("table index is nil"));
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[cfg(feature = "lua-32bits")]
+    #[test]
+    fn test_to_lua_32bits() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Math).unwrap();
+            assert!(check(thread, &7i64, "math.type(v) == 'integer' and v == 7"));
+            // out of the range of a 32-bit integer
+            assert!(check(
+                thread,
+                &(1i64 << 40),
+                "math.type(v) == 'float' and v == 2^40"
+            ));
+            assert!(check(thread, &(u64::MAX >> 1), "math.type(v) == 'float'"));
+        })
+        .unwrap()
+    }
+}
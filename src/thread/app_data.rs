@@ -0,0 +1,134 @@
+use crate::thread::Thread;
+use std::{any::Any, ptr};
+
+impl Thread {
+    /// Stores `data` as the application data of the state, replacing and dropping the
+    /// previous one. The data is dropped when the state is closed.
+    ///
+    /// The data is shared by all the coroutines of the state, it is meant for the host state
+    /// needed by Rust functions called from Lua, see [`app_data`].
+    ///
+    /// A pointer to the data, of type `*mut T`, is also stored in the extra space of the main
+    /// thread of the state (see `lua_getextraspace`) so that C functions can reach it without
+    /// a registry lookup. The pointer must be read from the main thread, found in the registry
+    /// at `LUA_RIDX_MAINTHREAD`: coroutines get a copy of the extra space of the main thread
+    /// when they are created, which is not updated when the data is replaced or taken.
+    /// The pointer is only valid until the data is replaced or taken, and it must be cast back
+    /// to the exact type `T`: reading it as any other type is undefined behavior.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::LoadingMode, Thread};
+    ///
+    /// struct Host {
+    ///     calls: u32,
+    /// }
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.set_app_data(Host { calls: 0 });
+    ///     thread.push_function(|thread| {
+    ///         thread.app_data::<Host>().unwrap().calls += 1;
+    ///         Ok(0)
+    ///     }).unwrap();
+    ///     unsafe { pollua::sys::lua_setglobal(thread.as_raw().as_ptr(), b"ping\0".as_ptr() as *const _) };
+    ///
+    ///     thread
+    ///         .caller_load("ping() ping()", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call().map(|_| ()))
+    ///         .unwrap();
+    ///     assert_eq!(thread.app_data::<Host>().unwrap().calls, 2);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`app_data`]: #method.app_data
+    pub fn set_app_data<T: 'static>(&mut self, data: T) {
+        let mut data = Box::new(data);
+        let data_ptr = &mut *data as *mut T as *mut libc::c_void;
+        // drops the previous data
+        self.extra_mut().app_data = Some(data);
+        unsafe { self.set_extra_space(data_ptr) };
+    }
+
+    /// Returns the application data of the state, or `None` if there is none
+    /// or if it is not of type `T`.
+    pub fn app_data<T: 'static>(&mut self) -> Option<&mut T> {
+        self.extra()?;
+        self.extra_mut().app_data.as_mut()?.downcast_mut()
+    }
+
+    /// Removes the application data of the state and returns it.
+    /// Returns `None` and keeps the data if it is not of type `T`.
+    pub fn take_app_data<T: 'static>(&mut self) -> Option<T> {
+        self.extra()?;
+        let extra = self.extra_mut();
+        if !extra.app_data.as_ref()?.is::<T>() {
+            return None;
+        }
+        let data = extra.app_data.take()?.downcast().ok()?;
+        unsafe { self.set_extra_space(ptr::null_mut()) };
+        Some(*data)
+    }
+
+    /// Writes `data` in the extra space of the main thread.
+    unsafe fn set_extra_space(&mut self, data: *mut libc::c_void) {
+        let main = self.main_thread();
+        let space = sys::lua_getextraspace(main.as_ptr()) as *mut *mut libc::c_void;
+        ptr::write_unaligned(space, data);
+    }
+}
+
+/// The type of the application data.
+pub(super) type AppData = Box<dyn Any>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::ThreadRef;
+    use std::{cell::Cell, rc::Rc};
+
+    struct DropFlag(Rc<Cell<u32>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_app_data() {
+        let drops = Rc::new(Cell::new(0));
+        let flag = drops.clone();
+        Thread::spawn(move |thread| {
+            assert!(thread.app_data::<i32>().is_none());
+            thread.set_app_data(42i32);
+            assert_eq!(thread.app_data::<i32>(), Some(&mut 42));
+            assert!(thread.app_data::<u32>().is_none());
+            unsafe {
+                let space = sys::lua_getextraspace(thread.as_raw().as_ptr()) as *mut *mut i32;
+                assert_eq!(**space, 42);
+            }
+
+            assert_eq!(thread.take_app_data::<u32>(), None);
+            assert_eq!(thread.take_app_data::<i32>(), Some(42));
+            assert!(thread.app_data::<i32>().is_none());
+
+            // the pointer is kept in the main thread, even when set from a coroutine
+            let raw = thread.new_coroutine().as_raw();
+            let mut co = unsafe { ThreadRef::from_raw(raw) };
+            co.set_app_data(7i32);
+            unsafe {
+                let space = sys::lua_getextraspace(thread.as_raw().as_ptr()) as *mut *mut i32;
+                assert_eq!(**space, 7);
+            }
+            thread.remove(-1);
+
+            thread.set_app_data(DropFlag(flag.clone()));
+            // replacing the data drops the previous one
+            thread.set_app_data(DropFlag(flag));
+            assert_eq!(thread.app_data::<DropFlag>().unwrap().0.get(), 1);
+        })
+        .unwrap();
+        // the data is dropped when the state is closed
+        assert_eq!(drops.get(), 2);
+    }
+}
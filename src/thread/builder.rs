@@ -0,0 +1,237 @@
+use crate::thread::{
+    alloc_default, alloc_limited, extra::PanicHandler, MemoryLimit, StdLib, Thread, ThreadError,
+};
+use std::{
+    fmt, ptr,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Configures and creates a [`Thread`].
+///
+/// Unlike the [`Thread::spawn`] functions, [`build`] returns the thread itself,
+/// which can be kept as long as needed. The Lua state is closed when the thread is dropped.
+///
+/// # Examples
+/// ```
+/// use pollua::thread::{StdLib, ThreadBuilder};
+///
+/// let mut thread = ThreadBuilder::new()
+///     .memory_limit(1024 * 1024)
+///     .open_libs(&[StdLib::Base, StdLib::String])
+///     .panic_handler(|msg| eprintln!("Lua panic: {}", msg.unwrap_or("?")))
+///     .build()
+///     .unwrap();
+/// thread.do_string("greeting = string.upper('hello')", None).unwrap();
+/// assert_eq!(thread.get_global::<String, _>("greeting").unwrap(), "HELLO");
+/// ```
+///
+/// [`Thread`]: struct.Thread.html
+/// [`Thread::spawn`]: struct.Thread.html#method.spawn
+/// [`build`]: #method.build
+pub struct ThreadBuilder {
+    allocator: sys::lua_Alloc,
+    userdata: *mut libc::c_void,
+    memory_limit: Option<usize>,
+    libs: Vec<StdLib>,
+    panic_handler: Option<PanicHandler>,
+}
+
+impl ThreadBuilder {
+    /// Creates a builder for a thread using the default allocator, without any library.
+    #[inline]
+    pub fn new() -> ThreadBuilder {
+        ThreadBuilder {
+            allocator: None,
+            userdata: ptr::null_mut(),
+            memory_limit: None,
+            libs: Vec::new(),
+            panic_handler: None,
+        }
+    }
+
+    /// Sets the allocator function of the state and the userdata passed to it.
+    /// `None` selects the default allocator.
+    ///
+    /// # Safety
+    /// If present, the allocator function must behave exactly as defined in [`the Lua manual`],
+    /// behavior is undefined if the function pointer is invalid, returns invalid allocations,
+    /// or frees memory incorrectly. `userdata` must stay valid until the state is closed.
+    ///
+    /// [`the Lua manual`]: https://www.lua.org/manual/5.3/manual.html#lua_Alloc
+    #[inline]
+    pub unsafe fn allocator<U>(mut self, allocator: sys::lua_Alloc, userdata: *mut U) -> Self {
+        self.allocator = allocator;
+        self.userdata = userdata as *mut libc::c_void;
+        self
+    }
+
+    /// Limits the memory used by Lua to `limit` bytes.
+    /// Allocations that would exceed the limit fail with [`ErrorKind::OutOfMemory`].
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[inline]
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Opens the standard libraries in `libs` once the state is created,
+    /// see [`Thread::open_lib`]. May be called several times.
+    ///
+    /// [`Thread::open_lib`]: struct.Thread.html#method.open_lib
+    #[inline]
+    pub fn open_libs(mut self, libs: &[StdLib]) -> Self {
+        self.libs.extend_from_slice(libs);
+        self
+    }
+
+    /// Sets the handler called when an error is raised outside of any protected call,
+    /// see [`Thread::set_panic_handler`].
+    ///
+    /// [`Thread::set_panic_handler`]: struct.Thread.html#method.set_panic_handler
+    #[inline]
+    pub fn panic_handler<F: Fn(Option<&str>) + 'static>(mut self, f: F) -> Self {
+        self.panic_handler = Some(Rc::new(f));
+        self
+    }
+
+    /// Creates the thread.
+    ///
+    /// Returns an error if the state cannot be created or a library cannot be opened.
+    pub fn build(self) -> Result<Thread, ThreadError> {
+        let mut thread = match self.memory_limit {
+            Some(limit) => unsafe {
+                let (allocator, userdata) = match self.allocator {
+                    Some(allocator) => (allocator, self.userdata),
+                    None => (alloc_default as _, ptr::null_mut()),
+                };
+                let mut memory = Box::new(MemoryLimit {
+                    used: AtomicUsize::new(0),
+                    limit: AtomicUsize::new(limit),
+                    allocator,
+                    userdata,
+                });
+                let ud = &mut *memory as *mut MemoryLimit as *mut libc::c_void;
+                let mut thread = Thread::new(Some(alloc_limited), ud)?;
+                // the limit is lifted while the data is stored: the allocation must not fail
+                // outside of a protected call
+                memory.limit.store(usize::MAX, Ordering::Relaxed);
+                let extra = thread.extra_mut();
+                extra.memory_limit = Some(memory);
+                if let Some(memory) = &extra.memory_limit {
+                    memory.limit.store(limit, Ordering::Relaxed);
+                }
+                thread
+            },
+            None => unsafe { Thread::new(self.allocator, self.userdata)? },
+        };
+        if let Some(handler) = self.panic_handler {
+            thread.extra_mut().panic_handler = Some(handler);
+        }
+        for &lib in &self.libs {
+            thread.open_lib(lib)?;
+        }
+        Ok(thread)
+    }
+
+    /// Creates the thread and runs `f` with it, like [`Thread::spawn`].
+    /// The Lua state is closed before returning.
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    pub fn spawn<F, T>(self, f: F) -> Result<T, ThreadError>
+    where
+        F: FnOnce(&mut Thread) -> T,
+    {
+        self.build()?.run_scoped(f)
+    }
+}
+
+impl Default for ThreadBuilder {
+    #[inline]
+    fn default() -> ThreadBuilder {
+        ThreadBuilder::new()
+    }
+}
+
+impl fmt::Debug for ThreadBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadBuilder")
+            .field("allocator", &self.allocator.is_some())
+            .field("memory_limit", &self.memory_limit)
+            .field("libs", &self.libs)
+            .field("panic_handler", &self.panic_handler.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ErrorKind;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_builder_build() {
+        let mut thread = ThreadBuilder::new()
+            .open_libs(&[StdLib::Base])
+            .open_libs(&[StdLib::Math])
+            .build()
+            .unwrap();
+        thread
+            .do_string("x = math.floor(4.5) assert(string == nil)", None)
+            .unwrap();
+        assert_eq!(thread.get_global::<i64, _>("x").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_builder_memory_limit() {
+        let mut thread = ThreadBuilder::new()
+            .memory_limit(128 * 1024)
+            .panic_handler(|_| ())
+            .build()
+            .unwrap();
+        let err = thread
+            .do_string("local t = {} for i = 1, 1e6 do t[i] = i end", None)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+        // the state is still usable
+        thread.do_string("x = 1", None).unwrap();
+        drop(thread);
+
+        match ThreadBuilder::new().memory_limit(16).build() {
+            Err(ThreadError::Lua(e)) => assert_eq!(e.kind(), ErrorKind::OutOfMemory),
+            _ => panic!("expected an out of memory error"),
+        }
+    }
+
+    #[test]
+    fn test_builder_allocator() {
+        unsafe extern "C" fn counting(
+            ud: *mut libc::c_void,
+            ptr: *mut libc::c_void,
+            osize: usize,
+            nsize: usize,
+        ) -> *mut libc::c_void {
+            let count = &*(ud as *const Cell<usize>);
+            count.set(count.get() + 1);
+            alloc_default(ptr::null_mut(), ptr, osize, nsize)
+        }
+
+        let count = Cell::new(0usize);
+        let builder = unsafe {
+            ThreadBuilder::new().allocator(
+                Some(counting),
+                &count as *const Cell<usize> as *mut Cell<usize>,
+            )
+        };
+        // the memory limit uses the custom allocator
+        let n = builder
+            .memory_limit(1024 * 1024)
+            .spawn(|thread| thread.do_string("x = {}", None).map(|_| 1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 1);
+        assert!(count.get() > 0);
+    }
+}
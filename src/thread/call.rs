@@ -1,12 +1,14 @@
 use crate::{
-    thread::{Thread, ThreadRef},
-    value::{Pushable, Pusher, ValueType},
-    LuaResult,
+    thread::{CancelToken, Reference, Table, Thread, ThreadRef},
+    value::{FromLua, FromLuaMulti, LuaValue, ToLua, ToLuaMulti, ValueType},
+    Error, ErrorKind, LuaResult,
 };
 use std::{
     cell::UnsafeCell,
     iter::{DoubleEndedIterator, FusedIterator},
+    mem::{self, ManuallyDrop},
     ops::Index,
+    slice,
 };
 
 /// Used to call Lua functions.
@@ -19,16 +21,27 @@ pub struct Caller<'a> {
     thread: ThreadRef<'a>,
     /// Number of arguments pushed to the stack.
     nargs: libc::c_int,
+    /// Number of results requested by [`call`], may be `LUA_MULTRET`.
+    ///
+    /// [`call`]: #method.call
+    nresults: libc::c_int,
 }
 
 impl<'a> Caller<'a> {
     pub(super) fn from_global(mut thread: ThreadRef<'a>, name: &[u8]) -> Option<Caller<'a>> {
         // check if _G[name] is a function
-        if thread.push_global(name) != sys::LUA_TFUNCTION {
-            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
-            None
-        } else {
-            Some(Caller { thread, nargs: 0 })
+        match thread.push_global(name) {
+            Ok(sys::LUA_TFUNCTION) => Some(Caller {
+                thread,
+                nargs: 0,
+                nresults: sys::LUA_MULTRET,
+            }),
+            Ok(_) => {
+                unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+                None
+            }
+            // raised by an __index metamethod of the global table
+            Err(_) => None,
         }
     }
 
@@ -43,36 +56,467 @@ impl<'a> Caller<'a> {
             sys::lua_type(thread.as_raw().as_ptr(), -1),
             sys::LUA_TFUNCTION
         );
-        Caller { thread, nargs: 0 }
+        Caller {
+            thread,
+            nargs: 0,
+            nresults: sys::LUA_MULTRET,
+        }
+    }
+
+    /// Returns the thread and the number of arguments, leaving the function and its arguments
+    /// on the stack.
+    pub(super) fn into_parts(self) -> (ThreadRef<'a>, libc::c_int) {
+        let mut caller = ManuallyDrop::new(self);
+        let thread = unsafe { ThreadRef::from_raw(caller.thread.as_raw()) };
+        (thread, caller.nargs)
     }
 
+    /// Pushes `arg` as the next argument of the call.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the argument,
+    /// the call is dropped along with its function and arguments.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread
+    ///         .caller_load("local a, b = ... sum = a + b", None, LoadingMode::Text)
+    ///         .and_then(|c| c.arg(20i64)?.arg(22i64)?.call().map(|_| ()))
+    ///         .unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("sum").unwrap(), 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
     #[inline]
-    pub fn arg<A: Pushable>(mut self, arg: A) -> Caller<'a> {
-        unsafe { arg.push(Pusher(ThreadRef::from_raw(self.thread.as_raw()))) }
+    pub fn arg<A: ToLua>(mut self, arg: A) -> LuaResult<Caller<'a>> {
+        self.thread.push(arg)?;
         self.nargs += 1;
+        Ok(self)
+    }
+
+    /// Pushes every item of `args` as arguments of the call, in order.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the arguments.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[inline]
+    pub fn args<A: ToLua, I: IntoIterator<Item = A>>(self, args: I) -> LuaResult<Caller<'a>> {
+        args.into_iter().try_fold(self, Caller::arg)
+    }
+
+    /// Sets the number of results of [`call`] and [`call_unprotected`], the results are
+    /// adjusted to `n` values, dropping extra values and filling missing ones with `nil`.
+    /// `LUA_MULTRET`, the default, keeps all the results.
+    ///
+    /// # Panics
+    /// This panics if `n` is negative and not `LUA_MULTRET`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let mut values = thread
+    ///         .caller_load("return 1, 2, 3", None, LoadingMode::Text)
+    ///         .and_then(|c| c.results(2).call())
+    ///         .unwrap();
+    ///     assert_eq!(values.len(), 2);
+    ///     assert_eq!(values.value::<i64>(1).unwrap(), 2);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`call_unprotected`]: #method.call_unprotected
+    #[inline]
+    pub fn results(mut self, n: libc::c_int) -> Caller<'a> {
+        assert!(
+            n >= 0 || n == sys::LUA_MULTRET,
+            "invalid number of results: {}",
+            n
+        );
+        self.nresults = n;
         self
     }
 
+    /// Replaces the environment of the function with a new table filled by `build`,
+    /// so that the function only sees the globals set in that table.
+    ///
+    /// On Lua 5.2+ the table replaces the upvalue named `_ENV`, a runtime error is returned
+    /// if the function has none: C functions and Lua functions that never access a global.
+    /// On Lua 5.1 the table is set with `lua_setfenv`.
+    /// Combined with opening only the needed libraries, this allows running untrusted code
+    /// in a sandbox. Returns the error of `build` if it fails.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, StdLib, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_lib(StdLib::Base).unwrap();
+    ///     let n: i64 = thread
+    ///         .caller_load("return print == nil and limit * 2", None, LoadingMode::Text)
+    ///         .and_then(|c| c.with_environment(|env| env.set("limit", 21i64)))
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!(n, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::caller_load`]: struct.Thread.html#method.caller_load
+    pub fn with_environment<F>(mut self, build: F) -> LuaResult<Caller<'a>>
+    where
+        F: FnOnce(&mut Table<'_>) -> LuaResult<()>,
+    {
+        let mut table = self.thread.new_table();
+        build(&mut table)?;
+        let function = table.into_raw() - self.nargs - 1;
+        #[cfg(LUA_VERSION = "5.2")]
+        {
+            let mut n = 1;
+            loop {
+                match self.thread.get_upvalue(function, n) {
+                    Ok(Some(name)) => {
+                        self.thread.remove(-1);
+                        if name == "_ENV" {
+                            self.thread.set_upvalue(function, n);
+                            break;
+                        }
+                    }
+                    Ok(None) => {
+                        self.thread.remove(-1);
+                        return Err(Error::runtime("function has no _ENV upvalue"));
+                    }
+                    Err(e) => {
+                        self.thread.remove(-1);
+                        return Err(e);
+                    }
+                }
+                n += 1;
+            }
+        }
+        #[cfg(not(LUA_VERSION = "5.2"))]
+        unsafe {
+            sys::lua_setfenv(self.thread.as_raw().as_ptr(), function);
+        }
+        Ok(self)
+    }
+
+    /// Dumps the function as a binary chunk that can be loaded again with
+    /// [`LoadingMode::Binary`].
+    /// If `strip` is true, debug information is left out (Lua 5.3+ only, ignored otherwise).
+    /// Returns a runtime error if the function is not a Lua function.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let chunk = thread
+    ///         .caller_load("return 42", None, LoadingMode::Text)
+    ///         .and_then(|mut c| c.dump(true))
+    ///         .unwrap();
+    ///     let n: i64 = thread
+    ///         .caller_load(&chunk, None, LoadingMode::Binary)
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!(n, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`LoadingMode::Binary`]: enum.LoadingMode.html#variant.Binary
+    pub fn dump(&mut self, strip: bool) -> LuaResult<Vec<u8>> {
+        unsafe extern "C" fn writer(
+            _l: *mut sys::lua_State,
+            p: *const libc::c_void,
+            sz: usize,
+            ud: *mut libc::c_void,
+        ) -> libc::c_int {
+            (*(ud as *mut Vec<u8>)).extend_from_slice(slice::from_raw_parts(p as *const u8, sz));
+            0
+        }
+
+        let mut buf = Vec::new();
+        unsafe {
+            let ptr = self.thread.as_raw().as_ptr();
+            // lua_dump uses the value at the top of the stack, copy the function above the args
+            sys::lua_pushvalue(ptr, -self.nargs - 1);
+            let data = &mut buf as *mut Vec<u8> as *mut libc::c_void;
+            #[cfg(LUA_VERSION = "5.3")]
+            let code = sys::lua_dump(ptr, Some(writer), data, strip as libc::c_int);
+            #[cfg(not(LUA_VERSION = "5.3"))]
+            let code = {
+                let _ = strip;
+                sys::lua_dump(ptr, Some(writer), data)
+            };
+            sys::lua_pop(ptr, 1);
+            if code != 0 {
+                let msg = String::from("unable to dump given function");
+                return Err(Error::new(ErrorKind::Runtime, Some(msg)));
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Stores the function in the registry, consuming the `Caller`, so that it can be called
+    /// many times with [`Thread::call_reference`] without being loaded again.
+    /// The arguments pushed so far are discarded.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let square = thread
+    ///         .caller_load("local x = ... return x * x", None, LoadingMode::Text)
+    ///         .unwrap()
+    ///         .into_reference();
+    ///     for x in 1..4i64 {
+    ///         let y: i64 = thread.call_reference(&square, x).unwrap();
+    ///         assert_eq!(y, x * x);
+    ///     }
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::call_reference`]: struct.Thread.html#method.call_reference
+    pub fn into_reference(self) -> Reference {
+        let (mut thread, nargs) = self.into_parts();
+        unsafe { sys::lua_pop(thread.as_raw().as_ptr(), nargs) };
+        thread.reference_top()
+    }
+
+    /// Pushes `args` as the next arguments of the call and executes it, consuming the `Caller`.
+    /// `args` is either a single value or a tuple of values, pushed in order.
+    /// All the results are kept unless a number of results was set with [`results`].
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the arguments.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let s: String = thread
+    ///         .caller_load("local a, b, c = ... return a .. b .. c", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_with((1i64, "two", 3.5)))
+    ///         .and_then(|mut values| values.value(0))
+    ///         .unwrap();
+    ///     assert_eq!(s, "1two3.5");
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`results`]: #method.results
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn call_with<A: ToLuaMulti>(mut self, args: A) -> LuaResult<ReturnValues<'a>> {
+        self.thread.ensure_stack(A::LEN)?;
+        let top = self.thread.stack_len();
+        if let Err(e) = unsafe { args.push_multi(&mut self.thread) } {
+            // the values pushed before the error
+            unsafe { sys::lua_settop(self.thread.as_raw().as_ptr(), top) };
+            return Err(e);
+        }
+        self.nargs += A::LEN;
+        self.call()
+    }
+
     /// Executes the call, consuming the `Caller`.
+    /// All the results are kept unless a number of results was set with [`results`].
+    ///
+    /// [`results`]: #method.results
     pub fn call(mut self) -> LuaResult<ReturnValues<'a>> {
-        unsafe {
-            // stack top before function and args were pushed
-            let top = sys::lua_gettop(self.thread.as_raw().as_ptr()) - self.nargs - 1;
-            let status = sys::lua_pcall(
-                self.thread.as_raw().as_ptr(),
-                self.nargs,
-                sys::LUA_MULTRET,
-                0,
-            );
-            self.nargs = -1;
-            let nresults = sys::lua_gettop(self.thread.as_raw().as_ptr()) - top;
-            self.thread
-                .get_error(status)
-                .map(|_| ReturnValues::new(self, nresults))
+        self.thread.ensure_stack(self.nresults.max(0))?;
+        let (status, nresults) = unsafe { self.pcall() };
+        self.thread
+            .get_error(status)
+            .map(|_| ReturnValues::new(self, nresults))
+    }
+
+    /// Executes the call like [`call`], aborting it with an [`ErrorKind::Timeout`] error
+    /// once it executed about `max_instructions` instructions of the interpreter.
+    ///
+    /// The limit is enforced by a count hook replacing the count hook of the thread for the
+    /// duration of the call, the previous one is restored afterwards. Once the limit is hit,
+    /// the error is raised again at every instruction, so a Lua `pcall` cannot catch it for
+    /// good. Time spent in Rust or C functions is not counted.
+    ///
+    /// Only the calling thread and the coroutines created during the call are hooked, as Lua
+    /// copies the hook of a thread to the coroutines it creates. Coroutines created before
+    /// the call and resumed by it run without limit.
+    ///
+    /// # Panics
+    /// This panics if `max_instructions` is 0.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{LoadingMode, Thread}, ErrorKind};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let err = thread
+    ///         .caller_load("while true do end", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_limited(10_000).map(|_| ()))
+    ///         .unwrap_err();
+    ///     assert_eq!(err.kind(), ErrorKind::Timeout);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`ErrorKind::Timeout`]: ../enum.ErrorKind.html#variant.Timeout
+    pub fn call_limited(self, max_instructions: u32) -> LuaResult<ReturnValues<'a>> {
+        const MSG: &str = "instruction limit exceeded";
+        self.call_aborting(max_instructions, ErrorKind::Timeout, MSG, || true)
+    }
+
+    /// Executes the call like [`call`], aborting it with an [`ErrorKind::Interrupted`] error
+    /// once `token` is cancelled, possibly from another thread.
+    ///
+    /// The token is polled by a count hook every 1000 instructions of the interpreter,
+    /// so the call is not aborted as soon as the token is cancelled, and a call blocked in
+    /// a Rust or C function is only aborted once it returns to Lua code. Like with
+    /// [`call_limited`], the hook replaces the count hook of the thread for the duration
+    /// of the call and a Lua `pcall` cannot catch the error for good. It also only reaches
+    /// the calling thread and the coroutines created during the call: coroutines created
+    /// before the call and resumed by it cannot be cancelled.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{CancelToken, LoadingMode, Thread}, ErrorKind};
+    /// use std::{thread, time::Duration};
+    ///
+    /// let token = CancelToken::new();
+    /// let canceller = token.clone();
+    /// let handle = thread::spawn(move || {
+    ///     thread::sleep(Duration::from_millis(10));
+    ///     canceller.cancel();
+    /// });
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let err = thread
+    ///         .caller_load("while true do end", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_cancellable(&token).map(|_| ()))
+    ///         .unwrap_err();
+    ///     assert_eq!(err.kind(), ErrorKind::Interrupted);
+    /// }).unwrap();
+    /// handle.join().unwrap();
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`call_limited`]: #method.call_limited
+    /// [`ErrorKind::Interrupted`]: ../enum.ErrorKind.html#variant.Interrupted
+    pub fn call_cancellable(self, token: &CancelToken) -> LuaResult<ReturnValues<'a>> {
+        const MSG: &str = "call cancelled";
+        let token = token.clone();
+        self.call_aborting(1000, ErrorKind::Interrupted, MSG, move || {
+            token.is_cancelled()
+        })
+    }
+
+    /// Executes the call with an abort hook, see [`Thread::set_abort_hook`].
+    /// The error of an aborted call gets the kind `kind`.
+    ///
+    /// [`Thread::set_abort_hook`]: struct.Thread.html#method.set_abort_hook
+    fn call_aborting<F>(
+        mut self,
+        count: u32,
+        kind: ErrorKind,
+        msg: &'static str,
+        abort: F,
+    ) -> LuaResult<ReturnValues<'a>>
+    where
+        F: FnMut() -> bool + 'static,
+    {
+        self.thread.ensure_stack(self.nresults.max(0))?;
+        let hook = self.thread.set_abort_hook(count, kind, msg, abort);
+        let (status, nresults) = unsafe { self.pcall() };
+        let aborted = self.thread.remove_abort_hook(hook);
+        match self.thread.get_error(status) {
+            Ok(()) => Ok(ReturnValues::new(self, nresults)),
+            Err(mut e) => {
+                if let Some(kind) = aborted {
+                    e.kind = kind;
+                }
+                Err(e)
+            }
         }
     }
 
+    /// Calls the function in protected mode,
+    /// returning the status and the number of values left on the stack by the call.
+    ///
+    /// # Safety
+    /// The stack must have room for the results.
+    unsafe fn pcall(&mut self) -> (libc::c_int, libc::c_int) {
+        // stack top before function and args were pushed
+        let top = sys::lua_gettop(self.thread.as_raw().as_ptr()) - self.nargs - 1;
+        let status = self.thread.pcall(self.nargs, self.nresults);
+        self.nargs = -1;
+        (status, sys::lua_gettop(self.thread.as_raw().as_ptr()) - top)
+    }
+
+    /// Executes the call, consuming the `Caller`,
+    /// and converts the first return value to `R`, or the first return values if `R` is
+    /// a tuple. Missing return values are read as `nil`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let n: i64 = thread
+    ///         .caller_load("return 6 * 7", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!(n, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// Several return values are read with a tuple:
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let (n, s): (i64, String) = thread
+    ///         .caller_load("return 42, 'text', true", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!((n, s.as_str()), (42, "text"));
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn call_ret<R: FromLuaMulti>(self) -> LuaResult<R> {
+        self.calln(R::LEN as u32)?.values()
+    }
+
+    /// Executes the call, consuming the `Caller`,
+    /// and converts the first `nresults` return values to `R`.
+    /// Missing return values are read as `nil`.
+    pub fn call_ret_n<R: FromLua>(self, nresults: u32) -> LuaResult<Vec<R>> {
+        let mut values = self.calln(nresults)?;
+        (0..values.len()).map(|i| values.value(i)).collect()
+    }
+
+    /// Executes the call with `LUA_MULTRET`, consuming the `Caller`,
+    /// and collects all the return values, whatever their number.
+    /// A function returning nothing gives an empty `Vec`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{LoadingMode, Thread}, value::LuaValue};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let values = thread
+    ///         .caller_load("return ...", None, LoadingMode::Text)
+    ///         .and_then(|c| c.arg(1i64)?.arg("two")?.call_all())
+    ///         .unwrap();
+    ///     assert_eq!(values.len(), 2);
+    ///     assert!(matches!(values[0], LuaValue::Integer(1)));
+    /// }).unwrap()
+    /// ```
+    pub fn call_all(self) -> LuaResult<Vec<LuaValue>> {
+        let mut values = self.results(sys::LUA_MULTRET).call()?;
+        (0..values.len()).map(|i| values.value(i)).collect()
+    }
+
     /// Executes the call unprotected, consuming the `Caller`.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the results.
     ///
     /// # Safety
     /// Any error inside the called function will be propagated upwards with a `longjmp`,
@@ -82,30 +526,23 @@ impl<'a> Caller<'a> {
     ///
     /// [`call`]: #method.call
     /// [Lua error handling]: https://www.lua.org/manual/5.3/manual.html#4.6
-    pub unsafe fn call_unprotected(mut self) -> ReturnValues<'a> {
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub unsafe fn call_unprotected(mut self) -> LuaResult<ReturnValues<'a>> {
+        self.thread.ensure_stack(self.nresults.max(0))?;
         // stack top before function and args were pushed
         let top = sys::lua_gettop(self.thread.as_raw().as_ptr()) - self.nargs - 1;
-        sys::lua_pcall(
-            self.thread.as_raw().as_ptr(),
-            self.nargs,
-            sys::LUA_MULTRET,
-            0,
-        );
+        sys::lua_pcall(self.thread.as_raw().as_ptr(), self.nargs, self.nresults, 0);
         self.nargs = -1;
         let nresults = sys::lua_gettop(self.thread.as_raw().as_ptr()) - top;
-        ReturnValues::new(self, nresults)
+        Ok(ReturnValues::new(self, nresults))
     }
 
     /// Executes the call, consuming the `Caller`.
     /// The number of results is adjusted to `nresults`.
     pub fn calln(mut self, nresults: u32) -> LuaResult<ReturnValues<'a>> {
+        self.thread.ensure_stack(nresults as libc::c_int)?;
         unsafe {
-            let status = sys::lua_pcall(
-                self.thread.as_raw().as_ptr(),
-                self.nargs,
-                nresults as libc::c_int,
-                0,
-            );
+            let status = self.thread.pcall(self.nargs, nresults as libc::c_int);
             self.nargs = -1;
             self.thread
                 .get_error(status)
@@ -115,6 +552,7 @@ impl<'a> Caller<'a> {
 
     /// Executes the call unprotected, consuming the `Caller`.
     /// The number of results is adjusted to `nresults`.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the results.
     ///
     /// # Safety
     /// Any error inside the called function will be propagated upwards with a `longjmp`,
@@ -124,14 +562,16 @@ impl<'a> Caller<'a> {
     ///
     /// [`call`]: #method.call
     /// [Lua error handling]: https://www.lua.org/manual/5.3/manual.html#4.6
-    pub unsafe fn calln_unprotected(mut self, nresults: u32) -> ReturnValues<'a> {
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub unsafe fn calln_unprotected(mut self, nresults: u32) -> LuaResult<ReturnValues<'a>> {
+        self.thread.ensure_stack(nresults as libc::c_int)?;
         sys::lua_call(
             self.thread.as_raw().as_ptr(),
             self.nargs,
             nresults as libc::c_int,
         );
         self.nargs = -1;
-        ReturnValues::new(self, nresults as libc::c_int)
+        Ok(ReturnValues::new(self, nresults as libc::c_int))
     }
 }
 
@@ -176,6 +616,40 @@ impl<'a> ReturnValues<'a> {
         unsafe { &mut *self.thread.get() }.as_raw().as_ptr()
     }
 
+    /// Converts the return value at the given position to `R`.
+    /// The value is left on the stack and popped along with the others on drop.
+    ///
+    /// # Panics
+    /// This panics if `index` is out of bounds.
+    pub fn value<R: FromLua>(&mut self, index: usize) -> LuaResult<R> {
+        assert!(
+            index < self.nresults as usize,
+            "return value out of bounds: \
+             the number of return values is {}, but the index is {}",
+            self.nresults,
+            index
+        );
+        let relative = -self.nresults + (index as libc::c_int);
+        let stack_index = self.thread().abs_index(relative);
+        R::from_lua(self.thread(), stack_index).map_err(|e| {
+            let msg = format!(
+                "bad return value #{} ({})",
+                index + 1,
+                e.msg().unwrap_or("invalid value")
+            );
+            Error::new(e.kind(), Some(msg))
+        })
+    }
+
+    /// Converts the first return values to `R`, a single value or a tuple.
+    ///
+    /// # Panics
+    /// This panics if there are less return values than `R` reads.
+    #[inline]
+    pub fn values<R: FromLuaMulti>(&mut self) -> LuaResult<R> {
+        R::from_lua_multi(self, 0)
+    }
+
     /// Returns the number of values returned by the call.
     #[inline]
     pub fn len(&self) -> usize {
@@ -200,11 +674,20 @@ impl<'a> ReturnValues<'a> {
         }
     }
 
+    /// Consumes the handle, leaving the return values on the stack.
+    /// Returns the number of values left.
+    #[inline]
+    pub fn into_raw(self) -> usize {
+        let nresults = self.nresults as usize;
+        mem::forget(self);
+        nresults
+    }
+
     /// Returns an iterator over the return values.
     #[inline]
     pub fn iter<'b>(&'b self) -> Iter<'a, 'b> {
         Iter {
-            values: &self,
+            values: self,
             start: 0,
             end: self.nresults,
         }
@@ -299,7 +782,7 @@ impl FusedIterator for Iter<'_, '_> {}
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{value::LuaNil, ErrorKind};
+    use crate::{thread::LoadingMode, value::LuaNil};
     use std::mem;
 
     fn stack_top(thread: &mut Thread) -> libc::c_int {
@@ -335,7 +818,8 @@ mod test {
                 let return_values = thread
                     .caller_global("test_call")
                     .unwrap()
-                    .calln_unprotected(0);
+                    .calln_unprotected(0)
+                    .unwrap();
                 assert_eq!(return_values.get(0), None);
             }
             assert_eq!(stack_top(thread), top);
@@ -361,7 +845,8 @@ mod test {
                 let return_values = thread
                     .caller_global("test_call")
                     .unwrap()
-                    .calln_unprotected(4);
+                    .calln_unprotected(4)
+                    .unwrap();
                 assert_eq!(return_values[0], ValueType::Boolean);
                 assert_eq!(return_values[1], ValueType::Number);
                 assert_eq!(return_values.get(2), Some(ValueType::String));
@@ -400,7 +885,8 @@ mod test {
                 let values = thread
                     .caller_global("test_call")
                     .unwrap()
-                    .calln_unprotected(0);
+                    .calln_unprotected(0)
+                    .unwrap();
                 let mut iter = values.iter();
                 assert_eq!(iter.len(), 0);
                 assert_eq!(iter.next(), None);
@@ -426,6 +912,119 @@ mod test {
         .unwrap()
     }
 
+    #[test]
+    fn test_call_ret() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let chunk = "return 1, 'two', 3";
+
+            let n: i64 = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_ret())
+                .unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(stack_top(thread), top);
+
+            let values: Vec<String> = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_ret_n(3))
+                .unwrap();
+            assert_eq!(values, ["1", "two", "3"]);
+            assert_eq!(stack_top(thread), top);
+
+            let err = thread
+                .caller_load("return 1", None, LoadingMode::Text)
+                .and_then(|c| c.call_ret_n::<i64>(2))
+                .unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad return value #2 (integer expected, got nil)")
+            );
+            assert_eq!(stack_top(thread), top);
+
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_ret_n::<i64>(3))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(
+                err.msg(),
+                Some("bad return value #2 (integer expected, got string)")
+            );
+            assert_eq!(stack_top(thread), top);
+
+            let nil: LuaNil = thread
+                .caller_load("", None, LoadingMode::Text)
+                .and_then(|c| c.call_ret())
+                .unwrap();
+            assert_eq!(nil, LuaNil);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_dump() {
+        unsafe extern "C" fn test_call(_l: *mut sys::lua_State) -> libc::c_int {
+            0
+        }
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let chunk = "local a, b = ... return a * b";
+
+            let mut caller = thread
+                .caller_load(chunk, Some("=chunk"), LoadingMode::Text)
+                .unwrap()
+                .arg(6i64)
+                .unwrap()
+                .arg(7i64)
+                .unwrap();
+            let full = caller.dump(false).unwrap();
+            let stripped = caller.dump(true).unwrap();
+            // dumping leaves the function and its arguments in place
+            assert_eq!(caller.call_ret::<i64>().unwrap(), 42);
+            assert_eq!(stack_top(thread), top);
+            assert!(full.starts_with(b"\x1bLua"));
+            assert!(stripped.len() <= full.len());
+
+            for chunk in &[full, stripped] {
+                let n: i64 = thread
+                    .caller_load(chunk, None, LoadingMode::Binary)
+                    .unwrap()
+                    .arg(4i64)
+                    .unwrap()
+                    .arg(5i64)
+                    .unwrap()
+                    .call_ret()
+                    .unwrap();
+                assert_eq!(n, 20);
+                assert_eq!(stack_top(thread), top);
+            }
+
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Binary)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Syntax);
+
+            unsafe {
+                sys::lua_register(
+                    thread.as_raw().as_ptr(),
+                    b"test_call\0".as_ptr() as *const _,
+                    Some(test_call),
+                );
+            }
+            let err = thread
+                .caller_global("test_call")
+                .unwrap()
+                .dump(false)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
     #[test]
     fn test_call_error() {
         unsafe extern "C" fn test_call(l: *mut sys::lua_State) -> libc::c_int {
@@ -456,7 +1055,7 @@ mod test {
         unsafe extern "C" fn test_sum(l: *mut sys::lua_State) -> libc::c_int {
             let mut sum = 0.0;
             let nargs = match sys::lua_gettop(l) {
-                n if n == 0 => sys::lua_error(l),
+                0 => sys::lua_error(l),
                 n => n,
             };
             for i in 1..=nargs {
@@ -491,7 +1090,9 @@ mod test {
                     .caller_global("test_sum")
                     .unwrap()
                     .arg(42.0)
+                    .unwrap()
                     .arg(LuaNil)
+                    .unwrap()
                     .call()
                     .unwrap_err();
                 assert_eq!(err.kind(), ErrorKind::Runtime);
@@ -503,7 +1104,9 @@ mod test {
                     .caller_global("test_sum")
                     .unwrap()
                     .arg(1.0f64)
+                    .unwrap()
                     .arg(2.0f64)
+                    .unwrap()
                     .call()
                     .unwrap();
                 assert_eq!(return_values.get(0), Some(ValueType::Number));
@@ -515,14 +1118,367 @@ mod test {
                     .caller_global("test_sum")
                     .unwrap()
                     .arg(-2.0f64)
+                    .unwrap()
                     .arg("24")
+                    .unwrap()
                     .arg(7.2f64)
+                    .unwrap()
                     .call()
                     .unwrap();
                 assert_eq!(return_values.get(0), Some(ValueType::Number));
                 assert_eq!(return_values.get(1), None);
             }
             assert_eq!(stack_top(thread), top);
+
+            {
+                let return_values = thread
+                    .caller_global("test_sum")
+                    .unwrap()
+                    .args((1..=100).map(f64::from))
+                    .unwrap()
+                    .call()
+                    .unwrap();
+                assert_eq!(return_values.get(0), Some(ValueType::Number));
+                assert_eq!(return_values.get(1), None);
+            }
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_many_args() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(crate::thread::StdLib::Base).unwrap();
+            let top = stack_top(thread);
+            // more arguments than the LUA_MINSTACK slots guaranteed by Lua
+            let n: i64 = thread
+                .caller_load("return select('#', ...)", None, LoadingMode::Text)
+                .and_then(|c| c.args(0..200i64)?.call_ret())
+                .unwrap();
+            assert_eq!(n, 200);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_all() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let values = thread
+                .caller_load("return", None, LoadingMode::Text)
+                .and_then(|c| c.call_all())
+                .unwrap();
+            assert!(values.is_empty());
+
+            let values = thread
+                .caller_load("return ...", None, LoadingMode::Text)
+                .and_then(|c| c.args(0..100i64)?.results(1).call_all())
+                .unwrap();
+            assert_eq!(values.len(), 100);
+            assert!(matches!(values[99], LuaValue::Integer(99)));
+
+            let err = thread
+                .caller_load("return nil + 1", None, LoadingMode::Text)
+                .and_then(|c| c.call_all())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_limited() {
+        use crate::thread::StdLib;
+        use std::{cell::Cell, rc::Rc};
+
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let calls = Rc::new(Cell::new(0));
+            let counter = calls.clone();
+            thread.set_count_hook(1, move |_| {
+                counter.set(counter.get() + 1);
+                Ok(())
+            });
+            let top = stack_top(thread);
+
+            let n: i64 = thread
+                .caller_load("return 6 * 7", None, LoadingMode::Text)
+                .and_then(|c| c.results(1).call_limited(1000))
+                .and_then(|mut values| values.value(0))
+                .unwrap();
+            assert_eq!(n, 42);
+
+            // the error is raised again after being caught by Lua code
+            let chunk = "while not pcall(function() while true do end end) do end";
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_limited(1000).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Timeout);
+            assert_eq!(err.msg(), Some("instruction limit exceeded"));
+            assert_eq!(stack_top(thread), top);
+
+            // errors of the function keep their kind
+            let err = thread
+                .caller_load("return nil + 1", None, LoadingMode::Text)
+                .and_then(|c| c.call_limited(1000).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+
+            // the previous count hook is restored
+            assert_eq!(calls.get(), 0);
+            thread
+                .caller_load("local a = 1", None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap();
+            assert!(calls.get() > 0);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_cancellable() {
+        use std::{thread, time::Duration};
+
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        Thread::spawn(move |lua| {
+            let top = stack_top(lua);
+            let n: i64 = lua
+                .caller_load(
+                    "local n = 0 for i = 1, 10000 do n = n + i end return n",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call_cancellable(&token))
+                .and_then(|mut values| values.value(0))
+                .unwrap();
+            assert_eq!(n, 50_005_000);
+
+            let handle = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                canceller.cancel();
+            });
+            let err = lua
+                .caller_load("while true do end", None, LoadingMode::Text)
+                .and_then(|c| c.call_cancellable(&token).map(|_| ()))
+                .unwrap_err();
+            handle.join().unwrap();
+            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            assert_eq!(err.msg(), Some("call cancelled"));
+            assert_eq!(stack_top(lua), top);
+
+            // the token stays cancelled until it is reset
+            let chunk = "for i = 1, 10000 do end";
+            let err = lua
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_cancellable(&token).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Interrupted);
+            token.reset();
+            lua.caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call_cancellable(&token).map(|_| ()))
+                .unwrap();
+            assert!(unsafe { sys::lua_gethook(lua.as_raw().as_ptr()) }.is_none());
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_results() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let chunk = "return 1, 'two', 3.5";
+            {
+                let mut values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .unwrap()
+                    .call()
+                    .unwrap();
+                assert_eq!(values.len(), 3);
+                assert_eq!(values.value::<String>(1).unwrap(), "two");
+                assert_eq!(values.value::<f64>(2).unwrap(), 3.5);
+                let err = values.value::<i64>(1).unwrap_err();
+                assert_eq!(
+                    err.msg(),
+                    Some("bad return value #2 (integer expected, got string)")
+                );
+            }
+            assert_eq!(stack_top(thread), top);
+            {
+                let mut values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .unwrap()
+                    .results(1)
+                    .call()
+                    .unwrap();
+                assert_eq!(values.len(), 1);
+                assert_eq!(values.value::<i64>(0).unwrap(), 1);
+            }
+            assert_eq!(stack_top(thread), top);
+            {
+                // missing results are nil
+                let values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .unwrap()
+                    .results(5)
+                    .call()
+                    .unwrap();
+                let types: Vec<_> = values.iter().collect();
+                assert_eq!(
+                    types,
+                    [
+                        ValueType::Number,
+                        ValueType::String,
+                        ValueType::Number,
+                        ValueType::Nil,
+                        ValueType::Nil
+                    ]
+                );
+            }
+            assert_eq!(stack_top(thread), top);
+            {
+                let values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .unwrap()
+                    .results(0)
+                    .results(sys::LUA_MULTRET)
+                    .call()
+                    .unwrap();
+                assert_eq!(values.len(), 3);
+            }
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid number of results: -2")]
+    fn test_call_results_invalid() {
+        let _ = Thread::spawn(move |thread| {
+            let _ = thread
+                .caller_load("return", None, LoadingMode::Text)
+                .map(|c| c.results(-2));
+        });
+    }
+
+    #[test]
+    fn test_call_with_environment() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(crate::thread::StdLib::Base).unwrap();
+            thread.globals().set("secret", 1i64).unwrap();
+            let top = stack_top(thread);
+
+            let chunk =
+                "local n = ... hidden = secret == nil and print == nil return n + base, hidden";
+            {
+                let mut values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .unwrap()
+                    .arg(40i64)
+                    .unwrap()
+                    .with_environment(|env| env.set("base", 2i64))
+                    .unwrap()
+                    .call()
+                    .unwrap();
+                assert_eq!(values.value::<i64>(0).unwrap(), 42);
+                assert!(values.value::<bool>(1).unwrap());
+            }
+            assert_eq!(stack_top(thread), top);
+            // the assignment went to the sandbox, not to the globals
+            assert!(thread.get_global::<LuaNil, _>("hidden").is_ok());
+
+            // a failing builder drops the caller
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .unwrap()
+                .with_environment(|_| Err(crate::Error::runtime("no environment")))
+                .unwrap_err();
+            assert_eq!(err.msg(), Some("no environment"));
+            assert_eq!(stack_top(thread), top);
+
+            // the environment is not always the first upvalue
+            thread
+                .do_string(
+                    "local count = 0 \
+                     function counter() count = count + 1 return count, step end \
+                     function constant() return 1 end",
+                    None,
+                )
+                .unwrap();
+            let (count, step): (i64, i64) = thread
+                .caller_global("counter")
+                .unwrap()
+                .with_environment(|env| env.set("step", 5i64))
+                .and_then(|c| c.call_ret())
+                .unwrap();
+            assert_eq!((count, step), (1, 5));
+            assert_eq!(stack_top(thread), top);
+
+            #[cfg(LUA_VERSION = "5.2")]
+            {
+                let err = thread
+                    .caller_global("constant")
+                    .unwrap()
+                    .with_environment(|_| Ok(()))
+                    .unwrap_err();
+                assert_eq!(err.msg(), Some("function has no _ENV upvalue"));
+                assert_eq!(stack_top(thread), top);
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_tuples() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let chunk = "return ...";
+
+            let (a, b, c): (i64, String, f64) = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .unwrap()
+                .arg(1i64)
+                .unwrap()
+                .arg("two")
+                .unwrap()
+                .arg(3.5)
+                .unwrap()
+                .call_ret()
+                .unwrap();
+            assert_eq!((a, b.as_str(), c), (1, "two", 3.5));
+            assert_eq!(stack_top(thread), top);
+
+            {
+                let mut values = thread
+                    .caller_load(chunk, None, LoadingMode::Text)
+                    .and_then(|c| c.call_with((1i64, 2i64, 3i64, 4i64, 5i64, 6i64, 7i64, 8i64)))
+                    .unwrap();
+                assert_eq!(values.len(), 8);
+                let all: (i64, i64, i64, i64, i64, i64, i64, i64) = values.values().unwrap();
+                assert_eq!(all, (1, 2, 3, 4, 5, 6, 7, 8));
+            }
+            assert_eq!(stack_top(thread), top);
+
+            // missing values are read as nil
+            let (n, LuaNil): (i64, LuaNil) = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.arg(42i64)?.call_ret())
+                .unwrap();
+            assert_eq!(n, 42);
+
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.arg(1i64)?.arg("text")?.call_ret::<(i64, i64)>())
+                .unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad return value #2 (integer expected, got string)")
+            );
+            assert_eq!(stack_top(thread), top);
         })
         .unwrap()
     }
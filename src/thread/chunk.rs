@@ -0,0 +1,115 @@
+use crate::{
+    thread::{Caller, LoadingMode, Thread},
+    LuaResult,
+};
+
+/// A Lua chunk compiled to bytecode, created by [`Thread::compile`].
+///
+/// The chunk can be loaded by any thread with [`Thread::load_compiled`] without being parsed
+/// again, which saves the cost of compiling the same script in many Lua states.
+/// The debug information is kept, so errors report the chunk name and line numbers of
+/// the source.
+///
+/// [`Thread::compile`]: struct.Thread.html#method.compile
+/// [`Thread::load_compiled`]: struct.Thread.html#method.load_compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledChunk {
+    bytecode: Vec<u8>,
+}
+
+impl CompiledChunk {
+    /// Returns the bytecode of the chunk.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytecode
+    }
+
+    /// Returns the bytecode of the chunk, consuming it.
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytecode
+    }
+}
+
+impl Thread {
+    /// Compiles the Lua source code `source` without running it.
+    /// `chunk_name` is used in error messages and debug information like in [`caller_load`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// let chunk = Thread::spawn(move |thread| {
+    ///     thread.compile("return 6 * 7", Some("=answer"))
+    /// }).unwrap().unwrap();
+    ///
+    /// // the chunk can be loaded in other states without being parsed again
+    /// let n: i64 = Thread::spawn(move |thread| {
+    ///     thread.load_compiled(&chunk).and_then(|c| c.call_ret())
+    /// }).unwrap().unwrap();
+    /// assert_eq!(n, 42);
+    /// ```
+    ///
+    /// [`caller_load`]: #method.caller_load
+    pub fn compile<B: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        source: &B,
+        chunk_name: Option<&str>,
+    ) -> LuaResult<CompiledChunk> {
+        let mut caller = self.caller_load_impl(source.as_ref(), chunk_name, LoadingMode::Text)?;
+        let bytecode = caller.dump(false)?;
+        Ok(CompiledChunk { bytecode })
+    }
+
+    /// Loads a chunk compiled with [`compile`] and creates a [`Caller`] for it.
+    ///
+    /// [`compile`]: #method.compile
+    /// [`Caller`]: struct.Caller.html
+    #[inline]
+    pub fn load_compiled(&mut self, chunk: &CompiledChunk) -> LuaResult<Caller<'_>> {
+        self.caller_load_impl(&chunk.bytecode, None, LoadingMode::Binary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{thread::StdLib, ErrorKind};
+
+    #[test]
+    fn test_compile() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = thread.stack_len();
+
+            let chunk = thread
+                .compile("local n = ... counter = (counter or 0) + n", None)
+                .unwrap();
+            assert_eq!(thread.stack_len(), top);
+            for _ in 0..3 {
+                thread
+                    .load_compiled(&chunk)
+                    .and_then(|c| c.arg(2i64)?.calln(0).map(|_| ()))
+                    .unwrap();
+            }
+            assert_eq!(thread.get_global::<i64, _>("counter").unwrap(), 6);
+
+            // the chunk name and line numbers are kept
+            let chunk = thread.compile("\nerror('oops')", Some("=script")).unwrap();
+            let err = thread
+                .load_compiled(&chunk)
+                .and_then(|c| c.calln(0).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("script:2: oops"));
+
+            let err = thread.compile("return +", None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Syntax);
+            // binary chunks are not accepted as source
+            let err = thread.compile(chunk.as_bytes(), None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Syntax);
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+}
@@ -0,0 +1,178 @@
+use super::{
+    app_data::AppData,
+    function::PendingYield,
+    hook::{CountHook, LineHook},
+    panic::Payload,
+    MemoryLimit, Thread,
+};
+use crate::Error;
+use std::{any::TypeId, cell::Cell, collections::HashMap, mem, ptr, rc::Rc};
+
+/// Handler called when an error is raised outside of any protected call.
+pub(super) type PanicHandler = Rc<dyn Fn(Option<&str>)>;
+
+/// Registry key of the `Extra` userdata, the address of this static is used as a light userdata.
+static EXTRA_KEY: u8 = 0;
+
+/// Per-state data used by the wrapper.
+/// It is stored in a full userdata anchored in the registry and shared by all the coroutines
+/// of a state, the userdata finalizer drops it when the state is closed.
+pub(super) struct Extra {
+    /// Whether protected calls install a traceback message handler.
+    pub traceback: bool,
+    /// Maximum length of the error messages collected by [`Thread::get_error`].
+    ///
+    /// [`Thread::get_error`]: struct.Thread.html#method.get_error
+    pub max_error_len: Option<usize>,
+    /// Set to false when the state is closed, shared with the values that outlive borrows
+    /// of the thread, like [`Reference`].
+    ///
+    /// [`Reference`]: struct.Reference.html
+    pub alive: Rc<Cell<bool>>,
+    /// Callback of the count hook.
+    pub count_hook: Option<CountHook>,
+    /// Callback of the line hook.
+    pub line_hook: Option<LineHook>,
+    /// Custom handler of Lua panics.
+    ///
+    /// It is kept here rather than in the extra space of the thread (`lua_getextraspace`):
+    /// the extra space only holds one pointer, which is used by the application data, and
+    /// it is copied to coroutines when they are created, so a handler set later would not
+    /// be seen by older coroutines. The panic function reaches the registry just as well.
+    pub panic_handler: Option<PanicHandler>,
+    /// Data set by [`Thread::set_app_data`].
+    ///
+    /// [`Thread::set_app_data`]: struct.Thread.html#method.set_app_data
+    pub app_data: Option<AppData>,
+    /// Yields requested by the running Rust functions, by coroutine: a function may resume
+    /// another coroutine between the request and its return.
+    pub pending_yields: HashMap<*mut sys::lua_State, PendingYield>,
+    /// Allocator data of a state created with a memory limit, taken out of the userdata
+    /// by the destructor of [`Thread`] so that it outlives the state.
+    ///
+    /// [`Thread`]: struct.Thread.html
+    pub memory_limit: Option<Box<MemoryLimit>>,
+    /// Registry references to the metatables of the [`UserData`] types pushed so far.
+    ///
+    /// [`UserData`]: trait.UserData.html
+    pub metatables: HashMap<TypeId, libc::c_int>,
+    /// Whether the panics of Rust functions are kept in `caught_panic` instead of being
+    /// resumed, set for a [`ScopedThread`].
+    ///
+    /// [`ScopedThread`]: struct.ScopedThread.html
+    pub catch_panics: bool,
+    /// First panic payload kept since it was last taken.
+    pub caught_panic: Option<Payload>,
+    /// Error raised outside of any protected call, kept by the panic function for
+    /// [`Thread::spawn`] and [`ScopedThread::run`].
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`ScopedThread::run`]: struct.ScopedThread.html#method.run
+    pub lua_panic: Option<Error>,
+}
+
+impl Default for Extra {
+    fn default() -> Extra {
+        Extra {
+            traceback: false,
+            max_error_len: None,
+            alive: Rc::new(Cell::new(true)),
+            count_hook: None,
+            line_hook: None,
+            panic_handler: None,
+            app_data: None,
+            pending_yields: HashMap::new(),
+            memory_limit: None,
+            metatables: HashMap::new(),
+            catch_panics: false,
+            caught_panic: None,
+            lua_panic: None,
+        }
+    }
+}
+
+impl Drop for Extra {
+    fn drop(&mut self) {
+        self.alive.set(false);
+        // the state is being closed without the destructor of `Thread`, which normally takes
+        // the memory limit: it is still used by the allocator and must be leaked
+        if let Some(memory) = self.memory_limit.take() {
+            Box::leak(memory);
+        }
+    }
+}
+
+impl Thread {
+    /// Returns the extra data of this state, or `None` if it was never created, if it was
+    /// dropped because the state is being closed, or if the stack cannot grow to look it up.
+    pub(super) fn extra(&self) -> Option<&Extra> {
+        unsafe { self.get_extra().as_ref() }
+    }
+
+    /// Returns the extra data of this state, creating it if needed.
+    ///
+    /// # Panics
+    /// This panics if the stack cannot grow to look the data up, or if there is not enough
+    /// memory to create it.
+    pub(super) fn extra_mut(&mut self) -> &mut Extra {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            assert!(
+                sys::lua_checkstack(ptr, 1) != 0,
+                "cannot reach the extra data: stack overflow"
+            );
+            let mut extra = self.get_extra();
+            if extra.is_null() {
+                // the allocations may raise a memory error
+                extra = self
+                    .protected(|thread| {
+                        let ptr = thread.raw.as_ptr();
+                        let extra =
+                            sys::lua_newuserdata(ptr, mem::size_of::<Extra>()) as *mut Extra;
+                        ptr::write(extra, Extra::default());
+                        // metatable with the finalizer
+                        sys::lua_createtable(ptr, 0, 1);
+                        sys::lua_pushcfunction(ptr, Some(extra_gc));
+                        sys::lua_setfield(ptr, -2, b"__gc\0".as_ptr() as *const _);
+                        sys::lua_setmetatable(ptr, -2);
+                        sys::lua_rawsetp(ptr, sys::LUA_REGISTRYINDEX, extra_key());
+                        extra
+                    })
+                    .unwrap_or_else(|e| panic!("cannot create the extra data: {}", e));
+            }
+            &mut *extra
+        }
+    }
+
+    /// Looks the extra data up in the registry, returns null if there is none.
+    unsafe fn get_extra(&self) -> *mut Extra {
+        let ptr = self.raw.as_ptr();
+        if sys::lua_checkstack(ptr, 1) == 0 {
+            return ptr::null_mut();
+        }
+        sys::lua_rawgetp(ptr, sys::LUA_REGISTRYINDEX, extra_key());
+        let extra = sys::lua_touserdata(ptr, -1) as *mut Extra;
+        sys::lua_pop(ptr, 1);
+        extra
+    }
+}
+
+#[inline]
+fn extra_key() -> *const libc::c_void {
+    &EXTRA_KEY as *const u8 as *const libc::c_void
+}
+
+unsafe extern "C" fn extra_gc(l: *mut sys::lua_State) -> libc::c_int {
+    let extra = sys::lua_touserdata(l, 1) as *mut Extra;
+    sys::lua_rawgetp(l, sys::LUA_REGISTRYINDEX, extra_key());
+    let registered = sys::lua_touserdata(l, -1) as *mut Extra;
+    // the finalizer may be called with another value, like from `debug.getmetatable`
+    if extra.is_null() || extra != registered {
+        return 0;
+    }
+    // later finalizers calling back into Rust must not reach the dropped data
+    sys::lua_pushnil(l);
+    sys::lua_rawsetp(l, sys::LUA_REGISTRYINDEX, extra_key());
+    ptr::drop_in_place(extra);
+    0
+}
@@ -0,0 +1,775 @@
+use crate::{
+    thread::{
+        panic::{push_panic, Payload},
+        Thread, ThreadRef,
+    },
+    Error, LuaResult,
+};
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+};
+
+impl Thread {
+    /// Pushes a Rust closure onto the stack as a Lua function.
+    ///
+    /// When called from Lua, the closure receives the thread running the call with the
+    /// arguments on the stack, and returns the number of results it pushed, like a
+    /// `lua_CFunction`.
+    /// Returning an error raises a Lua error with the error message.
+    /// A panic raises a Lua error as well, its payload is resumed once the error reaches Rust
+    /// code through [`Thread::get_error`], and [`Thread::spawn`] returns it as
+    /// [`ThreadError::Panic`].
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the function.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push_function(|thread| {
+    ///         let n = unsafe { pollua::sys::luaL_checkinteger(thread.as_raw().as_ptr(), 1) };
+    ///         unsafe { pollua::sys::lua_pushinteger(thread.as_raw().as_ptr(), n * 2) };
+    ///         Ok(1)
+    ///     }).unwrap();
+    ///     unsafe { pollua::sys::lua_setglobal(thread.as_raw().as_ptr(), b"double\0".as_ptr() as *const _) };
+    ///
+    ///     let n: i64 = thread
+    ///         .caller_load("return double(21)", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!(n, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::get_error`]: struct.Thread.html#method.get_error
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`ThreadError::Panic`]: enum.ThreadError.html#variant.Panic
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn push_function<F>(&mut self, f: F) -> LuaResult<()>
+    where
+        F: Fn(&mut Thread) -> LuaResult<libc::c_int> + 'static,
+    {
+        // the closure userdata and its metatable, with a slot for the finalizer
+        self.ensure_stack(3)?;
+        unsafe { self.push_function_unchecked(f) };
+        Ok(())
+    }
+
+    /// Pushes `f` like [`push_function`], without growing the stack.
+    ///
+    /// # Safety
+    /// The stack must have room for at least 3 more values.
+    ///
+    /// [`push_function`]: #method.push_function
+    pub(super) unsafe fn push_function_unchecked<F>(&mut self, f: F)
+    where
+        F: Fn(&mut Thread) -> LuaResult<libc::c_int> + 'static,
+    {
+        let ptr = self.raw.as_ptr();
+        // the closure is boxed so that the userdata alignment does not matter
+        let data = sys::lua_newuserdata(ptr, mem::size_of::<Box<F>>()) as *mut Box<F>;
+        ptr::write(data, Box::new(f));
+        sys::lua_createtable(ptr, 0, 1);
+        sys::lua_pushcfunction(ptr, Some(drop_function::<F>));
+        sys::lua_setfield(ptr, -2, b"__gc\0".as_ptr() as *const _);
+        sys::lua_setmetatable(ptr, -2);
+        sys::lua_pushcclosure(ptr, Some(call_function::<F>), 1);
+    }
+
+    /// Runs `f` in protected mode, like a function called with `lua_pcall`: a Lua error
+    /// raised by a Lua API function called by `f` is returned as an error instead of
+    /// jumping over the Rust frames of the caller.
+    ///
+    /// Lua errors are raised with `longjmp`, which does not run the destructors of the frames
+    /// it skips. A Rust function called by Lua that calls a raising API function directly,
+    /// like `luaL_checkinteger`, skips its own frames and leaks or corrupts the values they
+    /// hold. With `protected`, the jump stops at the end of `f`: the values of the caller
+    /// are dropped normally. The frames of `f` itself are still skipped, so `f` should only
+    /// hold values without destructors while it calls raising functions.
+    ///
+    /// `f` runs in a new stack frame, it does not see the values on the stack of the caller,
+    /// see [`protected_args`] to pass some of them.
+    /// On success, the values `f` leaves on its stack are pushed onto the stack of the thread.
+    /// A panic in `f` is handled like a panic in a function pushed with [`push_function`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{sys, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let top = thread.stack_len();
+    ///     let name = String::from("kept");
+    ///     let err = thread
+    ///         .protected(|thread| unsafe {
+    ///             sys::lua_pushboolean(thread.as_raw().as_ptr(), 1);
+    ///             sys::luaL_checkinteger(thread.as_raw().as_ptr(), -1)
+    ///         })
+    ///         .unwrap_err();
+    ///     assert!(err.msg().unwrap().contains("number expected, got boolean"));
+    ///     assert_eq!(name, "kept");
+    ///
+    ///     let n = thread.protected(|thread| thread.push(1i64).map(|_| 2)).unwrap();
+    ///     assert_eq!(n.unwrap(), 2);
+    ///     assert_eq!(thread.stack_len(), top + 1);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`push_function`]: #method.push_function
+    /// [`protected_args`]: #method.protected_args
+    #[inline]
+    pub fn protected<F, R>(&mut self, f: F) -> LuaResult<R>
+    where
+        F: FnOnce(&mut Thread) -> R,
+    {
+        self.protected_args(0, f)
+    }
+
+    /// A variant of [`protected`] that copies the `nargs` values at the top of the stack into
+    /// the stack frame of `f`, at indices 1 to `nargs`, like the arguments of a function.
+    /// The values are copied: they stay on the stack of the caller.
+    ///
+    /// # Panics
+    /// This panics if `nargs` is negative or if the stack holds less than `nargs` values.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{sys, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push(20i64).unwrap();
+    ///     thread.push(22i64).unwrap();
+    ///     let sum = thread
+    ///         .protected_args(2, |thread| unsafe {
+    ///             let ptr = thread.as_raw().as_ptr();
+    ///             sys::luaL_checkinteger(ptr, 1) + sys::luaL_checkinteger(ptr, 2)
+    ///         })
+    ///         .unwrap();
+    ///     assert_eq!(sum, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`protected`]: #method.protected
+    pub fn protected_args<F, R>(&mut self, nargs: libc::c_int, f: F) -> LuaResult<R>
+    where
+        F: FnOnce(&mut Thread) -> R,
+    {
+        assert!(
+            nargs >= 0 && nargs <= self.stack_len(),
+            "cannot pass {} values to a protected call: the stack holds {} values",
+            nargs,
+            self.stack_len()
+        );
+        struct Protected<F, R> {
+            f: Option<F>,
+            result: Option<R>,
+        }
+
+        unsafe extern "C" fn trampoline<F, R>(l: *mut sys::lua_State) -> libc::c_int
+        where
+            F: FnOnce(&mut Thread) -> R,
+        {
+            let data = &mut *(sys::lua_touserdata(l, 1) as *mut Protected<F, R>);
+            sys::lua_remove(l, 1);
+            let f = data.f.take().expect("protected function called twice");
+            let mut thread = ThreadRef::from_raw(NonNull::new_unchecked(l));
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&mut thread))) {
+                Ok(result) => data.result = Some(result),
+                // lua_error does not return, nothing may be left to drop when calling it
+                Err(payload) => Raise::Panic(payload).raise(l),
+            }
+            sys::lua_gettop(l)
+        }
+
+        self.ensure_stack(3 + nargs)?;
+        let mut data = Protected {
+            f: Some(f),
+            result: None,
+        };
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let first = sys::lua_gettop(ptr) - nargs + 1;
+            sys::lua_pushcfunction(ptr, Some(trampoline::<F, R>));
+            sys::lua_pushlightuserdata(ptr, &mut data as *mut Protected<F, R> as *mut _);
+            for index in first..first + nargs {
+                sys::lua_pushvalue(ptr, index);
+            }
+            let code = self.pcall(nargs + 1, sys::LUA_MULTRET);
+            self.get_error(code)?;
+        }
+        Ok(data.result.expect("protected function did not run"))
+    }
+}
+
+/// Error raised by a Rust function.
+pub(super) enum Raise {
+    Message(String),
+    Panic(Payload),
+}
+
+impl Raise {
+    /// Runs `f`, catching errors and panics.
+    pub(super) fn catch<T, F: FnOnce() -> LuaResult<T>>(f: F) -> Result<T, Raise> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(e)) => Err(Raise::Message(
+                e.msg().unwrap_or_else(|| e.kind().as_str()).to_owned(),
+            )),
+            Err(payload) => Err(Raise::Panic(payload)),
+        }
+    }
+
+    /// Raises the error in Lua.
+    ///
+    /// # Safety
+    /// This calls `lua_error`, no value with a destructor may be alive in the calling frames
+    /// up to the protected call.
+    pub(super) unsafe fn raise(self, l: *mut sys::lua_State) -> ! {
+        match self {
+            Raise::Message(msg) => {
+                sys::lua_pushlstring(l, msg.as_ptr() as *const libc::c_char, msg.len());
+                mem::drop(msg);
+            }
+            Raise::Panic(payload) => push_panic(l, Box::into_raw(Box::new(payload))),
+        }
+        sys::lua_error(l)
+    }
+}
+
+/// Rest of a Rust function that yielded, see [`Thread::yield_with`].
+///
+/// [`Thread::yield_with`]: struct.Thread.html#method.yield_with
+pub(super) type Continuation = Box<dyn FnOnce(&mut Thread) -> LuaResult<libc::c_int>>;
+
+/// Yield requested by [`Thread::yield_with`], performed once the Rust function returns.
+///
+/// [`Thread::yield_with`]: struct.Thread.html#method.yield_with
+pub(super) struct PendingYield {
+    nresults: libc::c_int,
+    continuation: Continuation,
+}
+
+/// How a Rust function returns to Lua.
+enum Outcome {
+    /// Returns the given number of results.
+    Return(libc::c_int),
+    /// Yields the given number of values, the continuation is stored in the userdata at
+    /// the given stack index.
+    #[cfg_attr(not(LUA_VERSION = "5.3"), allow(dead_code))]
+    Yield(libc::c_int, libc::c_int),
+}
+
+#[cfg(LUA_VERSION = "5.3")]
+impl Thread {
+    /// Yields the `nresults` values at the top of the stack from a Rust function,
+    /// `continuation` runs when the coroutine is resumed.
+    ///
+    /// The function pushed with [`push_function`] must return the result of this method,
+    /// the coroutine yields once it has returned so that no Rust frame is skipped. The
+    /// function may resume other coroutines in the meantime, the yield is kept for the
+    /// running coroutine. When resumed, the continuation receives the stack of the function, below
+    /// the yielded values, followed by the values passed to `resume`, and returns like the
+    /// function itself: it may yield again.
+    /// The continuation is dropped without being called if the coroutine is never resumed.
+    ///
+    /// Returns a runtime error if the function is not running in a coroutine that can
+    /// yield, see [`is_yieldable`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{LoadingMode, StdLib}, value::FromLua, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_lib(StdLib::Coroutine).unwrap();
+    ///     // yields its argument and returns the double of the resume value
+    ///     thread.push_function(|thread| {
+    ///         thread.yield_with(1, |thread| {
+    ///             let n = i64::from_lua(thread, -1)?;
+    ///             thread.push(n * 2)?;
+    ///             Ok(1)
+    ///         })
+    ///     }).unwrap();
+    ///     unsafe { pollua::sys::lua_setglobal(thread.as_raw().as_ptr(), b"pause\0".as_ptr() as *const _) };
+    ///
+    ///     let results: Vec<i64> = thread
+    ///         .caller_load(
+    ///             "local co = coroutine.wrap(pause) return co(1), co(21)",
+    ///             None,
+    ///             LoadingMode::Text,
+    ///         )
+    ///         .and_then(|c| c.call_ret_n(2))
+    ///         .unwrap();
+    ///     assert_eq!(results, [1, 42]);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`push_function`]: #method.push_function
+    /// [`is_yieldable`]: #method.is_yieldable
+    pub fn yield_with<F>(
+        &mut self,
+        nresults: libc::c_int,
+        continuation: F,
+    ) -> LuaResult<libc::c_int>
+    where
+        F: FnOnce(&mut Thread) -> LuaResult<libc::c_int> + 'static,
+    {
+        if !self.is_yieldable() {
+            return Err(Error::runtime("attempt to yield from outside a coroutine"));
+        }
+        if nresults < 0 || nresults > self.stack_len() {
+            let msg = format!("invalid number of values to yield ({})", nresults);
+            return Err(Error::runtime(msg));
+        }
+        let l = self.raw.as_ptr();
+        let pending = PendingYield {
+            nresults,
+            continuation: Box::new(continuation),
+        };
+        self.extra_mut().pending_yields.insert(l, pending);
+        Ok(nresults)
+    }
+}
+
+/// Checks the result of a Rust function or continuation and performs the pending yield.
+unsafe fn finish(
+    thread: &mut Thread,
+    result: Result<libc::c_int, Raise>,
+) -> Result<Outcome, Raise> {
+    let l = thread.as_raw().as_ptr();
+    let pending = match thread.extra() {
+        Some(extra) if extra.pending_yields.contains_key(&l) => {
+            thread.extra_mut().pending_yields.remove(&l)
+        }
+        _ => None,
+    };
+    let n = result?;
+    match pending {
+        Some(PendingYield {
+            nresults,
+            continuation,
+        }) => {
+            if nresults < 0 || nresults > thread.stack_len() {
+                let msg = format!("invalid number of values to yield ({})", nresults);
+                return Err(Raise::Message(msg));
+            }
+            // the userdata and its metatable, with a slot for the finalizer
+            if let Err(e) = thread.ensure_stack(3) {
+                return Err(Raise::Message(
+                    e.msg().unwrap_or("stack overflow").to_owned(),
+                ));
+            }
+            let l = thread.as_raw().as_ptr();
+            // stored below the yielded values, it stays on the stack while suspended
+            let data = sys::lua_newuserdata(l, mem::size_of::<Option<Continuation>>())
+                as *mut Option<Continuation>;
+            ptr::write(data, Some(continuation));
+            sys::lua_createtable(l, 0, 1);
+            sys::lua_pushcfunction(l, Some(drop_continuation));
+            sys::lua_setfield(l, -2, b"__gc\0".as_ptr() as *const _);
+            sys::lua_setmetatable(l, -2);
+            sys::lua_insert(l, -nresults - 1);
+            Ok(Outcome::Yield(nresults, thread.stack_len() - nresults))
+        }
+        None if n < 0 || n > thread.stack_len() => {
+            Err(Raise::Message(format!("invalid number of results ({})", n)))
+        }
+        None => Ok(Outcome::Return(n)),
+    }
+}
+
+/// Returns, yields or raises an error from a Rust function.
+///
+/// # Safety
+/// This may call `lua_error` or `lua_yieldk`, no value with a destructor may be alive in the
+/// calling frames.
+unsafe fn complete(l: *mut sys::lua_State, outcome: Result<Outcome, Raise>) -> libc::c_int {
+    match outcome {
+        Ok(Outcome::Return(n)) => n,
+        #[cfg(LUA_VERSION = "5.3")]
+        Ok(Outcome::Yield(n, index)) => {
+            sys::lua_yieldk(l, n, index as sys::lua_KContext, Some(continue_function))
+        }
+        #[cfg(not(LUA_VERSION = "5.3"))]
+        Ok(Outcome::Yield(..)) => unreachable!("yield_with requires Lua 5.3"),
+        Err(raise) => raise.raise(l),
+    }
+}
+
+/// Runs the closure stored in the first upvalue.
+/// Returns the number of results, the values to yield or the error to raise.
+unsafe fn run_function<F>(l: *mut sys::lua_State) -> Result<Outcome, Raise>
+where
+    F: Fn(&mut Thread) -> LuaResult<libc::c_int>,
+{
+    let f = &**(sys::lua_touserdata(l, sys::lua_upvalueindex(1)) as *const Box<F>);
+    let mut thread = ThreadRef::from_raw(NonNull::new_unchecked(l));
+    let result = Raise::catch(|| f(&mut thread));
+    finish(&mut thread, result)
+}
+
+unsafe extern "C" fn call_function<F>(l: *mut sys::lua_State) -> libc::c_int
+where
+    F: Fn(&mut Thread) -> LuaResult<libc::c_int>,
+{
+    // lua_error does not return, nothing may be left to drop when calling it
+    let outcome = run_function::<F>(l);
+    complete(l, outcome)
+}
+
+/// Runs the continuation stored in the userdata at `index`.
+#[cfg(LUA_VERSION = "5.3")]
+unsafe fn run_continuation(l: *mut sys::lua_State, index: libc::c_int) -> Result<Outcome, Raise> {
+    let data = sys::lua_touserdata(l, index) as *mut Option<Continuation>;
+    let continuation = (*data).take();
+    sys::lua_remove(l, index);
+    let continuation = continuation.expect("continuation called twice");
+    let mut thread = ThreadRef::from_raw(NonNull::new_unchecked(l));
+    let result = Raise::catch(|| continuation(&mut thread));
+    finish(&mut thread, result)
+}
+
+#[cfg(LUA_VERSION = "5.3")]
+unsafe extern "C" fn continue_function(
+    l: *mut sys::lua_State,
+    _status: libc::c_int,
+    ctx: sys::lua_KContext,
+) -> libc::c_int {
+    let outcome = run_continuation(l, ctx as libc::c_int);
+    complete(l, outcome)
+}
+
+unsafe extern "C" fn drop_function<F>(l: *mut sys::lua_State) -> libc::c_int {
+    let data = sys::lua_touserdata(l, 1) as *mut Box<F>;
+    // a panicking destructor must not unwind into Lua
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| ptr::drop_in_place(data)));
+    0
+}
+
+unsafe extern "C" fn drop_continuation(l: *mut sys::lua_State) -> libc::c_int {
+    let data = sys::lua_touserdata(l, 1) as *mut Option<Continuation>;
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| ptr::drop_in_place(data)));
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::{LoadingMode, StdLib, ThreadError},
+        value::{FromLua, LuaNil},
+        ErrorKind,
+    };
+    use std::{cell::Cell, rc::Rc};
+
+    fn set_function<F>(thread: &mut Thread, name: &str, f: F)
+    where
+        F: Fn(&mut Thread) -> LuaResult<libc::c_int> + 'static,
+    {
+        thread.push_function(f).unwrap();
+        let mut buf = Vec::new();
+        unsafe {
+            sys::lua_setglobal(
+                thread.as_raw().as_ptr(),
+                crate::util::cstr_buf(Some(name), &mut buf),
+            )
+        };
+    }
+
+    fn run<R: FromLua>(thread: &mut Thread, chunk: &str) -> LuaResult<R> {
+        thread
+            .caller_load(chunk, Some("=test"), LoadingMode::Text)
+            .and_then(|c| c.call_ret())
+    }
+
+    #[test]
+    fn test_push_function() {
+        let calls = Rc::new(Cell::new(0));
+        let dropped = Rc::new(Cell::new(false));
+
+        struct DropFlag(Rc<Cell<bool>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let counter = calls.clone();
+        let flag = DropFlag(dropped.clone());
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            set_function(thread, "sum", move |thread| {
+                let _ = &flag;
+                counter.set(counter.get() + 1);
+                let mut sum = 0;
+                for i in 1..=thread.stack_len() {
+                    sum += i64::from_lua(thread, i)?;
+                }
+                thread.push(sum)?;
+                Ok(1)
+            });
+            let top = thread.stack_len();
+
+            assert_eq!(run::<i64>(thread, "return sum(1, 2, 3)").unwrap(), 6);
+            assert_eq!(run::<i64>(thread, "return sum()").unwrap(), 0);
+            let err = run::<i64>(thread, "return sum(1, {})").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("integer expected, got table"));
+            // errors can be caught by Lua code
+            assert!(!run::<bool>(thread, "return pcall(sum, 'x')").unwrap());
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap();
+        assert_eq!(calls.get(), 4);
+        // the closure is dropped when the state is closed
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_push_function_panic() {
+        let result = Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            set_function(thread, "boom", |_| panic!("boom"));
+            set_function(thread, "bad", |_| Ok(10));
+            let top = thread.stack_len();
+
+            let err = run::<LuaNil>(thread, "bad()").unwrap_err();
+            assert_eq!(err.msg(), Some("invalid number of results (10)"));
+
+            // Lua code can catch the panic like any other error
+            let msg: String = run(thread, "local _, e = pcall(boom) return tostring(e)").unwrap();
+            assert_eq!(msg, "panic in Rust function: boom");
+            assert_eq!(thread.stack_len(), top);
+
+            let _ = run::<LuaNil>(thread, "boom()");
+            unreachable!("the panic should be resumed");
+        });
+        match result {
+            Err(ThreadError::Panic(payload)) => {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"))
+            }
+            _ => panic!("expected a panic"),
+        }
+    }
+
+    #[test]
+    fn test_protected() {
+        let dropped = Rc::new(Cell::new(0));
+
+        struct DropCount(Rc<Cell<i32>>);
+        impl Drop for DropCount {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let count = dropped.clone();
+        let result = Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            set_function(thread, "check", move |thread| {
+                let _guard = DropCount(count.clone());
+                let n = thread.protected(|thread| unsafe {
+                    sys::luaL_checkinteger(thread.as_raw().as_ptr(), 1)
+                });
+                // the argument is not visible in the frame of the closure
+                assert!(n.is_err());
+                Ok(0)
+            });
+            run::<LuaNil>(thread, "check(1) check('x')").unwrap();
+
+            // the argument is copied into the frame of the closure
+            set_function(thread, "double", |thread| {
+                // the value left by the closure is pushed onto the stack of the function
+                thread.protected_args(1, |thread| unsafe {
+                    let ptr = thread.as_raw().as_ptr();
+                    let n = sys::luaL_checkinteger(ptr, 1);
+                    sys::lua_settop(ptr, 0);
+                    sys::lua_pushinteger(ptr, n * 2);
+                })?;
+                Ok(1)
+            });
+            assert_eq!(run::<i64>(thread, "return double(21)").unwrap(), 42);
+            let err = run::<LuaNil>(thread, "double('x')").unwrap_err();
+            assert!(err.msg().unwrap().contains("number expected, got string"));
+
+            let _ = thread.protected(|_| panic!("boom"));
+            unreachable!("the panic should be resumed");
+        });
+        match result {
+            Err(ThreadError::Panic(payload)) => {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"))
+            }
+            _ => panic!("expected a panic"),
+        }
+        assert_eq!(dropped.get(), 2);
+    }
+
+    #[cfg(LUA_VERSION = "5.3")]
+    #[test]
+    fn test_yield_with() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            // yields x + 1 and returns the resume value times 2
+            set_function(thread, "wait", |thread| {
+                let x = i64::from_lua(thread, 1)?;
+                thread.push(x + 1)?;
+                thread.yield_with(1, |thread| {
+                    let y = i64::from_lua(thread, -1)?;
+                    thread.push(y * 2)?;
+                    Ok(1)
+                })
+            });
+            let top = thread.stack_len();
+
+            let results: Vec<i64> = thread
+                .caller_load(
+                    "local co = coroutine.wrap(function(x) return wait(x) + 1 end) \
+                     return co(1), co(10)",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call_ret_n(2))
+                .unwrap();
+            assert_eq!(results, [2, 21]);
+
+            let err = run::<LuaNil>(thread, "wait(1)").unwrap_err();
+            assert_eq!(err.msg(), Some("attempt to yield from outside a coroutine"));
+            assert_eq!(thread.stack_len(), top);
+
+            // the values to yield are popped after the yield is requested
+            set_function(thread, "shrink", |thread| {
+                thread.push(1)?;
+                let n = thread.yield_with(1, |_| Ok(0))?;
+                thread.remove(-1);
+                Ok(n)
+            });
+            let err = run::<LuaNil>(thread, "coroutine.wrap(shrink)()").unwrap_err();
+            // coroutine.wrap adds the position to the error
+            assert_eq!(
+                err.msg(),
+                Some("test:1: invalid number of values to yield (1)")
+            );
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[cfg(LUA_VERSION = "5.3")]
+    #[test]
+    fn test_yield_with_chained() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            // yields 1, 2, 3 then returns the sum of the resume values
+            set_function(thread, "count", |thread| {
+                thread.push(1)?;
+                thread.yield_with(1, |thread| {
+                    let a = i64::from_lua(thread, -1)?;
+                    thread.push(2)?;
+                    thread.yield_with(1, move |thread| {
+                        let b = i64::from_lua(thread, -1)?;
+                        thread.push(3)?;
+                        thread.yield_with(1, move |thread| {
+                            let c = i64::from_lua(thread, -1)?;
+                            thread.push(a + b + c)?;
+                            Ok(1)
+                        })
+                    })
+                })
+            });
+
+            let results: Vec<i64> = thread
+                .caller_load(
+                    "local co = coroutine.wrap(count) return co(), co(10), co(20), co(30)",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call_ret_n(4))
+                .unwrap();
+            assert_eq!(results, [1, 2, 3, 60]);
+        })
+        .unwrap()
+    }
+
+    #[cfg(LUA_VERSION = "5.3")]
+    #[test]
+    fn test_yield_with_interleaved() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            // yields x + 1 and returns the resume value times 2
+            set_function(thread, "wait", |thread| {
+                let x = i64::from_lua(thread, 1)?;
+                thread.push(x + 1)?;
+                thread.yield_with(1, |thread| {
+                    let y = i64::from_lua(thread, -1)?;
+                    thread.push(y * 2)?;
+                    Ok(1)
+                })
+            });
+            // requests a yield of 100, then resumes the other coroutine before returning
+            set_function(thread, "relay", |thread| {
+                thread.push(100)?;
+                let n = thread.yield_with(1, |thread| {
+                    let y = i64::from_lua(thread, -1)?;
+                    thread.push(y)?;
+                    Ok(1)
+                })?;
+                let inner: i64 = thread
+                    .caller_global("other")
+                    .unwrap()
+                    .arg(5i64)?
+                    .call_ret()?;
+                thread.set_global("inner", inner)?;
+                Ok(n)
+            });
+
+            let results: Vec<i64> = thread
+                .caller_load(
+                    "other = coroutine.wrap(wait) \
+                     local co = coroutine.wrap(relay) \
+                     local first = co() \
+                     local second = other(10) \
+                     return first, inner, second, co(7)",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call_ret_n(4))
+                .unwrap();
+            assert_eq!(results, [100, 6, 20, 7]);
+        })
+        .unwrap()
+    }
+
+    #[cfg(LUA_VERSION = "5.3")]
+    #[test]
+    fn test_yield_with_drop() {
+        let dropped = Rc::new(Cell::new(false));
+
+        struct DropFlag(Rc<Cell<bool>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let flag = dropped.clone();
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            set_function(thread, "suspend", move |thread| {
+                let flag = DropFlag(flag.clone());
+                thread.yield_with(0, move |_| {
+                    let _ = &flag;
+                    Ok(0)
+                })
+            });
+            // the coroutine is never resumed
+            run::<LuaNil>(thread, "coroutine.wrap(suspend)()").unwrap();
+        })
+        .unwrap();
+        // the continuation is dropped when the state is closed
+        assert!(dropped.get());
+    }
+}
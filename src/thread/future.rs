@@ -0,0 +1,330 @@
+use crate::{
+    thread::{Caller, ResumeStatus, Thread, ThreadRef},
+    value::FromLua,
+    Error, LuaResult,
+};
+use std::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr::NonNull,
+    task::{Context, Poll},
+};
+
+/// A Lua function running in a coroutine, driven as a [`Future`].
+/// Created by [`Thread::run_async`].
+///
+/// Each poll resumes the coroutine: a yield makes the future pending and wakes the task
+/// right away so that other tasks can run before the coroutine is resumed, a return makes
+/// it ready with the first return value converted to `R`.
+/// The values yielded by the coroutine are discarded and resumes pass no values.
+///
+/// The coroutine lives on the stack of the thread the function was loaded on, which stays
+/// borrowed until the future is dropped. Dropping the future before completion abandons
+/// the coroutine.
+///
+/// [`Future`]: https://doc.rust-lang.org/std/future/trait.Future.html
+/// [`Thread::run_async`]: struct.Thread.html#method.run_async
+pub struct AsyncCall<'a, R> {
+    thread: ThreadRef<'a>,
+    coroutine: NonNull<sys::lua_State>,
+    /// Stack index of the coroutine in `thread`.
+    index: libc::c_int,
+    /// Number of arguments of the first resume, `None` once the coroutine has started.
+    nargs: Option<libc::c_int>,
+    finished: bool,
+    _marker: PhantomData<fn() -> R>,
+}
+
+impl Thread {
+    /// Runs the function of `caller` in a new coroutine, returning a future that resumes
+    /// the coroutine each time it is polled.
+    ///
+    /// This lets Lua code yield to the executor, see [`AsyncCall`] for the details.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the coroutine,
+    /// or the stack of the coroutine to fit the arguments; the call is dropped along with
+    /// its function and arguments in that case.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, StdLib, Thread};
+    /// use std::{
+    ///     future::Future,
+    ///     pin::Pin,
+    ///     task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    /// };
+    ///
+    /// // a waker that does nothing, executors such as Tokio provide their own
+    /// fn noop_raw_waker() -> RawWaker {
+    ///     fn clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+    ///     fn noop(_: *const ()) {}
+    ///     static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    ///     RawWaker::new(std::ptr::null(), &VTABLE)
+    /// }
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_lib(StdLib::Coroutine).unwrap();
+    ///     let caller = thread
+    ///         .caller_load("coroutine.yield() return 42", None, LoadingMode::Text)
+    ///         .unwrap();
+    ///     let mut call = Thread::run_async::<i64>(caller).unwrap();
+    ///
+    ///     let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    ///     let mut cx = Context::from_waker(&waker);
+    ///     assert!(Pin::new(&mut call).poll(&mut cx).is_pending());
+    ///     match Pin::new(&mut call).poll(&mut cx) {
+    ///         Poll::Ready(result) => assert_eq!(result.unwrap(), 42),
+    ///         Poll::Pending => panic!("the coroutine should have returned"),
+    ///     }
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`AsyncCall`]: struct.AsyncCall.html
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn run_async<R: FromLua>(caller: Caller<'_>) -> LuaResult<AsyncCall<'_, R>> {
+        let (mut thread, nargs) = caller.into_parts();
+        unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            if let Err(e) = thread.ensure_stack(1) {
+                sys::lua_pop(ptr, nargs + 1);
+                return Err(e);
+            }
+            // lua_newthread never returns null, it raises a memory error instead
+            let raw = NonNull::new_unchecked(sys::lua_newthread(ptr));
+            // move the function and its arguments above the coroutine to the coroutine
+            sys::lua_insert(ptr, -nargs - 2);
+            if let Err(e) = ThreadRef::from_raw(raw).ensure_stack(nargs + 1) {
+                sys::lua_pop(ptr, nargs + 2);
+                return Err(e);
+            }
+            sys::lua_xmove(ptr, raw.as_ptr(), nargs + 1);
+            Ok(AsyncCall {
+                index: sys::lua_gettop(ptr),
+                thread,
+                coroutine: raw,
+                nargs: Some(nargs),
+                finished: false,
+                _marker: PhantomData,
+            })
+        }
+    }
+}
+
+impl<R: FromLua> Future for AsyncCall<'_, R> {
+    type Output = LuaResult<R>;
+
+    /// Resumes the coroutine.
+    ///
+    /// # Panics
+    /// This panics if the future is polled after it has completed,
+    /// and resumes the panics of the Rust functions called by the coroutine.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<LuaResult<R>> {
+        let this = self.get_mut();
+        assert!(!this.finished, "AsyncCall polled after completion");
+        let mut coroutine = unsafe { ThreadRef::from_raw(this.coroutine) };
+        let ptr = this.coroutine.as_ptr();
+
+        let status = coroutine.resume(this.nargs.take().unwrap_or(0));
+        match status {
+            Ok(ResumeStatus::Yielded) => {
+                unsafe { sys::lua_settop(ptr, 0) };
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Ok(ResumeStatus::Finished) => {
+                this.finished = true;
+                // a missing return value is read as nil
+                if coroutine.stack_len() == 0 {
+                    unsafe { sys::lua_pushnil(ptr) };
+                }
+                let result = R::from_lua(&mut coroutine, 1).map_err(|e| {
+                    let msg = format!(
+                        "bad return value #1 ({})",
+                        e.msg().unwrap_or("invalid value")
+                    );
+                    Error::new(e.kind(), Some(msg))
+                });
+                unsafe { sys::lua_settop(ptr, 0) };
+                Poll::Ready(result)
+            }
+            Err(e) => {
+                this.finished = true;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+impl<R> fmt::Debug for AsyncCall<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncCall")
+            .field("thread", &self.thread)
+            .field("coroutine", &self.coroutine)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl<R> Drop for AsyncCall<'_, R> {
+    fn drop(&mut self) {
+        // the coroutine can be collected once removed from the stack
+        unsafe { sys::lua_remove(self.thread.as_raw().as_ptr(), self.index) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::{LoadingMode, StdLib},
+        value::LuaNil,
+        ErrorKind,
+    };
+    use std::{
+        cell::Cell,
+        rc::Rc,
+        task::{RawWaker, RawWakerVTable, Waker},
+    };
+
+    thread_local! {
+        static WAKES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn counting_waker() -> Waker {
+        fn raw() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw()
+            }
+            fn wake(_: *const ()) {
+                WAKES.with(|w| w.set(w.get() + 1));
+            }
+            fn drop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        unsafe { Waker::from_raw(raw()) }
+    }
+
+    /// Polls `future` until it is ready, returning the result and the number of polls.
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let waker = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return (output, polls);
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_async() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = thread.stack_len();
+            let caller = thread
+                .caller_load(
+                    "local n = ... for i = 1, 3 do coroutine.yield(i) end return n * 2",
+                    None,
+                    LoadingMode::Text,
+                )
+                .unwrap()
+                .arg(21i64)
+                .unwrap();
+
+            WAKES.with(|w| w.set(0));
+            let (result, polls) = block_on(Thread::run_async::<i64>(caller).unwrap());
+            assert_eq!(result.unwrap(), 42);
+            assert_eq!(polls, 4);
+            // the task is woken on each yield
+            assert_eq!(WAKES.with(Cell::get), 3);
+            assert_eq!(thread.stack_len(), top);
+
+            let caller = thread
+                .caller_load("coroutine.yield()", None, LoadingMode::Text)
+                .unwrap();
+            let (result, _) = block_on(Thread::run_async::<LuaNil>(caller).unwrap());
+            assert!(result.is_ok());
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_async_error() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = thread.stack_len();
+            let caller = thread
+                .caller_load(
+                    "coroutine.yield() error('stop', 0)",
+                    None,
+                    LoadingMode::Text,
+                )
+                .unwrap();
+            let (result, polls) = block_on(Thread::run_async::<i64>(caller).unwrap());
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("stop"));
+            assert_eq!(polls, 2);
+
+            let caller = thread
+                .caller_load("return {}", None, LoadingMode::Text)
+                .unwrap();
+            let (result, _) = block_on(Thread::run_async::<i64>(caller).unwrap());
+            assert_eq!(
+                result.unwrap_err().msg(),
+                Some("bad return value #1 (integer expected, got table)")
+            );
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_async_drop() {
+        let dropped = Rc::new(Cell::new(false));
+
+        struct DropFlag(Rc<Cell<bool>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let flag = dropped.clone();
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = thread.stack_len();
+            let flag = DropFlag(flag);
+            thread
+                .push_function(move |_| {
+                    let _ = &flag;
+                    Ok(0)
+                })
+                .unwrap();
+            unsafe { sys::lua_setglobal(thread.as_raw().as_ptr(), b"g\0".as_ptr() as *const _) };
+            {
+                let caller = thread
+                    .caller_load(
+                        "local f = g g = nil coroutine.yield() f()",
+                        None,
+                        LoadingMode::Text,
+                    )
+                    .unwrap();
+                let mut call = Thread::run_async::<LuaNil>(caller).unwrap();
+                let waker = counting_waker();
+                let mut cx = Context::from_waker(&waker);
+                assert!(Pin::new(&mut call).poll(&mut cx).is_pending());
+            }
+            assert_eq!(thread.stack_len(), top);
+            // the abandoned coroutine no longer references the function
+            thread.gc_collect();
+            assert!(dropped.get());
+        })
+        .unwrap()
+    }
+}
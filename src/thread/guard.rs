@@ -0,0 +1,107 @@
+use crate::thread::Thread;
+use std::ops::{Deref, DerefMut};
+
+/// Restores the stack top of a [`Thread`] when dropped.
+/// Created by [`Thread::stack_guard`].
+///
+/// The guard dereferences to the thread, so values can be pushed through it
+/// and they will be popped once the guard goes out of scope.
+///
+/// # Examples
+/// ```
+/// use pollua::Thread;
+///
+/// Thread::spawn(move |thread| {
+///     let top = thread.stack_len();
+///     {
+///         let mut guard = thread.stack_guard();
+///         unsafe { pollua::sys::lua_pushinteger(guard.as_raw().as_ptr(), 42) };
+///         assert_eq!(guard.stack_len(), top + 1);
+///     }
+///     assert_eq!(thread.stack_len(), top);
+/// }).unwrap()
+/// ```
+///
+/// [`Thread`]: struct.Thread.html
+/// [`Thread::stack_guard`]: struct.Thread.html#method.stack_guard
+#[derive(Debug)]
+pub struct StackGuard<'a> {
+    thread: &'a mut Thread,
+    top: libc::c_int,
+}
+
+impl<'a> StackGuard<'a> {
+    #[inline]
+    pub(super) fn new(thread: &'a mut Thread) -> StackGuard<'a> {
+        let top = thread.stack_len();
+        StackGuard { thread, top }
+    }
+
+    /// Returns the stack top that will be restored.
+    #[inline]
+    pub fn top(&self) -> libc::c_int {
+        self.top
+    }
+}
+
+impl Deref for StackGuard<'_> {
+    type Target = Thread;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.thread
+    }
+}
+
+impl DerefMut for StackGuard<'_> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.thread
+    }
+}
+
+impl Drop for StackGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::lua_settop(self.thread.as_raw().as_ptr(), self.top) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stack_top(thread: &mut Thread) -> libc::c_int {
+        unsafe { sys::lua_gettop(thread.as_raw().as_ptr()) }
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            {
+                let mut guard = thread.stack_guard();
+                assert_eq!(guard.top(), top);
+                unsafe {
+                    sys::lua_pushinteger(guard.as_raw().as_ptr(), 1);
+                    sys::lua_pushinteger(guard.as_raw().as_ptr(), 2);
+                }
+                assert_eq!(stack_top(&mut guard), top + 2);
+
+                {
+                    let mut inner = guard.stack_guard();
+                    assert_eq!(inner.top(), top + 2);
+                    // values below the recorded top can be popped too
+                    unsafe { sys::lua_settop(inner.as_raw().as_ptr(), top) };
+                }
+                // the stack is grown back with nils
+                assert_eq!(stack_top(&mut guard), top + 2);
+                assert_eq!(
+                    unsafe { sys::lua_type(guard.as_raw().as_ptr(), -1) },
+                    sys::LUA_TNIL
+                );
+            }
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+}
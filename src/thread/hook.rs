@@ -0,0 +1,396 @@
+use crate::{
+    thread::{function::Raise, Thread, ThreadRef},
+    Error, ErrorKind, LuaResult,
+};
+use std::{
+    cell::{Cell, RefCell},
+    ptr::NonNull,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Callback of a count hook, shared so that it can be replaced while it runs.
+pub(super) type CountHook = Rc<RefCell<dyn FnMut(&mut Thread) -> LuaResult<()>>>;
+
+/// Callback of a line hook, shared so that it can be replaced while it runs.
+pub(super) type LineHook = Rc<RefCell<dyn FnMut(&mut Thread, u32) -> LuaResult<()>>>;
+
+/// A flag cancelling the calls made with [`Caller::call_cancellable`],
+/// which can be set from another thread.
+///
+/// Clones share the same flag. Once cancelled, the token stays cancelled until it is
+/// [`reset`].
+///
+/// [`Caller::call_cancellable`]: struct.Caller.html#method.call_cancellable
+/// [`reset`]: #method.reset
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    #[inline]
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels the calls using this token.
+    #[inline]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    /// Returns true if the token was cancelled.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears the flag, so that the token can be used for new calls.
+    #[inline]
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed)
+    }
+}
+
+/// Count hook installed for the duration of a call to abort it,
+/// see [`Thread::set_abort_hook`].
+///
+/// [`Thread::set_abort_hook`]: struct.Thread.html#method.set_abort_hook
+pub(super) struct AbortHook {
+    /// Count hook and count replaced by the abort hook.
+    previous: Option<(CountHook, libc::c_int)>,
+    /// Set once the hook aborted the call.
+    aborted: Rc<Cell<bool>>,
+    kind: ErrorKind,
+}
+
+impl Thread {
+    /// Sets a hook calling `f` every `count` instructions executed by the interpreter.
+    /// Returning an error from `f` raises a Lua error with the error message,
+    /// which aborts the running code unless it is caught by a Lua `pcall`.
+    ///
+    /// The hook is installed on this thread only, coroutines created afterwards inherit it.
+    /// Setting a new count hook replaces the previous one.
+    ///
+    /// # Panics
+    /// This panics if `count` is 0.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let mut budget = 100;
+    ///     thread.set_count_hook(1000, move |_| {
+    ///         budget -= 1;
+    ///         if budget == 0 {
+    ///             return Err(pollua::Error::runtime("instruction budget exhausted"));
+    ///         }
+    ///         Ok(())
+    ///     });
+    ///     let err = thread
+    ///         .caller_load("while true do end", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call().map(|_| ()))
+    ///         .unwrap_err();
+    ///     assert_eq!(err.msg(), Some("instruction budget exhausted"));
+    /// }).unwrap()
+    /// ```
+    pub fn set_count_hook<F>(&mut self, count: u32, f: F)
+    where
+        F: FnMut(&mut Thread) -> LuaResult<()> + 'static,
+    {
+        assert!(count > 0, "the hook count must be positive");
+        self.extra_mut().count_hook = Some(Rc::new(RefCell::new(f)));
+        let count = count.min(libc::c_int::MAX as u32) as libc::c_int;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let mask = sys::lua_gethookmask(ptr) | sys::LUA_MASKCOUNT;
+            sys::lua_sethook(ptr, Some(hook), mask, count);
+        }
+    }
+
+    /// Removes the count hook of this thread.
+    pub fn remove_count_hook(&mut self) {
+        unsafe { self.disable_hook_events(sys::LUA_MASKCOUNT) };
+        if self.extra().is_some() {
+            self.extra_mut().count_hook = None;
+        }
+    }
+
+    /// Sets a hook calling `f` with the line number each time the interpreter is about to
+    /// start the execution of a new line of code, or when it jumps back in the code.
+    /// Returning an error from `f` raises a Lua error with the error message.
+    ///
+    /// The hook is installed on this thread only, coroutines created afterwards inherit it.
+    /// Setting a new line hook replaces the previous one, the count hook is kept.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    /// use std::{cell::RefCell, rc::Rc};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let lines = Rc::new(RefCell::new(Vec::new()));
+    ///     let hook_lines = lines.clone();
+    ///     thread.set_line_hook(move |_, line| {
+    ///         hook_lines.borrow_mut().push(line);
+    ///         Ok(())
+    ///     });
+    ///     thread
+    ///         .caller_load("local a = 1\nlocal b = 2", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call().map(|_| ()))
+    ///         .unwrap();
+    ///     assert_eq!(*lines.borrow(), [1, 2]);
+    /// }).unwrap()
+    /// ```
+    pub fn set_line_hook<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Thread, u32) -> LuaResult<()> + 'static,
+    {
+        self.extra_mut().line_hook = Some(Rc::new(RefCell::new(f)));
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let mask = sys::lua_gethookmask(ptr) | sys::LUA_MASKLINE;
+            sys::lua_sethook(ptr, Some(hook), mask, sys::lua_gethookcount(ptr));
+        }
+    }
+
+    /// Removes the line hook of this thread.
+    pub fn remove_line_hook(&mut self) {
+        unsafe { self.disable_hook_events(sys::LUA_MASKLINE) };
+        if self.extra().is_some() {
+            self.extra_mut().line_hook = None;
+        }
+    }
+
+    /// Replaces the count hook with a hook called every `count` instructions that raises
+    /// an error with the message `msg` once `abort` returns true, and keeps raising it at
+    /// every instruction so that Lua code cannot catch it for good.
+    pub(super) fn set_abort_hook<F>(
+        &mut self,
+        count: u32,
+        kind: ErrorKind,
+        msg: &'static str,
+        mut abort: F,
+    ) -> AbortHook
+    where
+        F: FnMut() -> bool + 'static,
+    {
+        let previous = unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_gethookmask(ptr) & sys::LUA_MASKCOUNT != 0 {
+                let count = sys::lua_gethookcount(ptr);
+                self.extra()
+                    .and_then(|e| e.count_hook.clone())
+                    .map(|h| (h, count))
+            } else {
+                None
+            }
+        };
+        let aborted = Rc::new(Cell::new(false));
+        let flag = aborted.clone();
+        self.set_count_hook(count, move |thread| {
+            if flag.get() || abort() {
+                if !flag.replace(true) {
+                    unsafe {
+                        let ptr = thread.raw.as_ptr();
+                        sys::lua_sethook(ptr, Some(hook), sys::lua_gethookmask(ptr), 1);
+                    }
+                }
+                return Err(Error::runtime(msg));
+            }
+            Ok(())
+        });
+        AbortHook {
+            previous,
+            aborted,
+            kind,
+        }
+    }
+
+    /// Restores the count hook replaced by `hook`, and returns the kind of the error of
+    /// the call if the hook aborted it.
+    pub(super) fn remove_abort_hook(&mut self, hook: AbortHook) -> Option<ErrorKind> {
+        match hook.previous {
+            Some((f, count)) => {
+                self.extra_mut().count_hook = Some(f);
+                unsafe {
+                    let ptr = self.raw.as_ptr();
+                    let mask = sys::lua_gethookmask(ptr) | sys::LUA_MASKCOUNT;
+                    sys::lua_sethook(ptr, Some(self::hook), mask, count);
+                }
+            }
+            None => self.remove_count_hook(),
+        }
+        if hook.aborted.get() {
+            Some(hook.kind)
+        } else {
+            None
+        }
+    }
+
+    /// Disables the hook events of `mask`, keeping the other events and the count.
+    unsafe fn disable_hook_events(&mut self, mask: libc::c_int) {
+        let ptr = self.raw.as_ptr();
+        let mask = sys::lua_gethookmask(ptr) & !mask;
+        if mask == 0 {
+            sys::lua_sethook(ptr, None, 0, 0);
+        } else {
+            sys::lua_sethook(ptr, Some(hook), mask, sys::lua_gethookcount(ptr));
+        }
+    }
+}
+
+/// Runs the Rust callback of the hook event.
+unsafe fn run_hook(l: *mut sys::lua_State, ar: *mut sys::lua_Debug) -> Result<(), Raise> {
+    let mut thread = ThreadRef::from_raw(NonNull::new_unchecked(l));
+    let extra = match thread.extra() {
+        Some(extra) => extra,
+        None => return Ok(()),
+    };
+    // a hook running in another coroutine cannot run reentrantly, the event is skipped
+    match (*ar).event {
+        sys::LUA_HOOKCOUNT => match extra.count_hook.clone() {
+            Some(hook) => Raise::catch(|| match hook.try_borrow_mut() {
+                Ok(mut f) => f(&mut thread),
+                Err(_) => Ok(()),
+            }),
+            None => Ok(()),
+        },
+        sys::LUA_HOOKLINE => match extra.line_hook.clone() {
+            Some(hook) => {
+                let line = (*ar).currentline.max(0) as u32;
+                Raise::catch(|| match hook.try_borrow_mut() {
+                    Ok(mut f) => f(&mut thread, line),
+                    Err(_) => Ok(()),
+                })
+            }
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+unsafe extern "C" fn hook(l: *mut sys::lua_State, ar: *mut sys::lua_Debug) {
+    // lua_error does not return, nothing may be left to drop when calling it
+    if let Err(raise) = run_hook(l, ar) {
+        raise.raise(l)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{thread::LoadingMode, Error, ErrorKind};
+    use std::cell::Cell;
+
+    #[test]
+    fn test_count_hook() {
+        Thread::spawn(move |thread| {
+            let calls = Rc::new(Cell::new(0));
+            let counter = calls.clone();
+            thread.set_count_hook(10, move |_| {
+                counter.set(counter.get() + 1);
+                if counter.get() >= 50 {
+                    Err(Error::runtime("too many instructions"))
+                } else {
+                    Ok(())
+                }
+            });
+
+            let top = thread.stack_len();
+            let err = thread
+                .caller_load(
+                    "local i = 0 while true do i = i + 1 end",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("too many instructions"));
+            assert_eq!(calls.get(), 50);
+            assert_eq!(thread.stack_len(), top);
+
+            thread.remove_count_hook();
+            thread
+                .caller_load("for i = 1, 1000 do end", None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap();
+            assert_eq!(calls.get(), 50);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_count_hook_replace() {
+        Thread::spawn(move |thread| {
+            let replaced = Rc::new(Cell::new(false));
+            let flag = replaced.clone();
+            thread.set_count_hook(1, move |thread| {
+                let flag = flag.clone();
+                // replacing the hook from inside the hook
+                thread.set_count_hook(1, move |_| {
+                    flag.set(true);
+                    Err(Error::runtime("stop"))
+                });
+                Ok(())
+            });
+            let err = thread
+                .caller_load("while true do end", None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.msg(), Some("stop"));
+            assert!(replaced.get());
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_line_hook() {
+        Thread::spawn(move |thread| {
+            let lines = Rc::new(RefCell::new(Vec::new()));
+            let hook_lines = lines.clone();
+            thread.set_line_hook(move |_, line| {
+                hook_lines.borrow_mut().push(line);
+                if line == 5 {
+                    Err(Error::runtime("breakpoint"))
+                } else {
+                    Ok(())
+                }
+            });
+            let counts = Rc::new(Cell::new(0));
+            let counter = counts.clone();
+            thread.set_count_hook(1, move |_| {
+                counter.set(counter.get() + 1);
+                Ok(())
+            });
+
+            let chunk = "local a = 1\nlocal b = 2\n\nlocal c = a + b\nlocal d = c";
+            let err = thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.msg(), Some("breakpoint"));
+            assert_eq!(*lines.borrow(), [1, 2, 4, 5]);
+            // both hooks are called
+            assert!(counts.get() > 0);
+
+            thread.remove_line_hook();
+            let count = counts.get();
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap();
+            assert_eq!(lines.borrow().len(), 4);
+            assert!(counts.get() > count);
+
+            thread.remove_count_hook();
+            assert!(unsafe { sys::lua_gethook(thread.as_raw().as_ptr()) }.is_none());
+        })
+        .unwrap()
+    }
+}
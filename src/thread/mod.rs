@@ -1,23 +1,67 @@
-use crate::{util, Error, ErrorKind, LuaResult};
+use crate::{
+    util,
+    value::{FromLua, LuaValue, NumberKind, ToLua, ValueType},
+    Error, ErrorKind, LuaResult,
+};
 
 use std::{
     alloc::{self, Layout},
     any::Any,
-    error, fmt,
+    convert::TryFrom,
+    error,
+    ffi::{CStr, CString},
+    fmt,
+    io::{self, Read},
     marker::PhantomData,
-    mem::ManuallyDrop,
+    mem::{self, ManuallyDrop},
     ops::{Deref, DerefMut},
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    path::Path,
     ptr::{self, NonNull},
+    rc::Rc,
     slice,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
+mod app_data;
+mod builder;
 mod call;
+mod chunk;
+mod extra;
+mod function;
+mod future;
+mod guard;
+mod hook;
+mod ops;
+mod panic;
+mod reference;
+mod scoped;
+mod table;
+mod userdata;
+
+use panic::CallbackPanic;
 
+pub use builder::*;
 pub use call::*;
+pub use chunk::*;
+pub use future::*;
+pub use guard::*;
+pub use hook::*;
+pub use ops::*;
+pub use reference::*;
+pub use scoped::*;
+pub use table::*;
+pub use userdata::*;
 
 #[derive(Debug)]
 pub enum ThreadError {
+    /// A Rust function called by Lua panicked, holds the panic payload.
     Panic(Box<dyn Any + Send + 'static>),
+    /// A Lua error: the thread could not be created, an error was raised outside of any
+    /// protected call, or an error converted with `From`, like a [`LuaResult`] returned
+    /// with `?` from a function returning a `ThreadError`.
+    ///
+    /// [`LuaResult`]: ../type.LuaResult.html
     Lua(Error),
 }
 
@@ -35,11 +79,24 @@ impl error::Error for ThreadError {
 }
 
 impl fmt::Display for ThreadError {
+    /// Formats the error as `lua error: <message>`, followed by the traceback on a new line
+    /// if present, or `panicked while running thread: <panic message>`.
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: ", error::Error::description(self))?;
+        f.write_str(error::Error::description(self))?;
         match self {
-            ThreadError::Panic(panic) => fmt::Debug::fmt(panic, f),
-            ThreadError::Lua(error) => fmt::Display::fmt(error, f),
+            ThreadError::Panic(payload) => write!(f, ": {}", panic::panic_message(&**payload)),
+            ThreadError::Lua(error) => {
+                write!(
+                    f,
+                    ": {}",
+                    error.msg().unwrap_or_else(|| error.kind().as_str())
+                )?;
+                match error.traceback() {
+                    Some(traceback) => write!(f, "\n{}", traceback),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
@@ -50,10 +107,58 @@ impl From<Error> for ThreadError {
     }
 }
 
+impl ThreadError {
+    /// Returns the Lua error, or `None` if the thread panicked.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{LuaResultExt, Thread}, ErrorKind};
+    ///
+    /// let err = Thread::spawn(move |thread| {
+    ///     thread.do_string("return nil + 1", None).into_thread_result()
+    /// }).unwrap().unwrap_err();
+    /// assert_eq!(err.as_lua().map(|e| e.kind()), Some(ErrorKind::Runtime));
+    /// ```
+    #[inline]
+    pub fn as_lua(&self) -> Option<&Error> {
+        match self {
+            ThreadError::Lua(error) => Some(error),
+            ThreadError::Panic(_) => None,
+        }
+    }
+}
+
+/// Extension methods for [`LuaResult`].
+///
+/// [`LuaResult`]: ../type.LuaResult.html
+pub trait LuaResultExt<T> {
+    /// Converts the error into a [`ThreadError`], like `map_err(ThreadError::from)`.
+    ///
+    /// [`ThreadError`]: enum.ThreadError.html
+    fn into_thread_result(self) -> Result<T, ThreadError>;
+}
+
+impl<T> LuaResultExt<T> for LuaResult<T> {
+    #[inline]
+    fn into_thread_result(self) -> Result<T, ThreadError> {
+        self.map_err(ThreadError::Lua)
+    }
+}
+
 /// Lua thread (state) wrapper.
+///
+/// `Thread` is neither `Send` nor `Sync`: a Lua state must not be used from several threads,
+/// and it may hold Rust values that are not `Send` themselves, like the closures passed to
+/// [`push_function`]. See [`SendThread`] for moving an idle state to another thread.
+///
+/// [`push_function`]: #method.push_function
+/// [`SendThread`]: struct.SendThread.html
 #[derive(Debug)]
+#[repr(transparent)]
 pub struct Thread {
     raw: NonNull<sys::lua_State>,
+    /// Opts out of `Send` and `Sync` explicitly.
+    _marker: PhantomData<*mut ()>,
 }
 
 impl Thread {
@@ -75,6 +180,91 @@ impl Thread {
         unsafe { Thread::spawn_with_allocator(f, None, ptr::null_mut::<()>()) }
     }
 
+    /// Creates a new Lua state using the default allocator and returns the owned thread.
+    /// The state is closed when the thread is dropped.
+    ///
+    /// Unlike [`Thread::spawn`], panics of Rust functions called by Lua are not turned into
+    /// errors, they resume when the call returns. See [`ThreadBuilder`] for more options.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// let mut thread = Thread::open().unwrap();
+    /// thread.set_global("answer", 42i64).unwrap();
+    /// assert_eq!(thread.get_global::<i64, _>("answer").unwrap(), 42);
+    /// ```
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`ThreadBuilder`]: struct.ThreadBuilder.html
+    #[inline]
+    pub fn open() -> Result<Thread, ThreadError> {
+        ThreadBuilder::new().build()
+    }
+
+    /// Creates a new Lua state using the default allocator and returns a guard owning it,
+    /// see [`ScopedThread`]. The state is closed when the guard is dropped.
+    ///
+    /// Unlike [`Thread::open`], panics of Rust functions called by Lua are caught like with
+    /// [`Thread::spawn`]: the call returns an error and the guard keeps the panic payload.
+    ///
+    /// [`ScopedThread`]: struct.ScopedThread.html
+    /// [`Thread::open`]: struct.Thread.html#method.open
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    #[inline]
+    pub fn scoped() -> Result<ScopedThread, ThreadError> {
+        Thread::open().map(ScopedThread::new)
+    }
+
+    /// A variant of [`Thread::spawn`] that opens the standard libraries in `libs`, and only
+    /// those, in the new state before running `f`.
+    /// The other libraries are left out, unlike with [`open_libs`] which opens all of them.
+    /// Each call creates a new state and opens `libs` in it.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{StdLib, Thread};
+    ///
+    /// Thread::spawn_with_libs(&[StdLib::Base, StdLib::String], move |thread| {
+    ///     thread.do_string("assert(string.rep('a', 3) == 'aaa')", None).unwrap();
+    ///     assert!(thread.do_string("io.write('no io')", None).is_err());
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`open_libs`]: #method.open_libs
+    pub fn spawn_with_libs<F, T>(libs: &[StdLib], f: F) -> Result<T, ThreadError>
+    where
+        F: FnOnce(&mut Thread) -> T,
+    {
+        ThreadBuilder::new().open_libs(libs).spawn(f)
+    }
+
+    /// A variant of [`Thread::spawn`] that limits the memory used by Lua to `limit` bytes.
+    /// Allocations that would exceed the limit fail with [`ErrorKind::OutOfMemory`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::{LoadingMode, Thread}, ErrorKind};
+    ///
+    /// Thread::spawn_with_memory_limit(64 * 1024, move |thread| {
+    ///     let err = thread
+    ///         .caller_load("local t = {} for i = 1, 1e6 do t[i] = i end", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call().map(|_| ()))
+    ///         .unwrap_err();
+    ///     assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn spawn_with_memory_limit<F, T>(limit: usize, f: F) -> Result<T, ThreadError>
+    where
+        F: FnOnce(&mut Thread) -> T,
+    {
+        ThreadBuilder::new().memory_limit(limit).spawn(f)
+    }
+
     /// A variant of [`Thread::spawn`] that takes an optional allocator function.alloc
     ///
     /// # Safety
@@ -92,9 +282,29 @@ impl Thread {
     where
         F: FnOnce(&mut Thread) -> T,
     {
-        Thread::new(allocator, userdata as *mut libc::c_void)
-            .map(|mut t| f(&mut t))
-            .map_err(ThreadError::from)
+        let mut thread = Thread::new(allocator, userdata as *mut libc::c_void)?;
+        thread.run_scoped(f)
+    }
+
+    /// Runs `f` with this thread, turning the panics of Lua callbacks and the errors raised
+    /// outside of any protected call into errors.
+    pub(super) fn run_scoped<F, T>(&mut self, f: F) -> Result<T, ThreadError>
+    where
+        F: FnOnce(&mut Thread) -> T,
+    {
+        // the panic function keeps its error in the extra data
+        self.extra_mut();
+        let result = catch_unwind(AssertUnwindSafe(|| f(self)));
+        result.map_err(|payload| {
+            // only the panics coming from Lua are turned into errors
+            match payload.downcast::<CallbackPanic>() {
+                Ok(p) => ThreadError::Panic(p.0),
+                Err(payload) => match self.extra_mut().lua_panic.take() {
+                    Some(error) => ThreadError::Lua(error),
+                    None => resume_unwind(payload),
+                },
+            }
+        })
     }
 
     /// Creates a `Thread` from an allocator function.
@@ -109,14 +319,19 @@ impl Thread {
     ///
     /// [`the Lua manual`]: https://www.lua.org/manual/5.3/manual.html#lua_Alloc
     unsafe fn new(allocator: sys::lua_Alloc, userdata: *mut libc::c_void) -> LuaResult<Thread> {
-        let mut thread = Thread {
-            raw: NonNull::new(match allocator {
-                Some(_) => sys::lua_newstate(allocator, userdata),
-                None => sys::lua_newstate(Some(alloc_default), ptr::null_mut()),
-            })
-            .ok_or_else(|| Error::new(ErrorKind::OutOfMemory, None))?,
-        };
-        sys::lua_atpanic(thread.raw.as_ptr(), Some(at_panic));
+        let raw = NonNull::new(match allocator {
+            Some(_) => sys::lua_newstate(allocator, userdata),
+            None => sys::lua_newstate(Some(alloc_default), ptr::null_mut()),
+        })
+        .ok_or_else(|| Error::new(ErrorKind::OutOfMemory, None))?;
+        let mut thread = Thread::from_raw(raw);
+        // the panic function unwinds through the C frames of `lua_error`, Lua only calls it
+        // from C so the ABIs only differ by the unwinding
+        let panicf = mem::transmute::<
+            unsafe extern "C-unwind" fn(*mut sys::lua_State) -> libc::c_int,
+            unsafe extern "C" fn(*mut sys::lua_State) -> libc::c_int,
+        >(at_panic);
+        sys::lua_atpanic(thread.raw.as_ptr(), Some(panicf));
         thread.check_version()?;
         Ok(thread)
     }
@@ -134,49 +349,218 @@ impl Thread {
 
         // If luaL_checkversion failed, pcall will return an error
         sys::lua_pushcfunction(self.raw.as_ptr(), Some(check));
-        self.get_error(sys::lua_pcall(self.raw.as_ptr(), 0, 0, 0))
+        let code = self.pcall(0, 0);
+        self.get_error(code)
+    }
+
+    /// Calls the function below the `nargs` values at the top of the stack in protected mode,
+    /// installing the traceback message handler if it is enabled.
+    /// Returns the status code of `lua_pcall`.
+    ///
+    /// # Safety
+    /// Behavior is undefined if the stack does not hold a function followed by `nargs` values.
+    pub(crate) unsafe fn pcall(
+        &mut self,
+        nargs: libc::c_int,
+        nresults: libc::c_int,
+    ) -> libc::c_int {
+        let ptr = self.raw.as_ptr();
+        if self.traceback_enabled() {
+            // put the message handler below the function
+            let base = sys::lua_gettop(ptr) - nargs;
+            sys::lua_pushcfunction(ptr, Some(traceback_handler));
+            sys::lua_insert(ptr, base);
+            let status = sys::lua_pcall(ptr, nargs, nresults, base);
+            sys::lua_remove(ptr, base);
+            status
+        } else {
+            sys::lua_pcall(ptr, nargs, nresults, 0)
+        }
+    }
+
+    /// Sets whether protected calls made through this thread record a Lua stack traceback
+    /// in runtime errors, see [`Error::traceback`]. Disabled by default.
+    ///
+    /// The setting is shared by all the coroutines of the state.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, StdLib, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_lib(StdLib::Base).unwrap();
+    ///     thread.set_traceback_enabled(true);
+    ///     let err = thread
+    ///         .caller_load("error('oops')", Some("=script"), LoadingMode::Text)
+    ///         .and_then(|c| c.call().map(|_| ()))
+    ///         .unwrap_err();
+    ///     assert_eq!(err.msg(), Some("script:1: oops"));
+    ///     assert!(err.traceback().unwrap().starts_with("stack traceback:"));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Error::traceback`]: ../struct.Error.html#method.traceback
+    #[inline]
+    pub fn set_traceback_enabled(&mut self, enabled: bool) {
+        self.extra_mut().traceback = enabled;
+    }
+
+    /// Returns whether protected calls record a stack traceback in runtime errors.
+    #[inline]
+    pub fn traceback_enabled(&self) -> bool {
+        self.extra().is_some_and(|e| e.traceback)
+    }
+
+    /// Limits the error messages and tracebacks collected by [`get_error`] to `max_len`
+    /// bytes, longer ones are cut and end with `...`. `None`, the default, disables the limit.
+    ///
+    /// The start of the message is kept, it usually holds the location and the cause of the
+    /// error. The setting is shared by all the coroutines of the state.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.set_max_error_len(Some(16));
+    ///     let err = thread.do_string("local x = nil + 1", Some("=script")).unwrap_err();
+    ///     assert_eq!(err.msg(), Some("script:1: attemp..."));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`get_error`]: #method.get_error
+    #[inline]
+    pub fn set_max_error_len(&mut self, max_len: Option<usize>) {
+        self.extra_mut().max_error_len = max_len;
+    }
+
+    /// Returns the maximum length of the error messages, see [`set_max_error_len`].
+    ///
+    /// [`set_max_error_len`]: #method.set_max_error_len
+    #[inline]
+    pub fn max_error_len(&self) -> Option<usize> {
+        self.extra().and_then(|e| e.max_error_len)
+    }
+
+    /// Sets the function called with the error message when an error is raised outside of
+    /// any protected call, instead of panicking.
+    ///
+    /// Lua cannot recover from such errors: when the handler returns, Lua aborts the process.
+    /// The handler can log the error before that happens, or exit the process another way.
+    /// Without a handler, the panic function panics instead, which [`Thread::spawn`] and
+    /// [`ScopedThread::run`] turn into an error.
+    ///
+    /// The handler is shared by all the coroutines of the state.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.set_panic_handler(|msg| {
+    ///         eprintln!("unprotected Lua error: {}", msg.unwrap_or("<no error message>"));
+    ///     });
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    /// [`ScopedThread::run`]: struct.ScopedThread.html#method.run
+    pub fn set_panic_handler<F: Fn(Option<&str>) + 'static>(&mut self, f: F) {
+        self.extra_mut().panic_handler = Some(Rc::new(f));
+    }
+
+    /// Removes the handler set by [`set_panic_handler`], Lua panics panic again.
+    ///
+    /// [`set_panic_handler`]: #method.set_panic_handler
+    pub fn remove_panic_handler(&mut self) {
+        if self.extra().is_some() {
+            self.extra_mut().panic_handler = None;
+        }
     }
 
     /// Returns the error for the given `code`.
-    /// If `code` is not `LUA_OK` then the object at stack index -1 is used as the error message.
+    /// If `code` is not `LUA_OK` then the object at stack index -1 is used as the error message
+    /// and popped from the stack.
+    /// If the traceback message handler is enabled, the traceback it appended to the message
+    /// of a runtime error is moved to [`Error::traceback`]. Both are cut to the length set with
+    /// [`set_max_error_len`].
+    ///
+    /// # Panics
+    /// If the error was raised by a panic in a Rust function called by Lua, the panic is resumed,
+    /// unless the state belongs to a [`ScopedThread`]: the payload is kept by the guard and
+    /// a runtime error is returned.
+    ///
+    /// [`Error::traceback`]: ../struct.Error.html#method.traceback
+    /// [`set_max_error_len`]: #method.set_max_error_len
+    /// [`ScopedThread`]: struct.ScopedThread.html
     pub fn get_error(&mut self, code: libc::c_int) -> LuaResult<()> {
         if code == sys::LUA_OK {
-            Ok(())
-        } else {
-            Err(Error {
-                kind: match code {
-                    sys::LUA_ERRRUN => ErrorKind::Runtime,
-                    sys::LUA_ERRSYNTAX => ErrorKind::Syntax,
-                    sys::LUA_ERRMEM => ErrorKind::OutOfMemory,
-                    sys::LUA_ERRERR => ErrorKind::MessageHandler,
-                    sys::LUA_ERRGCMM => ErrorKind::GarbageCollection,
-                    sys::LUA_ERRFILE | _ => ErrorKind::Io,
-                },
-                msg: unsafe {
-                    // check if there is a value at stack index -1
-                    if sys::lua_isnone(self.as_raw().as_ptr(), -1) == 0 {
-                        let mut len = 0usize;
-                        // get the error object as a c string
-                        let s = sys::luaL_tolstring(self.as_raw().as_ptr(), -1, &mut len as *mut _);
-                        // luaL_tolstring also pushes its result to the stack, so we have to pop it.
-                        sys::lua_pop(self.as_raw().as_ptr(), -1);
-                        if s.is_null() {
-                            None
-                        } else {
-                            // s is garanteed to be a valid c string at this point.
-                            let buf = slice::from_raw_parts(s as *const u8, len);
-                            Some(String::from_utf8_lossy(buf).into_owned())
-                        }
-                    } else {
-                        None
-                    }
-                },
-            })
+            return Ok(());
+        }
+        let kind = error_kind(code);
+        let mut msg = unsafe {
+            let ptr = self.raw.as_ptr();
+            // resume the panic of a Rust function
+            if let Some(payload) = panic::take_panic(ptr, -1) {
+                sys::lua_pop(ptr, 1);
+                if !self.extra().is_some_and(|extra| extra.catch_panics) {
+                    resume_unwind(Box::new(CallbackPanic(payload)));
+                }
+                let msg = format!(
+                    "panic in Rust function: {}",
+                    panic::panic_message(&*payload)
+                );
+                let extra = self.extra_mut();
+                if extra.caught_panic.is_none() {
+                    extra.caught_panic = Some(payload);
+                }
+                return Err(Error::new(ErrorKind::Runtime, Some(msg)));
+            }
+            // check if there is a value at stack index -1
+            if sys::lua_isnone(ptr, -1) == 0 {
+                let mut len = 0usize;
+                // get the error object as a c string
+                let s = sys::luaL_tolstring(ptr, -1, &mut len as *mut _);
+                let msg = if s.is_null() {
+                    None
+                } else {
+                    // s is garanteed to be a valid c string at this point.
+                    let buf = slice::from_raw_parts(s as *const u8, len);
+                    Some(String::from_utf8_lossy(buf).into_owned())
+                };
+                // luaL_tolstring also pushes its result to the stack,
+                // so we have to pop it along with the error object.
+                sys::lua_pop(ptr, 2);
+                msg
+            } else {
+                None
+            }
+        };
+        let mut traceback = None;
+        if kind == ErrorKind::Runtime && self.traceback_enabled() {
+            if let Some(m) = &mut msg {
+                // the format used by luaL_traceback
+                if let Some(i) = m.rfind("\nstack traceback:\n") {
+                    traceback = Some(m.split_off(i + 1));
+                    m.pop();
+                }
+            }
         }
+        if let Some(max_len) = self.max_error_len() {
+            for s in msg.iter_mut().chain(traceback.iter_mut()) {
+                truncate_message(s, max_len);
+            }
+        }
+        let mut error = Error::new(kind, msg);
+        error.traceback = traceback;
+        Err(error)
     }
 
     /// Returns the Lua version number.
     ///
+    /// # Panics
+    /// This panics if Lua does not provide the version number, see [`try_version`].
+    ///
     /// # Examples
     /// ```
     /// use pollua::thread::Thread;
@@ -187,9 +571,95 @@ impl Thread {
     ///
     /// assert_eq!(thread_version, pollua::lua_version());
     /// ```
+    ///
+    /// [`try_version`]: #method.try_version
+    #[inline]
+    pub fn version(&self) -> crate::Number {
+        self.try_version()
+            .expect("lua_version returned a null pointer")
+    }
+
+    /// Returns the Lua version number,
+    /// or `None` if `lua_version` returns a null pointer.
+    #[inline]
+    pub fn try_version(&self) -> Option<crate::Number> {
+        unsafe { sys::lua_version(self.raw.as_ptr()).as_ref().copied() }
+    }
+
+    /// Checks that the Lua headers this crate was built with, the core that created the
+    /// state and the core running the call all agree, like `luaL_checkversion`.
+    /// Returns the expected and actual version numbers, which are equal on success.
+    ///
+    /// A mismatch between the versions is reported as an [`ErrorKind::Version`] error holding
+    /// both of them, see [`Error::versions`]. The other checks of `luaL_checkversion`
+    /// (several Lua cores in the process, numeric types of different sizes) fail with a
+    /// runtime error carrying its own message.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let (expected, actual) = thread.verify_version().unwrap();
+    ///     assert_eq!(expected, actual);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::Version`]: ../enum.ErrorKind.html#variant.Version
+    /// [`Error::versions`]: ../struct.Error.html#method.versions
+    pub fn verify_version(&mut self) -> LuaResult<(crate::Number, crate::Number)> {
+        let expected = sys::LUA_VERSION_NUM;
+        let actual = match self.try_version() {
+            Some(version) => version,
+            None => {
+                let msg = String::from("the Lua core did not report its version");
+                return Err(Error::new(ErrorKind::Runtime, Some(msg)));
+            }
+        };
+        if actual != expected {
+            let msg = format!("expected {}, got {}", expected, actual);
+            let mut err = Error::new(ErrorKind::Version, Some(msg));
+            err.versions = Some((expected, actual));
+            return Err(err);
+        }
+        unsafe { self.check_version()? };
+        Ok((expected, actual))
+    }
+
+    /// Performs a full garbage-collection cycle.
+    #[inline]
+    pub fn gc_collect(&mut self) {
+        unsafe { sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCCOLLECT, 0) };
+    }
+
+    /// Returns the total amount of memory in use by Lua, in bytes.
+    #[inline]
+    pub fn gc_count(&self) -> usize {
+        unsafe {
+            let kbytes = sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCCOUNT, 0) as usize;
+            let bytes = sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCCOUNTB, 0) as usize;
+            kbytes * 1024 + bytes
+        }
+    }
+
+    /// Stops the garbage collector.
     #[inline]
-    pub fn version(&self) -> sys::lua_Number {
-        unsafe { *sys::lua_version(self.raw.as_ptr()) }
+    pub fn gc_stop(&mut self) {
+        unsafe { sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCSTOP, 0) };
+    }
+
+    /// Restarts the garbage collector.
+    #[inline]
+    pub fn gc_restart(&mut self) {
+        unsafe { sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCRESTART, 0) };
+    }
+
+    /// Performs an incremental step of garbage collection,
+    /// as if `kb` kilobytes of memory had been allocated.
+    /// Returns `true` if the step finished a collection cycle.
+    #[inline]
+    pub fn gc_step(&mut self, kb: i32) -> bool {
+        unsafe { sys::lua_gc(self.raw.as_ptr(), sys::LUA_GCSTEP, kb as libc::c_int) != 0 }
     }
 
     /// Returns a raw pointer the wrapped `lua_State`.
@@ -201,6 +671,31 @@ impl Thread {
         self.raw
     }
 
+    /// Returns a raw pointer to the wrapped `lua_State` from a shared reference,
+    /// for read-only accesses like `lua_gettop` or `lua_type`.
+    ///
+    /// The pointer must not be used to modify the state, for instance by pushing values,
+    /// while the thread is borrowed. Use [`as_raw`] for that.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// fn top(thread: &Thread) -> libc::c_int {
+    ///     unsafe { pollua::sys::lua_gettop(thread.as_raw_const().as_ptr()) }
+    /// }
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     assert_eq!(top(thread), thread.stack_len());
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`as_raw`]: #method.as_raw
+    #[inline]
+    pub fn as_raw_const(&self) -> NonNull<sys::lua_State> {
+        self.raw
+    }
+
     /// Constructs a `Thread` from a raw pointer.
     ///
     /// After calling this function, the raw pointer is owned by the resulting `Thread`.
@@ -212,7 +707,36 @@ impl Thread {
     /// [`lua_close`]: sys::lua_close
     #[inline]
     pub unsafe fn from_raw(raw: NonNull<sys::lua_State>) -> Thread {
-        Thread { raw }
+        Thread {
+            raw,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Consumes the `Thread`, returning the raw pointer without closing the state.
+    ///
+    /// The caller becomes responsible for closing the state, either with [`lua_close`] or by
+    /// turning the pointer back into a `Thread` with [`from_raw`].
+    /// The allocator data of a state created with a memory limit is leaked if the state is
+    /// closed with [`lua_close`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// let mut thread = Thread::open().unwrap();
+    /// thread.set_global("answer", 42i64).unwrap();
+    ///
+    /// let raw = thread.into_raw();
+    /// let mut thread = unsafe { Thread::from_raw(raw) };
+    /// assert_eq!(thread.get_global::<i64, _>("answer").unwrap(), 42);
+    /// ```
+    ///
+    /// [`lua_close`]: sys::lua_close
+    /// [`from_raw`]: #method.from_raw
+    #[inline]
+    pub fn into_raw(self) -> NonNull<sys::lua_State> {
+        ManuallyDrop::new(self).raw
     }
 
     /// Creates a `Thread` reference (of type [`ThreadRef`]) from a `lua_State` pointer.
@@ -226,228 +750,2886 @@ impl Thread {
         &mut *raw.cast::<Thread>().as_ptr()
     }
 
-    /// Loads a Lua chunk and creates a [`Caller`] for it if there were no errors.
-    /// The resulting [`Caller`] takes no argmuents and returns nothing.
-    ///
-    /// [`Caller`]: struct.Caller.html
-    #[inline(always)]
-    pub fn caller_load<'a, B: AsRef<[u8]> + ?Sized>(
-        &'a mut self,
-        to_load: &B,
-        chunk_name: Option<&str>,
-        mode: LoadingMode,
-    ) -> LuaResult<Caller<'a>> {
-        self.caller_load_impl(to_load.as_ref(), chunk_name, mode)
+    /// Returns the number of values on the stack (the index of the top value).
+    #[inline]
+    pub fn stack_len(&self) -> libc::c_int {
+        unsafe { sys::lua_gettop(self.raw.as_ptr()) }
     }
 
-    /// Creates a [`Caller`] for the given global function name.
-    /// Returns `None` if `_G.[name]` is not defined or is not a function.alloc
+    /// Converts `index` to an absolute index, like `lua_absindex`, so that it keeps referring
+    /// to the same value when more values are pushed.
+    /// Positive indices and pseudo-indices are returned unchanged.
     ///
-    /// [`Caller`]: struct.Caller.html
-    #[inline(always)]
-    pub fn caller_global<S: AsRef<[u8]> + ?Sized>(&mut self, name: &S) -> Option<Caller> {
-        Caller::from_global(ThreadRef::from_ref(self), name.as_ref())
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push(1).unwrap();
+    ///     let index = thread.abs_index(-1);
+    ///     assert_eq!(index, thread.stack_len());
+    ///     thread.push(2).unwrap();
+    ///     assert_eq!(thread.abs_index(-2), index);
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn abs_index(&self, index: libc::c_int) -> libc::c_int {
+        unsafe { sys::lua_absindex(self.raw.as_ptr(), index) }
     }
 
-    /// Creates a [`Caller`] for the function located at the top of the stack.
+    /// Returns the type of the value at `index`, or `None` if the index is not valid.
     ///
-    /// # Safety
-    /// Behavior is undefined if the value at the top of the stack is not a function.
+    /// # Examples
+    /// ```
+    /// use pollua::{value::ValueType, Thread};
     ///
-    /// [`Caller`]: struct.Caller.html
-    #[inline(always)]
-    pub(crate) unsafe fn caller_stack_unchecked(&mut self) -> Caller {
-        Caller::from_stack_unchecked(ThreadRef::from_ref(self))
+    /// Thread::spawn(move |thread| {
+    ///     thread.push("text").unwrap();
+    ///     assert_eq!(thread.type_of(-1), Some(ValueType::String));
+    ///     assert_eq!(thread.type_of(thread.stack_len() + 1), None);
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn type_of(&self, index: libc::c_int) -> Option<ValueType> {
+        ValueType::from_code(unsafe { sys::lua_type(self.raw.as_ptr(), index) })
     }
 
-    /// Similar to `lua_getglobal`, but accepts any string.
-    #[inline(always)]
-    fn push_global<S: AsRef<[u8]> + ?Sized>(&mut self, name: &S) -> libc::c_int {
-        self.push_global_impl(name.as_ref())
+    /// Ensures that the stack has space for at least `extra` more values.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to that size.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[inline]
+    pub fn ensure_stack(&mut self, extra: libc::c_int) -> LuaResult<()> {
+        if unsafe { sys::lua_checkstack(self.raw.as_ptr(), extra) } != 0 {
+            Ok(())
+        } else {
+            let msg = format!("stack overflow (cannot grow by {} values)", extra);
+            Err(Error::new(ErrorKind::OutOfMemory, Some(msg)))
+        }
     }
-}
 
-// Method impls
-impl Thread {
-    fn caller_load_impl<'a>(
-        &'a mut self,
-        buffer: &[u8],
-        chunk_name: Option<&str>,
-        mode: LoadingMode,
-    ) -> LuaResult<Caller<'a>> {
-        let mut name_buf = Vec::new();
+    /// Copies the value at `from` to the stack position `to`, replacing the value there,
+    /// like `lua_copy`.
+    ///
+    /// # Panics
+    /// This panics if either index is not a valid stack index.
+    #[inline]
+    pub fn copy(&mut self, from: libc::c_int, to: libc::c_int) {
+        let from = self.checked_index(from, "copy");
+        let to = self.checked_index(to, "copy");
+        unsafe { sys::lua_copy(self.raw.as_ptr(), from, to) }
+    }
+
+    /// Moves the top value into the stack position `index`, shifting up the values above it,
+    /// like `lua_insert`.
+    ///
+    /// # Panics
+    /// This panics if `index` is not a valid stack index.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::FromLua, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let top = thread.stack_len();
+    ///     thread.push(1).unwrap();
+    ///     thread.push(2).unwrap();
+    ///     thread.push(3).unwrap();
+    ///     // 3 1 2
+    ///     thread.insert(-3);
+    ///     // 3 1
+    ///     thread.remove(-1);
+    ///     // 1
+    ///     thread.replace(-2);
+    ///     assert_eq!(thread.stack_len(), top + 1);
+    ///     assert_eq!(i64::from_lua(thread, -1).unwrap(), 1);
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn insert(&mut self, index: libc::c_int) {
+        let index = self.checked_index(index, "insert");
+        unsafe { sys::lua_insert(self.raw.as_ptr(), index) }
+    }
+
+    /// Removes the value at `index`, shifting down the values above it, like `lua_remove`.
+    ///
+    /// # Panics
+    /// This panics if `index` is not a valid stack index.
+    #[inline]
+    pub fn remove(&mut self, index: libc::c_int) {
+        let index = self.checked_index(index, "remove");
+        unsafe { sys::lua_remove(self.raw.as_ptr(), index) }
+    }
+
+    /// Pops the top value and sets it at the stack position `index`, like `lua_replace`.
+    ///
+    /// # Panics
+    /// This panics if `index` is not a valid stack index.
+    #[inline]
+    pub fn replace(&mut self, index: libc::c_int) {
+        let index = self.checked_index(index, "replace");
+        unsafe { sys::lua_replace(self.raw.as_ptr(), index) }
+    }
+
+    /// Pushes the value of the upvalue `n` of the function at `funcindex` onto the stack
+    /// and returns the name of the upvalue, like `lua_getupvalue`.
+    /// Upvalues are numbered from 1, the names of C function upvalues are empty.
+    /// Returns `None` and pushes nothing if the function has no upvalue `n`
+    /// or the value is not a function.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the value.
+    ///
+    /// # Panics
+    /// This panics if `funcindex` is not a valid stack index.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn get_upvalue(
+        &mut self,
+        funcindex: libc::c_int,
+        n: libc::c_int,
+    ) -> LuaResult<Option<String>> {
+        let funcindex = self.checked_index(funcindex, "get_upvalue");
+        self.ensure_stack(1)?;
         unsafe {
-            let code = sys::luaL_loadbufferx(
-                self.as_raw().as_ptr(),
-                util::cstr_unchecked(Some(buffer)),
-                buffer.len(),
-                util::cstr_buf(chunk_name, &mut name_buf),
-                util::cstr_unchecked(Some(match mode {
-                    LoadingMode::Binary => "b\0",
-                    LoadingMode::Text => "t\0",
-                    LoadingMode::Auto => "bt\0",
-                })),
-            );
-            match self.get_error(code) {
-                Ok(()) => Ok(self.caller_stack_unchecked()),
-                Err(e) => Err(e),
+            let name = sys::lua_getupvalue(self.raw.as_ptr(), funcindex, n);
+            if name.is_null() {
+                Ok(None)
+            } else {
+                Ok(Some(CStr::from_ptr(name).to_string_lossy().into_owned()))
             }
         }
     }
 
-    fn push_global_impl(&mut self, name: &[u8]) -> libc::c_int {
+    /// Pops the top value and assigns it to the upvalue `n` of the function at `funcindex`,
+    /// returning the name of the upvalue, like `lua_setupvalue`.
+    /// Returns `None` and pops nothing if the function has no upvalue `n`
+    /// or the value is not a function.
+    ///
+    /// # Panics
+    /// This panics if `funcindex` is not a valid stack index.
+    pub fn set_upvalue(&mut self, funcindex: libc::c_int, n: libc::c_int) -> Option<String> {
+        let funcindex = self.checked_index(funcindex, "set_upvalue");
         unsafe {
-            let ptr = self.raw.as_ptr();
-            // push the global env onto the stack
-            sys::lua_rawgeti(ptr, sys::LUA_REGISTRYINDEX, sys::LUA_RIDX_GLOBALS);
-            // push the global variable name onto the stack
-            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
-            // fetch _G[name]
-            let value_type = sys::lua_rawget(ptr, -2);
-            // remove the global env from the stack
-            sys::lua_replace(ptr, -2);
-            value_type
+            let name = sys::lua_setupvalue(self.raw.as_ptr(), funcindex, n);
+            if name.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+            }
         }
     }
-}
 
-impl Drop for Thread {
-    fn drop(&mut self) {
+    /// Pushes `value` onto the stack, growing the stack if needed.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow, the stack is
+    /// left untouched in that case.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let top = thread.stack_len();
+    ///     // more values than the LUA_MINSTACK slots guaranteed by Lua
+    ///     for i in 0..100i64 {
+    ///         thread.push(i).unwrap();
+    ///     }
+    ///     assert_eq!(thread.stack_len(), top + 100);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[inline]
+    pub fn push<V: ToLua>(&mut self, value: V) -> LuaResult<()> {
+        self.ensure_stack(1)?;
+        unsafe { value.push(self) }
+    }
+
+    /// Formats `args` and pushes the resulting string onto the stack,
+    /// a safe replacement for `lua_pushfstring`.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::FromLua, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push_formatted(format_args!("{} + {} = {}", 1, 2, 1 + 2)).unwrap();
+    ///     assert_eq!(String::from_lua(thread, -1).unwrap(), "1 + 2 = 3");
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn push_formatted(&mut self, args: fmt::Arguments<'_>) -> LuaResult<()> {
+        match args.as_str() {
+            // no formatting needed
+            Some(s) => self.push(s),
+            None => self.push(fmt::format(args)),
+        }
+    }
+
+    /// Pushes `ptr` onto the stack as a light userdata.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the value.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[inline]
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // the pointer is only stored, never dereferenced
+    pub fn push_light_userdata(&mut self, ptr: *mut libc::c_void) -> LuaResult<()> {
+        self.ensure_stack(1)?;
+        unsafe { sys::lua_pushlightuserdata(self.raw.as_ptr(), ptr) };
+        Ok(())
+    }
+
+    /// Returns the pointer of the light userdata at `index`,
+    /// or `None` if the value is not a light userdata.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let mut handle = 42;
+    ///     let ptr = &mut handle as *mut i32 as *mut libc::c_void;
+    ///     thread.push_light_userdata(ptr).unwrap();
+    ///     assert_eq!(thread.to_light_userdata(-1), Some(ptr));
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn to_light_userdata(&self, index: libc::c_int) -> Option<*mut libc::c_void> {
         unsafe {
-            sys::lua_close(self.raw.as_ptr());
+            let ptr = self.raw.as_ptr();
+            if sys::lua_islightuserdata(ptr, index) != 0 {
+                Some(sys::lua_touserdata(ptr, index))
+            } else {
+                None
+            }
         }
     }
-}
 
-/// Used by the [`Thread::load_bytes`] method, describes how the bytes should be interpreted.
-///
-/// [`Thread::load_bytes`]: struct.Thread.html#method.load_bytes
-#[derive(Debug, Copy, Clone)]
-pub enum LoadingMode {
-    Binary,
-    Text,
-    Auto,
-}
+    /// Returns the number at `index` with its subtype,
+    /// or `None` if the value is not a number. Strings are not converted.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::NumberKind, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push(2i64).unwrap();
+    ///     thread.push(2.0).unwrap();
+    ///     assert_eq!(thread.to_number_kind(-2), Some(NumberKind::Integer(2)));
+    ///     assert_eq!(thread.to_number_kind(-1), Some(NumberKind::Float(2.0)));
+    /// }).unwrap()
+    /// ```
+    pub fn to_number_kind(&self, index: libc::c_int) -> Option<NumberKind> {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_type(ptr, index) != sys::LUA_TNUMBER {
+                return None;
+            }
+            #[cfg(LUA_VERSION = "5.3")]
+            {
+                if sys::lua_isinteger(ptr, index) != 0 {
+                    return Some(NumberKind::Integer(sys::lua_tointeger(ptr, index)));
+                }
+            }
+            Some(NumberKind::Float(sys::lua_tonumber(ptr, index)))
+        }
+    }
 
-/// A mutable reference to a [`Thread`].
-///
-/// [`Thread`]: struct.Thread.html
-pub struct ThreadRef<'a> {
-    // thread's destructor must never be called, as it would trigger a double-free.
-    thread: ManuallyDrop<Thread>,
-    _marker: PhantomData<&'a mut Thread>,
-}
+    /// Reads the integer at `index` like `i64::from_lua`, checking that it fits in an `i32`.
+    /// Returns a runtime error if the value is not an integer or is out of range.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push(42i64).unwrap();
+    ///     assert_eq!(thread.try_to_i32(-1).unwrap(), 42);
+    ///     thread.push(1e10).unwrap();
+    ///     assert!(thread.try_to_i32(-1).is_err());
+    /// }).unwrap()
+    /// ```
+    pub fn try_to_i32(&mut self, index: libc::c_int) -> LuaResult<i32> {
+        let n = i64::from_lua(self, index)?;
+        i32::try_from(n).map_err(|_| {
+            let msg = format!("number {} is not representable as a 32-bit integer", n);
+            Error::new(ErrorKind::Runtime, Some(msg))
+        })
+    }
 
-impl<'a> ThreadRef<'a> {
-    /// Creates a `ThreadRef` from a raw `lua_State` pointer.
+    /// Reads the string or number at `index` like `String::from_lua`, but returns a runtime
+    /// error if the string is not valid UTF-8 instead of replacing the invalid sequences.
     ///
-    /// # Safety
-    /// Behavior is undefined if `raw` is invalid or multiple reference to this `lua_State` exists.
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push("héllo").unwrap();
+    ///     assert_eq!(thread.to_str_strict(-1).unwrap(), "héllo");
+    ///     thread.push(&b"\xffbinary"[..]).unwrap();
+    ///     assert!(thread.to_str_strict(-1).is_err());
+    ///     assert_eq!(thread.to_str_lossy(-1).unwrap(), "\u{fffd}binary");
+    /// }).unwrap()
+    /// ```
+    pub fn to_str_strict(&mut self, index: libc::c_int) -> LuaResult<String> {
+        let bytes = Vec::<u8>::from_lua(self, index)?;
+        String::from_utf8(bytes).map_err(|e| {
+            let msg = format!("invalid UTF-8 in string: {}", e.utf8_error());
+            Error::new(ErrorKind::Runtime, Some(msg))
+        })
+    }
+
+    /// Reads the string or number at `index` like `String::from_lua`:
+    /// invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT CHARACTER`.
     #[inline]
-    pub unsafe fn from_raw(raw: NonNull<sys::lua_State>) -> ThreadRef<'a> {
-        ThreadRef {
-            thread: ManuallyDrop::new(Thread::from_raw(raw)),
-            _marker: PhantomData,
+    pub fn to_str_lossy(&mut self, index: libc::c_int) -> LuaResult<String> {
+        String::from_lua(self, index)
+    }
+
+    /// Returns the bytes of the string at `index` without copying them or assuming any
+    /// encoding, or `None` if the value is not a string. Numbers are not converted, since
+    /// `lua_tolstring` would replace the number on the stack with its string.
+    ///
+    /// The slice borrows the buffer of the Lua string, which is only alive while the
+    /// value stays on the stack: the borrow of the thread lasts as long as the slice so
+    /// the value cannot be popped in the meantime. Copy the bytes with `to_vec` to keep
+    /// them longer.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push(&b"\x00\xffhash"[..]).unwrap();
+    ///     assert_eq!(thread.to_bytes(-1), Some(&b"\x00\xffhash"[..]));
+    ///     thread.push(42i64).unwrap();
+    ///     assert_eq!(thread.to_bytes(-1), None);
+    /// }).unwrap()
+    /// ```
+    pub fn to_bytes(&mut self, index: libc::c_int) -> Option<&[u8]> {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_type(ptr, index) != sys::LUA_TSTRING {
+                return None;
+            }
+            let mut len = 0;
+            let s = sys::lua_tolstring(ptr, index, &mut len);
+            Some(slice::from_raw_parts(s as *const u8, len))
         }
     }
 
-    /// Creates a `ThreadRef` from a [`Thread`] mutable reference.
+    /// Converts `s` to a number following the Lua lexical conventions, like `lua_stringtonumber`:
+    /// hexadecimal and exponent notations are accepted, surrounding whitespace is ignored,
+    /// and the result is an integer or a float depending on the notation.
+    /// Returns `None` if the whole string is not a valid numeral.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the number.
     ///
-    /// [`Thread`]: struct.Thread.html
-    #[inline]
-    pub fn from_ref(thread: &'a mut Thread) -> ThreadRef<'a> {
-        unsafe { ThreadRef::from_raw(thread.as_raw()) }
+    /// # Examples
+    /// ```
+    /// use pollua::{value::LuaValue, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     match thread.string_to_number("0x10").unwrap() {
+    ///         Some(LuaValue::Integer(16)) => (),
+    ///         n => panic!("unexpected number: {:?}", n),
+    ///     }
+    ///     match thread.string_to_number(" 1e2 ").unwrap() {
+    ///         Some(LuaValue::Number(n)) => assert_eq!(n, 100.0),
+    ///         n => panic!("unexpected number: {:?}", n),
+    ///     }
+    ///     assert!(thread.string_to_number("12px").unwrap().is_none());
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[cfg(LUA_VERSION = "5.3")]
+    pub fn string_to_number(&mut self, s: &str) -> LuaResult<Option<LuaValue>> {
+        // a nul byte would end the numeral early
+        let s = match CString::new(s) {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+        self.ensure_stack(1)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_stringtonumber(ptr, s.as_ptr()) == 0 {
+                return Ok(None);
+            }
+            let n = LuaValue::from_lua(self, -1).ok();
+            sys::lua_pop(ptr, 1);
+            Ok(n)
+        }
     }
-}
 
-impl fmt::Debug for ThreadRef<'_> {
+    /// Creates a new empty table and pushes it onto the stack,
+    /// see [`Table`].
+    ///
+    /// [`Table`]: struct.Table.html
     #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.thread.fmt(f)
+    pub fn new_table(&mut self) -> Table<'_> {
+        unsafe {
+            sys::lua_createtable(self.raw.as_ptr(), 0, 0);
+            Table::from_stack_unchecked(ThreadRef::from_ref(self))
+        }
     }
-}
 
-impl<'a> Deref for ThreadRef<'a> {
-    type Target = Thread;
+    /// Creates a table sized for `funcs`, sets each name to its C function and pushes the
+    /// table onto the stack, like `luaL_newlib`. A `None` function is set as `false`,
+    /// a placeholder to be replaced later, like `luaL_setfuncs` does.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the table and an
+    /// entry.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{sys, Thread};
+    ///
+    /// unsafe extern "C" fn answer(l: *mut sys::lua_State) -> libc::c_int {
+    ///     sys::lua_pushinteger(l, 42);
+    ///     1
+    /// }
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     // leave the table on the stack for lua_setglobal
+    ///     thread.new_lib(&[("answer", Some(answer)), ("later", None)]).unwrap().into_raw();
+    ///     unsafe { sys::lua_setglobal(thread.as_raw().as_ptr(), b"mylib\0".as_ptr() as *const _) };
+    ///     thread.do_string("x = mylib.answer() y = mylib.later", None).unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("x").unwrap(), 42);
+    ///     assert!(!thread.get_global::<bool, _>("y").unwrap());
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn new_lib(&mut self, funcs: &[(&str, sys::lua_CFunction)]) -> LuaResult<Table<'_>> {
+        self.ensure_stack(3)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let size = libc::c_int::try_from(funcs.len()).unwrap_or(libc::c_int::MAX);
+            sys::lua_createtable(ptr, 0, size);
+            for &(name, func) in funcs {
+                sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+                match func {
+                    Some(_) => sys::lua_pushcfunction(ptr, func),
+                    None => sys::lua_pushboolean(ptr, 0),
+                }
+                sys::lua_rawset(ptr, -3);
+            }
+            Ok(Table::from_stack_unchecked(ThreadRef::from_ref(self)))
+        }
+    }
 
+    /// Pushes the global table onto the stack and returns a [`Table`] handle to it.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.globals().set("answer", 42i64).unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("answer").unwrap(), 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Table`]: struct.Table.html
     #[inline]
-    fn deref(&self) -> &Self::Target {
-        &self.thread
+    pub fn globals(&mut self) -> Table<'_> {
+        self.push_globals();
+        unsafe { Table::from_stack_unchecked(ThreadRef::from_ref(self)) }
     }
-}
 
-impl<'a> DerefMut for ThreadRef<'a> {
+    /// Returns a guard that restores the current stack top when dropped,
+    /// see [`StackGuard`].
+    ///
+    /// [`StackGuard`]: struct.StackGuard.html
     #[inline]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.thread
+    pub fn stack_guard(&mut self) -> StackGuard<'_> {
+        StackGuard::new(self)
     }
-}
 
-/// Default panic handler function.
-unsafe extern "C" fn at_panic(thread: *mut sys::lua_State) -> libc::c_int {
-    match Thread::ref_from_raw(NonNull::new_unchecked(thread)).get_error(sys::LUA_ERRRUN) {
-        Ok(()) => 0,
-        Err(Error { msg: None, .. }) => panic!("Lua panic: <no error message>"),
-        Err(Error { msg: Some(m), .. }) => panic!("Lua panic: {}", m),
+    /// Creates a new coroutine thread sharing the same global environment as this thread.
+    ///
+    /// The new thread is pushed onto the stack of this thread, which keeps it from being
+    /// garbage collected, it is up to the caller to pop it once it is no longer needed.
+    /// The returned reference borrows this thread: the coroutine must not outlive its parent
+    /// and must not be used once it has been removed from the parent's stack.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     // coroutines share their globals with the parent thread
+    ///     thread.new_coroutine().set_global("co_var", 42i64).unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("co_var").unwrap(), 42);
+    /// }).unwrap()
+    /// ```
+    pub fn new_coroutine(&mut self) -> ThreadRef<'_> {
+        unsafe {
+            // lua_newthread never returns null, it raises a memory error instead
+            let raw = NonNull::new_unchecked(sys::lua_newthread(self.raw.as_ptr()));
+            ThreadRef::from_raw(raw)
+        }
     }
-}
 
-/// Default allocation function.
-/// Uses the liballoc functions instead of the one from libc.
-unsafe extern "C" fn alloc_default(
-    _ud: *mut libc::c_void,
-    ptr: *mut libc::c_void,
-    osize: usize,
-    nsize: usize,
-) -> *mut libc::c_void {
-    if nsize == 0 {
-        alloc::dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(osize, 1));
-        ptr::null_mut()
-    } else if ptr.is_null() {
-        alloc::alloc(Layout::from_size_align_unchecked(nsize, 1)) as *mut _
-    } else {
-        alloc::realloc(
-            ptr as *mut u8,
-            Layout::from_size_align_unchecked(osize, 1),
-            nsize,
-        ) as *mut _
-    }
-}
+    /// Starts or resumes the coroutine running in this thread.
+    ///
+    /// To start a coroutine, push the function and its `nargs` arguments onto the stack.
+    /// To resume it, pop the values it yielded and push the `nargs` values to be passed
+    /// as the results of the yield.
+    ///
+    /// Once finished or yielded, the values returned or yielded by the coroutine are left on
+    /// the stack.
+    pub fn resume(&mut self, nargs: libc::c_int) -> LuaResult<ResumeStatus> {
+        #[cfg(LUA_VERSION = "5.2")]
+        let code = unsafe { sys::lua_resume(self.raw.as_ptr(), ptr::null_mut(), nargs) };
+        #[cfg(not(LUA_VERSION = "5.2"))]
+        let code = unsafe { sys::lua_resume(self.raw.as_ptr(), nargs) };
 
-#[cfg(test)]
-mod test {
-    use super::*;
+        match code {
+            sys::LUA_YIELD => Ok(ResumeStatus::Yielded),
+            code => self.get_error(code).map(|_| ResumeStatus::Finished),
+        }
+    }
 
-    fn stack_top(thread: &mut Thread) -> libc::c_int {
-        unsafe { sys::lua_gettop(thread.as_raw().as_ptr()) }
+    /// Returns the status of this thread, like `lua_status`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{Thread, ThreadStatus};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     assert_eq!(thread.status(), ThreadStatus::Ok);
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn status(&self) -> ThreadStatus {
+        match unsafe { sys::lua_status(self.raw.as_ptr()) } {
+            sys::LUA_OK => ThreadStatus::Ok,
+            sys::LUA_YIELD => ThreadStatus::Yield,
+            code => ThreadStatus::Error(error_kind(code)),
+        }
     }
 
-    fn type_at(thread: &mut Thread, index: libc::c_int) -> libc::c_int {
-        unsafe { sys::lua_type(thread.as_raw().as_ptr(), index) }
+    /// Returns true if the running function can yield, like `lua_isyieldable`.
+    /// Only coroutines can yield, and not from within a non-yieldable call such as a
+    /// metamethod called from C or a protected call made by Rust code.
+    #[cfg(LUA_VERSION = "5.3")]
+    #[inline]
+    pub fn is_yieldable(&self) -> bool {
+        unsafe { sys::lua_isyieldable(self.raw.as_ptr()) != 0 }
     }
 
-    #[test]
+    /// Pushes this thread onto its own stack, like `lua_pushthread`.
+    /// Returns true if it is the main thread of the state.
+    ///
+    /// Threads are compared by identity, so the pushed value can be used to find the running
+    /// coroutine in a table.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the thread.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::ValueType, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     assert!(thread.push_self().unwrap());
+    ///     assert_eq!(thread.type_of(-1), Some(ValueType::Thread));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn push_self(&mut self) -> LuaResult<bool> {
+        self.ensure_stack(1)?;
+        Ok(unsafe { sys::lua_pushthread(self.raw.as_ptr()) != 0 })
+    }
+
+    /// Moves the `n` values at the top of the stack onto the stack of `dest`, like `lua_xmove`.
+    /// This is how arguments are passed to and results read from a coroutine created with
+    /// [`new_coroutine`].
+    ///
+    /// # Panics
+    /// This panics if `n` is negative, if the stack holds less than `n` values or if `dest`
+    /// does not belong to the same Lua state.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{ResumeStatus, Thread, ThreadRef};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let code = b"local a, b = ... return a * b\0";
+    ///     unsafe { pollua::sys::luaL_loadstring(thread.as_raw().as_ptr(), code.as_ptr() as _) };
+    ///     thread.push(6i64).unwrap();
+    ///     thread.push(7i64).unwrap();
+    ///
+    ///     let raw = thread.new_coroutine().as_raw();
+    ///     // the coroutine stays on the stack, keeping it alive
+    ///     let mut co = unsafe { ThreadRef::from_raw(raw) };
+    ///     unsafe { pollua::sys::lua_insert(thread.as_raw().as_ptr(), -4) };
+    ///     thread.move_values_to(&mut co, 3).unwrap();
+    ///     assert_eq!(co.resume(2).unwrap(), ResumeStatus::Finished);
+    ///     co.move_values_to(thread, 1).unwrap();
+    ///     assert_eq!(thread.to_number_kind(-1), Some(pollua::value::NumberKind::Integer(42)));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`new_coroutine`]: #method.new_coroutine
+    pub fn move_values_to(&mut self, dest: &mut Thread, n: libc::c_int) -> LuaResult<()> {
+        assert!(
+            n >= 0 && self.stack_len() >= n,
+            "not enough values on the stack to move {} values",
+            n
+        );
+        // threads of the same state share the registry
+        let same_state = unsafe {
+            sys::lua_topointer(self.raw.as_ptr(), sys::LUA_REGISTRYINDEX)
+                == sys::lua_topointer(dest.raw.as_ptr(), sys::LUA_REGISTRYINDEX)
+        };
+        assert!(
+            same_state,
+            "cannot move values between threads of different Lua states"
+        );
+        dest.ensure_stack(n)?;
+        unsafe { sys::lua_xmove(self.raw.as_ptr(), dest.raw.as_ptr(), n) };
+        Ok(())
+    }
+
+    /// Loads a Lua chunk and creates a [`Caller`] for it if there were no errors.
+    /// The resulting [`Caller`] takes no argmuents and returns nothing.
+    ///
+    /// [`Caller`]: struct.Caller.html
+    #[inline(always)]
+    pub fn caller_load<'a, B: AsRef<[u8]> + ?Sized>(
+        &'a mut self,
+        to_load: &B,
+        chunk_name: Option<&str>,
+        mode: LoadingMode,
+    ) -> LuaResult<Caller<'a>> {
+        self.caller_load_impl(to_load.as_ref(), chunk_name, mode)
+    }
+
+    /// Loads a Lua chunk from the file at `path` and creates a [`Caller`] for it
+    /// if there were no errors.
+    /// Returns an error of kind [`ErrorKind::FileLoad`] if the file could not be read.
+    ///
+    /// [`Caller`]: struct.Caller.html
+    /// [`ErrorKind::FileLoad`]: ../enum.ErrorKind.html#variant.FileLoad
+    #[inline(always)]
+    pub fn caller_load_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: LoadingMode,
+    ) -> LuaResult<Caller<'_>> {
+        self.caller_load_file_impl(path.as_ref(), mode)
+    }
+
+    /// Loads a Lua chunk read from `reader` and creates a [`Caller`] for it
+    /// if there were no errors.
+    /// The chunk is read in pieces as the parser needs them instead of being loaded in memory
+    /// all at once.
+    /// Returns an error of kind [`ErrorKind::Io`] if reading failed.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{LoadingMode, Thread};
+    /// use std::io::Cursor;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let script = Cursor::new("return 40 + 2");
+    ///     let n: i64 = thread
+    ///         .caller_load_reader(script, Some("=script"), LoadingMode::Text)
+    ///         .and_then(|c| c.call_ret())
+    ///         .unwrap();
+    ///     assert_eq!(n, 42);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`Caller`]: struct.Caller.html
+    /// [`ErrorKind::Io`]: ../enum.ErrorKind.html#variant.Io
+    #[inline(always)]
+    pub fn caller_load_reader<R: Read>(
+        &mut self,
+        mut reader: R,
+        chunk_name: Option<&str>,
+        mode: LoadingMode,
+    ) -> LuaResult<Caller<'_>> {
+        self.caller_load_reader_impl(&mut reader, chunk_name, mode)
+    }
+
+    /// Loads and runs a Lua chunk, like `luaL_dostring`, discarding its results.
+    /// Both text and binary chunks are accepted.
+    ///
+    /// Load errors are returned with their kind, like [`ErrorKind::Syntax`], and errors raised
+    /// while running the chunk are returned with the kind of the call error, like
+    /// [`ErrorKind::Runtime`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{ErrorKind, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.do_string("answer = 42", Some("=init")).unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("answer").unwrap(), 42);
+    ///
+    ///     let err = thread.do_string("answer =", None).unwrap_err();
+    ///     assert_eq!(err.kind(), ErrorKind::Syntax);
+    ///     let err = thread.do_string("answer.x = 1", None).unwrap_err();
+    ///     assert_eq!(err.kind(), ErrorKind::Runtime);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::Syntax`]: ../enum.ErrorKind.html#variant.Syntax
+    /// [`ErrorKind::Runtime`]: ../enum.ErrorKind.html#variant.Runtime
+    #[inline]
+    pub fn do_string<B: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        code: &B,
+        chunk_name: Option<&str>,
+    ) -> LuaResult<()> {
+        self.caller_load_impl(code.as_ref(), chunk_name, LoadingMode::Auto)
+            .and_then(|c| c.calln(0).map(|_| ()))
+    }
+
+    /// Loads and runs the Lua chunk in the file at `path`, like `luaL_dofile`,
+    /// discarding its results.
+    /// Errors are returned the same way as [`do_string`], with the kind
+    /// [`ErrorKind::FileLoad`] if the file could not be read.
+    ///
+    /// [`do_string`]: #method.do_string
+    /// [`ErrorKind::FileLoad`]: ../enum.ErrorKind.html#variant.FileLoad
+    #[inline]
+    pub fn do_file<P: AsRef<Path>>(&mut self, path: P) -> LuaResult<()> {
+        self.caller_load_file_impl(path.as_ref(), LoadingMode::Auto)
+            .and_then(|c| c.calln(0).map(|_| ()))
+    }
+
+    /// Creates a [`Caller`] for the given global function name.
+    /// Returns `None` if `_G.[name]` is not defined or is not a function, or if an `__index`
+    /// metamethod of the global table raises an error.
+    ///
+    /// [`Caller`]: struct.Caller.html
+    #[inline(always)]
+    pub fn caller_global<S: AsRef<[u8]> + ?Sized>(&mut self, name: &S) -> Option<Caller<'_>> {
+        Caller::from_global(ThreadRef::from_ref(self), name.as_ref())
+    }
+
+    /// Creates a [`Caller`] for the function located at the top of the stack.
+    ///
+    /// # Safety
+    /// Behavior is undefined if the value at the top of the stack is not a function.
+    ///
+    /// [`Caller`]: struct.Caller.html
+    #[inline(always)]
+    pub(crate) unsafe fn caller_stack_unchecked(&mut self) -> Caller<'_> {
+        Caller::from_stack_unchecked(ThreadRef::from_ref(self))
+    }
+
+    /// Similar to `lua_getglobal`, but accepts any string.
+    #[inline(always)]
+    fn push_global<S: AsRef<[u8]> + ?Sized>(&mut self, name: &S) -> LuaResult<libc::c_int> {
+        self.push_global_impl(name.as_ref())
+    }
+
+    /// Sets the value of the global variable `name` (`_G.[name]`), like `lua_setglobal`.
+    /// A `__newindex` metamethod of the global table is called, and the error it raises is
+    /// returned.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::LuaNumber, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.set_global("answer", 42.0).unwrap();
+    ///     assert_eq!(thread.get_global::<LuaNumber, _>("answer").unwrap(), 42.0.into());
+    /// }).unwrap()
+    /// ```
+    #[inline(always)]
+    pub fn set_global<S: AsRef<[u8]> + ?Sized, V: ToLua>(
+        &mut self,
+        name: &S,
+        value: V,
+    ) -> LuaResult<()> {
+        self.set_global_impl(name.as_ref(), value)
+    }
+
+    /// Sets a string global variable for each `(name, value)` pair of `globals`, in order,
+    /// like [`set_global`] does: the `__newindex` metamethod of the global table is honoured.
+    /// Stops at the first error and returns it, the variables set before are kept.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    /// use std::collections::HashMap;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let mut config = HashMap::new();
+    ///     config.insert(String::from("host"), String::from("localhost"));
+    ///     config.insert(String::from("port"), String::from("8080"));
+    ///     thread.set_globals_from(config).unwrap();
+    ///     assert_eq!(thread.get_global::<String, _>("host").unwrap(), "localhost");
+    ///     assert_eq!(thread.get_global::<i64, _>("port").unwrap(), 8080);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`set_global`]: #method.set_global
+    pub fn set_globals_from<I, K, V>(&mut self, globals: I) -> LuaResult<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        for (name, value) in globals {
+            self.set_global_impl(name.as_ref(), value.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the global variable `name` (`_G.[name]`), like `lua_getglobal`.
+    /// Returns an error of kind [`ErrorKind::Runtime`] if the global is not of type `V`,
+    /// or the error raised by an `__index` metamethod of the global table.
+    ///
+    /// [`ErrorKind::Runtime`]: ../enum.ErrorKind.html#variant.Runtime
+    #[inline(always)]
+    pub fn get_global<V: FromLua, S: AsRef<[u8]> + ?Sized>(&mut self, name: &S) -> LuaResult<V> {
+        self.get_global_impl(name.as_ref())
+    }
+
+    /// Opens all the standard Lua libraries.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::LoadingMode, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_libs().unwrap();
+    ///     thread
+    ///         .caller_load("print('Hello, World!')", None, LoadingMode::Text)
+    ///         .and_then(|c| c.call())
+    ///         .unwrap();
+    /// }).unwrap()
+    /// ```
+    pub fn open_libs(&mut self) -> LuaResult<()> {
+        unsafe extern "C" fn open(l: *mut sys::lua_State) -> libc::c_int {
+            sys::luaL_openlibs(l);
+            0
+        }
+
+        unsafe {
+            sys::lua_pushcfunction(self.raw.as_ptr(), Some(open));
+            let code = self.pcall(0, 0);
+            self.get_error(code)
+        }
+    }
+
+    /// Opens a single standard Lua library and sets it as a global.
+    ///
+    /// Unlike [`open_libs`], this allows choosing which libraries scripts have access to.
+    ///
+    /// [`open_libs`]: #method.open_libs
+    pub fn open_lib(&mut self, lib: StdLib) -> LuaResult<()> {
+        self.require_impl(lib.name().as_bytes(), lib.opener(), true)
+    }
+
+    /// Opens the module `name` with `opener` and stores it in `package.loaded[name]`,
+    /// like `luaL_requiref`, so that `require(name)` returns the module.
+    /// The module is also set as the global `name` if `set_global` is true.
+    ///
+    /// `opener` is only called if the module is not loaded yet, it receives `name` as its
+    /// argument and returns the module. The table of loaded modules is kept in the registry,
+    /// the package library is only needed to call `require` from Lua.
+    /// Returns a runtime error if `name` contains a nul byte or `opener` is `None`,
+    /// and the error raised by `opener`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::StdLib, Thread};
+    ///
+    /// unsafe extern "C" fn open_answer(l: *mut pollua::sys::lua_State) -> libc::c_int {
+    ///     pollua::sys::lua_createtable(l, 0, 1);
+    ///     pollua::sys::lua_pushinteger(l, 42);
+    ///     pollua::sys::lua_setfield(l, -2, b"value\0".as_ptr() as *const _);
+    ///     1
+    /// }
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.open_lib(StdLib::Package).unwrap();
+    ///     thread.preload_module("answer", Some(open_answer), false).unwrap();
+    ///     thread.do_string("n = require('answer').value", None).unwrap();
+    ///     assert_eq!(thread.get_global::<i64, _>("n").unwrap(), 42);
+    /// }).unwrap()
+    /// ```
+    #[inline]
+    pub fn preload_module<S: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        name: &S,
+        opener: sys::lua_CFunction,
+        set_global: bool,
+    ) -> LuaResult<()> {
+        self.require_impl(name.as_ref(), opener, set_global)
+    }
+
+    /// Sets `package.path`, the path used by `require` to search for Lua modules.
+    /// Templates are separated by `;` and `?` is replaced by the module name,
+    /// for instance `"./scripts/?.lua;./scripts/?/init.lua"`.
+    ///
+    /// Returns a runtime error if the package library is not loaded,
+    /// see [`open_lib`] with [`StdLib::Package`].
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{thread::StdLib, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     assert!(thread.set_package_path("./?.lua").is_err());
+    ///     thread.open_lib(StdLib::Package).unwrap();
+    ///     thread.set_package_path("./scripts/?.lua").unwrap();
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`open_lib`]: #method.open_lib
+    /// [`StdLib::Package`]: enum.StdLib.html#variant.Package
+    #[inline]
+    pub fn set_package_path(&mut self, path: &str) -> LuaResult<()> {
+        self.set_package_field(b"path", path)
+    }
+
+    /// Sets `package.cpath`, the path used by `require` to search for C modules.
+    /// Returns a runtime error if the package library is not loaded, like [`set_package_path`].
+    ///
+    /// [`set_package_path`]: #method.set_package_path
+    #[inline]
+    pub fn set_package_cpath(&mut self, path: &str) -> LuaResult<()> {
+        self.set_package_field(b"cpath", path)
+    }
+
+    fn set_package_field(&mut self, field: &[u8], value: &str) -> LuaResult<()> {
+        self.ensure_stack(4)?;
+        let guard = self.stack_guard();
+        unsafe {
+            let ptr = guard.raw.as_ptr();
+            // the loaded modules, package.loaded in Lua
+            sys::lua_getfield(ptr, sys::LUA_REGISTRYINDEX, b"_LOADED\0".as_ptr() as _);
+            if sys::lua_type(ptr, -1) == sys::LUA_TTABLE {
+                sys::lua_getfield(ptr, -1, b"package\0".as_ptr() as _);
+            }
+            if sys::lua_type(ptr, -1) != sys::LUA_TTABLE {
+                return Err(Error::runtime("package library is not loaded"));
+            }
+            sys::lua_pushlstring(ptr, field.as_ptr() as *const _, field.len());
+            sys::lua_pushlstring(ptr, value.as_ptr() as *const _, value.len());
+            sys::lua_rawset(ptr, -3);
+        }
+        Ok(())
+    }
+
+    fn require_impl(
+        &mut self,
+        name: &[u8],
+        opener: sys::lua_CFunction,
+        set_global: bool,
+    ) -> LuaResult<()> {
+        unsafe extern "C" fn require(l: *mut sys::lua_State) -> libc::c_int {
+            // arguments: module name, module opener, set global
+            let glb = sys::lua_toboolean(l, 3);
+            sys::luaL_requiref(l, sys::lua_tostring(l, 1), sys::lua_tocfunction(l, 2), glb);
+            0
+        }
+
+        if name.contains(&0) {
+            return Err(Error::runtime("module name contains a nul byte"));
+        }
+        if opener.is_none() {
+            return Err(Error::runtime("module opener is null"));
+        }
+        self.ensure_stack(4)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_pushcfunction(ptr, Some(require));
+            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+            sys::lua_pushcfunction(ptr, opener);
+            sys::lua_pushboolean(ptr, set_global as libc::c_int);
+            let code = self.pcall(3, 0);
+            self.get_error(code)
+        }
+    }
+}
+
+// Method impls
+impl Thread {
+    /// Returns the absolute index of `index`, panicking if it is not a valid stack index.
+    /// Pseudo-indices are not stack indices.
+    fn checked_index(&self, index: libc::c_int, op: &str) -> libc::c_int {
+        let top = self.stack_len();
+        let abs = if index > sys::LUA_REGISTRYINDEX {
+            self.abs_index(index)
+        } else {
+            0
+        };
+        assert!(
+            (1..=top).contains(&abs),
+            "invalid stack index for {}: {} (stack length is {})",
+            op,
+            index,
+            top
+        );
+        abs
+    }
+
+    fn caller_load_impl<'a>(
+        &'a mut self,
+        buffer: &[u8],
+        chunk_name: Option<&str>,
+        mode: LoadingMode,
+    ) -> LuaResult<Caller<'a>> {
+        let mut name_buf = Vec::new();
+        unsafe {
+            let code = sys::luaL_loadbufferx(
+                self.as_raw().as_ptr(),
+                util::cstr_unchecked(Some(buffer)),
+                buffer.len(),
+                util::cstr_buf(chunk_name, &mut name_buf),
+                util::cstr_unchecked(Some(mode.as_str())),
+            );
+            self.load_result(code, chunk_name)
+        }
+    }
+
+    fn caller_load_reader_impl(
+        &mut self,
+        reader: &mut dyn Read,
+        chunk_name: Option<&str>,
+        mode: LoadingMode,
+    ) -> LuaResult<Caller<'_>> {
+        // Passed to lua_load as the reader data. It lives on this stack frame for the whole
+        // lua_load call and owns the buffer returned by `read`, which lua_load only uses until
+        // the next call to `read` or until it returns.
+        struct ChunkReader<'r> {
+            reader: &'r mut dyn Read,
+            buf: Vec<u8>,
+            error: Option<io::Error>,
+            panic: Option<panic::Payload>,
+        }
+
+        unsafe extern "C" fn read(
+            _l: *mut sys::lua_State,
+            ud: *mut libc::c_void,
+            size: *mut usize,
+        ) -> *const libc::c_char {
+            let chunk = &mut *(ud as *mut ChunkReader);
+            let ChunkReader { reader, buf, .. } = chunk;
+            let result = catch_unwind(AssertUnwindSafe(|| loop {
+                match reader.read(buf) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => return result,
+                }
+            }));
+            *size = match result {
+                Ok(Ok(n)) => n,
+                Ok(Err(e)) => {
+                    chunk.error = Some(e);
+                    0
+                }
+                Err(payload) => {
+                    chunk.panic = Some(payload);
+                    0
+                }
+            };
+            // a size of 0 ends the chunk
+            chunk.buf.as_ptr() as *const libc::c_char
+        }
+
+        let mut chunk = ChunkReader {
+            reader,
+            buf: vec![0; 4096],
+            error: None,
+            panic: None,
+        };
+        let mut name_buf = Vec::new();
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let data = &mut chunk as *mut ChunkReader as *mut libc::c_void;
+            let name = util::cstr_buf(chunk_name, &mut name_buf);
+            #[cfg(LUA_VERSION = "5.2")]
+            let code = sys::lua_load(
+                ptr,
+                Some(read),
+                data,
+                name,
+                util::cstr_unchecked(Some(mode.as_str())),
+            );
+            #[cfg(not(LUA_VERSION = "5.2"))]
+            let code = {
+                let _ = mode;
+                sys::lua_load(ptr, Some(read), data, name)
+            };
+
+            if let Some(payload) = chunk.panic {
+                // pop the function or the error message
+                sys::lua_pop(ptr, 1);
+                resume_unwind(payload);
+            }
+            if let Some(e) = chunk.error {
+                sys::lua_pop(ptr, 1);
+                let msg = format!("cannot read chunk: {}", e);
+                let mut error = Error::new(ErrorKind::Io, Some(msg));
+                error.source_name = chunk_name.map(String::from);
+                return Err(error);
+            }
+            self.load_result(code, chunk_name)
+        }
+    }
+
+    fn caller_load_file_impl(&mut self, path: &Path, mode: LoadingMode) -> LuaResult<Caller<'_>> {
+        let source_name = path.to_string_lossy();
+        #[cfg(unix)]
+        let path = {
+            use std::os::unix::ffi::OsStrExt;
+            path.as_os_str().as_bytes()
+        };
+        #[cfg(not(unix))]
+        let path = path.to_string_lossy();
+        #[cfg(not(unix))]
+        let path = path.as_bytes();
+
+        let mut path_buf = Vec::new();
+        unsafe {
+            let code = sys::luaL_loadfilex(
+                self.as_raw().as_ptr(),
+                util::cstr_buf(Some(path), &mut path_buf),
+                util::cstr_unchecked(Some(mode.as_str())),
+            );
+            self.load_result(code, Some(&source_name))
+        }
+    }
+
+    /// Returns a [`Caller`] for the loaded function, or the load error with its source name.
+    ///
+    /// # Safety
+    /// Behavior is undefined if `code` is not the status of a load,
+    /// with the function or the error object at the top of the stack.
+    ///
+    /// [`Caller`]: struct.Caller.html
+    unsafe fn load_result(
+        &mut self,
+        code: libc::c_int,
+        source_name: Option<&str>,
+    ) -> LuaResult<Caller<'_>> {
+        match self.get_error(code) {
+            Ok(()) => Ok(self.caller_stack_unchecked()),
+            Err(mut e) => {
+                e.source_name = source_name.map(String::from);
+                e.line = e.msg.as_deref().and_then(error_line);
+                Err(e)
+            }
+        }
+    }
+
+    /// Pushes the global table onto the stack.
+    #[inline]
+    fn push_globals(&mut self) {
+        #[cfg(LUA_VERSION = "5.2")]
+        unsafe {
+            sys::lua_pushglobaltable(self.raw.as_ptr())
+        };
+        #[cfg(not(LUA_VERSION = "5.2"))]
+        unsafe {
+            sys::lua_pushvalue(self.raw.as_ptr(), sys::LUA_GLOBALSINDEX)
+        };
+    }
+
+    fn push_global_impl(&mut self, name: &[u8]) -> LuaResult<libc::c_int> {
+        // __index of the global table may raise an error
+        self.protected(|thread| unsafe {
+            let ptr = thread.raw.as_ptr();
+            thread.push_globals();
+            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+            // fetch _G[name], honouring the metatable of _G like lua_getglobal
+            let value_type = sys::lua_gettable(ptr, -2);
+            sys::lua_remove(ptr, -2);
+            value_type
+        })
+    }
+
+    fn set_global_impl<V: ToLua>(&mut self, name: &[u8], value: V) -> LuaResult<()> {
+        let mut guard = self.stack_guard();
+        // __newindex of the global table may raise an error
+        guard.protected(move |thread| unsafe {
+            let ptr = thread.raw.as_ptr();
+            thread.push_globals();
+            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+            value.push(thread)?;
+            // _G[name] = value, honouring the metatable of _G like lua_setglobal
+            sys::lua_settable(ptr, -3);
+            Ok(())
+        })?
+    }
+
+    fn get_global_impl<V: FromLua>(&mut self, name: &[u8]) -> LuaResult<V> {
+        let mut guard = self.stack_guard();
+        guard.push_global_impl(name)?;
+        V::from_lua(&mut guard, -1).map_err(|e| {
+            let msg = format!(
+                "bad global '{}' ({})",
+                String::from_utf8_lossy(name),
+                e.msg().unwrap_or("invalid value")
+            );
+            Error::new(e.kind(), Some(msg))
+        })
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        // the memory limit is used by the allocator until the state is closed
+        let _memory = match self.extra() {
+            Some(_) => self.extra_mut().memory_limit.take(),
+            None => None,
+        };
+        unsafe {
+            sys::lua_close(self.raw.as_ptr());
+        }
+    }
+}
+
+/// An owned [`Thread`] that can be sent to another thread.
+///
+/// Moving a Lua state between threads is fine as long as it is not used concurrently,
+/// which ownership guarantees, but the state may hold Rust values that must stay on the
+/// thread that created them. Creating a `SendThread` is therefore unsafe.
+///
+/// # Examples
+/// ```
+/// use pollua::thread::{SendThread, Thread};
+/// use std::ptr::NonNull;
+///
+/// let raw = NonNull::new(unsafe { pollua::sys::luaL_newstate() }).unwrap();
+/// let mut thread = unsafe { Thread::from_raw(raw) };
+/// thread.set_global("answer", 42i64).unwrap();
+///
+/// // the state only holds Lua values
+/// let send = unsafe { SendThread::new(thread) };
+/// let answer = std::thread::spawn(move || {
+///     let mut thread = send.into_inner();
+///     thread.get_global::<i64, _>("answer").unwrap()
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(answer, 42);
+/// ```
+///
+/// [`Thread`]: struct.Thread.html
+#[derive(Debug)]
+pub struct SendThread(Thread);
+
+// Safety: SendThread::new requires the state not to hold values bound to the current thread.
+unsafe impl Send for SendThread {}
+
+impl SendThread {
+    /// Wraps `thread` so that it can be sent to another thread.
+    ///
+    /// # Safety
+    /// The state must not hold any Rust value that is not `Send`, like closures pushed
+    /// with [`Thread::push_function`] or hook callbacks capturing an `Rc`, and no
+    /// [`Reference`] to the state may remain on the current thread.
+    ///
+    /// [`Thread::push_function`]: struct.Thread.html#method.push_function
+    /// [`Reference`]: struct.Reference.html
+    #[inline]
+    pub unsafe fn new(thread: Thread) -> SendThread {
+        SendThread(thread)
+    }
+
+    /// Returns the wrapped thread.
+    #[inline]
+    pub fn into_inner(self) -> Thread {
+        self.0
+    }
+}
+
+/// Used by the [`Thread::load_bytes`] method, describes how the bytes should be interpreted.
+///
+/// [`Thread::load_bytes`]: struct.Thread.html#method.load_bytes
+#[derive(Debug, Copy, Clone)]
+pub enum LoadingMode {
+    Binary,
+    Text,
+    Auto,
+}
+
+impl LoadingMode {
+    /// Returns the mode as a nul-terminated string, as expected by `lua_load`.
+    fn as_str(self) -> &'static str {
+        match self {
+            LoadingMode::Binary => "b\0",
+            LoadingMode::Text => "t\0",
+            LoadingMode::Auto => "bt\0",
+        }
+    }
+}
+
+/// Returns the kind of error of a non-`LUA_OK` status code.
+fn error_kind(code: libc::c_int) -> ErrorKind {
+    match code {
+        sys::LUA_YIELD => ErrorKind::Yield,
+        sys::LUA_ERRRUN => ErrorKind::Runtime,
+        sys::LUA_ERRSYNTAX => ErrorKind::Syntax,
+        sys::LUA_ERRMEM => ErrorKind::OutOfMemory,
+        sys::LUA_ERRERR => ErrorKind::MessageHandler,
+        sys::LUA_ERRGCMM => ErrorKind::GarbageCollection,
+        sys::LUA_ERRFILE => ErrorKind::FileLoad,
+        code => ErrorKind::Unknown(code),
+    }
+}
+
+/// Cuts `msg` to at most `max_len` bytes, on a character boundary, and appends `...`
+/// if it was longer.
+fn truncate_message(msg: &mut String, max_len: usize) {
+    if msg.len() <= max_len {
+        return;
+    }
+    let mut end = max_len;
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    msg.truncate(end);
+    msg.push_str("...");
+}
+
+/// Returns the line number of an error message in the `chunkname:line: message` format.
+fn error_line(msg: &str) -> Option<u32> {
+    // the chunk name of string chunks is quoted and may contain colons
+    let start = if msg.starts_with("[string \"") {
+        msg.find("\"]:")? + 2
+    } else {
+        0
+    };
+    let mut parts = msg[start..].splitn(3, ':');
+    let (_chunk, line, _message) = (parts.next()?, parts.next()?, parts.next()?);
+    if !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit()) {
+        line.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// The status of a thread, returned by [`Thread::status`].
+///
+/// [`Thread::status`]: struct.Thread.html#method.status
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadStatus {
+    /// The thread is running, has not started or has finished:
+    /// it can run functions and start a new coroutine.
+    Ok,
+    /// The thread is a suspended coroutine that can be resumed.
+    Yield,
+    /// The coroutine stopped with an error and cannot be resumed.
+    Error(ErrorKind),
+}
+
+/// The state of a coroutine after a call to [`Thread::resume`].
+///
+/// [`Thread::resume`]: struct.Thread.html#method.resume
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResumeStatus {
+    /// The coroutine returned.
+    Finished,
+    /// The coroutine yielded and can be resumed again.
+    Yielded,
+}
+
+/// The standard Lua libraries, used by the [`Thread::open_lib`] method.
+///
+/// [`Thread::open_lib`]: struct.Thread.html#method.open_lib
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StdLib {
+    Base,
+    Package,
+    String,
+    Table,
+    Math,
+    Io,
+    Os,
+    Coroutine,
+    Utf8,
+    Debug,
+}
+
+impl StdLib {
+    /// Returns the global name of this library.
+    pub fn name(self) -> &'static str {
+        match self {
+            StdLib::Base => "_G",
+            StdLib::Package => sys::LUA_LOADLIBNAME,
+            StdLib::String => sys::LUA_STRLIBNAME,
+            StdLib::Table => sys::LUA_TABLIBNAME,
+            StdLib::Math => sys::LUA_MATHLIBNAME,
+            StdLib::Io => sys::LUA_IOLIBNAME,
+            StdLib::Os => sys::LUA_OSLIBNAME,
+            StdLib::Coroutine => sys::LUA_COLIBNAME,
+            StdLib::Utf8 => sys::LUA_UTF8LIBNAME,
+            StdLib::Debug => sys::LUA_DBLIBNAME,
+        }
+    }
+
+    /// Returns the `luaopen_*` function of this library.
+    fn opener(self) -> sys::lua_CFunction {
+        match self {
+            StdLib::Base => Some(sys::luaopen_base),
+            StdLib::Package => Some(sys::luaopen_package),
+            StdLib::String => Some(sys::luaopen_string),
+            StdLib::Table => Some(sys::luaopen_table),
+            StdLib::Math => Some(sys::luaopen_math),
+            StdLib::Io => Some(sys::luaopen_io),
+            StdLib::Os => Some(sys::luaopen_os),
+            StdLib::Coroutine => Some(sys::luaopen_coroutine),
+            StdLib::Utf8 => Some(sys::luaopen_utf8),
+            StdLib::Debug => Some(sys::luaopen_debug),
+        }
+    }
+}
+
+/// A mutable reference to a [`Thread`].
+///
+/// [`Thread`]: struct.Thread.html
+pub struct ThreadRef<'a> {
+    // thread's destructor must never be called, as it would trigger a double-free.
+    thread: ManuallyDrop<Thread>,
+    _marker: PhantomData<&'a mut Thread>,
+}
+
+impl<'a> ThreadRef<'a> {
+    /// Creates a `ThreadRef` from a raw `lua_State` pointer.
+    ///
+    /// # Safety
+    /// Behavior is undefined if `raw` is invalid or multiple reference to this `lua_State` exists.
+    #[inline]
+    pub unsafe fn from_raw(raw: NonNull<sys::lua_State>) -> ThreadRef<'a> {
+        ThreadRef {
+            thread: ManuallyDrop::new(Thread::from_raw(raw)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a `ThreadRef` from a [`Thread`] mutable reference.
+    ///
+    /// [`Thread`]: struct.Thread.html
+    #[inline]
+    pub fn from_ref(thread: &'a mut Thread) -> ThreadRef<'a> {
+        unsafe { ThreadRef::from_raw(thread.as_raw()) }
+    }
+}
+
+impl fmt::Debug for ThreadRef<'_> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.thread.fmt(f)
+    }
+}
+
+impl<'a> Deref for ThreadRef<'a> {
+    type Target = Thread;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.thread
+    }
+}
+
+impl<'a> DerefMut for ThreadRef<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.thread
+    }
+}
+
+/// Message handler appending a stack traceback to the error message,
+/// non-string error objects are converted the same way the standalone interpreter does.
+unsafe extern "C" fn traceback_handler(l: *mut sys::lua_State) -> libc::c_int {
+    let mut msg = sys::lua_tostring(l, 1);
+    if msg.is_null() {
+        if sys::luaL_callmeta(l, 1, b"__tostring\0".as_ptr() as *const _) != 0
+            && sys::lua_type(l, -1) == sys::LUA_TSTRING
+        {
+            return 1;
+        }
+        msg = sys::lua_pushfstring(
+            l,
+            b"(error object is a %s value)\0".as_ptr() as *const _,
+            sys::luaL_typename(l, 1),
+        );
+    }
+    sys::luaL_traceback(l, l, msg, 1);
+    1
+}
+
+/// Panic function of the states, calls the handler set by [`Thread::set_panic_handler`]
+/// or panics.
+///
+/// [`Thread::set_panic_handler`]: struct.Thread.html#method.set_panic_handler
+unsafe extern "C-unwind" fn at_panic(thread: *mut sys::lua_State) -> libc::c_int {
+    let mut thread = ThreadRef::from_raw(NonNull::new_unchecked(thread));
+    let error = match thread.get_error(sys::LUA_ERRRUN) {
+        Ok(()) => return 0,
+        Err(e) => e,
+    };
+    match thread.extra().and_then(|e| e.panic_handler.clone()) {
+        Some(handler) => handler(error.msg()),
+        None => {
+            let msg = format!("Lua panic: {}", error.msg().unwrap_or("<no error message>"));
+            // caught by `Thread::spawn` and `ScopedThread::run`
+            if thread.extra().is_some() {
+                thread.extra_mut().lua_panic = Some(error);
+            }
+            panic!("{}", msg);
+        }
+    }
+    // Lua aborts the process when the panic function returns
+    0
+}
+
+/// Default allocation function.
+/// Uses the liballoc functions instead of the one from libc.
+/// A panic in the global allocator is reported to Lua as an allocation failure.
+unsafe extern "C" fn alloc_default(
+    _ud: *mut libc::c_void,
+    ptr: *mut libc::c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut libc::c_void {
+    catch_unwind(|| {
+        if nsize == 0 {
+            if !ptr.is_null() {
+                alloc::dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(osize, 1));
+            }
+            ptr::null_mut()
+        } else if ptr.is_null() {
+            alloc::alloc(Layout::from_size_align_unchecked(nsize, 1)) as *mut _
+        } else {
+            alloc::realloc(
+                ptr as *mut u8,
+                Layout::from_size_align_unchecked(osize, 1),
+                nsize,
+            ) as *mut _
+        }
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Allocator userdata used by [`ThreadBuilder::memory_limit`].
+///
+/// [`ThreadBuilder::memory_limit`]: struct.ThreadBuilder.html#method.memory_limit
+struct MemoryLimit {
+    /// Number of live bytes allocated by Lua.
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    /// The allocator doing the allocations and its userdata.
+    allocator: unsafe extern "C" fn(
+        *mut libc::c_void,
+        *mut libc::c_void,
+        usize,
+        usize,
+    ) -> *mut libc::c_void,
+    userdata: *mut libc::c_void,
+}
+
+/// Allocation function that keeps track of the allocated bytes.
+/// Returns null if a growth request would exceed the limit,
+/// `ud` must point to a `MemoryLimit`.
+unsafe extern "C" fn alloc_limited(
+    ud: *mut libc::c_void,
+    ptr: *mut libc::c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut libc::c_void {
+    let memory = &*(ud as *const MemoryLimit);
+    // if `ptr` is null, `osize` holds the type of the object being allocated
+    let old = if ptr.is_null() { 0 } else { osize };
+    if nsize == 0 {
+        if !ptr.is_null() {
+            (memory.allocator)(memory.userdata, ptr, osize, 0);
+            memory.used.fetch_sub(old, Ordering::Relaxed);
+        }
+        return ptr::null_mut();
+    }
+
+    let used = memory.used.load(Ordering::Relaxed);
+    if nsize > old && used - old + nsize > memory.limit.load(Ordering::Relaxed) {
+        return ptr::null_mut();
+    }
+    let new = (memory.allocator)(memory.userdata, ptr, osize, nsize);
+    if !new.is_null() {
+        memory.used.store(used - old + nsize, Ordering::Relaxed);
+    }
+    new
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+
+    fn stack_top(thread: &mut Thread) -> libc::c_int {
+        unsafe { sys::lua_gettop(thread.as_raw().as_ptr()) }
+    }
+
+    fn type_at(thread: &mut Thread, index: libc::c_int) -> libc::c_int {
+        unsafe { sys::lua_type(thread.as_raw().as_ptr(), index) }
+    }
+
+    #[test]
     fn test_thread_push_global() {
         Thread::spawn(move |thread| {
-            let mut top;
-            top = stack_top(thread);
-            thread.push_global("undef_var");
-            assert_eq!(type_at(thread, -1), sys::LUA_TNIL);
+            let mut top;
+            top = stack_top(thread);
+            thread.push_global("undef_var").unwrap();
+            assert_eq!(type_at(thread, -1), sys::LUA_TNIL);
+            assert_eq!(stack_top(thread), top + 1);
+
+            unsafe {
+                sys::lua_pushinteger(thread.as_raw().as_ptr(), 42);
+                sys::lua_setglobal(thread.as_raw().as_ptr(), b"num_var\0".as_ptr() as *const _);
+            }
+            top = stack_top(thread);
+            thread.push_global("num_var").unwrap();
+            assert_eq!(type_at(thread, -1), sys::LUA_TNUMBER);
+            assert!(unsafe { sys::lua_isinteger(thread.as_raw().as_ptr(), -1) } != 0);
+            assert_eq!(
+                unsafe { sys::lua_tointeger(thread.as_raw().as_ptr(), -1) },
+                42
+            );
+            assert_eq!(stack_top(thread), top + 1);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_globals() {
+        use crate::value::{LuaNil, LuaNumber};
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            thread.set_global("num_var", 12.5).unwrap();
+            assert_eq!(stack_top(thread), top);
+            assert_eq!(
+                thread.get_global::<LuaNumber, _>("num_var").unwrap(),
+                LuaNumber::from(12.5)
+            );
+            assert_eq!(stack_top(thread), top);
+
+            let err = thread.get_global::<LuaNil, _>("num_var").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(
+                err.msg(),
+                Some("bad global 'num_var' (nil expected, got number)")
+            );
+            assert_eq!(stack_top(thread), top);
+
+            thread.set_global("num_var", LuaNil).unwrap();
+            assert_eq!(thread.get_global::<LuaNil, _>("num_var").unwrap(), LuaNil);
+            assert_eq!(stack_top(thread), top);
+
+            let globals: Vec<_> = (0..100)
+                .map(|i| (format!("var{}", i), format!("value\0{}", i)))
+                .collect();
+            thread.set_globals_from(globals).unwrap();
+            assert_eq!(stack_top(thread), top);
+            for i in &[0, 42, 99] {
+                let value: String = thread.get_global(&format!("var{}", i)).unwrap();
+                assert_eq!(value, format!("value\0{}", i));
+            }
+            thread.set_globals_from(vec![("borrowed", "str")]).unwrap();
+            assert_eq!(thread.get_global::<String, _>("borrowed").unwrap(), "str");
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_globals_metatable() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            // strict mode: undeclared globals cannot be read or written
+            thread
+                .do_string(
+                    "declared = 1
+                    setmetatable(_G, {
+                        __index = function(_, k) error('undeclared ' .. k, 2) end,
+                        __newindex = function(_, k) error('undeclared ' .. k, 2) end,
+                    })",
+                    None,
+                )
+                .unwrap();
+            let top = stack_top(thread);
+
+            thread.set_global("declared", 2i64).unwrap();
+            assert_eq!(thread.get_global::<i64, _>("declared").unwrap(), 2);
+            let err = thread.set_global("undef\0var", 1i64).unwrap_err();
+            assert!(err.msg().unwrap().contains("undeclared undef\0var"));
+            let err = thread.get_global::<i64, _>("undef").unwrap_err();
+            assert!(err.msg().unwrap().contains("undeclared undef"));
+            assert!(thread.caller_global("undef").is_none());
+            let err = thread
+                .set_globals_from(vec![("declared", "3"), ("undef", "4")])
+                .unwrap_err();
+            assert!(err.msg().unwrap().contains("undeclared undef"));
+            assert_eq!(thread.get_global::<String, _>("declared").unwrap(), "3");
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_globals_table() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            {
+                let mut globals = thread.globals();
+                globals.set("a", 1i64).unwrap();
+                globals.set("b", 2i64).unwrap();
+                assert_eq!(globals.get::<_, i64>("a").unwrap(), 1);
+                let sum: i64 = globals
+                    .pairs::<String, i64>()
+                    .unwrap()
+                    .filter_map(Result::ok)
+                    .map(|(_, v)| v)
+                    .sum();
+                assert_eq!(sum, 3);
+            }
+            assert_eq!(stack_top(thread), top);
+            assert_eq!(thread.get_global::<i64, _>("b").unwrap(), 2);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_load_reader() {
+        use std::io::Cursor;
+
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+            }
+        }
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            // larger than the read buffer
+            let script = format!("local t = {{ {} }} return #t", "1, ".repeat(5000));
+            let n: i64 = thread
+                .caller_load_reader(Cursor::new(script), None, LoadingMode::Text)
+                .and_then(|c| c.call_ret())
+                .unwrap();
+            assert_eq!(n, 5000);
+            assert_eq!(stack_top(thread), top);
+
+            let err = thread
+                .caller_load_reader(Failing, Some("@pipe"), LoadingMode::Text)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Io);
+            assert_eq!(err.msg(), Some("cannot read chunk: broken pipe"));
+            assert_eq!(err.source_name(), Some("@pipe"));
+            assert_eq!(stack_top(thread), top);
+
+            let err = thread
+                .caller_load_reader(Cursor::new("return +"), Some("=script"), LoadingMode::Text)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Syntax);
+            assert_eq!(err.source_name(), Some("=script"));
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_load_reader_large() {
+        use std::io::Cursor;
+
+        const SIZE: usize = 10 * 1024 * 1024;
+
+        Thread::spawn(move |thread| {
+            thread.gc_collect();
+            let memory = thread.gc_count();
+            {
+                // a long string spanning thousands of reads, every byte must come through intact
+                let payload: Vec<u8> = (0..SIZE).map(|i| b'a' + (i % 26) as u8).collect();
+                let mut script = b"return [[".to_vec();
+                script.extend_from_slice(&payload);
+                script.extend_from_slice(b"]]");
+                let value: Vec<u8> = thread
+                    .caller_load_reader(Cursor::new(script), None, LoadingMode::Text)
+                    .and_then(|c| c.call_ret())
+                    .unwrap();
+                assert!(value == payload);
+            }
+            // nothing is left behind by the load
+            thread.gc_collect();
+            assert!(thread.gc_count() < memory + 4096);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_load_error_line() {
+        assert_eq!(error_line("script:3: unexpected symbol"), Some(3));
+        assert_eq!(error_line("[string \"a:1: b\"]:12: '=' expected"), Some(12));
+        assert_eq!(error_line("binary string: bad binary format"), None);
+        assert_eq!(error_line("script:x: message"), None);
+        assert_eq!(error_line("script:+3: message"), None);
+        assert_eq!(error_line("no line"), None);
+
+        Thread::spawn(move |thread| {
+            let err = thread
+                .caller_load(
+                    "local a = 1\n\nreturn +",
+                    Some("=script"),
+                    LoadingMode::Text,
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Syntax);
+            assert_eq!(err.line(), Some(3));
+            let err = thread
+                .caller_load("\nx = = 1", None, LoadingMode::Text)
+                .unwrap_err();
+            assert_eq!(err.line(), Some(2));
+            let err = thread
+                .caller_load("return 1", None, LoadingMode::Binary)
+                .unwrap_err();
+            assert_eq!(err.line(), None);
+            // runtime errors are not load errors
+            let err = thread.do_string("\nerror('x')", None).unwrap_err();
+            assert_eq!(err.line(), None);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_load_file() {
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("pollua_test_load_{}.lua", process::id()));
+        fs::write(&path, "loaded_var = 21 * 2").unwrap();
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            thread
+                .caller_load_file(&path, LoadingMode::Text)
+                .and_then(|c| c.call())
+                .unwrap();
+            assert_eq!(thread.get_global::<i64, _>("loaded_var").unwrap(), 42);
+            fs::remove_file(&path).unwrap();
+
+            let err = thread
+                .caller_load_file(&path, LoadingMode::Auto)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::FileLoad);
+            assert!(err.msg().unwrap().contains(&*path.to_string_lossy()));
+            assert_eq!(err.source_name(), Some(&*path.to_string_lossy()));
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_do_file() {
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("pollua_test_do_{}.lua", process::id()));
+        fs::write(&path, "done_var = (done_var or 0) + 1 return 'ignored'").unwrap();
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            thread.do_file(&path).unwrap();
+            thread.do_file(&path).unwrap();
+            assert_eq!(thread.get_global::<i64, _>("done_var").unwrap(), 2);
+            assert_eq!(stack_top(thread), top);
+
+            fs::write(&path, "error('failed')").unwrap();
+            let err = thread.do_file(&path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            // only load errors have a source name
+            assert_eq!(err.source_name(), None);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(
+                thread.do_file(&path).unwrap_err().kind(),
+                ErrorKind::FileLoad
+            );
+
+            // binary chunks are accepted
+            let chunk = thread
+                .caller_load("done_var = 10", None, LoadingMode::Text)
+                .and_then(|mut c| c.dump(false))
+                .unwrap();
+            thread.do_string(&chunk, None).unwrap();
+            assert_eq!(thread.get_global::<i64, _>("done_var").unwrap(), 10);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_resume() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let ptr = thread.as_raw().as_ptr();
+
+            let code = b"local a = ... local b = coroutine.yield(a + 1) return a + b\0";
+            // leave the loaded function on the stack
+            assert_eq!(
+                unsafe { sys::luaL_loadstring(ptr, code.as_ptr() as *const _) },
+                sys::LUA_OK
+            );
+            unsafe { sys::lua_pushinteger(ptr, 10) };
+            assert_eq!(thread.resume(1).unwrap(), ResumeStatus::Yielded);
+            assert_eq!(stack_top(thread), 1);
+            assert_eq!(unsafe { sys::lua_tointeger(ptr, -1) }, 11);
+
+            unsafe { sys::lua_settop(ptr, 0) };
+            unsafe { sys::lua_pushinteger(ptr, 5) };
+            assert_eq!(thread.resume(1).unwrap(), ResumeStatus::Finished);
+            assert_eq!(stack_top(thread), 1);
+            assert_eq!(unsafe { sys::lua_tointeger(ptr, -1) }, 15);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_status() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = stack_top(thread);
+            {
+                let mut co = thread.new_coroutine();
+                assert_eq!(co.status(), ThreadStatus::Ok);
+                let code = b"if coroutine.yield() then error('stop') end\0";
+                let co_ptr = co.as_raw().as_ptr();
+                assert_eq!(
+                    unsafe { sys::luaL_loadstring(co_ptr, code.as_ptr() as *const _) },
+                    sys::LUA_OK
+                );
+                assert_eq!(co.status(), ThreadStatus::Ok);
+                assert_eq!(co.resume(0).unwrap(), ResumeStatus::Yielded);
+                assert_eq!(co.status(), ThreadStatus::Yield);
+                co.push(true).unwrap();
+                assert_eq!(co.resume(1).unwrap_err().kind(), ErrorKind::Runtime);
+                assert_eq!(co.status(), ThreadStatus::Error(ErrorKind::Runtime));
+            }
+            assert_eq!(thread.status(), ThreadStatus::Ok);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_stack_ops() {
+        Thread::spawn(move |thread| {
+            let base = stack_top(thread);
+            let ints = |thread: &mut Thread| -> Vec<i64> {
+                (base + 1..=stack_top(thread))
+                    .map(|i| i64::from_lua(thread, i).unwrap())
+                    .collect()
+            };
+            for i in 1..=4 {
+                thread.push(i).unwrap();
+            }
+            thread.insert(base + 1);
+            assert_eq!(ints(thread), [4, 1, 2, 3]);
+            thread.remove(-3);
+            assert_eq!(ints(thread), [4, 2, 3]);
+            thread.copy(-1, base + 1);
+            assert_eq!(ints(thread), [3, 2, 3]);
+            thread.push(5).unwrap();
+            thread.replace(-3);
+            assert_eq!(ints(thread), [3, 5, 3]);
+            // inserting or replacing the top value
+            thread.insert(-1);
+            thread.replace(-1);
+            assert_eq!(ints(thread), [3, 5]);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid stack index for remove: -2 (stack length is 1)")]
+    fn test_thread_stack_ops_invalid() {
+        let _ = Thread::spawn(move |thread| {
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), 0) };
+            thread.push(1).unwrap();
+            thread.remove(-2);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid stack index for copy")]
+    fn test_thread_stack_ops_pseudo_index() {
+        let _ = Thread::spawn(move |thread| {
+            thread.push(1).unwrap();
+            thread.copy(-1, sys::LUA_REGISTRYINDEX);
+        });
+    }
+
+    #[test]
+    fn test_thread_is_yieldable() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            assert!(!thread.is_yieldable());
+            thread
+                .push_function(|thread| {
+                    let yieldable = thread.is_yieldable();
+                    thread.push(yieldable)?;
+                    Ok(1)
+                })
+                .unwrap();
+            unsafe {
+                sys::lua_setglobal(
+                    thread.as_raw().as_ptr(),
+                    b"yieldable\0".as_ptr() as *const _,
+                )
+            };
+
+            let results: Vec<bool> = thread
+                .caller_load(
+                    "return yieldable(), coroutine.wrap(yieldable)(), \
+                            coroutine.wrap(function() return yieldable() end)(), \
+                            coroutine.wrap(function() return select(2, pcall(yieldable)) end)()",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call_ret_n(4))
+                .unwrap();
+            assert_eq!(results, [false, true, true, true]);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_move_values() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = stack_top(thread);
+            let raw = thread.new_coroutine().as_raw();
+            let mut co = unsafe { ThreadRef::from_raw(raw) };
+            let code = b"local a = ... local b = coroutine.yield(a + 1) return b, 'done'\0";
+            let ptr = thread.as_raw().as_ptr();
+            assert_eq!(
+                unsafe { sys::luaL_loadstring(ptr, code.as_ptr() as *const _) },
+                sys::LUA_OK
+            );
+            thread.push(1i64).unwrap();
+            thread.move_values_to(&mut co, 2).unwrap();
             assert_eq!(stack_top(thread), top + 1);
+            assert_eq!(co.resume(1).unwrap(), ResumeStatus::Yielded);
+            co.move_values_to(thread, 1).unwrap();
+            assert_eq!(i64::from_lua(thread, -1).unwrap(), 2);
 
-            unsafe {
-                sys::lua_pushinteger(thread.as_raw().as_ptr(), 42);
-                sys::lua_setglobal(thread.as_raw().as_ptr(), b"num_var\0".as_ptr() as *const _);
+            thread.push(10i64).unwrap();
+            thread.move_values_to(&mut co, 1).unwrap();
+            assert_eq!(co.resume(1).unwrap(), ResumeStatus::Finished);
+            co.move_values_to(thread, 2).unwrap();
+            assert_eq!(i64::from_lua(thread, -2).unwrap(), 10);
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "done");
+            assert_eq!(stack_top(&mut co), 0);
+            // moving nothing or to the same thread is a no-op
+            thread.move_values_to(&mut co, 0).unwrap();
+            let this = unsafe { &mut ThreadRef::from_raw(thread.as_raw()) };
+            thread.move_values_to(this, 2).unwrap();
+            assert_eq!(stack_top(thread), top + 4);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot move values between threads of different Lua states")]
+    fn test_thread_move_values_other_state() {
+        let _ = Thread::spawn(move |thread| {
+            let mut other =
+                unsafe { Thread::from_raw(NonNull::new(sys::luaL_newstate()).unwrap()) };
+            thread.push(1i64).unwrap();
+            let _ = thread.move_values_to(&mut other, 1);
+        });
+    }
+
+    #[test]
+    fn test_thread_new_coroutine() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Coroutine).unwrap();
+            let top = stack_top(thread);
+            {
+                let mut co = thread.new_coroutine();
+                let co_ptr = co.as_raw().as_ptr();
+                let code = b"co_var = coroutine.yield(1)\0";
+                assert_eq!(
+                    unsafe { sys::luaL_loadstring(co_ptr, code.as_ptr() as *const _) },
+                    sys::LUA_OK
+                );
+                assert_eq!(co.resume(0).unwrap(), ResumeStatus::Yielded);
+                unsafe { sys::lua_settop(co_ptr, 0) };
+                unsafe { sys::lua_pushinteger(co_ptr, 7) };
+                assert_eq!(co.resume(1).unwrap(), ResumeStatus::Finished);
+                assert_eq!(stack_top(&mut co), 0);
             }
-            top = stack_top(thread);
-            thread.push_global("num_var");
-            assert_eq!(type_at(thread, -1), sys::LUA_TNUMBER);
-            assert!(unsafe { sys::lua_isinteger(thread.as_raw().as_ptr(), -1) } != 0);
+            // the coroutine is kept on the parent's stack
+            assert_eq!(stack_top(thread), top + 1);
+            assert_eq!(type_at(thread, -1), sys::LUA_TTHREAD);
+            assert_eq!(thread.get_global::<i64, _>("co_var").unwrap(), 7);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_push_self() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            assert!(thread.push_self().unwrap());
+            assert_eq!(type_at(thread, -1), sys::LUA_TTHREAD);
+            let ptr = thread.as_raw().as_ptr();
+            {
+                let mut co = thread.new_coroutine();
+                assert!(!co.push_self().unwrap());
+                assert_eq!(stack_top(&mut co), 1);
+                // the coroutine pushed on its own stack is the one on the parent's stack
+                unsafe { sys::lua_xmove(co.as_raw().as_ptr(), ptr, 1) };
+            }
+            assert!(thread.same_identity(-1, -2));
+            assert!(!thread.same_identity(-1, top + 1));
+            assert_eq!(stack_top(thread), top + 3);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_open() {
+        let mut thread = Thread::open().unwrap();
+        thread.open_lib(StdLib::Base).unwrap();
+        let top = stack_top(&mut thread);
+        thread.do_string("counter = 1", None).unwrap();
+
+        // the state stays alive across uses
+        fn increment(thread: &mut Thread) {
+            thread.do_string("counter = counter + 1", None).unwrap();
+        }
+        increment(&mut thread);
+        increment(&mut thread);
+        assert_eq!(thread.get_global::<i64, _>("counter").unwrap(), 3);
+        assert_eq!(stack_top(&mut thread), top);
+    }
+
+    #[test]
+    fn test_thread_into_raw() {
+        let thread = ThreadBuilder::new()
+            .memory_limit(1024 * 1024)
+            .build()
+            .unwrap();
+        let raw = thread.into_raw();
+        unsafe {
+            assert_eq!(sys::lua_gettop(raw.as_ptr()), 0);
+            // closed by foreign code, the memory limit stays valid until the end
+            sys::lua_close(raw.as_ptr());
+        }
+    }
+
+    #[test]
+    fn test_thread_shared_inspection() {
+        fn inspect(thread: &Thread) -> (libc::c_int, Option<ValueType>, Option<NumberKind>, usize) {
+            let top = unsafe { sys::lua_gettop(thread.as_raw_const().as_ptr()) };
+            assert_eq!(top, thread.stack_len());
+            assert!(thread.raw_equal(-1, -1));
+            assert_eq!(thread.to_light_userdata(-1), None);
+            (
+                top,
+                thread.type_of(-1),
+                thread.to_number_kind(-1),
+                thread.raw_len(-1),
+            )
+        }
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            thread.push("four").unwrap();
+            assert_eq!(inspect(thread), (top + 1, Some(ValueType::String), None, 4));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_integer_conversions() {
+        Thread::spawn(move |thread| {
+            let base = stack_top(thread);
+            // not a no-op with `lua-32bits`
+            #[allow(clippy::useless_conversion)]
+            let max = i64::from(crate::Integer::MAX);
+            thread.push(max).unwrap();
+            thread.push(3.0).unwrap();
+            thread.push(1e20).unwrap();
+            thread.push(0.5).unwrap();
+            thread.push("12").unwrap();
+            thread.push("x").unwrap();
+            thread.push(i64::from(i32::MIN)).unwrap();
+
+            assert_eq!(i64::from_lua(thread, base + 1).unwrap(), max);
+            assert_eq!(i64::from_lua(thread, base + 2).unwrap(), 3);
+            for &index in &[base + 3, base + 4] {
+                let err = i64::from_lua(thread, index).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::Runtime);
+                assert_eq!(err.msg(), Some("number is not representable as an integer"));
+            }
+            assert_eq!(i64::from_lua(thread, base + 5).unwrap(), 12);
             assert_eq!(
-                unsafe { sys::lua_tointeger(thread.as_raw().as_ptr(), -1) },
-                42
+                i64::from_lua(thread, base + 6).unwrap_err().msg(),
+                Some("integer expected, got string")
             );
-            assert_eq!(stack_top(thread), top + 1);
+
+            assert_eq!(thread.try_to_i32(base + 2).unwrap(), 3);
+            assert_eq!(thread.try_to_i32(base + 7).unwrap(), i32::MIN);
+            // always representable with `lua-32bits`
+            if max > i64::from(i32::MAX) {
+                let err = thread.try_to_i32(base + 1).unwrap_err();
+                assert_eq!(
+                    err.msg(),
+                    Some("number 9223372036854775807 is not representable as a 32-bit integer")
+                );
+            }
+            assert!(thread.try_to_i32(base + 4).is_err());
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_string_conversions() {
+        Thread::spawn(move |thread| {
+            let base = stack_top(thread);
+            thread.push("héllo").unwrap();
+            thread.push(&b"ab\xc3"[..]).unwrap();
+            thread.push(42i64).unwrap();
+            thread.push(true).unwrap();
+
+            assert_eq!(thread.to_str_strict(base + 1).unwrap(), "héllo");
+            let err = thread.to_str_strict(base + 2).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert!(err.msg().unwrap().starts_with("invalid UTF-8 in string"));
+            assert_eq!(thread.to_str_lossy(base + 2).unwrap(), "ab\u{fffd}");
+            assert_eq!(thread.to_str_strict(base + 3).unwrap(), "42");
+            assert!(thread.to_str_strict(base + 4).is_err());
+
+            assert_eq!(thread.to_bytes(base + 2), Some(&b"ab\xc3"[..]));
+            assert_eq!(thread.to_bytes(base + 3), None);
+            // the number was not converted in place
+            assert_eq!(thread.type_of(base + 3), Some(ValueType::Number));
+            assert_eq!(thread.to_bytes(base + 5), None);
+            assert_eq!(stack_top(thread), base + 4);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_preload_module() {
+        unsafe extern "C" fn open_counter(l: *mut sys::lua_State) -> libc::c_int {
+            // counts the calls to the opener in a global
+            sys::lua_getglobal(l, b"opened\0".as_ptr() as _);
+            let n = sys::lua_tointeger(l, -1);
+            sys::lua_pushinteger(l, n + 1);
+            sys::lua_setglobal(l, b"opened\0".as_ptr() as _);
+            sys::lua_pushvalue(l, 1);
+            1
+        }
+
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Package).unwrap();
+            let top = stack_top(thread);
+
+            thread
+                .preload_module("counter", Some(open_counter), true)
+                .unwrap();
+            assert_eq!(
+                thread.get_global::<String, _>("counter").unwrap(),
+                "counter"
+            );
+            // the module is only opened once
+            thread
+                .preload_module("counter", Some(open_counter), false)
+                .unwrap();
+            thread
+                .do_string("assert(require('counter') == 'counter')", None)
+                .unwrap();
+            assert_eq!(thread.get_global::<i64, _>("opened").unwrap(), 1);
+
+            thread
+                .preload_module("hidden", Some(open_counter), false)
+                .unwrap();
+            assert!(thread
+                .get_global::<crate::value::LuaNil, _>("hidden")
+                .is_ok());
+            thread
+                .do_string("assert(package.loaded.hidden == 'hidden')", None)
+                .unwrap();
+
+            let err = thread
+                .preload_module(&b"a\0b"[..], Some(open_counter), false)
+                .unwrap_err();
+            assert_eq!(err.msg(), Some("module name contains a nul byte"));
+            let err = thread.preload_module("null", None, false).unwrap_err();
+            assert_eq!(err.msg(), Some("module opener is null"));
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_package_path() {
+        let dir = std::env::temp_dir().join(format!("pollua-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("greeting.lua"), "return 'hello'").unwrap();
+        let path = format!("{}/?.lua", dir.display());
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let err = thread.set_package_path("./?.lua").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("package library is not loaded"));
+            assert_eq!(stack_top(thread), top);
+
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Package).unwrap();
+            thread.set_package_path(&path).unwrap();
+            thread.set_package_cpath("").unwrap();
+            thread
+                .do_string(
+                    "assert(require('greeting') == 'hello') assert(package.cpath == '')",
+                    None,
+                )
+                .unwrap();
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_thread_upvalues() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            thread
+                .do_string(
+                    "local n = 41 counter = function() n = n + 1 return n end",
+                    None,
+                )
+                .unwrap();
+            unsafe { sys::lua_getglobal(thread.as_raw().as_ptr(), b"counter\0".as_ptr() as _) };
+            let f = stack_top(thread);
+
+            assert_eq!(thread.get_upvalue(f, 1).unwrap().as_deref(), Some("n"));
+            assert_eq!(i64::from_lua(thread, -1).unwrap(), 41);
+            thread.remove(-1);
+            assert_eq!(thread.get_upvalue(f, 2).unwrap(), None);
+            assert_eq!(thread.get_upvalue(f, 0).unwrap(), None);
+            assert_eq!(stack_top(thread), f);
+
+            thread.push(99i64).unwrap();
+            assert_eq!(thread.set_upvalue(f, 1).as_deref(), Some("n"));
+            assert_eq!(stack_top(thread), f);
+            let n: i64 = thread.caller_global("counter").unwrap().call_ret().unwrap();
+            assert_eq!(n, 100);
+
+            // nothing is popped if the upvalue does not exist
+            thread.push(1i64).unwrap();
+            assert_eq!(thread.set_upvalue(f, 2), None);
+            assert_eq!(stack_top(thread), f + 1);
+            thread.remove(-1);
+
+            // C function upvalues have empty names
+            thread.push_function(|_| Ok(0)).unwrap();
+            assert_eq!(thread.get_upvalue(-1, 1).unwrap().as_deref(), Some(""));
+            for _ in 0..3 {
+                thread.remove(-1);
+            }
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_memory_limit() {
+        Thread::spawn_with_memory_limit(256 * 1024, move |thread| {
+            thread.open_lib(StdLib::String).unwrap();
+            let top = stack_top(thread);
+            thread
+                .caller_load("small = string.rep('x', 1024)", None, LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap();
+
+            let err = thread
+                .caller_load(
+                    "big = {} for i = 1, 1e6 do big[i] = i end",
+                    None,
+                    LoadingMode::Text,
+                )
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+            assert_eq!(stack_top(thread), top);
+        })
+        .unwrap();
+
+        match Thread::spawn_with_memory_limit(16, |_| ()) {
+            Err(ThreadError::Lua(err)) => assert_eq!(err.kind(), ErrorKind::OutOfMemory),
+            _ => panic!("state creation should fail"),
+        }
+    }
+
+    #[test]
+    fn test_thread_stack_len() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            assert_eq!(top, stack_top(thread));
+            thread.ensure_stack(100).unwrap();
+            for i in 0..100 {
+                unsafe { sys::lua_pushinteger(thread.as_raw().as_ptr(), i) };
+            }
+            assert_eq!(thread.stack_len(), top + 100);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+
+            let err = thread.ensure_stack(sys::LUAI_MAXSTACK + 1).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+            assert_eq!(thread.stack_len(), top);
+
+            // pushing grows the stack until its hard limit
+            let err = loop {
+                if let Err(e) = thread.push(true) {
+                    break e;
+                }
+            };
+            assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+            assert!(thread.stack_len() <= sys::LUAI_MAXSTACK);
+
+            // the other pushing functions fail the same way, leaving the stack untouched
+            let full = thread.stack_len();
+            let errors = [
+                thread.push_self().unwrap_err(),
+                thread.push_light_userdata(ptr::null_mut()).unwrap_err(),
+                thread.push_function(|_| Ok(0)).unwrap_err(),
+                thread.string_to_number("1").unwrap_err(),
+                thread.new_lib(&[]).unwrap_err(),
+                thread.registry_set("pollua.test", 1i64).unwrap_err(),
+                thread.push(LuaSequence(vec![1i64])).unwrap_err(),
+            ];
+            for err in &errors {
+                assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+            }
+            assert_eq!(thread.stack_len(), full);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_try_version() {
+        let (version, try_version) =
+            Thread::spawn(move |thread| (thread.version(), thread.try_version())).unwrap();
+        assert_eq!(try_version, Some(version));
+        assert_eq!(version, crate::lua_version());
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            let versions = thread.verify_version().unwrap();
+            assert_eq!(versions, (sys::LUA_VERSION_NUM, version));
+            assert_eq!(stack_top(thread), top);
+            // other errors do not hold versions
+            let err = thread.do_string("error('oops')", None).unwrap_err();
+            assert_eq!(err.versions(), None);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_type_of() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            thread.push(true).unwrap();
+            thread.push(1i64).unwrap();
+            thread.push(()).unwrap();
+            thread.push_light_userdata(ptr::null_mut()).unwrap();
+            thread.new_table().into_raw();
+            assert_eq!(thread.type_of(top + 1), Some(ValueType::Boolean));
+            assert_eq!(thread.type_of(top + 2), Some(ValueType::Number));
+            assert_eq!(thread.type_of(top + 3), Some(ValueType::Nil));
+            assert_eq!(thread.type_of(-2), Some(ValueType::LightUserdata));
+            assert_eq!(thread.type_of(-1), Some(ValueType::Table));
+            assert_eq!(thread.type_of(top + 6), None);
+            assert_eq!(
+                thread.type_of(sys::LUA_REGISTRYINDEX),
+                Some(ValueType::Table)
+            );
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_push_formatted() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            thread.push_formatted(format_args!("plain")).unwrap();
+            let name = "x";
+            thread
+                .push_formatted(format_args!("{}={:.2} {:?}", name, 0.5, "\0"))
+                .unwrap();
+            assert_eq!(thread.stack_len(), top + 2);
+            assert_eq!(String::from_lua(thread, -2).unwrap(), "plain");
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "x=0.50 \"\\0\"");
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_to_number_kind() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let chunk = "return 3, 3.0, 2^53, 7 // 2, 7 / 2, '4', math.maxinteger + 1";
+            thread.open_lib(StdLib::Math).unwrap();
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.calln(7))
+                .map(ReturnValues::into_raw)
+                .unwrap();
+            let kinds: Vec<_> = (top + 1..=top + 7)
+                .map(|i| thread.to_number_kind(i))
+                .collect();
+            assert_eq!(
+                kinds,
+                [
+                    Some(NumberKind::Integer(3)),
+                    Some(NumberKind::Float(3.0)),
+                    Some(NumberKind::Float(9007199254740992.0)),
+                    Some(NumberKind::Integer(3)),
+                    Some(NumberKind::Float(3.5)),
+                    None,
+                    // integer overflow wraps around
+                    Some(NumberKind::Integer(crate::Integer::MIN)),
+                ]
+            );
+            assert_eq!(thread.to_number_kind(top + 8), None);
+
+            // the subtype is kept when pushing
+            thread.push(NumberKind::Float(3.0)).unwrap();
+            assert!(thread.raw_equal(-1, top + 2));
+            assert_eq!(thread.to_number_kind(-1), Some(NumberKind::Float(3.0)));
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_string_to_number() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let int = |thread: &mut Thread, s| match thread.string_to_number(s).unwrap() {
+                Some(LuaValue::Integer(n)) => Some(n),
+                _ => None,
+            };
+            let float = |thread: &mut Thread, s| match thread.string_to_number(s).unwrap() {
+                Some(LuaValue::Number(n)) => Some(n),
+                _ => None,
+            };
+            assert_eq!(int(thread, "42"), Some(42));
+            assert_eq!(int(thread, " -7\t"), Some(-7));
+            assert_eq!(int(thread, "0xff"), Some(255));
+            assert_eq!(float(thread, "42.0"), Some(42.0));
+            assert_eq!(float(thread, "0x1p4"), Some(16.0));
+            assert_eq!(float(thread, ".5e1"), Some(5.0));
+            // too large for an integer
+            assert_eq!(
+                float(thread, "9223372036854775808"),
+                Some(9223372036854775808.0)
+            );
+            for s in &[
+                "", " ", "1 2", "12px", "0x", "1\x002", "inf", "nan", "1_000",
+            ] {
+                assert!(thread.string_to_number(s).unwrap().is_none(), "{:?}", s);
+            }
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_light_userdata() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let mut x = 0u8;
+            let ptr = &mut x as *mut u8 as *mut libc::c_void;
+            thread.push_light_userdata(ptr).unwrap();
+            thread.push_light_userdata(ptr::null_mut()).unwrap();
+            assert_eq!(type_at(thread, -2), sys::LUA_TLIGHTUSERDATA);
+            assert_eq!(thread.to_light_userdata(-2), Some(ptr));
+            assert_eq!(thread.to_light_userdata(-1), Some(ptr::null_mut()));
+            // light userdata are compared by address
+            assert!(!thread.raw_equal(-1, -2));
+
+            // full userdata and other values are rejected
+            unsafe { sys::lua_newuserdata(thread.as_raw().as_ptr(), 8) };
+            assert_eq!(thread.to_light_userdata(-1), None);
+            thread.push(1i64).unwrap();
+            assert_eq!(thread.to_light_userdata(-1), None);
+            assert_eq!(thread.to_light_userdata(top + 10), None);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_get_error() {
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            assert!(thread.get_error(sys::LUA_OK).is_ok());
+            for &(code, kind) in &[
+                (sys::LUA_YIELD, ErrorKind::Yield),
+                (sys::LUA_ERRRUN, ErrorKind::Runtime),
+                (sys::LUA_ERRSYNTAX, ErrorKind::Syntax),
+                (sys::LUA_ERRMEM, ErrorKind::OutOfMemory),
+                (sys::LUA_ERRERR, ErrorKind::MessageHandler),
+                (sys::LUA_ERRGCMM, ErrorKind::GarbageCollection),
+                (sys::LUA_ERRFILE, ErrorKind::FileLoad),
+                (42, ErrorKind::Unknown(42)),
+            ] {
+                thread.set_global("msg", "message").unwrap();
+                thread.push_global("msg").unwrap();
+                let err = thread.get_error(code).unwrap_err();
+                assert_eq!(err.kind(), kind);
+                assert_eq!(err.msg(), Some("message"));
+                assert_eq!(stack_top(thread), top);
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_error_conversions() {
+        let result = Thread::spawn(move |thread| -> Result<(), ThreadError> {
+            thread.do_string("x = 1", None).into_thread_result()?;
+            thread
+                .do_string("return nil + 1", None)
+                .into_thread_result()?;
+            unreachable!()
+        })
+        .unwrap();
+        let err = result.unwrap_err();
+        let msg = err.as_lua().and_then(Error::msg).unwrap();
+        assert!(msg.ends_with("attempt to perform arithmetic on a nil value"));
+
+        assert!(ThreadError::Panic(Box::new("oops")).as_lua().is_none());
+    }
+
+    #[test]
+    fn test_thread_error_display() {
+        let err = ThreadError::from(Error::runtime("oops"));
+        assert_eq!(err.to_string(), "lua error: oops");
+        assert!(format!("{:?}", err).starts_with("Lua(Error { kind: Runtime"));
+        let err = ThreadError::from(Error::new(ErrorKind::OutOfMemory, None));
+        assert_eq!(err.to_string(), "lua error: out of memory");
+
+        let mut error = Error::runtime("oops");
+        error.traceback = Some(String::from("stack traceback:\n\t[C]: in ?"));
+        assert_eq!(
+            ThreadError::Lua(error).to_string(),
+            "lua error: oops\nstack traceback:\n\t[C]: in ?"
+        );
+
+        let err = ThreadError::Panic(Box::new(String::from("boom")));
+        assert_eq!(err.to_string(), "panicked while running thread: boom");
+        assert!(format!("{:?}", err).starts_with("Panic("));
+    }
+
+    #[test]
+    fn test_thread_max_error_len() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            assert_eq!(thread.max_error_len(), None);
+            let long = "é".repeat(100);
+            thread.set_global("long", &*long).unwrap();
+
+            let err = thread.do_string("error(long, 0)", None).unwrap_err();
+            assert_eq!(err.msg(), Some(&*long));
+
+            thread.set_max_error_len(Some(5));
+            assert_eq!(thread.max_error_len(), Some(5));
+            let err = thread.do_string("error(long, 0)", None).unwrap_err();
+            // cut on a character boundary
+            assert_eq!(err.msg(), Some("éé..."));
+            let err = thread.do_string("error('abc', 0)", None).unwrap_err();
+            assert_eq!(err.msg(), Some("abc"));
+
+            thread.set_traceback_enabled(true);
+            let err = thread.do_string("error('abcdef', 0)", None).unwrap_err();
+            assert_eq!(err.msg(), Some("abcde..."));
+            assert_eq!(err.traceback(), Some("stack..."));
+
+            thread.set_max_error_len(None);
+            let err = thread.do_string("error(long, 0)", None).unwrap_err();
+            assert_eq!(err.msg(), Some(&*long));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_traceback() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = stack_top(thread);
+            let chunk = "local function f() error('oops') end f()";
+            assert!(!thread.traceback_enabled());
+            let err = thread
+                .caller_load(chunk, Some("=chunk"), LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.msg(), Some("chunk:1: oops"));
+            assert_eq!(err.traceback(), None);
+            assert_eq!(stack_top(thread), top);
+
+            thread.set_traceback_enabled(true);
+            assert!(thread.traceback_enabled());
+            let err = thread
+                .caller_load(chunk, Some("=chunk"), LoadingMode::Text)
+                .and_then(|c| c.call().map(|_| ()))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("chunk:1: oops"));
+            assert_eq!(err.msg(), Some("chunk:1: oops"));
+            let traceback = err.traceback().unwrap();
+            assert!(traceback.starts_with("stack traceback:\n"));
+            assert!(traceback.contains("in local 'f'"));
+            assert_eq!(
+                err.to_string(),
+                format!("runtime error: chunk:1: oops\n{}", traceback)
+            );
+            assert_eq!(stack_top(thread), top);
+
+            // results are not affected by the message handler
+            let n: i64 = thread
+                .caller_load("return 42", None, LoadingMode::Text)
+                .and_then(|c| c.call_ret())
+                .unwrap();
+            assert_eq!(n, 42);
+            assert_eq!(stack_top(thread), top);
+
+            // the setting is shared with coroutines
+            assert!(thread.new_coroutine().traceback_enabled());
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_panic_handler() {
+        use std::cell::RefCell;
+
+        Thread::spawn(move |thread| {
+            let messages = Rc::new(RefCell::new(Vec::<Option<String>>::new()));
+            let handler_messages = messages.clone();
+            thread.set_panic_handler(move |msg| {
+                handler_messages.borrow_mut().push(msg.map(String::from))
+            });
+            let top = stack_top(thread);
+            // Lua calls the panic function with the error object on the stack
+            unsafe {
+                let ptr = thread.as_raw().as_ptr();
+                sys::lua_pushstring(ptr, b"unprotected\0".as_ptr() as *const _);
+                assert_eq!(at_panic(ptr), 0);
+            }
+            assert_eq!(*messages.borrow(), [Some(String::from("unprotected"))]);
+            assert_eq!(stack_top(thread), top);
+            thread.remove_panic_handler();
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_unprotected_error() {
+        let result = Thread::spawn(move |thread| unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            sys::lua_pushstring(ptr, b"unprotected\0".as_ptr() as *const _);
+            sys::lua_error(ptr);
+        });
+        match result {
+            Err(ThreadError::Lua(error)) => assert_eq!(error.msg(), Some("unprotected")),
+            _ => panic!("expected a Lua error"),
+        }
+    }
+
+    #[test]
+    fn test_thread_gc() {
+        Thread::spawn(move |thread| {
+            thread.gc_stop();
+            let before = thread.gc_count();
+            for i in 0..100i64 {
+                thread
+                    .set_global("garbage", format!("string number {}", i))
+                    .unwrap();
+            }
+            let after = thread.gc_count();
+            assert!(after > before);
+
+            thread.gc_restart();
+            thread.gc_collect();
+            assert!(thread.gc_count() < after);
+            while !thread.gc_step(0) {}
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_new_lib() {
+        unsafe extern "C" fn double(l: *mut sys::lua_State) -> libc::c_int {
+            sys::lua_pushinteger(l, sys::luaL_checkinteger(l, 1) * 2);
+            1
+        }
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            {
+                let mut lib = thread
+                    .new_lib(&[("double", Some(double)), ("todo", None)])
+                    .unwrap();
+                assert_eq!(lib.pairs::<String, LuaValue>().unwrap().count(), 2);
+                assert!(!lib.get::<_, bool>("todo").unwrap());
+                assert_eq!(
+                    lib.get::<_, LuaValue>("double").unwrap().value_type(),
+                    ValueType::Function
+                );
+            }
+            assert_eq!(stack_top(thread), top);
+            let empty = thread.new_lib(&[]).unwrap();
+            assert!(empty.is_empty());
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_thread_open_lib() {
+        fn global_type(thread: &mut Thread, name: &str) -> libc::c_int {
+            let t = thread.push_global(name).unwrap();
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+            t
+        }
+
+        Thread::spawn(move |thread| {
+            let top = stack_top(thread);
+            assert_eq!(global_type(thread, "math"), sys::LUA_TNIL);
+            assert_eq!(global_type(thread, "print"), sys::LUA_TNIL);
+
+            thread.open_lib(StdLib::Math).unwrap();
+            assert_eq!(global_type(thread, "math"), sys::LUA_TTABLE);
+            assert_eq!(global_type(thread, "string"), sys::LUA_TNIL);
+            assert_eq!(stack_top(thread), top);
+
+            thread.open_lib(StdLib::Base).unwrap();
+            assert_eq!(global_type(thread, "print"), sys::LUA_TFUNCTION);
+
+            thread.open_libs().unwrap();
+            for &lib in &[StdLib::String, StdLib::Debug, StdLib::Utf8, StdLib::Io] {
+                assert_eq!(global_type(thread, lib.name()), sys::LUA_TTABLE);
+            }
+            assert_eq!(stack_top(thread), top);
         })
         .unwrap()
     }
@@ -0,0 +1,708 @@
+use crate::{thread::Thread, value::ValueType, Error, LuaResult};
+use std::{ptr, slice};
+
+impl Thread {
+    /// Performs an arithmetic or bitwise operation over the values at the top of the stack,
+    /// with the same semantics as the corresponding Lua operator, metamethods included.
+    ///
+    /// The operands are popped: two for binary operations, the second one being at the top,
+    /// and one for unary operations. The result is pushed on success, nothing is pushed
+    /// if the operation raises an error.
+    ///
+    /// # Panics
+    /// This panics if the stack holds less values than the operation needs.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{ArithOp, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let ptr = thread.as_raw().as_ptr();
+    ///     unsafe {
+    ///         pollua::sys::lua_pushinteger(ptr, 7);
+    ///         pollua::sys::lua_pushinteger(ptr, 2);
+    ///     }
+    ///     thread.arith(ArithOp::Add).unwrap();
+    ///     assert_eq!(unsafe { pollua::sys::lua_tointeger(ptr, -1) }, 9);
+    /// }).unwrap()
+    /// ```
+    pub fn arith(&mut self, op: ArithOp) -> LuaResult<()> {
+        unsafe extern "C" fn arith(l: *mut sys::lua_State) -> libc::c_int {
+            // arguments: operands, operation
+            let op = sys::lua_tointeger(l, -1) as libc::c_int;
+            sys::lua_pop(l, 1);
+            sys::lua_arith(l, op);
+            1
+        }
+
+        let n = op.operands();
+        assert!(
+            self.stack_len() >= n,
+            "not enough operands on the stack for {:?}",
+            op
+        );
+        self.ensure_stack(2)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_pushcfunction(ptr, Some(arith));
+            sys::lua_insert(ptr, -n - 1);
+            sys::lua_pushinteger(ptr, op.as_raw() as sys::lua_Integer);
+            let code = self.pcall(n + 1, 1);
+            self.get_error(code)
+        }
+    }
+
+    /// Concatenates the `n` values at the top of the stack with the same semantics as the
+    /// `..` operator, metamethods included.
+    ///
+    /// The values are popped, the result is pushed on success and nothing is pushed if the
+    /// concatenation raises an error. If `n` is 1 the value is left as is, if `n` is 0 an
+    /// empty string is pushed.
+    ///
+    /// # Panics
+    /// This panics if `n` is negative or if the stack holds less than `n` values.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::FromLua, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push("answer: ").unwrap();
+    ///     thread.push(42i64).unwrap();
+    ///     thread.concat(2).unwrap();
+    ///     assert_eq!(String::from_lua(thread, -1).unwrap(), "answer: 42");
+    /// }).unwrap()
+    /// ```
+    pub fn concat(&mut self, n: libc::c_int) -> LuaResult<()> {
+        unsafe extern "C" fn concat(l: *mut sys::lua_State) -> libc::c_int {
+            sys::lua_concat(l, sys::lua_gettop(l));
+            1
+        }
+
+        assert!(
+            n >= 0 && self.stack_len() >= n,
+            "not enough values on the stack to concatenate {} values",
+            n
+        );
+        self.ensure_stack(1)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_pushcfunction(ptr, Some(concat));
+            sys::lua_insert(ptr, -n - 1);
+            let code = self.pcall(n, 1);
+            self.get_error(code)
+        }
+    }
+
+    /// Compares the values at `idx1` and `idx2` with the same semantics as the corresponding
+    /// Lua operator, metamethods included.
+    /// Returns `false` if any index is not valid.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::thread::{CompareOp, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let ptr = thread.as_raw().as_ptr();
+    ///     unsafe {
+    ///         pollua::sys::lua_pushinteger(ptr, 1);
+    ///         pollua::sys::lua_pushnumber(ptr, 1.5);
+    ///     }
+    ///     assert!(thread.compare(-2, -1, CompareOp::Lt).unwrap());
+    ///     assert!(!thread.compare(-2, -1, CompareOp::Eq).unwrap());
+    /// }).unwrap()
+    /// ```
+    pub fn compare(
+        &mut self,
+        idx1: libc::c_int,
+        idx2: libc::c_int,
+        op: CompareOp,
+    ) -> LuaResult<bool> {
+        unsafe extern "C" fn compare(l: *mut sys::lua_State) -> libc::c_int {
+            // arguments: operands, operation
+            let op = sys::lua_tointeger(l, 3) as libc::c_int;
+            let result = sys::lua_compare(l, 1, 2, op);
+            sys::lua_pushboolean(l, result);
+            1
+        }
+
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_type(ptr, idx1) == sys::LUA_TNONE
+                || sys::lua_type(ptr, idx2) == sys::LUA_TNONE
+            {
+                return Ok(false);
+            }
+            let (idx1, idx2) = (self.abs_index(idx1), self.abs_index(idx2));
+            self.ensure_stack(4)?;
+            sys::lua_pushcfunction(ptr, Some(compare));
+            sys::lua_pushvalue(ptr, idx1);
+            sys::lua_pushvalue(ptr, idx2);
+            sys::lua_pushinteger(ptr, op.as_raw() as sys::lua_Integer);
+            let code = self.pcall(3, 1);
+            self.get_error(code)?;
+            let result = sys::lua_toboolean(ptr, -1) != 0;
+            sys::lua_pop(ptr, 1);
+            Ok(result)
+        }
+    }
+
+    /// Returns true if the values at `idx1` and `idx2` are primitively equal,
+    /// without calling the `__eq` metamethod.
+    /// Returns `false` if any index is not valid.
+    #[inline]
+    pub fn raw_equal(&self, idx1: libc::c_int, idx2: libc::c_int) -> bool {
+        unsafe { sys::lua_rawequal(self.raw.as_ptr(), idx1, idx2) != 0 }
+    }
+
+    /// Returns the pointer identifying the value at `index`, like `lua_topointer`.
+    /// Tables, functions, threads and full userdata have a distinct pointer for as long as
+    /// they are alive, light userdata return their pointer.
+    /// Returns null for other values and invalid indices.
+    ///
+    /// The pointer can only be used to compare values, for instance as the key of a cache
+    /// of Lua values that are kept alive.
+    #[inline]
+    pub fn value_pointer(&self, index: libc::c_int) -> *const libc::c_void {
+        unsafe { sys::lua_topointer(self.raw.as_ptr(), index) }
+    }
+
+    /// Returns true if the values at `idx1` and `idx2` are the same object: the same table,
+    /// function, thread or userdata, compared with [`value_pointer`].
+    /// Returns `false` for other values, which have no identity, and invalid indices.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let first = thread.new_table().into_raw();
+    ///     let second = thread.new_table().into_raw();
+    ///     unsafe { pollua::sys::lua_pushvalue(thread.as_raw().as_ptr(), first) };
+    ///     assert!(thread.same_identity(first, -1));
+    ///     assert!(!thread.same_identity(first, second));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`value_pointer`]: #method.value_pointer
+    pub fn same_identity(&self, idx1: libc::c_int, idx2: libc::c_int) -> bool {
+        let has_identity = |index| {
+            matches!(
+                unsafe { sys::lua_type(self.raw.as_ptr(), index) },
+                sys::LUA_TTABLE | sys::LUA_TFUNCTION | sys::LUA_TTHREAD | sys::LUA_TUSERDATA
+            )
+        };
+        has_identity(idx1)
+            && has_identity(idx2)
+            && self.value_pointer(idx1) == self.value_pointer(idx2)
+    }
+
+    /// Returns the length of the value at `index`, as the `#` operator would,
+    /// metamethods included.
+    /// Returns a runtime error if the value has no length or if `__len` does not
+    /// return an integer.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.push("hello").unwrap();
+    ///     assert_eq!(thread.len(-1).unwrap(), 5);
+    /// }).unwrap()
+    /// ```
+    pub fn len(&mut self, index: libc::c_int) -> LuaResult<i64> {
+        unsafe extern "C" fn len(l: *mut sys::lua_State) -> libc::c_int {
+            sys::lua_len(l, 1);
+            1
+        }
+
+        self.ensure_stack(2)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let valid = sys::lua_type(ptr, index) != sys::LUA_TNONE;
+            let index = self.abs_index(index);
+            sys::lua_pushcfunction(ptr, Some(len));
+            if valid {
+                sys::lua_pushvalue(ptr, index);
+            } else {
+                sys::lua_pushnil(ptr);
+            }
+            let code = self.pcall(1, 1);
+            self.get_error(code)?;
+            let mut isnum = 0;
+            let len = sys::lua_tointegerx(ptr, -1, &mut isnum);
+            sys::lua_pop(ptr, 1);
+            if isnum != 0 {
+                Ok(len as i64)
+            } else {
+                Err(Error::runtime("object length is not an integer"))
+            }
+        }
+    }
+
+    /// Returns the raw length of the value at `index`, without calling metamethods:
+    /// the length of strings, the border of tables, the size of full userdata,
+    /// and 0 for other values.
+    #[inline]
+    pub fn raw_len(&self, index: libc::c_int) -> usize {
+        unsafe { sys::lua_rawlen(self.raw.as_ptr(), index) }
+    }
+
+    /// Returns the type and a rendering of each value of the stack, from the bottom to the top.
+    /// Values are rendered like `tostring` does, calling `__tostring` metamethods;
+    /// if a metamethod raises an error, the error message is rendered instead.
+    /// The stack is left untouched.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to render the values.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::ValueType, Thread};
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let top = thread.stack_len();
+    ///     thread.push(42i64).unwrap();
+    ///     thread.push("text").unwrap();
+    ///     let stack = thread.dump_stack().unwrap();
+    ///     assert_eq!(stack[top as usize], (ValueType::Number, String::from("42")));
+    ///     assert_eq!(stack[top as usize + 1], (ValueType::String, String::from("text")));
+    ///     assert_eq!(thread.stack_len(), top + 2);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn dump_stack(&mut self) -> LuaResult<Vec<(ValueType, String)>> {
+        unsafe extern "C" fn tostring(l: *mut sys::lua_State) -> libc::c_int {
+            sys::luaL_tolstring(l, 1, ptr::null_mut());
+            1
+        }
+
+        self.ensure_stack(2)?;
+        let top = self.stack_len();
+        let mut values = Vec::with_capacity(top as usize);
+        for index in 1..=top {
+            let ty = self.type_of(index).unwrap_or(ValueType::Nil);
+            let rendered = unsafe {
+                let ptr = self.raw.as_ptr();
+                sys::lua_pushcfunction(ptr, Some(tostring));
+                sys::lua_pushvalue(ptr, index);
+                let code = self.pcall(1, 1);
+                match self.get_error(code) {
+                    Ok(()) => {
+                        let mut len = 0;
+                        let s = sys::lua_tolstring(ptr, -1, &mut len);
+                        let buf = slice::from_raw_parts(s as *const u8, len);
+                        let rendered = String::from_utf8_lossy(buf).into_owned();
+                        sys::lua_pop(ptr, 1);
+                        rendered
+                    }
+                    Err(e) => format!("<error: {}>", e.msg().unwrap_or("?")),
+                }
+            };
+            values.push((ty, rendered));
+        }
+        Ok(values)
+    }
+}
+
+/// Arithmetic and bitwise operations, used by the [`Thread::arith`] method.
+///
+/// [`Thread::arith`]: struct.Thread.html#method.arith
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArithOp {
+    /// Addition (`+`).
+    Add,
+    /// Subtraction (`-`).
+    Sub,
+    /// Multiplication (`*`).
+    Mul,
+    /// Float division (`/`).
+    Div,
+    /// Modulo (`%`).
+    Mod,
+    /// Exponentiation (`^`).
+    Pow,
+    /// Floor division (`//`).
+    #[cfg(LUA_VERSION = "5.3")]
+    IDiv,
+    /// Bitwise AND (`&`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Band,
+    /// Bitwise OR (`|`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Bor,
+    /// Bitwise exclusive OR (`~`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Bxor,
+    /// Left shift (`<<`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Shl,
+    /// Right shift (`>>`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Shr,
+    /// Negation (unary `-`).
+    Unm,
+    /// Bitwise NOT (unary `~`).
+    #[cfg(LUA_VERSION = "5.3")]
+    Bnot,
+}
+
+impl ArithOp {
+    /// Returns the `LUA_OP*` constant of this operation.
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            ArithOp::Add => sys::LUA_OPADD,
+            ArithOp::Sub => sys::LUA_OPSUB,
+            ArithOp::Mul => sys::LUA_OPMUL,
+            ArithOp::Div => sys::LUA_OPDIV,
+            ArithOp::Mod => sys::LUA_OPMOD,
+            ArithOp::Pow => sys::LUA_OPPOW,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::IDiv => sys::LUA_OPIDIV,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Band => sys::LUA_OPBAND,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Bor => sys::LUA_OPBOR,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Bxor => sys::LUA_OPBXOR,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Shl => sys::LUA_OPSHL,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Shr => sys::LUA_OPSHR,
+            ArithOp::Unm => sys::LUA_OPUNM,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Bnot => sys::LUA_OPBNOT,
+        }
+    }
+
+    /// Returns the number of operands of this operation.
+    pub fn operands(self) -> libc::c_int {
+        match self {
+            ArithOp::Unm => 1,
+            #[cfg(LUA_VERSION = "5.3")]
+            ArithOp::Bnot => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Comparison operations, used by the [`Thread::compare`] method.
+///
+/// [`Thread::compare`]: struct.Thread.html#method.compare
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompareOp {
+    /// Equality (`==`).
+    Eq,
+    /// Less than (`<`).
+    Lt,
+    /// Less than or equal (`<=`).
+    Le,
+}
+
+impl CompareOp {
+    /// Returns the `LUA_OP*` constant of this operation.
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            CompareOp::Eq => sys::LUA_OPEQ,
+            CompareOp::Lt => sys::LUA_OPLT,
+            CompareOp::Le => sys::LUA_OPLE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::{LoadingMode, ReturnValues, StdLib},
+        value::FromLua,
+        ErrorKind,
+    };
+
+    fn binary(thread: &mut Thread, a: &str, b: &str, op: ArithOp) -> LuaResult<String> {
+        let chunk = format!("return {}, {}", a, b);
+        thread
+            .caller_load(&chunk, None, LoadingMode::Text)
+            .and_then(|c| c.calln(2))
+            // leaves the operands on the stack
+            .map(ReturnValues::into_raw)?;
+        thread.arith(op)?;
+        unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            let mut len = 0;
+            let s = sys::luaL_tolstring(ptr, -1, &mut len);
+            let s = String::from_utf8_lossy(std::slice::from_raw_parts(s as *const u8, len))
+                .into_owned();
+            sys::lua_pop(ptr, 2);
+            Ok(s)
+        }
+    }
+
+    #[cfg(LUA_VERSION = "5.3")]
+    #[test]
+    fn test_arith() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let cases = [
+                ("1", "2", ArithOp::Add, "3"),
+                ("1", "2.5", ArithOp::Add, "3.5"),
+                ("3", "2", ArithOp::Div, "1.5"),
+                ("7", "-3", ArithOp::Mod, "-2"),
+                ("2", "10", ArithOp::Pow, "1024.0"),
+                ("'10'", "2", ArithOp::Mul, "20.0"),
+                ("7", "2", ArithOp::IDiv, "3"),
+                ("12", "10", ArithOp::Band, "8"),
+                ("1", "4", ArithOp::Shl, "16"),
+            ];
+            for &(a, b, op, expected) in cases.iter() {
+                assert_eq!(binary(thread, a, b, op).unwrap(), expected, "{:?}", op);
+                assert_eq!(thread.stack_len(), top);
+            }
+
+            unsafe { sys::lua_pushinteger(thread.as_raw().as_ptr(), 5) };
+            thread.arith(ArithOp::Unm).unwrap();
+            thread.arith(ArithOp::Bnot).unwrap();
+            assert_eq!(
+                unsafe { sys::lua_tointeger(thread.as_raw().as_ptr(), -1) },
+                4
+            );
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+
+            let err = binary(thread, "1", "{}", ArithOp::Sub).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(
+                err.msg(),
+                Some("attempt to perform arithmetic on a table value")
+            );
+            assert!(binary(thread, "1.5", "1", ArithOp::Bor).is_err());
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_arith_metamethod() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let value = "setmetatable({}, { __add = function(a, b) return 42 end })";
+            assert_eq!(binary(thread, value, "1", ArithOp::Add).unwrap(), "42");
+            assert_eq!(binary(thread, "1", value, ArithOp::Add).unwrap(), "42");
+        })
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough operands")]
+    fn test_arith_underflow() {
+        let _ = Thread::spawn(move |thread| {
+            let _ = thread.arith(ArithOp::Add);
+        });
+    }
+
+    #[test]
+    fn test_concat() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = thread.stack_len();
+            let chunk = "return 'a', 1, 2.5, \
+                         setmetatable({}, { __concat = function(a, b) return 'meta' end }), {}";
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.calln(5))
+                .map(ReturnValues::into_raw)
+                .unwrap();
+
+            // only one of the values needs the metamethod
+            thread.concat(2).unwrap();
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "meta");
+            assert_eq!(thread.stack_len(), top + 4);
+
+            thread.new_table().into_raw();
+            let err = thread.concat(2).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("attempt to concatenate a table value"));
+            // the values are popped on error as well
+            assert_eq!(thread.stack_len(), top + 3);
+
+            thread.concat(3).unwrap();
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "a12.5");
+            assert_eq!(thread.stack_len(), top + 1);
+
+            thread.concat(1).unwrap();
+            assert_eq!(thread.stack_len(), top + 1);
+            thread.concat(0).unwrap();
+            assert_eq!(String::from_lua(thread, -1).unwrap(), "");
+            assert_eq!(thread.stack_len(), top + 2);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough values on the stack")]
+    fn test_concat_underflow() {
+        let _ = Thread::spawn(move |thread| {
+            thread.push(1i64).unwrap();
+            let _ = thread.concat(2);
+        });
+    }
+
+    #[test]
+    fn test_compare() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = thread.stack_len();
+            let chunk = "local mt = { __eq = function() return true end, \
+                                      __lt = function() return true end } \
+                         return 1, 2.0, 'a', 'b', setmetatable({}, mt), setmetatable({}, mt), {}";
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.calln(7))
+                .map(ReturnValues::into_raw)
+                .unwrap();
+            let (one, two, a, b, t1, t2, t3) = (1, 2, 3, 4, 5, 6, 7);
+            let base = top;
+
+            assert!(thread
+                .compare(base + one, base + two, CompareOp::Lt)
+                .unwrap());
+            assert!(thread
+                .compare(base + one, base + one, CompareOp::Le)
+                .unwrap());
+            assert!(!thread
+                .compare(base + two, base + one, CompareOp::Le)
+                .unwrap());
+            assert!(thread.compare(base + a, base + b, CompareOp::Lt).unwrap());
+            assert!(!thread.compare(base + one, base + a, CompareOp::Eq).unwrap());
+            // metamethods are called, except by raw_equal
+            assert!(thread.compare(base + t1, base + t2, CompareOp::Eq).unwrap());
+            assert!(thread.compare(base + t1, base + t2, CompareOp::Lt).unwrap());
+            assert!(!thread.raw_equal(base + t1, base + t2));
+            assert!(thread.raw_equal(base + t1, base + t1));
+            // only one of the tables needs the metamethod
+            assert!(thread.compare(base + t3, base + t1, CompareOp::Eq).unwrap());
+            // relative indices
+            assert!(thread.compare(-7, -6, CompareOp::Lt).unwrap());
+            // invalid indices
+            assert!(!thread.compare(base + 8, base + 8, CompareOp::Eq).unwrap());
+            assert!(!thread.raw_equal(base + 8, base + 8));
+            assert_eq!(thread.stack_len(), top + 7);
+
+            let err = thread
+                .compare(base + one, base + a, CompareOp::Lt)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("attempt to compare number with string"));
+            assert_eq!(thread.stack_len(), top + 7);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_len() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let top = thread.stack_len();
+            let chunk = "return 'abc', {1, 2, 3, 4}, \
+                         setmetatable({1}, { __len = function() return 42 end }), \
+                         setmetatable({}, { __len = function() return 'x' end }), 5";
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.calln(5))
+                .map(ReturnValues::into_raw)
+                .unwrap();
+
+            assert_eq!(thread.len(top + 1).unwrap(), 3);
+            assert_eq!(thread.len(top + 2).unwrap(), 4);
+            // __len is only used by len
+            assert_eq!(thread.len(top + 3).unwrap(), 42);
+            assert_eq!(thread.raw_len(top + 3), 1);
+            assert_eq!(thread.raw_len(-4), 4);
+            assert_eq!(thread.raw_len(top + 5), 0);
+
+            let err = thread.len(top + 4).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("object length is not an integer"));
+            let err = thread.len(top + 5).unwrap_err();
+            assert_eq!(err.msg(), Some("attempt to get length of a number value"));
+            let err = thread.len(top + 6).unwrap_err();
+            assert_eq!(err.msg(), Some("attempt to get length of a nil value"));
+            assert_eq!(thread.stack_len(), top + 5);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_same_identity() {
+        Thread::spawn(move |thread| {
+            let ptr = thread.as_raw().as_ptr();
+            let base = thread.stack_len();
+            thread.new_table().into_raw();
+            thread.new_table().into_raw();
+            unsafe { sys::lua_pushvalue(ptr, base + 1) };
+            thread.push_function(|_| Ok(0)).unwrap();
+            unsafe { sys::lua_pushvalue(ptr, -1) };
+            thread.push("text").unwrap();
+            thread.push("text").unwrap();
+            let mut x = 0;
+            thread
+                .push_light_userdata(&mut x as *mut i32 as *mut libc::c_void)
+                .unwrap();
+
+            // tables
+            assert!(thread.same_identity(base + 1, base + 3));
+            assert!(!thread.same_identity(base + 1, base + 2));
+            assert_eq!(
+                thread.value_pointer(base + 1),
+                thread.value_pointer(base + 3)
+            );
+            assert!(!thread.value_pointer(base + 1).is_null());
+            // functions
+            assert!(thread.same_identity(base + 4, base + 5));
+            assert!(!thread.same_identity(base + 1, base + 4));
+            // equal strings and light userdata have no identity
+            assert!(!thread.same_identity(base + 6, base + 7));
+            assert!(!thread.same_identity(base + 8, base + 8));
+            assert_eq!(
+                thread.value_pointer(base + 8),
+                &mut x as *mut i32 as *const _
+            );
+            // invalid indices
+            assert!(thread.value_pointer(base + 9).is_null());
+            assert!(!thread.same_identity(base + 9, base + 9));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_dump_stack() {
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            let base = thread.stack_len();
+            let chunk = "return nil, true, 1.5, setmetatable({}, { __tostring = function() \
+                         return 'custom' end }), setmetatable({}, { __tostring = function() \
+                         error('broken', 0) end })";
+            thread
+                .caller_load(chunk, None, LoadingMode::Text)
+                .and_then(|c| c.call())
+                .map(ReturnValues::into_raw)
+                .unwrap();
+            assert_eq!(thread.stack_len(), base + 5);
+
+            let stack = thread.dump_stack().unwrap();
+            assert_eq!(stack.len(), base as usize + 5);
+            assert_eq!(
+                &stack[base as usize..],
+                &[
+                    (ValueType::Nil, String::from("nil")),
+                    (ValueType::Boolean, String::from("true")),
+                    (ValueType::Number, String::from("1.5")),
+                    (ValueType::Table, String::from("custom")),
+                    (ValueType::Table, String::from("<error: broken>")),
+                ]
+            );
+            assert_eq!(thread.stack_len(), base + 5);
+            assert!(bool::from_lua(thread, base + 2).unwrap());
+        })
+        .unwrap()
+    }
+}
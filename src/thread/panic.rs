@@ -0,0 +1,87 @@
+use std::{
+    any::Any,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+/// Registry name of the metatable of panic error objects.
+const PANIC_METATABLE: &[u8] = b"pollua.Panic\0";
+
+/// A panic payload.
+pub(super) type Payload = Box<dyn Any + Send + 'static>;
+
+/// Panic payload re-raised on the Rust side by [`Thread::get_error`],
+/// caught by [`Thread::spawn`] and turned into [`ThreadError::Panic`].
+///
+/// [`Thread::get_error`]: struct.Thread.html#method.get_error
+/// [`Thread::spawn`]: struct.Thread.html#method.spawn
+/// [`ThreadError::Panic`]: enum.ThreadError.html#variant.Panic
+pub(super) struct CallbackPanic(pub Payload);
+
+/// Returns the message of a panic payload.
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<Any>"
+    }
+}
+
+/// Pushes an error object holding the panic `payload`.
+/// Converted to a string, the object gives the panic message.
+///
+/// # Safety
+/// `payload` must come from `Box::into_raw`, ownership is transferred to the error object.
+/// The payload is passed as a raw pointer so that it is only leaked if pushing raises
+/// a memory error.
+pub(super) unsafe fn push_panic(l: *mut sys::lua_State, payload: *mut Payload) {
+    let data = sys::lua_newuserdata(l, mem::size_of::<*mut Payload>()) as *mut *mut Payload;
+    ptr::write(data, payload);
+    if sys::luaL_newmetatable(l, PANIC_METATABLE.as_ptr() as *const _) != 0 {
+        sys::lua_pushcfunction(l, Some(panic_gc));
+        sys::lua_setfield(l, -2, b"__gc\0".as_ptr() as *const _);
+        sys::lua_pushcfunction(l, Some(panic_tostring));
+        sys::lua_setfield(l, -2, b"__tostring\0".as_ptr() as *const _);
+    }
+    sys::lua_setmetatable(l, -2);
+}
+
+/// Takes the payload out of the panic error object at `index`.
+/// Returns `None` if the value is not a panic error object or if its payload has
+/// already been taken.
+pub(super) unsafe fn take_panic(l: *mut sys::lua_State, index: libc::c_int) -> Option<Payload> {
+    let data = sys::luaL_testudata(l, index, PANIC_METATABLE.as_ptr() as *const _);
+    if data.is_null() {
+        return None;
+    }
+    let payload = mem::replace(&mut *(data as *mut *mut Payload), ptr::null_mut());
+    if payload.is_null() {
+        None
+    } else {
+        Some(*Box::from_raw(payload))
+    }
+}
+
+unsafe extern "C" fn panic_gc(l: *mut sys::lua_State) -> libc::c_int {
+    if let Some(payload) = take_panic(l, 1) {
+        // a panicking destructor must not unwind into Lua
+        let _ = panic::catch_unwind(AssertUnwindSafe(move || mem::drop(payload)));
+    }
+    0
+}
+
+unsafe extern "C" fn panic_tostring(l: *mut sys::lua_State) -> libc::c_int {
+    let payload = *(sys::lua_touserdata(l, 1) as *const *mut Payload);
+    let msg = if payload.is_null() {
+        "<payload taken>"
+    } else {
+        panic_message(&**payload)
+    };
+    sys::lua_pushstring(l, b"panic in Rust function: \0".as_ptr() as *const _);
+    sys::lua_pushlstring(l, msg.as_ptr() as *const libc::c_char, msg.len());
+    sys::lua_concat(l, 2);
+    1
+}
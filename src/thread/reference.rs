@@ -0,0 +1,405 @@
+use crate::{
+    thread::{Caller, Thread, ThreadRef},
+    value::{type_error, FromLua, FromLuaMulti, ToLua, ToLuaMulti, ValueType},
+    Error, LuaResult,
+};
+use std::{cell::Cell, ptr::NonNull, rc::Rc};
+
+/// A Lua value stored in the registry, keeping it alive while the `Reference` exists.
+/// Created by [`Thread::reference_top`] and pushed back with [`Thread::push_reference`].
+///
+/// Dropping the `Reference` releases the value with `luaL_unref`,
+/// it is safe to drop it after its thread has been closed.
+///
+/// # Examples
+/// ```
+/// use pollua::Thread;
+///
+/// Thread::spawn(move |thread| {
+///     let top = thread.stack_len();
+///     thread.new_table().into_raw();
+///     // the table is popped from the stack but kept alive by the registry
+///     let reference = thread.reference_top();
+///     assert_eq!(thread.stack_len(), top);
+///
+///     thread.push_reference(&reference).unwrap();
+///     assert_eq!(thread.stack_len(), top + 1);
+/// }).unwrap()
+/// ```
+///
+/// [`Thread::reference_top`]: struct.Thread.html#method.reference_top
+/// [`Thread::push_reference`]: struct.Thread.html#method.push_reference
+#[derive(Debug)]
+pub struct Reference {
+    /// The main thread of the state owning the reference.
+    main: NonNull<sys::lua_State>,
+    alive: Rc<Cell<bool>>,
+    key: libc::c_int,
+}
+
+impl Reference {
+    /// Returns true if the referenced value is nil.
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        self.key == sys::LUA_REFNIL
+    }
+}
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        if self.alive.get() {
+            unsafe { sys::luaL_unref(self.main.as_ptr(), sys::LUA_REGISTRYINDEX, self.key) }
+        }
+    }
+}
+
+impl Thread {
+    /// Pops the value at the top of the stack and stores it in the registry.
+    /// Returns a [`Reference`] to the value.
+    ///
+    /// # Panics
+    /// This panics if the stack is empty.
+    ///
+    /// [`Reference`]: struct.Reference.html
+    pub fn reference_top(&mut self) -> Reference {
+        assert!(self.stack_len() > 0, "cannot reference an empty stack");
+        let alive = self.extra_mut().alive.clone();
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            let key = sys::luaL_ref(ptr, sys::LUA_REGISTRYINDEX);
+            Reference {
+                main: self.main_thread(),
+                alive,
+                key,
+            }
+        }
+    }
+
+    /// Pushes the value of `reference` onto the stack.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the value.
+    ///
+    /// # Panics
+    /// This panics if `reference` was created by another Lua state.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn push_reference(&mut self, reference: &Reference) -> LuaResult<()> {
+        self.ensure_stack(1)?;
+        assert!(
+            self.main_thread() == reference.main,
+            "the reference belongs to another Lua state"
+        );
+        unsafe {
+            sys::lua_rawgeti(
+                self.raw.as_ptr(),
+                sys::LUA_REGISTRYINDEX,
+                reference.key as crate::Integer,
+            )
+        };
+        Ok(())
+    }
+
+    /// Calls the function of `reference` with `args`, a single value or a tuple of values,
+    /// and converts the first return value to `R`, or the first return values if `R` is
+    /// a tuple, like [`Caller::call_ret`].
+    /// Returns a runtime error if the referenced value is not a function.
+    ///
+    /// # Panics
+    /// This panics if `reference` was created by another Lua state.
+    ///
+    /// [`Caller::call_ret`]: struct.Caller.html#method.call_ret
+    pub fn call_reference<R: FromLuaMulti, A: ToLuaMulti>(
+        &mut self,
+        reference: &Reference,
+        args: A,
+    ) -> LuaResult<R> {
+        self.push_reference(reference)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_type(ptr, -1) != sys::LUA_TFUNCTION {
+                let err = type_error(self, -1, "function");
+                sys::lua_pop(ptr, 1);
+                return Err(err);
+            }
+            Caller::from_stack_unchecked(ThreadRef::from_ref(self))
+                .results(R::LEN as libc::c_int)
+                .call_with(args)?
+                .values()
+        }
+    }
+
+    /// Sets `registry[key] = value`, storing host state out of the reach of Lua code.
+    ///
+    /// The registry is shared by all the code running in the state, C libraries included,
+    /// so keys should be prefixed with a unique name. Setting a key to `nil` removes it.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the key and the
+    /// value.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     thread.registry_set("my_app.version", 3i64).unwrap();
+    ///     assert_eq!(thread.registry_get::<i64>("my_app.version").unwrap(), 3);
+    ///     assert!(thread.get_global::<i64, _>("my_app.version").is_err());
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn registry_set<V: ToLua>(&mut self, key: &str, value: V) -> LuaResult<()> {
+        self.ensure_stack(2)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_pushlstring(ptr, key.as_ptr() as *const libc::c_char, key.len());
+            if let Err(e) = value.push(self) {
+                sys::lua_pop(ptr, 1);
+                return Err(e);
+            }
+            sys::lua_rawset(ptr, sys::LUA_REGISTRYINDEX);
+        }
+        Ok(())
+    }
+
+    /// Returns `registry[key]` converted to `V`.
+    /// Returns an error of kind [`ErrorKind::Runtime`] if the value is not of type `V`,
+    /// or [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the key.
+    ///
+    /// [`ErrorKind::Runtime`]: ../enum.ErrorKind.html#variant.Runtime
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn registry_get<V: FromLua>(&mut self, key: &str) -> LuaResult<V> {
+        self.ensure_stack(1)?;
+        let mut guard = self.stack_guard();
+        unsafe {
+            let ptr = guard.raw.as_ptr();
+            sys::lua_pushlstring(ptr, key.as_ptr() as *const libc::c_char, key.len());
+            sys::lua_rawget(ptr, sys::LUA_REGISTRYINDEX);
+        }
+        V::from_lua(&mut guard, -1).map_err(|e| {
+            let msg = format!(
+                "bad registry value '{}' ({})",
+                key,
+                e.msg().unwrap_or("invalid value")
+            );
+            Error::new(e.kind(), Some(msg))
+        })
+    }
+
+    /// Pops the value at the top of the stack and stores it in the registry with the
+    /// pointer `key`, like `lua_rawsetp`.
+    ///
+    /// The pointer is only used as a key and never dereferenced. The address of a `static`
+    /// is a key that cannot collide with the keys of other libraries.
+    ///
+    /// # Panics
+    /// This panics if the stack is empty.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{value::ValueType, Thread};
+    ///
+    /// static KEY: u8 = 0;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let key = &KEY as *const u8 as *const libc::c_void;
+    ///     thread.new_table().into_raw();
+    ///     thread.registry_set_by_ptr(key);
+    ///     assert_eq!(thread.registry_get_by_ptr(key).unwrap(), ValueType::Table);
+    /// }).unwrap()
+    /// ```
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // the pointer is only used as a key
+    pub fn registry_set_by_ptr(&mut self, key: *const libc::c_void) {
+        assert!(
+            self.stack_len() > 0,
+            "cannot store a value from an empty stack"
+        );
+        unsafe { sys::lua_rawsetp(self.raw.as_ptr(), sys::LUA_REGISTRYINDEX, key) }
+    }
+
+    /// Pushes the value stored in the registry with the pointer `key` onto the stack,
+    /// like `lua_rawgetp`, and returns its type. `nil` is pushed if there is no such value.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the value.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    #[allow(clippy::not_unsafe_ptr_arg_deref)] // the pointer is only used as a key
+    pub fn registry_get_by_ptr(&mut self, key: *const libc::c_void) -> LuaResult<ValueType> {
+        self.ensure_stack(1)?;
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_rawgetp(ptr, sys::LUA_REGISTRYINDEX, key);
+            Ok(ValueType::from_code(sys::lua_type(ptr, -1)).unwrap_or(ValueType::Nil))
+        }
+    }
+
+    /// Returns the main thread of the state.
+    pub(super) fn main_thread(&mut self) -> NonNull<sys::lua_State> {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            sys::lua_rawgeti(ptr, sys::LUA_REGISTRYINDEX, sys::LUA_RIDX_MAINTHREAD);
+            let main = sys::lua_tothread(ptr, -1);
+            sys::lua_pop(ptr, 1);
+            NonNull::new_unchecked(main)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::{LoadingMode, Table, ThreadRef},
+        value::LuaNil,
+    };
+
+    #[test]
+    fn test_reference() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let mut table = thread.new_table();
+            table.set("x", 12i64).unwrap();
+            table.into_raw();
+            let reference = thread.reference_top();
+            assert!(!reference.is_nil());
+            assert_eq!(thread.stack_len(), top);
+
+            thread.gc_collect();
+            thread.push_reference(&reference).unwrap();
+            let mut table = unsafe { Table::from_stack_unchecked(ThreadRef::from_ref(thread)) };
+            assert_eq!(table.get::<_, i64>("x").unwrap(), 12);
+            drop(table);
+
+            thread.set_global("nil_var", LuaNil).unwrap();
+            thread.push_global("nil_var").unwrap();
+            let nil = thread.reference_top();
+            assert!(nil.is_nil());
+            thread.push_reference(&nil).unwrap();
+            assert_eq!(
+                unsafe { sys::lua_type(thread.as_raw().as_ptr(), -1) },
+                sys::LUA_TNIL
+            );
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+
+            // coroutines share the registry
+            {
+                let mut co = thread.new_coroutine();
+                co.push_reference(&reference).unwrap();
+                assert_eq!(co.stack_len(), 1);
+            }
+            unsafe { sys::lua_pop(thread.as_raw().as_ptr(), 1) };
+            assert_eq!(thread.stack_len(), top);
+            reference
+        })
+        // dropping after the state has been closed is fine
+        .map(drop)
+        .unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = "another Lua state")]
+    fn test_reference_other_state() {
+        Thread::spawn(|thread| {
+            thread.new_table().into_raw();
+            let reference = thread.reference_top();
+            // both states must be alive, or they may share the same address
+            Thread::spawn(move |other| other.push_reference(&reference))
+                .unwrap()
+                .unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_call_reference() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let add = thread
+                .caller_load(
+                    "local a, b = ... n = (n or 0) + 1 return a + b, n",
+                    None,
+                    LoadingMode::Text,
+                )
+                .unwrap()
+                .arg(1i64)
+                .unwrap()
+                .into_reference();
+            assert_eq!(thread.stack_len(), top);
+
+            for i in 0..3i64 {
+                let (sum, calls): (i64, i64) = thread.call_reference(&add, (i, 10i64)).unwrap();
+                assert_eq!((sum, calls), (i + 10, i + 1));
+            }
+            let err = thread.call_reference::<i64, _>(&add, ()).unwrap_err();
+            assert!(err.msg().unwrap().contains("attempt to perform arithmetic"));
+
+            thread.push(1i64).unwrap();
+            let number = thread.reference_top();
+            let err = thread.call_reference::<LuaNil, _>(&number, ()).unwrap_err();
+            assert_eq!(err.msg(), Some("function expected, got number"));
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_registry_keys() {
+        Thread::spawn(|thread| {
+            let top = thread.stack_len();
+            thread.registry_set("pollua.test", "value").unwrap();
+            assert_eq!(
+                thread.registry_get::<String>("pollua.test").unwrap(),
+                "value"
+            );
+            let err = thread.registry_get::<i64>("pollua.test").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad registry value 'pollua.test' (integer expected, got string)")
+            );
+
+            // coroutines share the registry
+            {
+                let mut co = thread.new_coroutine();
+                assert_eq!(co.registry_get::<String>("pollua.test").unwrap(), "value");
+                co.registry_set("pollua.test", LuaNil).unwrap();
+            }
+            thread.remove(-1);
+            assert!(thread.registry_get::<LuaNil>("pollua.test").is_ok());
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_registry_pointer_keys() {
+        static FIRST: u8 = 0;
+        static SECOND: u8 = 0;
+        let first = &FIRST as *const u8 as *const libc::c_void;
+        let second = &SECOND as *const u8 as *const libc::c_void;
+
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            thread.push(1i64).unwrap();
+            thread.registry_set_by_ptr(first);
+            thread.push("two").unwrap();
+            thread.registry_set_by_ptr(second);
+            assert_eq!(thread.stack_len(), top);
+
+            assert_eq!(
+                thread.registry_get_by_ptr(first).unwrap(),
+                ValueType::Number
+            );
+            assert_eq!(i64::from_lua(thread, -1).unwrap(), 1);
+            assert_eq!(
+                thread.registry_get_by_ptr(second).unwrap(),
+                ValueType::String
+            );
+            thread.remove(-1);
+            thread.remove(-1);
+
+            thread.push(LuaNil).unwrap();
+            thread.registry_set_by_ptr(first);
+            assert_eq!(thread.registry_get_by_ptr(first).unwrap(), ValueType::Nil);
+            thread.remove(-1);
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+}
@@ -0,0 +1,192 @@
+use crate::thread::{Thread, ThreadError};
+use std::ops::{Deref, DerefMut};
+
+/// A thread owning its Lua state, closed when the guard is dropped.
+/// Created by [`Thread::scoped`].
+///
+/// The guard dereferences to the thread, so a sequence of operations can use `?` instead of
+/// being nested in the closure of [`Thread::spawn`]. Like with [`Thread::spawn`], panics of
+/// Rust functions called by Lua do not unwind: the call returns a runtime error and the
+/// first panic payload is kept, to be returned by [`take_panic`] or [`close`]. Errors raised
+/// outside of any protected call are caught by [`run`].
+///
+/// # Examples
+/// ```
+/// use pollua::thread::{StdLib, Thread, ThreadError};
+///
+/// fn run() -> Result<i64, ThreadError> {
+///     let mut thread = Thread::scoped()?;
+///     thread.open_lib(StdLib::Base)?;
+///     thread.do_string("x = 20", None)?;
+///     thread.do_string("x = x * 2 + 2", None)?;
+///     let x = thread.get_global("x")?;
+///     thread.close()?;
+///     Ok(x)
+/// }
+///
+/// assert_eq!(run().unwrap(), 42);
+/// ```
+///
+/// [`Thread::scoped`]: struct.Thread.html#method.scoped
+/// [`Thread::spawn`]: struct.Thread.html#method.spawn
+/// [`take_panic`]: #method.take_panic
+/// [`close`]: #method.close
+/// [`run`]: #method.run
+#[derive(Debug)]
+pub struct ScopedThread {
+    thread: Thread,
+}
+
+impl ScopedThread {
+    #[inline]
+    pub(super) fn new(mut thread: Thread) -> ScopedThread {
+        thread.extra_mut().catch_panics = true;
+        ScopedThread { thread }
+    }
+
+    /// Returns the first panic of a Rust function called by Lua since the last call,
+    /// as a [`ThreadError::Panic`].
+    ///
+    /// [`ThreadError::Panic`]: enum.ThreadError.html#variant.Panic
+    #[inline]
+    pub fn take_panic(&mut self) -> Option<ThreadError> {
+        self.thread
+            .extra_mut()
+            .caught_panic
+            .take()
+            .map(ThreadError::Panic)
+    }
+
+    /// Runs `f` with the thread, turning the errors raised outside of any protected call
+    /// into a [`ThreadError::Lua`] like [`Thread::spawn`] does.
+    ///
+    /// Such an error happens when a raising Lua API function is called directly, without
+    /// a protected call. Lua cannot recover from it: afterwards, the state can only be closed.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::{sys, thread::{Thread, ThreadError}};
+    ///
+    /// let mut thread = Thread::scoped().unwrap();
+    /// let result = thread.run(|thread| unsafe {
+    ///     let ptr = thread.as_raw().as_ptr();
+    ///     sys::lua_pushstring(ptr, b"unprotected\0".as_ptr() as *const _);
+    ///     sys::lua_error(ptr);
+    /// });
+    /// match result {
+    ///     Err(ThreadError::Lua(error)) => assert_eq!(error.msg(), Some("unprotected")),
+    ///     _ => panic!("expected an error"),
+    /// }
+    /// ```
+    ///
+    /// [`ThreadError::Lua`]: enum.ThreadError.html#variant.Lua
+    /// [`Thread::spawn`]: struct.Thread.html#method.spawn
+    #[inline]
+    pub fn run<F, T>(&mut self, f: F) -> Result<T, ThreadError>
+    where
+        F: FnOnce(&mut Thread) -> T,
+    {
+        self.thread.run_scoped(f)
+    }
+
+    /// Closes the state, returning the panic payload that was not taken with
+    /// [`take_panic`] if any.
+    ///
+    /// [`take_panic`]: #method.take_panic
+    pub fn close(mut self) -> Result<(), ThreadError> {
+        match self.take_panic() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Deref for ScopedThread {
+    type Target = Thread;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.thread
+    }
+}
+
+impl DerefMut for ScopedThread {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.thread
+    }
+}
+
+impl Drop for ScopedThread {
+    fn drop(&mut self) {
+        // the payload is dropped here rather than by the finalizer of the extra data,
+        // a panicking destructor must not unwind into Lua
+        drop(self.take_panic());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{thread::StdLib, ErrorKind, LuaResult};
+
+    fn set_function<F>(thread: &mut Thread, name: &str, f: F)
+    where
+        F: Fn(&mut Thread) -> LuaResult<libc::c_int> + 'static,
+    {
+        thread.push_function(f).unwrap();
+        let mut buf = Vec::new();
+        unsafe {
+            sys::lua_setglobal(
+                thread.as_raw().as_ptr(),
+                crate::util::cstr_buf(Some(name), &mut buf),
+            )
+        };
+    }
+
+    #[test]
+    fn test_scoped_thread_panic() {
+        let mut thread = Thread::scoped().unwrap();
+        thread.open_lib(StdLib::Base).unwrap();
+        set_function(&mut thread, "boom", |_| panic!("boom"));
+        set_function(&mut thread, "second", |_| panic!("second"));
+
+        let err = thread.do_string("boom()", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Runtime);
+        assert_eq!(err.msg(), Some("panic in Rust function: boom"));
+        // the first panic is kept
+        assert!(thread.do_string("second()", None).is_err());
+        match thread.take_panic() {
+            Some(ThreadError::Panic(payload)) => {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"))
+            }
+            _ => panic!("expected a panic"),
+        }
+        assert!(thread.take_panic().is_none());
+
+        thread.do_string("x = 1", None).unwrap();
+        assert!(thread.do_string("second()", None).is_err());
+        assert!(thread.close().is_err());
+    }
+
+    #[test]
+    fn test_scoped_thread_unprotected_error() {
+        let mut thread = Thread::scoped().unwrap();
+        let value = thread.run(|thread| thread.stack_len()).unwrap();
+        assert_eq!(value, 0);
+
+        let result = thread.run(|thread| unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            sys::lua_pushstring(ptr, b"unprotected\0".as_ptr() as *const _);
+            sys::lua_error(ptr);
+        });
+        match result {
+            Err(ThreadError::Lua(error)) => {
+                assert_eq!(error.kind(), ErrorKind::Runtime);
+                assert_eq!(error.msg(), Some("unprotected"));
+            }
+            _ => panic!("expected a Lua error"),
+        }
+        assert!(thread.take_panic().is_none());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::{
+    thread::{Thread, ThreadRef},
+    value::{type_error, FromLua, ToLua, ValueType},
+    Error, ErrorKind, LuaResult,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    hash::{BuildHasher, Hash},
+    iter::{FromIterator, FusedIterator},
+    marker::PhantomData,
+    mem,
+};
+
+/// Handle to a Lua table on the stack.
+/// Created by [`Thread::new_table`].
+///
+/// The table stays on the stack while the handle is alive and is removed from it when the
+/// handle is dropped. Accesses are raw, they never invoke metamethods.
+///
+/// # Examples
+/// ```
+/// use pollua::Thread;
+///
+/// Thread::spawn(move |thread| {
+///     let mut table = thread.new_table();
+///     table.set("answer", 42i64).unwrap();
+///     table.set(1i64, "first").unwrap();
+///     assert_eq!(table.get::<_, i64>("answer").unwrap(), 42);
+///     assert_eq!(table.get::<_, String>(1i64).unwrap(), "first");
+///     assert_eq!(table.len(), 1);
+/// }).unwrap()
+/// ```
+///
+/// [`Thread::new_table`]: struct.Thread.html#method.new_table
+#[derive(Debug)]
+pub struct Table<'a> {
+    thread: ThreadRef<'a>,
+    /// Absolute stack index of the table.
+    index: libc::c_int,
+}
+
+impl<'a> Table<'a> {
+    /// Creates a `Table` with the top stack value as the table.
+    ///
+    /// # Safety
+    /// Behavior is undefined if the value at the top of the stack is not a table.
+    #[inline]
+    pub(crate) unsafe fn from_stack_unchecked(thread: ThreadRef<'a>) -> Table<'a> {
+        debug_assert_eq!(sys::lua_type(thread.raw.as_ptr(), -1), sys::LUA_TTABLE);
+        let index = thread.abs_index(-1);
+        Table { thread, index }
+    }
+
+    /// Sets `table[key] = value`.
+    /// Returns a runtime error if `key` is nil or NaN.
+    pub fn set<K: ToLua, V: ToLua>(&mut self, key: K, value: V) -> LuaResult<()> {
+        let index = self.index;
+        let mut guard = self.thread.stack_guard();
+        guard.push(key)?;
+        unsafe {
+            let ptr = guard.as_raw().as_ptr();
+            match sys::lua_type(ptr, -1) {
+                sys::LUA_TNIL => {
+                    let msg = String::from("table index is nil");
+                    return Err(Error::new(ErrorKind::Runtime, Some(msg)));
+                }
+                sys::LUA_TNUMBER if sys::lua_tonumber(ptr, -1).is_nan() => {
+                    let msg = String::from("table index is NaN");
+                    return Err(Error::new(ErrorKind::Runtime, Some(msg)));
+                }
+                _ => (),
+            }
+        }
+        guard.push(value)?;
+        unsafe { sys::lua_rawset(guard.as_raw().as_ptr(), index) };
+        Ok(())
+    }
+
+    /// Returns `table[key]` converted to `V`.
+    pub fn get<K: ToLua, V: FromLua>(&mut self, key: K) -> LuaResult<V> {
+        let index = self.index;
+        let mut guard = self.thread.stack_guard();
+        guard.push(key)?;
+        unsafe { sys::lua_rawget(guard.as_raw().as_ptr(), index) };
+        V::from_lua(&mut guard, -1)
+    }
+
+    /// Returns the raw length of the table, as the `#` operator would without metamethods.
+    #[inline]
+    pub fn len(&self) -> usize {
+        unsafe { sys::lua_rawlen(self.thread.raw.as_ptr(), self.index) }
+    }
+
+    /// Returns true if the sequence part of the table is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the key-value pairs of the table, in `lua_next` order.
+    /// Pairs that cannot be converted to `(K, V)` are yielded as errors.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the key and the
+    /// value.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let mut table = thread.new_table();
+    ///     table.set("a", 1i64).unwrap();
+    ///     table.set("b", 2i64).unwrap();
+    ///     let mut sum = 0;
+    ///     for pair in table.pairs::<String, i64>().unwrap() {
+    ///         let (_key, value) = pair.unwrap();
+    ///         sum += value;
+    ///     }
+    ///     assert_eq!(sum, 3);
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn pairs<K: FromLua, V: FromLua>(&mut self) -> LuaResult<TablePairs<'_, 'a, K, V>> {
+        self.thread.ensure_stack(3)?;
+        let top = self.thread.stack_len();
+        // the first key
+        unsafe { sys::lua_pushnil(self.thread.raw.as_ptr()) };
+        Ok(TablePairs {
+            table: self,
+            top,
+            finished: false,
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns the absolute stack index of the table.
+    #[inline]
+    pub fn index(&self) -> libc::c_int {
+        self.index
+    }
+
+    /// Consumes the handle, leaving the table on the stack.
+    /// Returns the absolute stack index of the table.
+    #[inline]
+    pub fn into_raw(self) -> libc::c_int {
+        let index = self.index;
+        mem::forget(self);
+        index
+    }
+}
+
+impl Drop for Table<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::lua_remove(self.thread.raw.as_ptr(), self.index) }
+    }
+}
+
+/// Creates a table and pushes it onto the stack, growing the stack to fit a key and a value.
+/// Returns [`ErrorKind::OutOfMemory`] and pushes nothing if the stack cannot grow.
+///
+/// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+unsafe fn push_table(
+    thread: &mut Thread,
+    narr: usize,
+    nrec: usize,
+) -> LuaResult<*mut sys::lua_State> {
+    thread.ensure_stack(3)?;
+    let ptr = thread.raw.as_ptr();
+    let size = |n: usize| libc::c_int::try_from(n).unwrap_or(libc::c_int::MAX);
+    sys::lua_createtable(ptr, size(narr), size(nrec));
+    Ok(ptr)
+}
+
+/// Pushes a table holding the `entries`, used by the `ToLua` impls of maps.
+/// Entries whose key is nil or NaN are left out.
+unsafe fn push_map<K: ToLua, V: ToLua, I: IntoIterator<Item = (K, V)>>(
+    thread: &mut Thread,
+    len: usize,
+    entries: I,
+) -> LuaResult<()> {
+    let top = thread.stack_len();
+    let ptr = push_table(thread, 0, len)?;
+    for (key, value) in entries {
+        let pushed = key.push(thread).and_then(|()| {
+            let valid = match sys::lua_type(ptr, -1) {
+                sys::LUA_TNIL => false,
+                sys::LUA_TNUMBER => !sys::lua_tonumber(ptr, -1).is_nan(),
+                _ => true,
+            };
+            if valid {
+                value.push(thread)?;
+                sys::lua_rawset(ptr, -3);
+            } else {
+                sys::lua_pop(ptr, 1);
+            }
+            Ok(())
+        });
+        if let Err(e) = pushed {
+            sys::lua_settop(ptr, top);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the key-value pairs of the table at `index`, used by the `FromLua` impls of maps.
+fn read_map<K: FromLua, V: FromLua, C: FromIterator<(K, V)>>(
+    thread: &mut Thread,
+    index: libc::c_int,
+) -> LuaResult<C> {
+    if thread.type_of(index) != Some(ValueType::Table) {
+        return Err(type_error(thread, index, "table"));
+    }
+    let index = thread.abs_index(index);
+    unsafe { sys::lua_pushvalue(thread.raw.as_ptr(), index) };
+    let mut table = unsafe { Table::from_stack_unchecked(ThreadRef::from_ref(thread)) };
+    let pairs = table.pairs()?.collect();
+    pairs
+}
+
+/// Wrapper pushing a `Vec` as a sequence, a table holding the elements from index 1.
+///
+/// `Vec` itself does not implement [`ToLua`]: [`ToLua::push`] takes `self` by value and
+/// would shadow `Vec::push` wherever the trait is imported. Sequences are read back
+/// directly as a `Vec`.
+///
+/// # Examples
+/// ```
+/// use pollua::{thread::LuaSequence, value::FromLua, Thread};
+///
+/// Thread::spawn(move |thread| {
+///     thread.push(LuaSequence(vec![1i64, 2, 3])).unwrap();
+///     assert_eq!(Vec::<i64>::from_lua(thread, -1).unwrap(), [1, 2, 3]);
+/// }).unwrap()
+/// ```
+///
+/// [`ToLua`]: ../value/trait.ToLua.html
+/// [`ToLua::push`]: ../value/trait.ToLua.html#tymethod.push
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LuaSequence<T>(pub Vec<T>);
+
+impl<T> From<Vec<T>> for LuaSequence<T> {
+    #[inline]
+    fn from(v: Vec<T>) -> LuaSequence<T> {
+        LuaSequence(v)
+    }
+}
+
+impl<T: ToLua> ToLua for LuaSequence<T> {
+    /// Pushes a sequence holding the elements, the first one at index 1.
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        let top = thread.stack_len();
+        let ptr = push_table(thread, self.0.len(), 0)?;
+        for (i, value) in self.0.into_iter().enumerate() {
+            if let Err(e) = value.push(thread) {
+                sys::lua_settop(ptr, top);
+                return Err(e);
+            }
+            sys::lua_rawseti(ptr, -2, i as sys::lua_Integer + 1);
+        }
+        Ok(())
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    /// Reads a sequence: a table whose keys are exactly the integers from 1 to its length.
+    /// Returns a runtime error if the value is not a table, if the table has other keys,
+    /// or if an element cannot be converted to `T`.
+    ///
+    /// `Vec<u8>` is an exception, it is read from a string.
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<Vec<T>> {
+        if thread.type_of(index) != Some(ValueType::Table) {
+            return Err(type_error(thread, index, "table"));
+        }
+        let index = thread.abs_index(index);
+        thread.ensure_stack(3)?;
+        let mut guard = thread.stack_guard();
+        let ptr = guard.raw.as_ptr();
+        let len = guard.raw_len(index);
+        // check the shape: `len` keys, all in 1..=len
+        let mut count = 0;
+        unsafe {
+            sys::lua_pushnil(ptr);
+            while sys::lua_next(ptr, index) != 0 {
+                sys::lua_pop(ptr, 1);
+                let valid = sys::lua_isinteger(ptr, -1) != 0
+                    && (1..=len as sys::lua_Integer).contains(&sys::lua_tointeger(ptr, -1));
+                if !valid {
+                    return Err(Error::runtime("table is not a sequence"));
+                }
+                count += 1;
+            }
+        }
+        if count != len {
+            return Err(Error::runtime("table is not a sequence"));
+        }
+        (1..=len)
+            .map(|i| {
+                unsafe { sys::lua_rawgeti(ptr, index, i as sys::lua_Integer) };
+                let value = T::from_lua(&mut guard, -1).map_err(|e| {
+                    let msg = format!(
+                        "bad element #{} ({})",
+                        i,
+                        e.msg().unwrap_or("invalid value")
+                    );
+                    Error::new(e.kind(), Some(msg))
+                });
+                unsafe { sys::lua_pop(ptr, 1) };
+                value
+            })
+            .collect()
+    }
+}
+
+impl<K: ToLua, V: ToLua, S> ToLua for HashMap<K, V, S> {
+    /// Pushes a table holding the entries, entries whose key is nil or NaN are left out.
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        let len = self.len();
+        push_map(thread, len, self)
+    }
+}
+
+impl<K, V, S> FromLua for HashMap<K, V, S>
+where
+    K: FromLua + Eq + Hash,
+    V: FromLua,
+    S: BuildHasher + Default,
+{
+    /// Reads the key-value pairs of a table.
+    /// Returns a runtime error if the value is not a table or if a pair cannot be converted.
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<HashMap<K, V, S>> {
+        read_map(thread, index)
+    }
+}
+
+impl<K: ToLua, V: ToLua> ToLua for BTreeMap<K, V> {
+    /// Pushes a table holding the entries, entries whose key is nil or NaN are left out.
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        let len = self.len();
+        push_map(thread, len, self)
+    }
+}
+
+impl<K: FromLua + Ord, V: FromLua> FromLua for BTreeMap<K, V> {
+    /// Reads the key-value pairs of a table.
+    /// Returns a runtime error if the value is not a table or if a pair cannot be converted.
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<BTreeMap<K, V>> {
+        read_map(thread, index)
+    }
+}
+
+/// Iterator over the key-value pairs of a table.
+/// This struct is created by the [`pairs`] method on [`Table`].
+///
+/// Between steps the last key is kept on the stack, the stack is restored once the
+/// iteration finishes or the iterator is dropped.
+///
+/// [`pairs`]: struct.Table.html#method.pairs
+/// [`Table`]: struct.Table.html
+#[derive(Debug)]
+pub struct TablePairs<'t, 'a, K, V> {
+    table: &'t mut Table<'a>,
+    /// Stack top before the first key was pushed.
+    top: libc::c_int,
+    finished: bool,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: FromLua, V: FromLua> Iterator for TablePairs<'_, '_, K, V> {
+    type Item = LuaResult<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let thread = &mut self.table.thread;
+        let ptr = thread.raw.as_ptr();
+        // pops the previous key, pushes the next key and its value
+        if unsafe { sys::lua_next(ptr, self.table.index) } == 0 {
+            self.finished = true;
+            return None;
+        }
+        // FromLua impls do not modify the values, so the key stays valid for lua_next,
+        // absolute indices stay valid if they push values
+        let key = thread.abs_index(-2);
+        let pair =
+            K::from_lua(thread, key).and_then(|k| V::from_lua(thread, key + 1).map(|v| (k, v)));
+        // pop the value, keep the key for the next step
+        unsafe { sys::lua_pop(ptr, 1) };
+        Some(pair)
+    }
+}
+
+impl<K: FromLua, V: FromLua> FusedIterator for TablePairs<'_, '_, K, V> {}
+
+impl<K, V> Drop for TablePairs<'_, '_, K, V> {
+    fn drop(&mut self) {
+        unsafe { sys::lua_settop(self.table.thread.raw.as_ptr(), self.top) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::LuaNil;
+
+    #[test]
+    fn test_table_set_get() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            {
+                let mut table = thread.new_table();
+                assert_eq!(table.index(), top + 1);
+                assert!(table.is_empty());
+
+                table.set("int", 42i64).unwrap();
+                table.set("float", 0.5).unwrap();
+                table.set("bool", true).unwrap();
+                for (i, s) in ["a", "b", "c"].iter().enumerate() {
+                    table.set(i as i64 + 1, *s).unwrap();
+                }
+                assert_eq!(table.len(), 3);
+
+                assert_eq!(table.get::<_, i64>("int").unwrap(), 42);
+                assert_eq!(table.get::<_, f64>("float").unwrap(), 0.5);
+                assert!(table.get::<_, bool>("bool").unwrap());
+                assert_eq!(table.get::<_, String>(2i64).unwrap(), "b");
+                assert_eq!(table.get::<_, LuaNil>("missing").unwrap(), LuaNil);
+                assert!(table.get::<_, i64>("bool").is_err());
+
+                table.set(3i64, LuaNil).unwrap();
+                assert_eq!(table.len(), 2);
+
+                let err = table.set(LuaNil, 1i64).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::Runtime);
+                assert_eq!(err.msg(), Some("table index is nil"));
+                let err = table.set(f64::NAN, 1i64).unwrap_err();
+                assert_eq!(err.msg(), Some("table index is NaN"));
+            }
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_table_pairs() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let mut table = thread.new_table();
+            for i in 1..=10i64 {
+                table.set(i, i * i).unwrap();
+            }
+            table.set("name", "squares").unwrap();
+
+            let mut sum = 0;
+            let mut count = 0;
+            for pair in table.pairs::<i64, i64>().unwrap() {
+                match pair {
+                    Ok((k, v)) => {
+                        assert_eq!(k * k, v);
+                        sum += v;
+                    }
+                    // the "name" pair
+                    Err(e) => assert_eq!(e.kind(), ErrorKind::Runtime),
+                }
+                count += 1;
+            }
+            assert_eq!(sum, 385);
+            assert_eq!(count, 11);
+            assert_eq!(table.thread.stack_len(), top + 1);
+
+            let mut pairs = table.pairs::<String, String>().unwrap();
+            assert!(pairs.next().unwrap().is_ok());
+            assert!(pairs.next().is_some());
+            // dropping the iterator early restores the stack
+            drop(pairs);
+            assert_eq!(table.thread.stack_len(), top + 1);
+            let mut pairs = table.pairs::<String, String>().unwrap();
+            assert_eq!(pairs.by_ref().count(), 11);
+            assert!(pairs.next().is_none());
+            drop(pairs);
+            assert_eq!(table.thread.stack_len(), top + 1);
+
+            let mut empty = table.thread.new_table();
+            assert!(empty.pairs::<LuaNil, LuaNil>().unwrap().next().is_none());
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_table_into_raw() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            let mut table = thread.new_table();
+            table.set("x", 1i64).unwrap();
+            assert_eq!(table.into_raw(), top + 1);
+            unsafe { sys::lua_setglobal(thread.as_raw().as_ptr(), b"t\0".as_ptr() as *const _) };
+            assert_eq!(thread.stack_len(), top);
+
+            thread.push_global("t").unwrap();
+            let mut table = unsafe { Table::from_stack_unchecked(ThreadRef::from_ref(thread)) };
+            assert_eq!(table.get::<_, i64>("x").unwrap(), 1);
+        })
+        .unwrap()
+    }
+
+    /// Reads an integer after pushing values, like conversions creating references do.
+    struct Pushing(i64);
+
+    impl FromLua for Pushing {
+        fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<Pushing> {
+            let mut guard = thread.stack_guard();
+            guard.push("padding")?;
+            guard.push("padding")?;
+            i64::from_lua(&mut guard, index).map(Pushing)
+        }
+    }
+
+    #[test]
+    fn test_table_pairs_pushing_conversions() {
+        Thread::spawn(move |thread| {
+            let mut table = thread.new_table();
+            table.set(2i64, 4i64).unwrap();
+            table.set(3i64, 9i64).unwrap();
+            let mut sum = 0;
+            for pair in table.pairs::<Pushing, Pushing>().unwrap() {
+                let (k, v) = pair.unwrap();
+                assert_eq!(k.0 * k.0, v.0);
+                sum += v.0;
+            }
+            assert_eq!(sum, 13);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_table_collections() {
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+
+            let v = vec![vec![1i64, 2], vec![], vec![3]];
+            let seq: Vec<_> = v.iter().cloned().map(LuaSequence).collect();
+            thread.push(LuaSequence(seq)).unwrap();
+            assert_eq!(Vec::<Vec<i64>>::from_lua(thread, -1).unwrap(), v);
+            {
+                let mut table = unsafe { Table::from_stack_unchecked(ThreadRef::from_ref(thread)) };
+                assert_eq!(table.len(), 3);
+                assert!(table.get::<_, Vec<i64>>(2i64).unwrap().is_empty());
+            }
+            // Vec<u8> is still a string
+            thread.push(b"bytes".to_vec()).unwrap();
+            assert_eq!(thread.type_of(-1), Some(ValueType::String));
+            thread.remove(-1);
+
+            let mut map = HashMap::new();
+            map.insert(String::from("a"), 1i64);
+            map.insert(String::from("b"), 2i64);
+            thread.push(map.clone()).unwrap();
+            assert_eq!(HashMap::<String, i64>::from_lua(thread, -1).unwrap(), map);
+            // a map is not a sequence
+            let err = Vec::<i64>::from_lua(thread, -1).unwrap_err();
+            assert_eq!(err.msg(), Some("table is not a sequence"));
+            let err = BTreeMap::<i64, i64>::from_lua(thread, -1).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            thread.remove(-1);
+
+            // nil keys are left out
+            let mut map = BTreeMap::new();
+            map.insert(Some(1i64), "one");
+            map.insert(None, "none");
+            thread.push(map).unwrap();
+            let read = BTreeMap::<i64, String>::from_lua(thread, -1).unwrap();
+            assert_eq!(
+                read.into_iter().collect::<Vec<_>>(),
+                [(1, String::from("one"))]
+            );
+            // a sequence is also a map
+            assert_eq!(Vec::<String>::from_lua(thread, -1).unwrap(), ["one"]);
+            thread.remove(-1);
+
+            thread
+                .do_string("holes = {1, nil, 3} mixed = {1, 'x'}", None)
+                .unwrap();
+            let err = thread.get_global::<Vec<i64>, _>("holes").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad global 'holes' (table is not a sequence)")
+            );
+            let err = thread.get_global::<Vec<i64>, _>("mixed").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad global 'mixed' (bad element #2 (integer expected, got string))")
+            );
+            let err = thread.get_global::<Vec<i64>, _>("missing").unwrap_err();
+            assert_eq!(
+                err.msg(),
+                Some("bad global 'missing' (table expected, got nil)")
+            );
+            assert_eq!(thread.stack_len(), top);
+        })
+        .unwrap()
+    }
+}
@@ -0,0 +1,574 @@
+use crate::{
+    thread::{Thread, ThreadRef},
+    Error, LuaResult,
+};
+use std::{
+    any::{self, TypeId},
+    cell::RefCell,
+    ffi::CStr,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+};
+
+/// Contents of the full userdata, `None` once the value has been dropped.
+/// The value is boxed so that the userdata alignment does not matter.
+type Data<T> = Option<Box<RefCell<T>>>;
+
+/// A trait for Rust types that can be pushed as Lua full userdata,
+/// see [`Thread::push_userdata`].
+///
+/// # Examples
+/// ```
+/// use pollua::thread::{LoadingMode, Thread, UserData, UserDataMethods};
+///
+/// struct Counter(i64);
+///
+/// impl UserData for Counter {
+///     fn add_methods(methods: &mut UserDataMethods<'_, Self>) {
+///         methods.add_method("incr", |_, counter| {
+///             counter.0 += 1;
+///             Ok(0)
+///         });
+///         methods.add_method("get", |thread, counter| {
+///             thread.push(counter.0)?;
+///             Ok(1)
+///         });
+///     }
+/// }
+///
+/// Thread::spawn(move |thread| {
+///     thread.push_userdata(Counter(41)).unwrap();
+///     unsafe { pollua::sys::lua_setglobal(thread.as_raw().as_ptr(), b"counter\0".as_ptr() as *const _) };
+///
+///     let n: i64 = thread
+///         .caller_load("counter:incr() return counter:get()", None, LoadingMode::Text)
+///         .and_then(|c| c.call_ret())
+///         .unwrap();
+///     assert_eq!(n, 42);
+/// }).unwrap()
+/// ```
+///
+/// [`Thread::push_userdata`]: struct.Thread.html#method.push_userdata
+pub trait UserData: Sized + 'static {
+    /// Registers the methods and metamethods of the type.
+    /// This is called once per state, when the metatable of the type is created.
+    fn add_methods(_methods: &mut UserDataMethods<'_, Self>) {}
+}
+
+/// Builder of the metatable of a [`UserData`] type,
+/// passed to [`UserData::add_methods`].
+///
+/// Methods and metamethods receive the thread running the call, with the userdata at
+/// index 1 followed by the arguments, and a mutable reference to the Rust value.
+/// They return the number of results they pushed, like functions pushed with
+/// [`Thread::push_function`].
+/// Calling a method on a value of another type or on a value that is already borrowed
+/// by a method further up the call stack raises an error.
+///
+/// [`UserData`]: trait.UserData.html
+/// [`UserData::add_methods`]: trait.UserData.html#method.add_methods
+/// [`Thread::push_function`]: struct.Thread.html#method.push_function
+#[derive(Debug)]
+pub struct UserDataMethods<'a, T> {
+    thread: &'a mut Thread,
+    /// Absolute stack index of the metatable.
+    metatable: libc::c_int,
+    /// Absolute stack index of the `__index` table holding the methods.
+    methods: libc::c_int,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<T: UserData> UserDataMethods<'_, T> {
+    /// Adds a method, called with `value:name(...)` from Lua.
+    pub fn add_method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Thread, &mut T) -> LuaResult<libc::c_int> + 'static,
+    {
+        let methods = self.methods;
+        self.set_field(methods, name, f);
+    }
+
+    /// Adds a metamethod, `name` is the full name of the metamethod, like `"__add"`.
+    /// Adding an `__index` metamethod replaces the methods added with [`add_method`].
+    ///
+    /// # Panics
+    /// This panics if `name` is `"__gc"`, the finalizer is used to drop the Rust value.
+    ///
+    /// [`add_method`]: #method.add_method
+    pub fn add_meta_method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Thread, &mut T) -> LuaResult<libc::c_int> + 'static,
+    {
+        assert_ne!(name, "__gc", "the __gc metamethod of userdata is reserved");
+        let metatable = self.metatable;
+        self.set_field(metatable, name, f);
+    }
+
+    fn set_field<F>(&mut self, table: libc::c_int, name: &str, f: F)
+    where
+        F: Fn(&mut Thread, &mut T) -> LuaResult<libc::c_int> + 'static,
+    {
+        // the stack was grown by `push_userdata_metatable`
+        let ptr = self.thread.as_raw().as_ptr();
+        unsafe {
+            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+            self.thread.push_function_unchecked(move |thread| {
+                // the value lives in its own allocation, the reference stays valid even if
+                // the userdata is removed from the stack
+                let cell = &*check_userdata::<T>(thread, 1)?;
+                let mut value = cell.try_borrow_mut().map_err(|_| {
+                    Error::runtime(format!("{} is already borrowed", any::type_name::<T>()))
+                })?;
+                f(thread, &mut value)
+            });
+            sys::lua_rawset(ptr, table);
+        }
+    }
+}
+
+impl Thread {
+    /// Pushes `value` onto the stack as a full userdata.
+    ///
+    /// The metatable of `T` is created on the first push, with the methods registered by
+    /// [`UserData::add_methods`], and cached in the registry for the next pushes.
+    /// Its `__name` field is the name of the Rust type, and its `__metatable` field is
+    /// `false` so that scripts cannot get the metatable and call the finalizer.
+    /// The value is dropped exactly once, by the `__gc` metamethod or when the state is
+    /// closed.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the userdata and
+    /// its metatable or if Lua cannot allocate them, `value` is dropped in that case.
+    ///
+    /// [`UserData::add_methods`]: trait.UserData.html#method.add_methods
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn push_userdata<T: UserData>(&mut self, value: T) -> LuaResult<()> {
+        // the allocations may raise a memory error, the value is only moved in afterwards
+        let data = self.protected(|thread| unsafe {
+            // the metatable first, the value must not be left without a finalizer
+            thread.push_userdata_metatable::<T>()?;
+            let ptr = thread.raw.as_ptr();
+            let data = sys::lua_newuserdata(ptr, mem::size_of::<Data<T>>()) as *mut Data<T>;
+            ptr::write(data, None);
+            sys::lua_insert(ptr, -2);
+            sys::lua_setmetatable(ptr, -2);
+            Ok(data)
+        })??;
+        unsafe { *data = Some(Box::new(RefCell::new(value))) };
+        Ok(())
+    }
+
+    /// Pushes a new full userdata with room for a `T` and returns a pointer to it,
+    /// aligned for `T`.
+    ///
+    /// `lua_newuserdata` only guarantees the alignment of the largest C scalar type,
+    /// so the block is over-allocated by `align_of::<T>() - 1` bytes and the pointer is
+    /// moved to the first aligned address. Lua never moves a userdata, the same pointer is
+    /// returned by [`to_userdata_aligned`].
+    ///
+    /// The memory is uninitialized and the userdata has no metatable: `T` is never dropped,
+    /// Lua frees the memory without running its destructor. Use [`push_userdata`] for
+    /// values that need to be dropped.
+    /// Returns [`ErrorKind::OutOfMemory`] if the stack cannot grow to fit the userdata or if
+    /// Lua cannot allocate it.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::Thread;
+    ///
+    /// #[derive(Clone, Copy)]
+    /// #[repr(align(64))]
+    /// struct Line([u8; 64]);
+    ///
+    /// Thread::spawn(move |thread| {
+    ///     let line = thread.new_userdata_aligned::<Line>().unwrap();
+    ///     assert_eq!(line as usize % 64, 0);
+    ///     unsafe { line.write(Line([1; 64])) };
+    ///     assert_eq!(thread.to_userdata_aligned::<Line>(-1), Some(line));
+    /// }).unwrap()
+    /// ```
+    ///
+    /// [`to_userdata_aligned`]: #method.to_userdata_aligned
+    /// [`push_userdata`]: #method.push_userdata
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    pub fn new_userdata_aligned<T>(&mut self) -> LuaResult<*mut T> {
+        // lua_newuserdata raises a memory error when the allocation fails
+        self.protected(|thread| unsafe {
+            let size = mem::size_of::<T>() + mem::align_of::<T>() - 1;
+            align_userdata(sys::lua_newuserdata(thread.raw.as_ptr(), size))
+        })
+    }
+
+    /// Returns the pointer to the `T` of the full userdata at `index` created with
+    /// [`new_userdata_aligned`], or `None` if the value is not a full userdata.
+    ///
+    /// The type of the userdata is not checked: the pointer is only valid if the userdata
+    /// was created with `new_userdata_aligned::<T>`.
+    ///
+    /// [`new_userdata_aligned`]: #method.new_userdata_aligned
+    pub fn to_userdata_aligned<T>(&self, index: libc::c_int) -> Option<*mut T> {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if sys::lua_type(ptr, index) == sys::LUA_TUSERDATA {
+                Some(align_userdata(sys::lua_touserdata(ptr, index)))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Pushes the metatable of `T`, creating it if needed.
+    fn push_userdata_metatable<T: UserData>(&mut self) -> LuaResult<()> {
+        unsafe {
+            let ptr = self.raw.as_ptr();
+            if let Some(key) = metatable_key::<T>(self) {
+                self.ensure_stack(1)?;
+                sys::lua_rawgeti(ptr, sys::LUA_REGISTRYINDEX, key as sys::lua_Integer);
+                return Ok(());
+            }
+            // the metatable, the methods, and a name and a function for each method
+            self.ensure_stack(6)?;
+            sys::lua_createtable(ptr, 0, 4);
+            let metatable = self.stack_len();
+            let name = any::type_name::<T>();
+            sys::lua_pushlstring(ptr, name.as_ptr() as *const libc::c_char, name.len());
+            sys::lua_setfield(ptr, metatable, b"__name\0".as_ptr() as *const _);
+            sys::lua_pushcfunction(ptr, Some(drop_userdata::<T>));
+            sys::lua_setfield(ptr, metatable, b"__gc\0".as_ptr() as *const _);
+            // hide the metatable from scripts, they must not call the finalizer
+            sys::lua_pushboolean(ptr, 0);
+            sys::lua_setfield(ptr, metatable, b"__metatable\0".as_ptr() as *const _);
+            sys::lua_createtable(ptr, 0, 0);
+            let methods = self.stack_len();
+
+            T::add_methods(&mut UserDataMethods {
+                thread: self,
+                metatable,
+                methods,
+                marker: PhantomData,
+            });
+
+            sys::lua_pushstring(ptr, b"__index\0".as_ptr() as *const _);
+            if sys::lua_rawget(ptr, metatable) == sys::LUA_TNIL {
+                sys::lua_pushvalue(ptr, methods);
+                sys::lua_setfield(ptr, metatable, b"__index\0".as_ptr() as *const _);
+            }
+            sys::lua_settop(ptr, metatable);
+            // keep the metatable in the registry for the next pushes and type checks
+            sys::lua_pushvalue(ptr, metatable);
+            let key = sys::luaL_ref(ptr, sys::LUA_REGISTRYINDEX);
+            self.extra_mut().metatables.insert(TypeId::of::<T>(), key);
+        }
+        Ok(())
+    }
+}
+
+/// Registry reference to the metatable of `T`, `None` if no `T` was pushed yet.
+fn metatable_key<T: UserData>(thread: &Thread) -> Option<libc::c_int> {
+    thread.extra()?.metatables.get(&TypeId::of::<T>()).copied()
+}
+
+/// First address aligned for `T` in a block allocated by `new_userdata_aligned`.
+fn align_userdata<T>(data: *mut libc::c_void) -> *mut T {
+    let align = mem::align_of::<T>();
+    let offset = (data as usize).wrapping_neg() & (align - 1);
+    (data as *mut u8).wrapping_add(offset) as *mut T
+}
+
+/// Returns true if the value at `index` is a full userdata with the metatable of `T`.
+///
+/// # Safety
+/// The stack must have room for two more values.
+unsafe fn is_userdata<T: UserData>(thread: &Thread, index: libc::c_int) -> bool {
+    let ptr = thread.raw.as_ptr();
+    sys::lua_type(ptr, index) == sys::LUA_TUSERDATA
+        && sys::lua_getmetatable(ptr, index) != 0
+        && match metatable_key::<T>(thread) {
+            Some(key) => {
+                sys::lua_rawgeti(ptr, sys::LUA_REGISTRYINDEX, key as sys::lua_Integer);
+                let same = sys::lua_rawequal(ptr, -1, -2) != 0;
+                sys::lua_pop(ptr, 2);
+                same
+            }
+            None => {
+                sys::lua_pop(ptr, 1);
+                false
+            }
+        }
+}
+
+/// Returns the value of the userdata of type `T` at `index`.
+///
+/// # Safety
+/// The stack must have room for two more values.
+unsafe fn check_userdata<T: UserData>(
+    thread: &mut Thread,
+    index: libc::c_int,
+) -> LuaResult<*const RefCell<T>> {
+    let ptr = thread.as_raw().as_ptr();
+    if !is_userdata::<T>(thread, index) {
+        let type_name = CStr::from_ptr(sys::luaL_typename(ptr, index)).to_string_lossy();
+        let msg = format!("{} expected, got {}", any::type_name::<T>(), type_name);
+        return Err(Error::runtime(msg));
+    }
+    match &*(sys::lua_touserdata(ptr, index) as *const Data<T>) {
+        Some(cell) => Ok(&**cell),
+        None => {
+            let msg = format!("{} has been dropped", any::type_name::<T>());
+            Err(Error::runtime(msg))
+        }
+    }
+}
+
+unsafe extern "C" fn drop_userdata<T: UserData>(l: *mut sys::lua_State) -> libc::c_int {
+    // the finalizer may be called with any value, like from `debug.getmetatable`
+    if !is_userdata::<T>(&ThreadRef::from_raw(NonNull::new_unchecked(l)), 1) {
+        return 0;
+    }
+    let data = sys::lua_touserdata(l, 1) as *mut Data<T>;
+    // a finalizer may run again on a resurrected object, the value is only dropped once
+    if let Some(cell) = (*data).take() {
+        if cell.try_borrow_mut().is_err() {
+            // still borrowed by a method whose userdata was removed from the stack
+            mem::forget(cell);
+        } else {
+            // a panicking destructor must not unwind into Lua
+            let _ = panic::catch_unwind(AssertUnwindSafe(move || mem::drop(cell)));
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        thread::{LoadingMode, StdLib, ThreadError},
+        value::{FromLua, LuaNil},
+        ErrorKind,
+    };
+    use std::{cell::Cell, rc::Rc};
+
+    struct Point {
+        x: i64,
+        y: i64,
+        drops: Rc<Cell<u32>>,
+    }
+
+    impl Drop for Point {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    impl UserData for Point {
+        fn add_methods(methods: &mut UserDataMethods<'_, Self>) {
+            methods.add_method("move", |thread, p| {
+                p.x += i64::from_lua(thread, 2)?;
+                p.y += i64::from_lua(thread, 3)?;
+                Ok(0)
+            });
+            methods.add_method("sum", |thread, p| {
+                thread.push(p.x + p.y)?;
+                Ok(1)
+            });
+            methods.add_method("call", |thread, _| {
+                // calls back into Lua with the value still borrowed
+                thread.caller_global("callback").unwrap().calln(0)?;
+                Ok(0)
+            });
+            methods.add_meta_method("__len", |thread, p| {
+                thread.push(p.x.abs() + p.y.abs())?;
+                Ok(1)
+            });
+        }
+    }
+
+    struct Empty;
+
+    impl UserData for Empty {}
+
+    fn set_global(thread: &mut Thread, name: &str) {
+        let mut buf = Vec::new();
+        unsafe {
+            sys::lua_setglobal(
+                thread.as_raw().as_ptr(),
+                crate::util::cstr_buf(Some(name), &mut buf),
+            )
+        };
+    }
+
+    fn run<R: FromLua>(thread: &mut Thread, chunk: &str) -> LuaResult<R> {
+        thread
+            .caller_load(chunk, Some("=test"), LoadingMode::Text)
+            .and_then(|c| c.call_ret())
+    }
+
+    #[test]
+    fn test_push_userdata() {
+        let drops = Rc::new(Cell::new(0));
+        let counter = drops.clone();
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            for name in &["p1", "p2"] {
+                thread
+                    .push_userdata(Point {
+                        x: 1,
+                        y: 2,
+                        drops: counter.clone(),
+                    })
+                    .unwrap();
+                set_global(thread, name);
+            }
+            thread.push_userdata(Empty).unwrap();
+            set_global(thread, "empty");
+            let top = thread.stack_len();
+
+            assert_eq!(
+                run::<i64>(thread, "p1:move(2, 3) return p1:sum()").unwrap(),
+                8
+            );
+            assert_eq!(run::<i64>(thread, "return p2:sum()").unwrap(), 3);
+            assert_eq!(
+                run::<i64>(thread, "p1:move(-10, 0) return #p1").unwrap(),
+                12
+            );
+            // the metatable is hidden from scripts
+            assert!(!run::<bool>(thread, "return getmetatable(p1)").unwrap());
+            thread.open_lib(StdLib::Debug).unwrap();
+            let shared = "return debug.getmetatable(p1) == debug.getmetatable(p2)";
+            assert!(run::<bool>(thread, shared).unwrap());
+            let name: String = run(thread, "return debug.getmetatable(p1).__name").unwrap();
+            assert_eq!(name, any::type_name::<Point>());
+
+            let err = run::<i64>(thread, "return p1.sum(empty)").unwrap_err();
+            let msg = format!("{} expected, got userdata", any::type_name::<Point>());
+            assert_eq!(err.msg(), Some(&*msg));
+            let err = run::<i64>(thread, "return p1.sum(1)").unwrap_err();
+            assert!(err.msg().unwrap().ends_with("expected, got number"));
+            let err = run::<LuaNil>(thread, "function callback() p1:sum() end p1:call()");
+            let msg = format!("{} is already borrowed", any::type_name::<Point>());
+            assert!(err.unwrap_err().msg().unwrap().contains(&msg));
+            assert_eq!(thread.stack_len(), top);
+
+            // the value is dropped by the finalizer
+            thread.do_string("p2 = nil collectgarbage()", None).unwrap();
+            assert_eq!(counter.get(), 1);
+        })
+        .unwrap();
+        // the other value is dropped when the state is closed
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn test_new_userdata_aligned() {
+        #[derive(Clone, Copy)]
+        #[repr(align(32))]
+        struct Simd([f32; 8]);
+
+        Thread::spawn(move |thread| {
+            let top = thread.stack_len();
+            for i in 0..16 {
+                let data = thread.new_userdata_aligned::<Simd>().unwrap();
+                assert_eq!(data as usize % 32, 0);
+                unsafe { data.write(Simd([i as f32; 8])) };
+                let byte = thread.new_userdata_aligned::<u8>().unwrap();
+                unsafe { byte.write(i) };
+            }
+            for i in 0..16 {
+                let index = top + 1 + 2 * i;
+                let data = thread.to_userdata_aligned::<Simd>(index).unwrap();
+                assert_eq!(unsafe { (*data).0 }, [i as f32; 8]);
+            }
+            thread.push(1i64).unwrap();
+            assert_eq!(thread.to_userdata_aligned::<Simd>(-1), None);
+            thread.push_light_userdata(ptr::null_mut()).unwrap();
+            assert_eq!(thread.to_userdata_aligned::<Simd>(-1), None);
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_userdata_gc_call() {
+        let drops = Rc::new(Cell::new(0));
+        let counter = drops.clone();
+        Thread::spawn(move |thread| {
+            thread.open_lib(StdLib::Base).unwrap();
+            thread.open_lib(StdLib::Debug).unwrap();
+            thread
+                .push_userdata(Point {
+                    x: 1,
+                    y: 2,
+                    drops: counter.clone(),
+                })
+                .unwrap();
+            set_global(thread, "p");
+            thread.push_userdata(Empty).unwrap();
+            set_global(thread, "empty");
+
+            // the finalizer ignores values of other types
+            let chunk = "local gc = debug.getmetatable(p).__gc
+                gc(1) gc(nil) gc(empty) gc({}) gc(io) return p:sum()";
+            assert_eq!(run::<i64>(thread, chunk).unwrap(), 3);
+            assert_eq!(counter.get(), 0);
+
+            // calling it on the value drops the value once
+            run::<LuaNil>(
+                thread,
+                "debug.getmetatable(p).__gc(p) debug.getmetatable(p).__gc(p)",
+            )
+            .unwrap();
+            assert_eq!(counter.get(), 1);
+            let err = run::<i64>(thread, "return p:sum()").unwrap_err();
+            let msg = format!("{} has been dropped", any::type_name::<Point>());
+            assert!(err.msg().unwrap().contains(&msg));
+        })
+        .unwrap();
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_push_userdata_out_of_memory() {
+        let drops = Rc::new(Cell::new(0));
+        let counter = drops.clone();
+        Thread::spawn_with_memory_limit(64 * 1024, move |thread| {
+            let top = thread.stack_len();
+            let err = loop {
+                let point = Point {
+                    x: 1,
+                    y: 2,
+                    drops: counter.clone(),
+                };
+                if let Err(e) = thread.push_userdata(point) {
+                    break e;
+                }
+            };
+            assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+            // the value that could not be pushed is dropped right away
+            assert_eq!(counter.get(), 1);
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+        })
+        .unwrap();
+        assert!(drops.get() > 1);
+    }
+
+    #[test]
+    fn test_userdata_gc_reserved() {
+        struct Gc;
+
+        impl UserData for Gc {
+            fn add_methods(methods: &mut UserDataMethods<'_, Self>) {
+                methods.add_meta_method("__gc", |_, _| Ok(0));
+            }
+        }
+
+        // the metatable is built in a protected call, the panic is returned by spawn
+        match Thread::spawn(move |thread| thread.push_userdata(Gc)) {
+            Err(ThreadError::Panic(payload)) => {
+                let msg = payload.downcast_ref::<String>().unwrap();
+                assert!(msg.contains("the __gc metamethod of userdata is reserved"));
+            }
+            _ => panic!("expected a panic"),
+        }
+    }
+}
@@ -1,9 +1,13 @@
-use crate::thread::{Thread, ThreadRef};
+use crate::{
+    thread::{Reference, ReturnValues, Thread},
+    Error, ErrorKind, Integer, LuaResult, Number,
+};
 
 use std::{
     ascii,
     borrow::Cow,
     cmp::Ordering,
+    ffi::CStr,
     fmt::{self, Pointer, Write},
     iter::{Product, Sum},
     num::ParseFloatError,
@@ -65,6 +69,27 @@ impl ValueType {
             ValueType::Table => sys::LUA_TTABLE,
         }
     }
+
+    /// Returns the name of this value type, as given by `lua_typename`.
+    ///
+    /// # Examples
+    /// ```
+    /// use pollua::value::ValueType;
+    ///
+    /// assert_eq!(ValueType::LightUserdata.name(), "userdata");
+    /// ```
+    pub fn name(self) -> &'static str {
+        // lua_typename does not use the state, the names are static strings of the Lua core
+        let name = unsafe { CStr::from_ptr(sys::lua_typename(ptr::null_mut(), self.code())) };
+        name.to_str().unwrap_or("?")
+    }
+}
+
+impl fmt::Display for ValueType {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 /// A type that can be pushed onto the stack.
@@ -92,25 +117,251 @@ pub trait Value: Sized + private::Sealed {
     }
 }
 
-/// Pushes a value onto the stack
-pub struct Pusher<'a>(pub(crate) ThreadRef<'a>);
+/// A trait for Rust values that can be pushed onto the stack.
+///
+/// Use [`Thread::push`] to push values safely, it grows the stack first.
+///
+/// [`Thread::push`]: ../thread/struct.Thread.html#method.push
+pub trait ToLua {
+    /// Pushes this value onto the stack of `thread`.
+    /// Values holding other values, like tables, grow the stack for them and return
+    /// [`ErrorKind::OutOfMemory`] without pushing anything if it cannot grow.
+    ///
+    /// # Safety
+    /// The stack of `thread` must have room for at least one more value.
+    ///
+    /// [`ErrorKind::OutOfMemory`]: ../enum.ErrorKind.html#variant.OutOfMemory
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()>;
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    /// Pushes the contained value, or `nil` if `None`.
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        match self {
+            Some(value) => value.push(thread),
+            None => LuaNil.push(thread),
+        }
+    }
+}
+
+impl ToLua for () {
+    /// Pushes `nil`.
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        LuaNil.push(thread)
+    }
+}
+
+impl ToLua for bool {
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        sys::lua_pushboolean(thread.as_raw().as_ptr(), self as libc::c_int);
+        Ok(())
+    }
+}
+
+impl ToLua for i64 {
+    /// Pushes this value as a Lua integer,
+    /// truncation may happen depending on the size of [`Integer`].
+    ///
+    /// [`Integer`]: ../type.Integer.html
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        sys::lua_pushinteger(thread.as_raw().as_ptr(), self as Integer);
+        Ok(())
+    }
+}
+
+/// A trait for Rust values that can be read from the stack.
+pub trait FromLua: Sized {
+    /// Reads the value at the given stack `index` without popping it.
+    /// Returns an error of kind [`ErrorKind::Runtime`] if the value cannot be converted.
+    ///
+    /// [`ErrorKind::Runtime`]: ../enum.ErrorKind.html#variant.Runtime
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<Self>;
+}
+
+/// Creates the error returned when the value at `index` is not of the `expected` type.
+pub(crate) fn type_error(thread: &mut Thread, index: libc::c_int, expected: &str) -> Error {
+    let got = unsafe { CStr::from_ptr(sys::luaL_typename(thread.as_raw().as_ptr(), index)) };
+    Error::new(
+        ErrorKind::Runtime,
+        Some(format!(
+            "{} expected, got {}",
+            expected,
+            got.to_string_lossy()
+        )),
+    )
+}
+
+/// A trait for Rust values that are pushed onto the stack as a fixed number of values,
+/// such as the arguments of a call.
+///
+/// Implemented for every [`ToLua`] type, pushed as one value, and for tuples of up to
+/// 8 [`ToLua`] types, pushed in order.
+///
+/// [`ToLua`]: trait.ToLua.html
+pub trait ToLuaMulti {
+    /// Number of values pushed by [`push_multi`].
+    ///
+    /// [`push_multi`]: #tymethod.push_multi
+    const LEN: libc::c_int;
+
+    /// Pushes the values onto the stack of `thread`.
+    /// On error, the values pushed so far are left on the stack.
+    ///
+    /// # Safety
+    /// The stack of `thread` must have room for at least [`LEN`] more values.
+    ///
+    /// [`LEN`]: #associatedconstant.LEN
+    unsafe fn push_multi(self, thread: &mut Thread) -> LuaResult<()>;
+}
+
+impl<T: ToLua> ToLuaMulti for T {
+    const LEN: libc::c_int = 1;
+
+    #[inline]
+    unsafe fn push_multi(self, thread: &mut Thread) -> LuaResult<()> {
+        self.push(thread)
+    }
+}
+
+/// A trait for Rust values read from a fixed number of values returned by a call.
+///
+/// Implemented for every [`FromLua`] type, read from one value, and for tuples of up to
+/// 8 [`FromLua`] types, read in order. This is used by [`Caller::call_ret`] to convert
+/// multiple return values.
+///
+/// [`FromLua`]: trait.FromLua.html
+/// [`Caller::call_ret`]: ../thread/struct.Caller.html#method.call_ret
+pub trait FromLuaMulti: Sized {
+    /// Number of values read by [`from_lua_multi`].
+    ///
+    /// [`from_lua_multi`]: #tymethod.from_lua_multi
+    const LEN: usize;
+
+    /// Converts the [`LEN`] return values starting at position `start`.
+    ///
+    /// [`LEN`]: #associatedconstant.LEN
+    fn from_lua_multi(values: &mut ReturnValues<'_>, start: usize) -> LuaResult<Self>;
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    const LEN: usize = 1;
 
-impl Pusher<'_> {
     #[inline]
-    pub fn push<V: Pushable>(self, value: &V) {
-        value.push(self);
+    fn from_lua_multi(values: &mut ReturnValues<'_>, start: usize) -> LuaResult<Self> {
+        values.value(start)
+    }
+}
+
+macro_rules! tuple_lua_multi_impl {
+    ($len:expr => $($name:ident $pos:tt),+) => {
+        impl<$($name: ToLua),+> ToLuaMulti for ($($name,)+) {
+            const LEN: libc::c_int = $len;
+
+            #[inline]
+            unsafe fn push_multi(self, thread: &mut Thread) -> LuaResult<()> {
+                $(self.$pos.push(thread)?;)+
+                Ok(())
+            }
+        }
+
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            const LEN: usize = $len;
+
+            #[inline]
+            fn from_lua_multi(values: &mut ReturnValues<'_>, start: usize) -> LuaResult<Self> {
+                Ok(($(values.value::<$name>(start + $pos)?,)+))
+            }
+        }
+    };
+}
+
+tuple_lua_multi_impl!(1 => A 0);
+tuple_lua_multi_impl!(2 => A 0, B 1);
+tuple_lua_multi_impl!(3 => A 0, B 1, C 2);
+tuple_lua_multi_impl!(4 => A 0, B 1, C 2, D 3);
+tuple_lua_multi_impl!(5 => A 0, B 1, C 2, D 3, E 4);
+tuple_lua_multi_impl!(6 => A 0, B 1, C 2, D 3, E 4, F 5);
+tuple_lua_multi_impl!(7 => A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple_lua_multi_impl!(8 => A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+impl FromLua for bool {
+    /// Converts the value using Lua's truthiness rules: only `false` and `nil` are false.
+    #[inline]
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<bool> {
+        Ok(unsafe { sys::lua_toboolean(thread.as_raw().as_ptr(), index) } != 0)
+    }
+}
+
+impl FromLua for i64 {
+    /// Reads an integer, floats with an exact integer value and numeric strings are converted.
+    /// Numbers that do not fit in an integer, or have a fractional part, are not rounded:
+    /// they are reported as not representable.
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<i64> {
+        let ptr = thread.as_raw().as_ptr();
+        let mut isnum = 0;
+        let n = unsafe { sys::lua_tointegerx(ptr, index, &mut isnum) };
+        if isnum != 0 {
+            Ok(n as i64)
+        } else if unsafe { sys::lua_isnumber(ptr, index) } != 0 {
+            let msg = String::from("number is not representable as an integer");
+            Err(Error::new(ErrorKind::Runtime, Some(msg)))
+        } else {
+            Err(type_error(thread, index, "integer"))
+        }
+    }
+}
+
+impl FromLua for f64 {
+    #[allow(clippy::unnecessary_cast)] // lua_Number is not always f64
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<f64> {
+        let mut isnum = 0;
+        let n = unsafe { sys::lua_tonumberx(thread.as_raw().as_ptr(), index, &mut isnum) };
+        if isnum == 0 {
+            Err(type_error(thread, index, "number"))
+        } else {
+            Ok(n as f64)
+        }
+    }
+}
+
+impl FromLua for Vec<u8> {
+    /// Reads a string or a number, numbers are converted the same way Lua does.
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<Vec<u8>> {
+        unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            match sys::lua_type(ptr, index) {
+                sys::LUA_TSTRING | sys::LUA_TNUMBER => {
+                    let mut len = 0usize;
+                    // luaL_tolstring does not modify the original value, unlike lua_tolstring.
+                    let s = sys::luaL_tolstring(ptr, index, &mut len);
+                    let bytes = slice::from_raw_parts(s as *const u8, len).to_vec();
+                    // pop the string pushed by luaL_tolstring
+                    sys::lua_pop(ptr, 1);
+                    Ok(bytes)
+                }
+                _ => Err(type_error(thread, index, "string")),
+            }
+        }
     }
 }
 
-/// A trait for values that can be pushed onto the stack.
-pub trait Pushable {
-    fn push(&self, pusher: Pusher);
+impl FromLua for String {
+    /// Reads a string or a number, numbers are converted the same way Lua does.
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD REPLACEMENT CHARACTER`.
+    #[inline]
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<String> {
+        Vec::<u8>::from_lua(thread, index).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
 }
 
 /// A Lua floating-point number.
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct LuaNumber {
-    value: sys::lua_Number,
+    value: Number,
 }
 
 impl Value for LuaNumber {
@@ -161,37 +412,39 @@ impl From<f32> for LuaNumber {
     /// Converts `f32` to `LuaNumber` losslessly.
     #[inline]
     fn from(v: f32) -> LuaNumber {
-        LuaNumber {
-            value: v as sys::lua_Number,
-        }
+        LuaNumber { value: v as Number }
     }
 }
 
 impl From<f64> for LuaNumber {
     /// Converts `f64` to `LuaNumber`,
-    /// truncation may happen depending on the size of [`lua_sys::lua_Number`].
+    /// truncation may happen depending on the size of [`Number`].
+    ///
+    /// [`Number`]: ../type.Number.html
     #[inline]
     fn from(v: f64) -> LuaNumber {
-        LuaNumber {
-            value: v as sys::lua_Number,
-        }
+        LuaNumber { value: v as Number }
     }
 }
 
-impl Into<f32> for LuaNumber {
-    /// Converts `LuaNumber` to `f64`,
-    /// truncation may happen depending on the size of [`lua_sys::lua_Number`].
+impl From<LuaNumber> for f32 {
+    /// Converts `LuaNumber` to `f32`,
+    /// truncation may happen depending on the size of [`Number`].
+    ///
+    /// [`Number`]: ../type.Number.html
     #[inline]
-    fn into(self) -> f32 {
-        self.value as f32
+    #[allow(clippy::unnecessary_cast)] // lua_Number is not always f64
+    fn from(n: LuaNumber) -> f32 {
+        n.value as f32
     }
 }
 
-impl Into<f64> for LuaNumber {
+impl From<LuaNumber> for f64 {
     /// Converts `LuaNumber` to `f64` losslessly.
     #[inline]
-    fn into(self) -> f64 {
-        self.value as f64
+    #[allow(clippy::unnecessary_cast)] // lua_Number is not always f64
+    fn from(n: LuaNumber) -> f64 {
+        n.value as f64
     }
 }
 
@@ -201,7 +454,7 @@ impl FromStr for LuaNumber {
     #[inline]
     fn from_str(src: &str) -> Result<LuaNumber, Self::Err> {
         Ok(LuaNumber {
-            value: sys::lua_Number::from_str(src)?,
+            value: Number::from_str(src)?,
         })
     }
 }
@@ -291,29 +544,37 @@ macro_rules! number_sum {
     };
 }
 
-number_sum!(LuaNumber, Product, product, sys::lua_Number);
-number_sum!(LuaNumber, Sum, sum, sys::lua_Number);
+number_sum!(LuaNumber, Product, product, Number);
+number_sum!(LuaNumber, Sum, sum, Number);
 
-impl Pushable for LuaNumber {
+impl FromLua for LuaNumber {
     #[inline]
-    fn push(&self, mut pusher: Pusher) {
-        unsafe { sys::lua_pushnumber(pusher.0.as_raw().as_mut(), self.value) }
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<LuaNumber> {
+        f64::from_lua(thread, index).map(LuaNumber::from)
     }
 }
 
-macro_rules! lua_number_pushable_impl {
+impl ToLua for LuaNumber {
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        sys::lua_pushnumber(thread.as_raw().as_ptr(), self.value);
+        Ok(())
+    }
+}
+
+macro_rules! lua_number_to_lua_impl {
     ($type:ty) => {
-        impl Pushable for $type {
+        impl ToLua for $type {
             #[inline]
-            fn push(&self, pusher: Pusher) {
-                LuaNumber::from(*self).push(pusher)
+            unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+                LuaNumber::from(self).push(thread)
             }
         }
     };
 }
 
-lua_number_pushable_impl!(f32);
-lua_number_pushable_impl!(f64);
+lua_number_to_lua_impl!(f32);
+lua_number_to_lua_impl!(f64);
 
 /// The Lua `nil` value.
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
@@ -337,10 +598,22 @@ impl Value for LuaNil {
     }
 }
 
-impl Pushable for LuaNil {
+impl FromLua for LuaNil {
+    #[inline]
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<LuaNil> {
+        if unsafe { sys::lua_isnil(thread.as_raw().as_ptr(), index) } != 0 {
+            Ok(LuaNil)
+        } else {
+            Err(type_error(thread, index, "nil"))
+        }
+    }
+}
+
+impl ToLua for LuaNil {
     #[inline]
-    fn push(&self, mut pusher: Pusher) {
-        unsafe { sys::lua_pushnil(pusher.0.as_raw().as_ptr()) }
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        sys::lua_pushnil(thread.as_raw().as_ptr());
+        Ok(())
     }
 }
 
@@ -421,7 +694,7 @@ impl LuaStr {
     /// [`String`]: std::string::String
     /// [U+FFFD]: std::char::REPLACEMENT_CHARACTER
     #[inline]
-    pub fn to_string_lossy(&self) -> Cow<str> {
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(self.as_bytes())
     }
 }
@@ -473,45 +746,44 @@ impl Eq for LuaStr {}
 impl PartialOrd for LuaStr {
     #[inline]
     fn partial_cmp(&self, other: &LuaStr) -> Option<Ordering> {
-        self.as_bytes().partial_cmp(&other.as_bytes())
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for LuaStr {
     #[inline]
     fn cmp(&self, other: &LuaStr) -> Ordering {
-        self.as_bytes().cmp(&other.as_bytes())
+        self.as_bytes().cmp(other.as_bytes())
     }
 }
 
-impl<'a> Pushable for &'a LuaStr {
+impl ToLua for &LuaStr {
     #[inline]
-    fn push(&self, mut pusher: Pusher) {
-        unsafe {
-            sys::lua_pushlstring(
-                pusher.0.as_raw().as_ptr(),
-                self.repr.0.as_ptr() as *const libc::c_char,
-                self.repr.0.len(),
-            );
-        }
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        sys::lua_pushlstring(
+            thread.as_raw().as_ptr(),
+            self.repr.0.as_ptr() as *const libc::c_char,
+            self.repr.0.len(),
+        );
+        Ok(())
     }
 }
 
-macro_rules! luastr_push_impl {
+macro_rules! luastr_to_lua_impl {
     ($type:ty) => {
-        impl Pushable for $type {
+        impl ToLua for $type {
             #[inline]
-            fn push(&self, pusher: Pusher) {
-                LuaStr::from_bytes(self).push(pusher)
+            unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+                LuaStr::from_bytes(&self).push(thread)
             }
         }
     };
 }
 
-luastr_push_impl!(&'_ [u8]);
-luastr_push_impl!(&'_ str);
-luastr_push_impl!(String);
-luastr_push_impl!(Vec<u8>);
+luastr_to_lua_impl!(&'_ [u8]);
+luastr_to_lua_impl!(&'_ str);
+luastr_to_lua_impl!(String);
+luastr_to_lua_impl!(Vec<u8>);
 
 /// `*mut T` lua wrapper type.
 /// Like `*mut T`, `LightUserdata<T>` is invariant over `T`
@@ -633,6 +905,158 @@ impl<T: ?Sized> From<NonNull<T>> for LightUserdata<T> {
     }
 }
 
+/// A Lua number with its subtype, returned by [`Thread::to_number_kind`].
+///
+/// Lua 5.3 numbers are either integers or floats, `2` and `2.0` are equal
+/// but `math.type` tells them apart.
+///
+/// [`Thread::to_number_kind`]: ../thread/struct.Thread.html#method.to_number_kind
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NumberKind {
+    /// An integer, only produced for Lua 5.3 and later.
+    Integer(Integer),
+    /// A float, the only kind of number before Lua 5.3.
+    Float(Number),
+}
+
+impl ToLua for NumberKind {
+    /// Pushes this number with its subtype.
+    #[inline]
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        match self {
+            NumberKind::Integer(n) => sys::lua_pushinteger(thread.as_raw().as_ptr(), n),
+            NumberKind::Float(n) => sys::lua_pushnumber(thread.as_raw().as_ptr(), n),
+        }
+        Ok(())
+    }
+}
+
+/// A Lua value of any type, for values whose type is not known ahead of time.
+///
+/// Strings are copied, while tables, functions, full userdata and threads are kept alive in
+/// the registry through a [`Reference`].
+///
+/// # Examples
+/// ```
+/// use pollua::{thread::LoadingMode, value::LuaValue, Thread};
+///
+/// Thread::spawn(move |thread| {
+///     let values: Vec<LuaValue> = thread
+///         .caller_load("return 1, 'two', {}", None, LoadingMode::Text)
+///         .and_then(|c| c.call_ret_n(3))
+///         .unwrap();
+///     match &values[..] {
+///         [LuaValue::Integer(1), LuaValue::String(s), LuaValue::Table(_)] => assert_eq!(s, b"two"),
+///         _ => panic!("unexpected values: {:?}", values),
+///     }
+/// }).unwrap()
+/// ```
+///
+/// [`Reference`]: ../thread/struct.Reference.html
+#[derive(Debug)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    /// An integer, only produced for Lua 5.3 and later.
+    Integer(Integer),
+    Number(Number),
+    String(Vec<u8>),
+    LightUserdata(LightUserdata<libc::c_void>),
+    Table(Reference),
+    Function(Reference),
+    Userdata(Reference),
+    Thread(Reference),
+}
+
+impl LuaValue {
+    /// Returns the Lua type of this value.
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            LuaValue::Nil => ValueType::Nil,
+            LuaValue::Boolean(_) => ValueType::Boolean,
+            LuaValue::Integer(_) | LuaValue::Number(_) => ValueType::Number,
+            LuaValue::String(_) => ValueType::String,
+            LuaValue::LightUserdata(_) => ValueType::LightUserdata,
+            LuaValue::Table(_) => ValueType::Table,
+            LuaValue::Function(_) => ValueType::Function,
+            LuaValue::Userdata(_) => ValueType::Userdata,
+            LuaValue::Thread(_) => ValueType::Thread,
+        }
+    }
+
+    /// Returns true if this value is nil.
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+}
+
+impl Default for LuaValue {
+    /// Returns `LuaValue::Nil`.
+    #[inline]
+    fn default() -> LuaValue {
+        LuaValue::Nil
+    }
+}
+
+impl FromLua for LuaValue {
+    fn from_lua(thread: &mut Thread, index: libc::c_int) -> LuaResult<LuaValue> {
+        unsafe {
+            let ptr = thread.as_raw().as_ptr();
+            let value = match sys::lua_type(ptr, index) {
+                sys::LUA_TNONE | sys::LUA_TNIL => LuaValue::Nil,
+                sys::LUA_TBOOLEAN => LuaValue::Boolean(sys::lua_toboolean(ptr, index) != 0),
+                #[cfg(LUA_VERSION = "5.3")]
+                sys::LUA_TNUMBER if sys::lua_isinteger(ptr, index) != 0 => {
+                    LuaValue::Integer(sys::lua_tointeger(ptr, index))
+                }
+                sys::LUA_TNUMBER => LuaValue::Number(sys::lua_tonumber(ptr, index)),
+                sys::LUA_TSTRING => LuaValue::String(Vec::<u8>::from_lua(thread, index)?),
+                sys::LUA_TLIGHTUSERDATA => {
+                    LuaValue::LightUserdata(LightUserdata::new(sys::lua_touserdata(ptr, index)))
+                }
+                code => {
+                    thread.ensure_stack(1)?;
+                    sys::lua_pushvalue(ptr, index);
+                    let reference = thread.reference_top();
+                    match code {
+                        sys::LUA_TTABLE => LuaValue::Table(reference),
+                        sys::LUA_TFUNCTION => LuaValue::Function(reference),
+                        sys::LUA_TTHREAD => LuaValue::Thread(reference),
+                        _ => LuaValue::Userdata(reference),
+                    }
+                }
+            };
+            Ok(value)
+        }
+    }
+}
+
+impl ToLua for LuaValue {
+    /// Pushes the value.
+    ///
+    /// # Panics
+    /// This panics if the value holds a [`Reference`] created by another Lua state.
+    ///
+    /// [`Reference`]: ../thread/struct.Reference.html
+    unsafe fn push(self, thread: &mut Thread) -> LuaResult<()> {
+        let ptr = thread.as_raw().as_ptr();
+        match self {
+            LuaValue::Nil => sys::lua_pushnil(ptr),
+            LuaValue::Boolean(b) => return b.push(thread),
+            LuaValue::Integer(n) => sys::lua_pushinteger(ptr, n),
+            LuaValue::Number(n) => sys::lua_pushnumber(ptr, n),
+            LuaValue::String(s) => return s.push(thread),
+            LuaValue::LightUserdata(p) => sys::lua_pushlightuserdata(ptr, p.as_ptr()),
+            LuaValue::Table(r)
+            | LuaValue::Function(r)
+            | LuaValue::Userdata(r)
+            | LuaValue::Thread(r) => return thread.push_reference(&r),
+        }
+        Ok(())
+    }
+}
+
 mod private {
     use super::*;
     pub trait Sealed {}
@@ -641,3 +1065,109 @@ mod private {
     impl Sealed for LuaNil {}
     impl Sealed for LuaStr {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_and_get<V: ToLua, R: FromLua>(thread: &mut Thread, value: V) -> LuaResult<R> {
+        unsafe {
+            let top = sys::lua_gettop(thread.as_raw().as_ptr());
+            value.push(thread)?;
+            let result = R::from_lua(thread, -1);
+            sys::lua_pop(thread.as_raw().as_ptr(), 1);
+            assert_eq!(sys::lua_gettop(thread.as_raw().as_ptr()), top);
+            result
+        }
+    }
+
+    #[test]
+    fn test_value_type_name() {
+        Thread::spawn(move |thread| {
+            let ptr = thread.as_raw().as_ptr();
+            for code in sys::LUA_TNIL..=sys::LUA_TTHREAD {
+                let name = unsafe { CStr::from_ptr(sys::lua_typename(ptr, code)) };
+                let value_type = ValueType::from_code(code).unwrap();
+                assert_eq!(value_type.name(), name.to_str().unwrap());
+                assert_eq!(value_type.to_string(), value_type.name());
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_to_from_lua() {
+        Thread::spawn(move |thread| {
+            assert_eq!(push_and_get::<_, i64>(thread, 42i64).unwrap(), 42);
+            assert_eq!(push_and_get::<_, i64>(thread, 8.0).unwrap(), 8);
+            assert_eq!(push_and_get::<_, i64>(thread, "-7").unwrap(), -7);
+            assert_eq!(push_and_get::<_, f64>(thread, 2.5).unwrap(), 2.5);
+            assert_eq!(push_and_get::<_, f64>(thread, 3i64).unwrap(), 3.0);
+            assert!(push_and_get::<_, bool>(thread, true).unwrap());
+            assert!(!push_and_get::<_, bool>(thread, ()).unwrap());
+            assert!(push_and_get::<_, bool>(thread, 0i64).unwrap());
+            assert_eq!(push_and_get::<_, String>(thread, "text").unwrap(), "text");
+            assert_eq!(push_and_get::<_, String>(thread, 12i64).unwrap(), "12");
+            assert_eq!(
+                push_and_get::<_, Vec<u8>>(thread, &b"a\0b"[..]).unwrap(),
+                b"a\0b"
+            );
+            assert_eq!(
+                push_and_get::<_, LuaNil>(thread, None::<i64>).unwrap(),
+                LuaNil
+            );
+            assert_eq!(push_and_get::<_, i64>(thread, Some(5i64)).unwrap(), 5);
+
+            let err = push_and_get::<_, i64>(thread, 1.5).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Runtime);
+            assert_eq!(err.msg(), Some("number is not representable as an integer"));
+            let err = push_and_get::<_, String>(thread, true).unwrap_err();
+            assert_eq!(err.msg(), Some("string expected, got boolean"));
+            let err = push_and_get::<_, f64>(thread, LuaNil).unwrap_err();
+            assert_eq!(err.msg(), Some("number expected, got nil"));
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lua_value() {
+        Thread::spawn(move |thread| {
+            assert!(push_and_get::<_, LuaValue>(thread, LuaNil)
+                .unwrap()
+                .is_nil());
+            match push_and_get(thread, true).unwrap() {
+                LuaValue::Boolean(true) => (),
+                v => panic!("unexpected value: {:?}", v),
+            }
+            match push_and_get(thread, 3i64).unwrap() {
+                LuaValue::Integer(3) => (),
+                v => panic!("unexpected value: {:?}", v),
+            }
+            match push_and_get(thread, 0.5).unwrap() {
+                LuaValue::Number(n) => assert_eq!(n, 0.5),
+                v => panic!("unexpected value: {:?}", v),
+            }
+            match push_and_get(thread, "text").unwrap() {
+                LuaValue::String(s) => assert_eq!(s, b"text"),
+                v => panic!("unexpected value: {:?}", v),
+            }
+
+            let top = thread.stack_len();
+            thread.new_table().into_raw();
+            let table = LuaValue::from_lua(thread, -1).unwrap();
+            assert_eq!(table.value_type(), ValueType::Table);
+            unsafe { table.push(thread).unwrap() };
+            assert!(thread.raw_equal(-1, -2));
+            unsafe { sys::lua_settop(thread.as_raw().as_ptr(), top) };
+
+            let mut x = 0;
+            let light = &mut x as *mut i32 as *mut libc::c_void;
+            let value = LuaValue::LightUserdata(LightUserdata::new(light));
+            match push_and_get(thread, value).unwrap() {
+                LuaValue::LightUserdata(p) => assert_eq!(p.as_ptr(), light),
+                v => panic!("unexpected value: {:?}", v),
+            }
+        })
+        .unwrap()
+    }
+}
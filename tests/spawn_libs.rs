@@ -0,0 +1,71 @@
+extern crate pollua;
+
+use pollua::{
+    thread::{StdLib, Thread},
+    ErrorKind,
+};
+use std::time::{Duration, Instant};
+
+const SPAWNS: u32 = 2000;
+
+/// Returns the total time spent spawning `SPAWNS` threads with `spawn`.
+fn time_spawns<F: Fn()>(spawn: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..SPAWNS {
+        spawn();
+    }
+    start.elapsed()
+}
+
+#[test]
+fn test_spawn_with_libs() {
+    Thread::spawn_with_libs(&[StdLib::Base, StdLib::Math], |thread| {
+        thread
+            .do_string(
+                "assert(math.max(1, 2) == 2 and type(print) == 'function')",
+                None,
+            )
+            .unwrap();
+        for lib in &[
+            "string",
+            "table",
+            "io",
+            "os",
+            "coroutine",
+            "debug",
+            "package",
+        ] {
+            let code = format!("assert({} == nil)", lib);
+            thread.do_string(&code, None).unwrap();
+        }
+    })
+    .unwrap();
+
+    // no library at all
+    Thread::spawn_with_libs(&[], |thread| {
+        let err = thread.do_string("print(1)", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Runtime);
+    })
+    .unwrap();
+}
+
+/// Prints the cost of spawning threads with all the libraries and with only the base library.
+/// Timings depend on the machine, so nothing is asserted.
+/// Run with `cargo test --release -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_spawn_with_libs() {
+    let all = time_spawns(|| {
+        Thread::spawn(|thread| thread.open_libs().unwrap()).unwrap();
+    });
+    let base = time_spawns(|| {
+        Thread::spawn_with_libs(&[StdLib::Base], |_| ()).unwrap();
+    });
+    let none = time_spawns(|| {
+        Thread::spawn(|_| ()).unwrap();
+    });
+    println!("{} spawns:", SPAWNS);
+    println!("  open_libs:                  {:?}", all);
+    println!("  spawn_with_libs(&[Base]):   {:?}", base);
+    println!("  no library:                 {:?}", none);
+}
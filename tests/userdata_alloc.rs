@@ -0,0 +1,60 @@
+extern crate pollua;
+
+use pollua::thread::{Thread, UserData};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const PUSHES: usize = 1000;
+
+/// Counts the allocations made by the current process.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[allow(dead_code)]
+struct Point(i64);
+
+impl UserData for Point {}
+
+#[test]
+fn test_userdata_metatable_reused() {
+    Thread::spawn(|thread| {
+        // creates the metatable
+        thread.push_userdata(Point(0)).unwrap();
+        thread.remove(-1);
+
+        // finalizers would make Lua allocate its own bookkeeping
+        thread.gc_stop();
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        for i in 0..PUSHES as i64 {
+            thread.push_userdata(Point(i)).unwrap();
+            thread.remove(-1);
+        }
+        let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+        thread.gc_restart();
+        // the userdata block and the boxed value, nothing for the metatable
+        assert!(
+            allocations <= 2 * PUSHES,
+            "{} allocations for {} pushes",
+            allocations,
+            PUSHES
+        );
+    })
+    .unwrap();
+}